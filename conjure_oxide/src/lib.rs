@@ -2,6 +2,8 @@
 
 pub use conjure_core::ast;
 pub use conjure_core::error::Error;
+#[cfg(feature = "html-report")]
+pub use conjure_core::html_report;
 pub use conjure_core::metadata::Metadata;
 pub use conjure_core::model::Model;
 pub use conjure_core::parse::{get_example_model, get_example_model_by_path, model_from_json};
@@ -14,6 +16,7 @@ pub use conjure_core::rule_engine::{
 pub use conjure_core::rules;
 pub use conjure_core::solver;
 pub use conjure_core::solver::SolverFamily;
+pub use conjure_core::viz;
 
 pub mod find_conjure;
 pub mod utils;
@@ -0,0 +1,150 @@
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use conjure_core::metadata::Summary;
+use conjure_oxide::ast::*;
+use conjure_oxide::Metadata;
+use uniplate::uniplate::Uniplate;
+
+fn reference(name: &str) -> Expression {
+    Expression::Reference(Metadata::new(), Name::UserName(String::from(name)))
+}
+
+fn constant(value: i32) -> Expression {
+    Expression::Constant(Metadata::new(), Constant::Int(value))
+}
+
+#[test]
+fn annotations_describe_subtree() {
+    let sum = Expression::Sum(Metadata::new(), vec![constant(1), constant(2)]);
+    let expr = Expression::Lt(
+        Metadata::new(),
+        Box::new(sum.clone()),
+        Box::new(reference("x")),
+    );
+
+    assert_eq!(sum.size(), 3);
+    assert_eq!(sum.depth(), 2);
+    assert!(!sum.contains_reference());
+
+    assert_eq!(expr.size(), 5);
+    assert_eq!(expr.depth(), 3);
+    assert!(expr.contains_reference());
+}
+
+#[test]
+fn annotations_are_not_kept_by_cloned_metadata() {
+    let old_sum = Expression::Sum(Metadata::new(), vec![constant(1)]);
+    let Expression::Sum(metadata, _) = &old_sum else {
+        unreachable!()
+    };
+    let sum = Expression::Sum(metadata.clone(), vec![constant(1)]);
+    assert!(!sum.contains_reference());
+
+    // A rule that reuses the metadata of the old expression for a new one
+    let Expression::Sum(metadata, _) = &sum else {
+        unreachable!()
+    };
+    let new_sum = Expression::Sum(metadata.clone(), vec![constant(1), reference("x")]);
+    assert_eq!(new_sum.size(), 3);
+    assert!(new_sum.contains_reference());
+}
+
+#[test]
+fn subtree_hash_ignores_metadata() {
+    let lt =
+        |a: Expression, b: Expression| Expression::Lt(Metadata::new(), Box::new(a), Box::new(b));
+    let mut clean = Metadata::new();
+    clean.clean = true;
+
+    let expr = lt(constant(1), reference("x"));
+    let same = Expression::Lt(clean, Box::new(constant(1)), Box::new(reference("x")));
+    assert_eq!(expr.subtree_hash(), same.subtree_hash());
+
+    assert_ne!(
+        expr.subtree_hash(),
+        lt(reference("x"), constant(1)).subtree_hash()
+    );
+    assert_ne!(
+        expr.subtree_hash(),
+        lt(constant(2), reference("x")).subtree_hash()
+    );
+    assert_ne!(
+        Expression::And(Metadata::new(), vec![expr.clone()]).subtree_hash(),
+        Expression::Or(Metadata::new(), vec![expr]).subtree_hash()
+    );
+}
+
+#[test]
+fn child_matches_children() {
+    let sum_eq = Expression::SumEq(
+        Metadata::new(),
+        vec![constant(1), reference("x")],
+        Box::new(constant(2)),
+    );
+    let ineq = Expression::Ineq(
+        Metadata::new(),
+        Box::new(reference("x")),
+        Box::new(reference("y")),
+        Box::new(constant(3)),
+    );
+    let not = Expression::Not(Metadata::new(), Box::new(ineq.clone()));
+
+    for expr in [sum_eq, ineq, not, constant(4)] {
+        let children: Vec<Expression> = (0..).map_while(|i| expr.child(i).cloned()).collect();
+        assert_eq!(children, expr.children());
+    }
+}
+
+// Counts the expressions it has been computed for, to check which summaries are cached
+static REFERENCED_COMPUTED: AtomicUsize = AtomicUsize::new(0);
+
+struct Referenced(BTreeSet<Name>);
+
+impl Summary for Referenced {
+    fn of_node(expression: &Expression) -> Self {
+        REFERENCED_COMPUTED.fetch_add(1, Ordering::SeqCst);
+        match expression {
+            Expression::Reference(_, name) => Referenced(BTreeSet::from([name.clone()])),
+            _ => Referenced(BTreeSet::new()),
+        }
+    }
+
+    fn combine(&mut self, child: &Self) {
+        self.0.extend(child.0.iter().cloned());
+    }
+}
+
+#[test]
+fn summaries_are_cached_and_recombined_along_the_spine() {
+    let names = |expr: &Expression| expr.summary::<Referenced>().0.clone();
+    let sum = Expression::Sum(Metadata::new(), vec![constant(1), reference("x")]);
+    let mut expr = Expression::Lt(Metadata::new(), Box::new(sum), Box::new(reference("y")));
+
+    let before = REFERENCED_COMPUTED.load(Ordering::SeqCst);
+    let xy = BTreeSet::from([
+        Name::UserName(String::from("x")),
+        Name::UserName(String::from("y")),
+    ]);
+    assert_eq!(names(&expr), xy);
+    assert_eq!(REFERENCED_COMPUTED.load(Ordering::SeqCst) - before, 5);
+    assert_eq!(names(&expr), xy);
+    assert_eq!(REFERENCED_COMPUTED.load(Ordering::SeqCst) - before, 5);
+
+    // Replace `y` with `z`, as the rewriter does
+    let mut buffer = Vec::new();
+    expr.take_children(&mut buffer);
+    buffer[1] = reference("z");
+    expr.restore_children(&mut buffer, 0);
+
+    let before = REFERENCED_COMPUTED.load(Ordering::SeqCst);
+    assert_eq!(
+        names(&expr),
+        BTreeSet::from([
+            Name::UserName(String::from("x")),
+            Name::UserName(String::from("z")),
+        ])
+    );
+    // Only the new child and the root are computed again
+    assert_eq!(REFERENCED_COMPUTED.load(Ordering::SeqCst) - before, 2);
+}
@@ -0,0 +1,633 @@
+//! The rule sets and expressions shared by the tests of the rewriter. Each rule set gives the
+//! rewriter one kind of rule to handle, such as rules that never reach a fixpoint, panic, or are
+//! slow, so each test file only uses some of them.
+#![allow(dead_code)]
+
+use std::sync::{Arc, Mutex};
+use std::thread::{self, ThreadId};
+use std::time::Duration;
+
+use conjure_core::meta::{Checkpointable, DerivedMeta, DiffMeta, MetaInvalidation, MetaListener};
+use conjure_oxide::{
+    ast::*, get_rule_set_by_name, register_rule, register_rule_set, rule_engine::Subtree,
+    ApplicationError, ApplicationResult, Metadata, Model, Reduction, RuleSet,
+};
+use uniplate::uniplate::Uniplate;
+
+register_rule_set!("PingPong", 0, ());
+
+#[register_rule(("PingPong", 100))]
+pub fn lt_to_gt(expr: &Expression, _: &Model) -> ApplicationResult {
+    match expr {
+        Expression::Lt(_, a, b) => Ok(Reduction::pure(Expression::Gt(
+            Metadata::new(),
+            b.clone(),
+            a.clone(),
+        ))),
+        _ => Err(ApplicationError::RuleNotApplicable),
+    }
+}
+
+#[register_rule(("PingPong", 100))]
+pub fn gt_to_lt(expr: &Expression, _: &Model) -> ApplicationResult {
+    match expr {
+        Expression::Gt(_, a, b) => Ok(Reduction::pure(Expression::Lt(
+            Metadata::new(),
+            b.clone(),
+            a.clone(),
+        ))),
+        _ => Err(ApplicationError::RuleNotApplicable),
+    }
+}
+
+register_rule_set!("NoOp", 0, ());
+
+#[register_rule(("NoOp", 100))]
+pub fn lt_identity(expr: &Expression, _: &Model) -> ApplicationResult {
+    match expr {
+        Expression::Lt(_, _, _) => Ok(Reduction::pure(expr.clone())),
+        _ => Err(ApplicationError::RuleNotApplicable),
+    }
+}
+
+register_rule_set!("Panic", 0, ());
+
+#[register_rule(("Panic", 100))]
+pub fn lt_panic(expr: &Expression, _: &Model) -> ApplicationResult {
+    match expr {
+        Expression::Lt(_, _, _) => panic!("lt_panic always panics"),
+        _ => Err(ApplicationError::RuleNotApplicable),
+    }
+}
+
+register_rule_set!("Grow", 0, ());
+
+#[register_rule(("Grow", 100))]
+pub fn lt_double_not(expr: &Expression, _: &Model) -> ApplicationResult {
+    match expr {
+        Expression::Lt(_, a, b) => Ok(Reduction::pure(Expression::Not(
+            Metadata::new(),
+            Box::new(Expression::Not(
+                Metadata::new(),
+                Box::new(Expression::Lt(Metadata::new(), a.clone(), b.clone())),
+            )),
+        ))),
+        _ => Err(ApplicationError::RuleNotApplicable),
+    }
+}
+
+register_rule_set!("Slow", 0, ());
+
+#[register_rule(("Slow", 100))]
+pub fn slow_lt_to_gt(expr: &Expression, mdl: &Model) -> ApplicationResult {
+    std::thread::sleep(Duration::from_millis(50));
+    lt_to_gt(expr, mdl)
+}
+
+register_rule_set!("Failing", 0, ());
+
+#[register_rule(("Failing", 100))]
+pub fn lt_bound_error(expr: &Expression, _: &Model) -> ApplicationResult {
+    match expr {
+        Expression::Lt(_, _, _) => Err(ApplicationError::BoundError),
+        _ => Err(ApplicationError::RuleNotApplicable),
+    }
+}
+
+register_rule_set!("Hot", 0, ());
+
+#[register_rule(("Hot", 100))]
+pub fn cold_neq_to_eq(expr: &Expression, _: &Model) -> ApplicationResult {
+    match expr {
+        Expression::Neq(_, a, b) => Ok(Reduction::pure(Expression::Not(
+            Metadata::new(),
+            Box::new(Expression::Eq(Metadata::new(), a.clone(), b.clone())),
+        ))),
+        _ => Err(ApplicationError::RuleNotApplicable),
+    }
+}
+
+#[register_rule(("Hot", 100))]
+pub fn hot_lt_to_gt(expr: &Expression, mdl: &Model) -> ApplicationResult {
+    lt_to_gt(expr, mdl)
+}
+
+register_rule_set!("Pure", 0, ());
+
+#[register_rule(("Pure", 100), pure)]
+pub fn pure_lt_to_gt(expr: &Expression, mdl: &Model) -> ApplicationResult {
+    lt_to_gt(expr, mdl)
+}
+
+register_rule_set!("PurePingPong", 0, ());
+
+#[register_rule(("PurePingPong", 100), pure)]
+pub fn pure_ping_lt_to_gt(expr: &Expression, mdl: &Model) -> ApplicationResult {
+    lt_to_gt(expr, mdl)
+}
+
+#[register_rule(("PurePingPong", 100), pure)]
+pub fn pure_pong_gt_to_lt(expr: &Expression, mdl: &Model) -> ApplicationResult {
+    gt_to_lt(expr, mdl)
+}
+
+register_rule_set!("Parallel", 0, ());
+
+#[register_rule(("Parallel", 100), pure)]
+pub fn parallel_lt_to_gt(expr: &Expression, mdl: &Model) -> ApplicationResult {
+    lt_to_gt(expr, mdl)
+}
+
+#[register_rule(("Parallel", 50), pure)]
+pub fn parallel_lt_double_not(expr: &Expression, mdl: &Model) -> ApplicationResult {
+    lt_double_not(expr, mdl)
+}
+
+register_rule_set!("ParallelPanic", 0, ());
+
+#[register_rule(("ParallelPanic", 100), pure)]
+pub fn parallel_panic_lt_to_gt(expr: &Expression, mdl: &Model) -> ApplicationResult {
+    lt_to_gt(expr, mdl)
+}
+
+#[register_rule(("ParallelPanic", 50), pure)]
+pub fn parallel_lt_panic(expr: &Expression, mdl: &Model) -> ApplicationResult {
+    lt_panic(expr, mdl)
+}
+
+register_rule_set!("Reach", 0, ());
+
+#[register_rule(("Reach", 100), applies_to(Lt), produces(Gt))]
+pub fn reach_lt_to_gt(expr: &Expression, mdl: &Model) -> ApplicationResult {
+    lt_to_gt(expr, mdl)
+}
+
+// Nothing produces Neq, so this rule can never apply to a model without one
+#[register_rule(("Reach", 100), applies_to(Neq), produces(Not, Eq))]
+pub fn reach_neq_to_eq(expr: &Expression, mdl: &Model) -> ApplicationResult {
+    cold_neq_to_eq(expr, mdl)
+}
+
+register_rule_set!("Subtree", 0, ());
+
+#[register_rule(("Subtree", 100))]
+pub fn subtree_lt_to_gt(expr: &mut Subtree, _: &Model) -> ApplicationResult {
+    match expr.take_if(|e| matches!(e, Expression::Lt(_, _, _))) {
+        Some(Expression::Lt(_, ref mut a, ref mut b)) => Ok(Reduction::pure(Expression::Gt(
+            Metadata::new(),
+            std::mem::take(b),
+            std::mem::take(a),
+        ))),
+        _ => Err(ApplicationError::RuleNotApplicable),
+    }
+}
+
+register_rule_set!("Greedy", 0, ());
+
+#[register_rule(("Greedy", 100))]
+pub fn greedy_lt(expr: &mut Subtree, _: &Model) -> ApplicationResult {
+    // Takes the expression, but never rewrites it
+    expr.take_if(|e| matches!(e, Expression::Lt(_, _, _)));
+    Err(ApplicationError::RuleNotApplicable)
+}
+
+register_rule_set!("Normalise", 0, ());
+
+#[register_rule(("Normalise", 100))]
+pub fn take_lt_identity(expr: &mut Subtree, _: &Model) -> ApplicationResult {
+    // "Normalises" an expression that is already normal
+    match expr.take_if(|e| matches!(e, Expression::Lt(_, _, _))) {
+        Some(lt) => Ok(Reduction::pure(lt)),
+        None => Err(ApplicationError::RuleNotApplicable),
+    }
+}
+
+#[register_rule(("Normalise", 50))]
+pub fn normalise_lt_to_gt(expr: &Expression, mdl: &Model) -> ApplicationResult {
+    lt_to_gt(expr, mdl)
+}
+
+register_rule_set!("Threads", 0, ());
+
+/// The threads rules in the "Threads" rule set were tried on.
+pub static TRIAL_THREADS: Mutex<Vec<ThreadId>> = Mutex::new(Vec::new());
+
+#[register_rule(("Threads", 100), pure)]
+pub fn threads_lt_to_gt(expr: &Expression, mdl: &Model) -> ApplicationResult {
+    if let Ok(mut threads) = TRIAL_THREADS.lock() {
+        threads.push(thread::current().id());
+    }
+    lt_to_gt(expr, mdl)
+}
+
+#[register_rule(("Threads", 50), pure)]
+pub fn threads_lt_double_not(expr: &Expression, mdl: &Model) -> ApplicationResult {
+    if let Ok(mut threads) = TRIAL_THREADS.lock() {
+        threads.push(thread::current().id());
+    }
+    lt_double_not(expr, mdl)
+}
+
+register_rule_set!("Aux", 0, ());
+
+pub fn aux() -> Name {
+    Name::UserName(String::from("aux"))
+}
+
+/// Applies only once `aux_lt_to_gt` has added `aux` to the model.
+#[register_rule(("Aux", 100))]
+pub fn aux_eq_to_neq(expr: &Expression, mdl: &Model) -> ApplicationResult {
+    match expr {
+        Expression::Eq(metadata, a, b) if mdl.variables.contains_key(&aux()) => Ok(
+            Reduction::pure(Expression::Neq(metadata.clone(), a.clone(), b.clone())),
+        ),
+        _ => Err(ApplicationError::RuleNotApplicable),
+    }
+}
+
+#[register_rule(("Aux", 100))]
+pub fn aux_lt_to_gt(expr: &Expression, mdl: &Model) -> ApplicationResult {
+    let Ok(reduction) = lt_to_gt(expr, mdl) else {
+        return Err(ApplicationError::RuleNotApplicable);
+    };
+    let domain = Domain::IntDomain(vec![Range::Bounded(0, 1)]);
+    let symbols = SymbolTable::from([(aux(), DecisionVariable::new(domain))]);
+    Ok(Reduction::with_symbols(reduction.new_expression, symbols))
+}
+
+register_rule_set!("MutModel", 0, ());
+
+/// Changes the model, then turns out not to apply.
+#[register_rule(("MutModel", 100))]
+pub fn mut_model_declines(_: &Expression, mdl: &mut Model) -> ApplicationResult {
+    let domain = Domain::IntDomain(vec![Range::Bounded(0, 1)]);
+    mdl.add_variable(
+        Name::UserName(String::from("declined")),
+        DecisionVariable::new(domain),
+    );
+    Err(ApplicationError::RuleNotApplicable)
+}
+
+#[register_rule(("MutModel", 50))]
+pub fn mut_model_lt_to_gt(expr: &Expression, mdl: &mut Model) -> ApplicationResult {
+    let reduction = lt_to_gt(expr, mdl)?;
+    let domain = Domain::IntDomain(vec![Range::Bounded(0, 1)]);
+    mdl.add_variable(aux(), DecisionVariable::new(domain.clone()));
+    let name = mdl.gensym();
+    mdl.add_variable(name, DecisionVariable::new(domain));
+    Ok(reduction)
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LtRewrites(pub usize);
+
+impl DiffMeta for LtRewrites {
+    fn diff(&self, previous: Option<&Self>) -> Option<String> {
+        (previous != Some(self)).then(|| format!("{:?} -> {:?}", previous, self))
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GtRewrites(pub usize);
+
+register_rule_set!("Meta", 0, ());
+
+#[register_rule(("Meta", 100))]
+pub fn meta_lt_to_gt(expr: &Expression, mdl: &Model) -> ApplicationResult {
+    let count = mdl.meta.get::<LtRewrites>().map_or(0, |count| count.0);
+    Ok(lt_to_gt(expr, mdl)?.with_meta(LtRewrites(count + 1)))
+}
+
+#[register_rule(("Meta", 50))]
+pub fn meta_gt_to_not_leq(expr: &Expression, mdl: &mut Model) -> ApplicationResult {
+    let reduction = costly_gt_to_not_leq(expr, mdl)?;
+    mdl.meta.get_or_default::<GtRewrites>().0 += 1;
+    Ok(reduction)
+}
+
+/// Whether `guarded_lt_to_gt` applies, which only it reads.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Guard(pub bool);
+
+impl MetaListener for Guard {
+    fn on_change(&self, previous: Option<&Self>) -> MetaInvalidation {
+        match previous {
+            Some(previous) if previous == self => MetaInvalidation::Nothing,
+            _ => MetaInvalidation::Rules(vec!["guarded_lt_to_gt"]),
+        }
+    }
+}
+
+register_rule_set!("Guarded", 0, ());
+
+#[register_rule(("Guarded", 100))]
+pub fn guarded_lt_to_gt(expr: &Expression, mdl: &Model) -> ApplicationResult {
+    match mdl.meta.get::<Guard>() {
+        Some(Guard(true)) => lt_to_gt(expr, mdl),
+        _ => Err(ApplicationError::RuleNotApplicable),
+    }
+}
+
+/// Removes a negation, opening the guard.
+#[register_rule(("Guarded", 50))]
+pub fn open_guard(expr: &Expression, _: &Model) -> ApplicationResult {
+    match expr {
+        Expression::Not(_, inner) => Ok(Reduction::pure(*inner.clone()).with_meta(Guard(true))),
+        _ => Err(ApplicationError::RuleNotApplicable),
+    }
+}
+
+/// In scope in the children of a negation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Negated;
+
+register_rule_set!("Scoped", 0, ());
+
+#[register_rule(("Scoped", 100))]
+pub fn negated_lt_to_gt(expr: &Expression, mdl: &Model) -> ApplicationResult {
+    match mdl.meta.get::<Negated>() {
+        Some(Negated) => lt_to_gt(expr, mdl),
+        None => Err(ApplicationError::RuleNotApplicable),
+    }
+}
+
+/// Read by the rules of "Declared", which are only tried if it is set.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Unlocked;
+
+register_rule_set!("Declared", 0, ());
+
+#[register_rule(("Declared", 100), pure, reads_meta(Unlocked))]
+pub fn unlocked_lt_to_gt(expr: &Expression, mdl: &Model) -> ApplicationResult {
+    assert_eq!(mdl.meta.get::<Unlocked>(), Some(&Unlocked));
+    lt_to_gt(expr, mdl)
+}
+
+#[register_rule(("Declared", 50), pure, reads_meta(Unlocked))]
+pub fn unlocked_never_applies(_: &Expression, mdl: &Model) -> ApplicationResult {
+    assert_eq!(mdl.meta.get::<Unlocked>(), Some(&Unlocked));
+    Err(ApplicationError::RuleNotApplicable)
+}
+
+register_rule_set!("Undeclared", 0, ());
+
+#[register_rule(("Undeclared", 100), writes_meta(LtRewrites))]
+pub fn undeclared_meta_lt_to_gt(expr: &Expression, mdl: &Model) -> ApplicationResult {
+    Ok(lt_to_gt(expr, mdl)?.with_meta(GtRewrites(1)))
+}
+
+/// The number of `<` in the constraints.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LtCount(pub usize);
+
+impl LtCount {
+    pub fn of(expression: &Expression) -> usize {
+        expression
+            .universe()
+            .iter()
+            .filter(|node| matches!(node, Expression::Lt(_, _, _)))
+            .count()
+    }
+}
+
+impl DerivedMeta for LtCount {
+    fn derive(model: &Model) -> Self {
+        LtCount(LtCount::of(&model.constraints))
+    }
+
+    fn update(&mut self, before: &Expression, after: &Expression, _: &Model) -> MetaInvalidation {
+        self.0 = self.0 + LtCount::of(after) - LtCount::of(before);
+        MetaInvalidation::Rules(vec!["lt_to_gt_unless_last"])
+    }
+}
+
+register_rule_set!("Derived", 0, ());
+
+#[register_rule(("Derived", 100), reads_meta(LtCount))]
+pub fn lt_to_gt_unless_last(expr: &Expression, mdl: &Model) -> ApplicationResult {
+    match mdl.meta.get::<LtCount>() {
+        Some(LtCount(count)) if *count > 1 => lt_to_gt(expr, mdl),
+        _ => Err(ApplicationError::RuleNotApplicable),
+    }
+}
+
+/// The rules tried on comparisons so far, shared by every copy of the model.
+#[derive(Clone, Default)]
+pub struct Journal(pub Arc<Mutex<Vec<&'static str>>>);
+
+impl Journal {
+    pub fn record(expr: &Expression, mdl: &Model, rule: &'static str) {
+        if !matches!(expr, Expression::Lt(_, _, _) | Expression::Gt(_, _, _)) {
+            return;
+        }
+        if let Some(Ok(mut entries)) = mdl.meta.get::<Journal>().map(|journal| journal.0.lock()) {
+            entries.push(rule);
+        }
+    }
+
+    pub fn entries(&self) -> Vec<&'static str> {
+        self.0
+            .lock()
+            .map(|entries| entries.clone())
+            .unwrap_or_default()
+    }
+}
+
+impl Checkpointable for Journal {
+    fn checkpoint(&self) -> Self {
+        Journal(Arc::new(Mutex::new(self.entries())))
+    }
+
+    fn rewind(&mut self, checkpoint: Self) {
+        if let Ok(mut entries) = self.0.lock() {
+            *entries = checkpoint.entries();
+        }
+    }
+}
+
+register_rule_set!("Journaled", 0, ());
+
+#[register_rule(("Journaled", 100))]
+pub fn journaled_lt_to_gt(expr: &Expression, mdl: &Model) -> ApplicationResult {
+    Journal::record(expr, mdl, "journaled_lt_to_gt");
+    lt_to_gt(expr, mdl)
+}
+
+#[register_rule(("Journaled", 50))]
+pub fn journaled_lt_to_gt_with_aux(expr: &Expression, mdl: &Model) -> ApplicationResult {
+    Journal::record(expr, mdl, "journaled_lt_to_gt_with_aux");
+    aux_lt_to_gt(expr, mdl)
+}
+
+/// Never applies, but is tried on every model reached.
+#[register_rule(("Journaled", 10))]
+pub fn journaled_never(expr: &Expression, mdl: &Model) -> ApplicationResult {
+    Journal::record(expr, mdl, "journaled_never");
+    Err(ApplicationError::RuleNotApplicable)
+}
+
+/// The number of times `budgeted_lt_to_gt` has applied in this run.
+#[derive(Default)]
+pub struct Budget(pub usize);
+
+register_rule_set!("Scratch", 0, ());
+
+/// Applies to the first two comparisons of a run only.
+#[register_rule(("Scratch", 100))]
+pub fn budgeted_lt_to_gt(expr: &Expression, mdl: &Model) -> ApplicationResult {
+    let reduction = lt_to_gt(expr, mdl)?;
+    mdl.scratch.with(|budget: &mut Budget| match budget.0 {
+        0 | 1 => {
+            budget.0 += 1;
+            Ok(reduction)
+        }
+        _ => Err(ApplicationError::RuleNotApplicable),
+    })
+}
+
+register_rule_set!("Effects", 0, ());
+
+#[register_rule(("Effects", 100))]
+pub fn effects_lt_to_gt(expr: &Expression, mdl: &Model) -> ApplicationResult {
+    lt_to_gt(expr, mdl)
+}
+
+/// Adds `aux` whenever it applies, but never gets to, as `effects_lt_to_gt` applies first, unless
+/// a rewrite selector chooses it.
+#[register_rule(("Effects", 50))]
+pub fn effects_lt_to_gt_with_aux(expr: &Expression, mdl: &Model) -> ApplicationResult {
+    aux_lt_to_gt(expr, mdl)
+}
+
+register_rule_set!("Explain", 0, ());
+
+#[register_rule(("Explain", 100), applies_to(Lt), produces(Gt))]
+pub fn explain_lt_to_gt(expr: &Expression, mdl: &Model) -> ApplicationResult {
+    lt_to_gt(expr, mdl)
+}
+
+#[register_rule(("Explain", 50))]
+pub fn explain_gt_needs_aux(expr: &Expression, mdl: &Model) -> ApplicationResult {
+    match expr {
+        Expression::Gt(_, _, _) if !mdl.variables.contains_key(&aux()) => Err(
+            ApplicationError::NotApplicable(String::from("aux is not in the model")),
+        ),
+        _ => Err(ApplicationError::RuleNotApplicable),
+    }
+}
+
+#[register_rule(("Explain", 20))]
+pub fn explain_gt_bound_error(expr: &Expression, _: &Model) -> ApplicationResult {
+    match expr {
+        Expression::Gt(_, _, _) => Err(ApplicationError::BoundError),
+        _ => Err(ApplicationError::RuleNotApplicable),
+    }
+}
+
+#[register_rule(("Explain", 10))]
+pub fn explain_neq_to_eq(expr: &Expression, mdl: &Model) -> ApplicationResult {
+    cold_neq_to_eq(expr, mdl)
+}
+
+register_rule_set!("Saturate", 0, ());
+
+#[register_rule(("Saturate", 100), pure)]
+pub fn saturate_lt_to_gt(expr: &Expression, mdl: &Model) -> ApplicationResult {
+    lt_to_gt(expr, mdl)
+}
+
+#[register_rule(("Saturate", 100), pure)]
+pub fn saturate_gt_to_lt(expr: &Expression, mdl: &Model) -> ApplicationResult {
+    gt_to_lt(expr, mdl)
+}
+
+#[register_rule(("Saturate", 50), pure)]
+pub fn saturate_lt_to_not_geq(expr: &Expression, _: &Model) -> ApplicationResult {
+    match expr {
+        Expression::Lt(_, a, b) => Ok(Reduction::pure(Expression::Not(
+            Metadata::new(),
+            Box::new(Expression::Geq(Metadata::new(), a.clone(), b.clone())),
+        ))),
+        _ => Err(ApplicationError::RuleNotApplicable),
+    }
+}
+
+register_rule_set!("Overlap", 0, ());
+
+#[register_rule(("Overlap", 100))]
+pub fn overlap_lt_to_gt(expr: &Expression, mdl: &Model) -> ApplicationResult {
+    lt_to_gt(expr, mdl)
+}
+
+#[register_rule(("Overlap", 100))]
+pub fn overlap_lt_to_not_geq(expr: &Expression, mdl: &Model) -> ApplicationResult {
+    saturate_lt_to_not_geq(expr, mdl)
+}
+
+/// Applies wherever the others do, but is never used, as they come first.
+#[register_rule(("Overlap", 50))]
+pub fn overlap_lt_to_gt_late(expr: &Expression, mdl: &Model) -> ApplicationResult {
+    lt_to_gt(expr, mdl)
+}
+
+register_rule_set!("Costly", 0, ());
+
+#[register_rule(("Costly", 100))]
+pub fn costly_lt_to_gt(expr: &Expression, mdl: &Model) -> ApplicationResult {
+    lt_to_gt(expr, mdl)
+}
+
+#[register_rule(("Costly", 100))]
+pub fn costly_gt_to_not_leq(expr: &Expression, _: &Model) -> ApplicationResult {
+    match expr {
+        Expression::Gt(_, a, b) => Ok(Reduction::pure(Expression::Not(
+            Metadata::new(),
+            Box::new(Expression::Leq(Metadata::new(), a.clone(), b.clone())),
+        ))),
+        _ => Err(ApplicationError::RuleNotApplicable),
+    }
+}
+
+register_rule_set!("Tiered", 0, ());
+
+#[register_rule(("Tiered", 10))]
+pub fn tiered_lt_to_gt(expr: &Expression, mdl: &Model) -> ApplicationResult {
+    lt_to_gt(expr, mdl)
+}
+
+#[register_rule(("Tiered", 100))]
+pub fn tiered_gt_to_not_leq(expr: &Expression, mdl: &Model) -> ApplicationResult {
+    costly_gt_to_not_leq(expr, mdl)
+}
+
+pub fn rule_sets(name: &str) -> Vec<&'static RuleSet<'static>> {
+    get_rule_set_by_name(name).into_iter().collect()
+}
+
+pub fn x_lt_y() -> Expression {
+    Expression::Lt(
+        Metadata::new(),
+        Box::new(Expression::Reference(
+            Metadata::new(),
+            Name::UserName(String::from("x")),
+        )),
+        Box::new(Expression::Reference(
+            Metadata::new(),
+            Name::UserName(String::from("y")),
+        )),
+    )
+}
+
+pub fn sum_of_constants() -> Expression {
+    Expression::Eq(
+        Metadata::new(),
+        Box::new(Expression::Sum(
+            Metadata::new(),
+            vec![
+                Expression::Constant(Metadata::new(), Constant::Int(1)),
+                Expression::Constant(Metadata::new(), Constant::Int(2)),
+            ],
+        )),
+        Box::new(Expression::Constant(Metadata::new(), Constant::Int(3))),
+    )
+}
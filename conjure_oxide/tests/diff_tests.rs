@@ -0,0 +1,147 @@
+use conjure_oxide::ast::*;
+use conjure_oxide::Metadata;
+
+fn reference(name: &str) -> Expression {
+    Expression::Reference(Metadata::new(), Name::UserName(String::from(name)))
+}
+
+fn constant(value: i32) -> Expression {
+    Expression::Constant(Metadata::new(), Constant::Int(value))
+}
+
+fn lt(a: Expression, b: Expression) -> Expression {
+    Expression::Lt(Metadata::new(), Box::new(a), Box::new(b))
+}
+
+fn and(exprs: Vec<Expression>) -> Expression {
+    Expression::And(Metadata::new(), exprs)
+}
+
+/// Checks that applying the diff from `before` to `after` to `before` gives `after`.
+fn assert_round_trip(before: &Expression, after: &Expression) -> Vec<TreeEdit> {
+    let edits = before.diff(after);
+    let mut patched = before.clone();
+    assert!(patched.apply_edits(&edits));
+    assert_eq!(&patched, after);
+    assert_eq!(patched.size(), after.size());
+    edits
+}
+
+#[test]
+fn diff_of_equal_expressions_is_empty() {
+    let expr = and(vec![lt(reference("x"), constant(1)), reference("b")]);
+    assert!(assert_round_trip(&expr, &expr.clone()).is_empty());
+}
+
+#[test]
+fn diff_records_only_changed_sub_expressions() {
+    let before = and(vec![
+        lt(reference("x"), constant(1)),
+        lt(reference("y"), constant(2)),
+    ]);
+    let after = and(vec![
+        lt(reference("x"), constant(1)),
+        lt(reference("y"), constant(3)),
+    ]);
+
+    let edits = assert_round_trip(&before, &after);
+    assert_eq!(
+        edits,
+        [TreeEdit::Changed {
+            path: vec![1, 1],
+            expression: constant(3),
+        }]
+    );
+}
+
+#[test]
+fn diff_replaces_expressions_of_another_variant() {
+    let before = lt(reference("x"), constant(1));
+    let after = Expression::Not(Metadata::new(), Box::new(before.clone()));
+
+    let edits = assert_round_trip(&before, &after);
+    assert_eq!(
+        edits,
+        [TreeEdit::Changed {
+            path: vec![],
+            expression: after.clone(),
+        }]
+    );
+}
+
+#[test]
+fn diff_matches_operands_at_either_end() {
+    let a = reference("a");
+    let b = reference("b");
+    let c = reference("c");
+    let d = reference("d");
+
+    let inserted = assert_round_trip(
+        &and(vec![a.clone(), d.clone()]),
+        &and(vec![a.clone(), b.clone(), c.clone(), d.clone()]),
+    );
+    assert_eq!(
+        inserted,
+        [
+            TreeEdit::Inserted {
+                path: vec![1],
+                expression: b.clone(),
+            },
+            TreeEdit::Inserted {
+                path: vec![2],
+                expression: c.clone(),
+            },
+        ]
+    );
+
+    let deleted = assert_round_trip(
+        &and(vec![a.clone(), b.clone(), c.clone(), d.clone()]),
+        &and(vec![a.clone(), d.clone()]),
+    );
+    assert_eq!(
+        deleted,
+        [
+            TreeEdit::Deleted { path: vec![1] },
+            TreeEdit::Deleted { path: vec![1] },
+        ]
+    );
+
+    // A changed operand in the middle is diffed, and the rest inserted after it
+    let changed = assert_round_trip(
+        &and(vec![a.clone(), b.clone(), d.clone()]),
+        &and(vec![a.clone(), c.clone(), c.clone(), d.clone()]),
+    );
+    assert_eq!(
+        changed,
+        [
+            TreeEdit::Changed {
+                path: vec![1],
+                expression: c.clone(),
+            },
+            TreeEdit::Inserted {
+                path: vec![2],
+                expression: c.clone(),
+            },
+        ]
+    );
+}
+
+#[test]
+fn apply_edits_fails_on_paths_not_in_the_expression() {
+    let mut expr = reference("a");
+    let edits = [TreeEdit::Changed {
+        path: vec![0],
+        expression: constant(1),
+    }];
+    assert!(!expr.apply_edits(&edits));
+    assert!(!expr.apply_edits(&[TreeEdit::Deleted { path: vec![] }]));
+    assert_eq!(expr, reference("a"));
+}
+
+#[test]
+fn at_path_follows_child_indices() {
+    let expr = and(vec![reference("a"), lt(reference("x"), constant(1))]);
+    assert_eq!(expr.at_path(&[]), Some(&expr));
+    assert_eq!(expr.at_path(&[1, 1]), Some(&constant(1)));
+    assert_eq!(expr.at_path(&[1, 2]), None);
+}
@@ -0,0 +1,46 @@
+use conjure_oxide::ast::*;
+use conjure_oxide::Metadata;
+
+fn reference(name: &str) -> Expression {
+    Expression::Reference(Metadata::new(), Name::UserName(String::from(name)))
+}
+
+#[test]
+fn interner_shares_equal_subexpressions() {
+    let x_lt_y = Expression::Lt(
+        Metadata::new(),
+        Box::new(reference("x")),
+        Box::new(reference("y")),
+    );
+    let expr = Expression::And(
+        Metadata::new(),
+        vec![
+            x_lt_y.clone(),
+            Expression::Not(Metadata::new(), Box::new(x_lt_y.clone())),
+        ],
+    );
+
+    let mut interner = ExpressionInterner::new();
+    let id = interner.intern(&expr);
+
+    // And, Not, Lt, x, y
+    assert_eq!(interner.len(), 5);
+    let [lt, not] = interner.children(id) else {
+        panic!("Expected two children");
+    };
+    assert_eq!(interner.children(*not), &[*lt]);
+    assert_eq!(interner.find(&x_lt_y), Some(*lt));
+    assert_eq!(interner.get(id), expr);
+}
+
+#[test]
+fn interner_ignores_metadata() {
+    let mut clean = reference("x");
+    clean.set_clean(true);
+
+    let mut interner = ExpressionInterner::new();
+    let id = interner.intern(&reference("x"));
+
+    assert_eq!(interner.intern(&clean), id);
+    assert_eq!(interner.find(&reference("y")), None);
+}
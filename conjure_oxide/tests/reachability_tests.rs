@@ -0,0 +1,77 @@
+// Tests for skipping expressions that no rule can change
+
+use std::collections::HashMap;
+
+use conjure_oxide::{
+    ast::*,
+    get_rule_set_by_name, register_rule, register_rule_set,
+    rule_engine::{rewrite_model_with_options, RewriteOptions},
+    ApplicationError, ApplicationResult, Metadata, Model, Reduction, RuleSet,
+};
+
+register_rule_set!("Reach", 0, ());
+
+#[register_rule(("Reach", 100), applies_to(Lt), produces(Gt))]
+fn reach_lt_to_gt(expr: &Expression, _: &Model) -> ApplicationResult {
+    match expr {
+        Expression::Lt(_, a, b) => Ok(Reduction::pure(Expression::Gt(
+            Metadata::new(),
+            b.clone(),
+            a.clone(),
+        ))),
+        _ => Err(ApplicationError::RuleNotApplicable),
+    }
+}
+
+// Nothing produces Neq, so this rule can never apply to a model without one
+#[register_rule(("Reach", 100), applies_to(Neq), produces(Not, Eq))]
+fn reach_neq_to_eq(expr: &Expression, _: &Model) -> ApplicationResult {
+    match expr {
+        Expression::Neq(_, a, b) => Ok(Reduction::pure(Expression::Not(
+            Metadata::new(),
+            Box::new(Expression::Eq(Metadata::new(), a.clone(), b.clone())),
+        ))),
+        _ => Err(ApplicationError::RuleNotApplicable),
+    }
+}
+
+fn reference(name: &str) -> Expression {
+    Expression::Reference(Metadata::new(), Name::UserName(String::from(name)))
+}
+
+fn x_lt_y() -> Expression {
+    Expression::Lt(
+        Metadata::new(),
+        Box::new(reference("x")),
+        Box::new(reference("y")),
+    )
+}
+
+#[test]
+fn rewrite_skips_expressions_no_rule_can_change() {
+    let x_eq_y = Expression::Eq(
+        Metadata::new(),
+        Box::new(reference("x")),
+        Box::new(reference("y")),
+    );
+    let mut children = vec![x_eq_y; 4];
+    children.push(x_lt_y());
+    let model = Model::new(
+        HashMap::new(),
+        Expression::And(Metadata::new(), children),
+        Default::default(),
+    );
+
+    let rule_sets: Vec<&RuleSet> = get_rule_set_by_name("Reach").into_iter().collect();
+    let outcome = rewrite_model_with_options(&model, &rule_sets, &RewriteOptions::new()).unwrap();
+
+    let Expression::And(_, children) = &outcome.model.constraints else {
+        panic!("expected an And, got {}", outcome.model.constraints);
+    };
+    assert!(matches!(children[4], Expression::Gt(_, _, _)));
+
+    // Only x < y is ever tried, as no rule applies to the other variants
+    let context = model.context.read().unwrap();
+    let attempts = context.stats.rewriter_runs[0].rewriter_rule_application_attempts;
+    assert_eq!(attempts, Some(1));
+}
@@ -0,0 +1,400 @@
+// Tests for how the rewriter handles rules that fail, panic, or break its checks
+
+mod common;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use conjure_oxide::{
+    ast::*,
+    rule_engine::{
+        rewrite_model_with_options, DiscardReason, DiscardedEffects, ErrorCategory, MetaSnapshot,
+        NoOpPolicy, ReductionObserver, ReproBundle, RewriteError, RewriteOptions, RewriteStatus,
+        RuleError, RuleErrorKind, RuleErrorPolicy,
+    },
+    ApplicationError, Metadata, Model, Rule,
+};
+
+use common::*;
+
+#[test]
+fn rewrite_skips_no_op_rules() {
+    let model = Model::new(HashMap::new(), x_lt_y(), Default::default());
+
+    let outcome =
+        rewrite_model_with_options(&model, &rule_sets("NoOp"), &RewriteOptions::new()).unwrap();
+
+    assert_eq!(outcome.status, RewriteStatus::Fixpoint);
+    assert_eq!(outcome.model.constraints, x_lt_y());
+}
+
+#[test]
+fn rewrite_reports_no_op_rules() {
+    let model = Model::new(HashMap::new(), x_lt_y(), Default::default());
+    let options = RewriteOptions::new().on_no_op(NoOpPolicy::Error);
+
+    let result = rewrite_model_with_options(&model, &rule_sets("NoOp"), &options);
+
+    match result {
+        Err(RewriteError::Rule(RuleError {
+            rule,
+            kind: RuleErrorKind::NoOpRewrite,
+            ..
+        })) => assert_eq!(rule, "lt_identity"),
+        _ => panic!("Expected the no-op rule to be reported"),
+    }
+}
+
+#[test]
+fn rewrite_catches_panics() {
+    let expr = Expression::Not(Metadata::new(), Box::new(x_lt_y()));
+    let model = Model::new(HashMap::new(), expr, Default::default());
+    let options = RewriteOptions::new().catch_panics(true);
+
+    let result = rewrite_model_with_options(&model, &rule_sets("Panic"), &options);
+
+    match result {
+        Err(RewriteError::Rule(RuleError {
+            rule,
+            path,
+            kind: RuleErrorKind::Panicked { message },
+            ..
+        })) => {
+            assert_eq!(rule, "lt_panic");
+            assert_eq!(path, vec![0]);
+            assert_eq!(message, "lt_panic always panics");
+        }
+        _ => panic!("Expected the panic to be caught"),
+    }
+}
+
+#[test]
+fn rewrite_labels_expressions_in_errors() {
+    let model = Model::new(HashMap::new(), x_lt_y(), Default::default());
+    let options = RewriteOptions::new()
+        .check_invariant(|model| match model.constraints {
+            Expression::Gt(_, _, _) => Err(String::from("constraints contain >")),
+            _ => Ok(()),
+        })
+        .label_nodes(|expression| format!("<{}>", expression.variant_name()));
+
+    let error = rewrite_model_with_options(&model, &rule_sets("PingPong"), &options).unwrap_err();
+    let RewriteError::Rule(RuleError { kind, .. }) = error else {
+        panic!("Expected a rule error, got {:?}", error);
+    };
+    assert_eq!(
+        kind.to_string(),
+        "invariant violated: constraints contain >\nConstraints: <Gt>"
+    );
+}
+
+#[test]
+fn rewrite_checks_invariant() {
+    let model = Model::new(HashMap::new(), x_lt_y(), Default::default());
+    let options = RewriteOptions::new().check_invariant(|model| match model.constraints {
+        Expression::Gt(_, _, _) => Err(String::from("constraints contain >")),
+        _ => Ok(()),
+    });
+
+    let result = rewrite_model_with_options(&model, &rule_sets("PingPong"), &options);
+
+    match result {
+        Err(RewriteError::Rule(RuleError {
+            rule,
+            kind:
+                RuleErrorKind::InvariantViolated {
+                    message,
+                    constraints,
+                    shown,
+                },
+            ..
+        })) => {
+            assert_eq!(rule, "lt_to_gt");
+            assert_eq!(message, "constraints contain >");
+            assert!(constraints.is_gt());
+            assert_eq!(*shown, constraints.to_string());
+        }
+        _ => panic!("Expected the invariant to be violated"),
+    }
+}
+
+#[test]
+fn rewrite_rule_errors_have_context() {
+    let model = Model::new(HashMap::new(), x_lt_y(), Default::default());
+    let options = RewriteOptions::new().check_invariant(|model| match model.constraints {
+        Expression::Lt(_, _, _) => Err(String::from("constraints contain <")),
+        _ => Ok(()),
+    });
+
+    let error = rewrite_model_with_options(&model, &rule_sets("PingPong"), &options).unwrap_err();
+
+    assert_eq!(error.category(), ErrorCategory::Rule);
+    assert_eq!(error.rule(), Some("gt_to_lt"));
+    match &error {
+        RewriteError::Rule(RuleError {
+            rule,
+            path,
+            iteration,
+            ..
+        }) => {
+            assert_eq!(rule, "gt_to_lt");
+            assert!(path.is_empty());
+            assert_eq!(*iteration, 2);
+        }
+        _ => panic!("Expected a rule error"),
+    }
+    assert_eq!(
+        error.to_string(),
+        "Rule gt_to_lt failed on the expression at [] after 2 rewrites"
+    );
+    assert!(error
+        .source()
+        .is_some_and(|source| source.is::<RuleErrorKind>()));
+}
+
+#[test]
+fn rewrite_ignores_rule_errors() {
+    let model = Model::new(HashMap::new(), x_lt_y(), Default::default());
+
+    let outcome =
+        rewrite_model_with_options(&model, &rule_sets("Failing"), &RewriteOptions::new()).unwrap();
+
+    assert_eq!(outcome.status, RewriteStatus::Fixpoint);
+    assert_eq!(outcome.model.constraints, x_lt_y());
+}
+
+#[test]
+fn rewrite_reports_rule_errors() {
+    let model = Model::new(HashMap::new(), x_lt_y(), Default::default());
+    let options = RewriteOptions::new().on_rule_error_in("Failing", RuleErrorPolicy::Error);
+
+    let result = rewrite_model_with_options(&model, &rule_sets("Failing"), &options);
+
+    match result {
+        Err(RewriteError::Rule(RuleError {
+            rule,
+            kind: RuleErrorKind::Failed(ApplicationError::BoundError),
+            ..
+        })) => assert_eq!(rule, "lt_bound_error"),
+        _ => panic!("Expected the rule error to be reported"),
+    }
+}
+
+#[test]
+fn rewrite_rule_set_error_policy_overrides_default() {
+    let model = Model::new(HashMap::new(), x_lt_y(), Default::default());
+    let options = RewriteOptions::new()
+        .on_rule_error(RuleErrorPolicy::Error)
+        .on_rule_error_in("Failing", RuleErrorPolicy::Warn);
+
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Failing"), &options).unwrap();
+
+    assert_eq!(outcome.model.constraints, x_lt_y());
+}
+
+#[test]
+fn rewrite_attaches_checkpoint_to_rule_errors() {
+    let model = Model::new(HashMap::new(), x_lt_y(), Default::default());
+    let options = RewriteOptions::new().max_size(10).checkpoint_every(2);
+
+    let result = rewrite_model_with_options(&model, &rule_sets("Grow"), &options);
+
+    match result {
+        Err(RewriteError::Rule(RuleError {
+            iteration,
+            checkpoint: Some(checkpoint),
+            ..
+        })) => {
+            assert_eq!(iteration, 4);
+            assert_eq!(checkpoint.rewrites, 2);
+            assert!(checkpoint.constraints.is_not());
+        }
+        _ => panic!("Expected a rule error with a checkpoint"),
+    }
+}
+
+#[test]
+fn rewrite_returns_partial_on_error() {
+    let model = Model::new(HashMap::new(), x_lt_y(), Default::default());
+    let options = RewriteOptions::new().max_size(10).partial_on_error(true);
+
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Grow"), &options).unwrap();
+
+    assert_eq!(outcome.status, RewriteStatus::Error);
+    assert!(!outcome.is_complete());
+    // The rewrite that broke the limit is undone
+    assert!(outcome.model.constraints.is_not());
+    assert_eq!(outcome.model.constraints.size(), 9);
+    match outcome.error {
+        Some(RewriteError::Rule(RuleError {
+            kind: RuleErrorKind::SizeLimitExceeded { .. },
+            checkpoint: Some(checkpoint),
+            ..
+        })) => {
+            // The input model is taken as a checkpoint, even without an interval
+            assert_eq!(checkpoint.rewrites, 0);
+            assert_eq!(checkpoint.constraints, x_lt_y());
+        }
+        _ => panic!("Expected a size limit error with a checkpoint"),
+    }
+
+    // Rewrites made in an arena cannot be undone, so the input model is returned
+    let options = RewriteOptions::new()
+        .max_size(10)
+        .partial_on_error(true)
+        .arena(true);
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Grow"), &options).unwrap();
+    assert_eq!(outcome.status, RewriteStatus::Error);
+    assert_eq!(outcome.model.constraints, x_lt_y());
+}
+
+#[test]
+fn rewrite_quarantines_failing_rules() {
+    let model = Model::new(HashMap::new(), x_lt_y(), Default::default());
+    let options = RewriteOptions::new()
+        .on_rule_error(RuleErrorPolicy::Error)
+        .quarantine_after(2);
+
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Failing"), &options).unwrap();
+
+    assert_eq!(outcome.status, RewriteStatus::Fixpoint);
+    assert_eq!(outcome.model.constraints, x_lt_y());
+    assert_eq!(outcome.quarantined.len(), 1);
+    assert_eq!(outcome.quarantined[0].rule, "lt_bound_error");
+    // The rule may fail twice, and is quarantined on the third failure
+    assert_eq!(outcome.quarantined[0].failures, 3);
+}
+
+#[test]
+fn rewrite_quarantine_undoes_bad_rewrites() {
+    let model = Model::new(HashMap::new(), x_lt_y(), Default::default());
+    let options = RewriteOptions::new()
+        .check_invariant(|model| match model.constraints {
+            Expression::Gt(_, _, _) => Err(String::from("constraints contain >")),
+            _ => Ok(()),
+        })
+        .quarantine_after(1);
+
+    let outcome = rewrite_model_with_options(&model, &rule_sets("PingPong"), &options).unwrap();
+
+    assert_eq!(outcome.status, RewriteStatus::Fixpoint);
+    assert_eq!(outcome.model.constraints, x_lt_y());
+    assert_eq!(outcome.quarantined.len(), 1);
+    assert_eq!(outcome.quarantined[0].rule, "lt_to_gt");
+    assert!(matches!(
+        outcome.quarantined[0].error.kind,
+        RuleErrorKind::InvariantViolated { .. }
+    ));
+}
+
+/// Counts the iterations it is told about.
+struct CountingObserver(Arc<AtomicUsize>);
+
+impl ReductionObserver for CountingObserver {
+    fn on_iteration(&self, _: &[&Rule], _: &Model) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[test]
+fn rewrite_quarantine_does_not_report_undone_rewrites() {
+    let model = Model::new(HashMap::new(), x_lt_y(), Default::default());
+    let iterations = Arc::new(AtomicUsize::new(0));
+    let options = RewriteOptions::new()
+        .check_invariant(|model| match model.constraints {
+            Expression::Gt(_, _, _) => Err(String::from("constraints contain >")),
+            _ => Ok(()),
+        })
+        .quarantine_after(1)
+        .observer(CountingObserver(iterations.clone()));
+
+    let outcome = rewrite_model_with_options(&model, &rule_sets("PingPong"), &options).unwrap();
+
+    assert_eq!(outcome.quarantined.len(), 1);
+    assert_eq!(iterations.load(Ordering::Relaxed), 0);
+}
+
+#[test]
+fn rewrite_captures_repro_bundle() {
+    let expr = Expression::Not(Metadata::new(), Box::new(x_lt_y()));
+    let model = Model::new(HashMap::new(), expr.clone(), Default::default());
+    let options = RewriteOptions::new()
+        .on_rule_error(RuleErrorPolicy::Error)
+        .capture_repro(true);
+
+    let result = rewrite_model_with_options(&model, &rule_sets("Failing"), &options);
+
+    let Err(RewriteError::Rule(RuleError {
+        repro: Some(repro), ..
+    })) = result
+    else {
+        panic!("Expected a rule error with a reproduction bundle");
+    };
+    assert_eq!(repro.rule, "lt_bound_error");
+    assert_eq!(repro.path, vec![0]);
+    assert_eq!(repro.expression, x_lt_y());
+    assert_eq!(repro.constraints, expr);
+    assert_eq!(repro.rule_sets, vec!["Failing"]);
+
+    let file = std::env::temp_dir().join("conjure_oxide_repro_bundle_test.json");
+    repro.save(&file).unwrap();
+    let loaded = ReproBundle::load(&file).unwrap();
+    std::fs::remove_file(&file).unwrap();
+
+    assert_eq!(loaded.expression, x_lt_y());
+    assert!(matches!(
+        loaded.replay(),
+        Some(Err(ApplicationError::BoundError))
+    ));
+}
+
+#[test]
+fn rewrite_reports_discarded_side_effects() {
+    let model = Model::new(HashMap::new(), x_lt_y(), Default::default());
+    let options = RewriteOptions::new().warn_on_discarded_effects(true);
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Effects"), &options).unwrap();
+
+    assert!(outcome.model.variables.is_empty());
+    assert_eq!(
+        outcome.discarded_effects,
+        [DiscardedEffects {
+            rule: String::from("effects_lt_to_gt_with_aux"),
+            path: vec![],
+            symbols: 1,
+            new_top: false,
+            meta: 0,
+            reason: DiscardReason::Superseded,
+        }]
+    );
+    assert!(outcome.discarded_effects[0]
+        .to_string()
+        .contains("rule effects_lt_to_gt_with_aux at []"));
+}
+
+#[test]
+fn rewrite_quarantine_restores_changes_made_to_the_model() {
+    let model = Model::new(HashMap::new(), x_lt_y(), Default::default());
+    let options = RewriteOptions::new()
+        .check_invariant(|model| match model.constraints {
+            Expression::Gt(_, _, _) => Err(String::from("constraints contain >")),
+            _ => Ok(()),
+        })
+        .quarantine_after(1);
+
+    let outcome = rewrite_model_with_options(&model, &rule_sets("MutModel"), &options).unwrap();
+
+    assert_eq!(outcome.quarantined.len(), 1);
+    assert_eq!(outcome.quarantined[0].rule, "mut_model_lt_to_gt");
+    assert_eq!(outcome.model.constraints, x_lt_y());
+    assert!(outcome.model.variables.is_empty());
+    // The names made by the two undone rewrites are not made again
+    assert_eq!(outcome.model.gensym(), Name::MachineName(2));
+
+    let mut symbols = outcome.model.variables.clone();
+    let snapshot = symbols.snapshot();
+    symbols.insert(aux(), DecisionVariable::new(Domain::BoolDomain));
+    symbols.restore(snapshot);
+    assert!(symbols.is_empty());
+}
@@ -0,0 +1,238 @@
+// Tests for the limits on how long, how far and how much the rewriter rewrites
+
+mod common;
+
+use std::collections::HashMap;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use conjure_oxide::{
+    ast::*,
+    rule_engine::{
+        rewrite_model_with_options, BudgetPolicy, DivergenceAction, DivergenceMonitor,
+        DivergenceReason, EngineError, RewriteError, RewriteOptions, RewriteStatus, RuleError,
+        RuleErrorKind,
+    },
+    Metadata, Model,
+};
+
+use common::*;
+
+#[test]
+fn rewrite_reaches_fixpoint() {
+    let model = Model::new(HashMap::new(), sum_of_constants(), Default::default());
+
+    let outcome =
+        rewrite_model_with_options(&model, &rule_sets("Constant"), &RewriteOptions::new()).unwrap();
+
+    assert_eq!(outcome.status, RewriteStatus::Fixpoint);
+    assert!(outcome.is_complete());
+    assert_eq!(
+        outcome.model.constraints,
+        Expression::Constant(Metadata::new(), Constant::Bool(true))
+    );
+}
+
+#[test]
+fn rewrite_budget_returns_partial() {
+    let model = Model::new(HashMap::new(), sum_of_constants(), Default::default());
+    let options = RewriteOptions::new().max_rewrites(0);
+
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Constant"), &options).unwrap();
+
+    assert_eq!(outcome.status, RewriteStatus::BudgetExhausted);
+    assert!(!outcome.is_complete());
+    assert_eq!(outcome.model.constraints, sum_of_constants());
+}
+
+#[test]
+fn rewrite_budget_errors() {
+    let model = Model::new(HashMap::new(), sum_of_constants(), Default::default());
+    let options = RewriteOptions::new()
+        .max_rewrites(0)
+        .on_budget_exhausted(BudgetPolicy::Error);
+
+    let result = rewrite_model_with_options(&model, &rule_sets("Constant"), &options);
+
+    assert!(matches!(
+        result,
+        Err(RewriteError::Engine(EngineError::BudgetExhausted {
+            rewrites: 0,
+            ..
+        }))
+    ));
+}
+
+#[test]
+fn rewrite_timeout_returns_partial() {
+    let model = Model::new(HashMap::new(), sum_of_constants(), Default::default());
+    let options = RewriteOptions::new().timeout(Duration::ZERO);
+
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Constant"), &options).unwrap();
+
+    assert_eq!(outcome.status, RewriteStatus::Timeout);
+    assert_eq!(outcome.model.constraints, sum_of_constants());
+}
+
+#[test]
+fn rewrite_detects_cycles() {
+    let model = Model::new(HashMap::new(), x_lt_y(), Default::default());
+    let options = RewriteOptions::new().detect_cycles(true);
+
+    let result = rewrite_model_with_options(&model, &rule_sets("PingPong"), &options);
+
+    assert!(result.as_ref().is_err_and(RewriteError::is_engine_error));
+    match result {
+        Err(RewriteError::Engine(EngineError::CycleDetected { rules })) => {
+            assert_eq!(rules, vec!["lt_to_gt", "gt_to_lt"])
+        }
+        _ => panic!("Expected a cycle to be detected"),
+    }
+}
+
+#[test]
+fn rewrite_size_limit() {
+    let model = Model::new(HashMap::new(), x_lt_y(), Default::default());
+    let options = RewriteOptions::new().max_size(10);
+
+    let result = rewrite_model_with_options(&model, &rule_sets("Grow"), &options);
+
+    match result {
+        Err(RewriteError::Rule(RuleError {
+            rule,
+            kind: RuleErrorKind::SizeLimitExceeded { size, limit },
+            ..
+        })) => {
+            assert_eq!(size, 11);
+            assert_eq!(limit, 10);
+            assert_eq!(rule, "lt_double_not");
+        }
+        _ => panic!("Expected the size limit to be exceeded"),
+    }
+}
+
+#[test]
+fn rewrite_discards_slow_rules() {
+    let model = Model::new(HashMap::new(), x_lt_y(), Default::default());
+    let options = RewriteOptions::new().slow_rule_threshold(Duration::from_millis(1));
+
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Slow"), &options).unwrap();
+
+    assert_eq!(outcome.model.constraints, x_lt_y());
+    assert!(!outcome.slow_rules.is_empty());
+    assert_eq!(outcome.slow_rules[0].rule, "slow_lt_to_gt");
+    assert!(outcome.slow_rules[0].path.is_empty());
+}
+
+#[test]
+fn rewrite_retries_rules_that_were_too_slow_in_an_earlier_run() {
+    let model = Model::new(HashMap::new(), x_lt_y(), Default::default());
+    let options = RewriteOptions::new().slow_rule_threshold(Duration::from_millis(1));
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Slow"), &options).unwrap();
+    assert_eq!(outcome.status, RewriteStatus::Fixpoint);
+    assert_eq!(outcome.model.constraints, x_lt_y());
+
+    let outcome =
+        rewrite_model_with_options(&outcome.model, &rule_sets("Slow"), &RewriteOptions::new())
+            .unwrap();
+    assert!(matches!(outcome.model.constraints, Expression::Gt(_, _, _)));
+}
+
+#[test]
+fn rewrite_cancelled() {
+    let model = Model::new(HashMap::new(), sum_of_constants(), Default::default());
+    let options = RewriteOptions::new().cancel_flag(Arc::new(AtomicBool::new(true)));
+
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Constant"), &options).unwrap();
+
+    assert_eq!(outcome.status, RewriteStatus::Cancelled);
+    assert_eq!(outcome.model.constraints, sum_of_constants());
+}
+
+#[test]
+fn rewrite_monitors_divergence() {
+    let model = Model::new(HashMap::new(), x_lt_y(), Default::default());
+    let warnings = Arc::new(Mutex::new(Vec::new()));
+    let seen = Arc::clone(&warnings);
+    let options = RewriteOptions::new()
+        .monitor_divergence(DivergenceMonitor {
+            window: 4,
+            max_growth: 2.0,
+            max_rule_share: 1.0,
+        })
+        .on_divergence(move |warning| {
+            seen.lock().unwrap().push(warning.clone());
+            DivergenceAction::Stop
+        });
+
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Grow"), &options).unwrap();
+
+    assert_eq!(outcome.status, RewriteStatus::Cancelled);
+    let warnings = warnings.lock().unwrap();
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(warnings[0].rewrites, 4);
+    assert_eq!(
+        warnings[0].reason,
+        DivergenceReason::Growing { from: 5, to: 11 }
+    );
+}
+
+#[test]
+fn rewrite_depth_limit() {
+    let expr = Expression::Not(
+        Metadata::new(),
+        Box::new(Expression::Not(Metadata::new(), Box::new(x_lt_y()))),
+    );
+    let model = Model::new(HashMap::new(), expr, Default::default());
+    let options = RewriteOptions::new().max_recursion_depth(2);
+
+    let result = rewrite_model_with_options(&model, &rule_sets("NoOp"), &options);
+
+    match result {
+        Err(RewriteError::Engine(EngineError::DepthLimitExceeded { limit, path })) => {
+            assert_eq!(limit, 2);
+            assert_eq!(path, vec![0, 0, 0]);
+        }
+        _ => panic!("Expected the depth limit to be exceeded"),
+    }
+}
+
+#[test]
+fn rewrite_handles_deep_expressions() {
+    // Far deeper than the call stack of a test thread could recurse
+    let depth = 1_000_000;
+    let nest = |mut expr: Expression| {
+        for _ in 0..depth {
+            expr = Expression::Not(Metadata::new(), Box::new(expr));
+        }
+        expr
+    };
+    let model = Model::new(HashMap::new(), nest(x_lt_y()), Default::default());
+    let expected = nest(pure_lt_to_gt(&x_lt_y(), &model).unwrap().new_expression);
+
+    let outcome =
+        rewrite_model_with_options(&model, &rule_sets("Pure"), &RewriteOptions::new()).unwrap();
+    // `Debug` formatting recurses, so the expressions are not shown on failure
+    assert!(outcome.model.constraints == expected);
+    assert!(outcome.model.constraints.to_string() == expected.to_string());
+}
+
+#[test]
+fn rewrite_tracks_peak_memory() {
+    let expr = Expression::And(Metadata::new(), vec![x_lt_y(); 4]);
+    let peak = |options: &RewriteOptions| {
+        let model = Model::new(HashMap::new(), expr.clone(), Default::default());
+        rewrite_model_with_options(&model, &rule_sets("Pure"), options).unwrap();
+        let context = model.context.read().unwrap();
+        context.stats.rewriter_runs[0].rewriter_peak_memory
+    };
+
+    assert_eq!(peak(&RewriteOptions::new()), None);
+    let tracked = peak(&RewriteOptions::new().track_memory(true)).unwrap();
+    assert!(tracked >= expr.size() * std::mem::size_of::<Expression>());
+
+    // Checkpoints hold a copy of the constraints
+    let with_checkpoints = peak(&RewriteOptions::new().track_memory(true).checkpoint_every(1));
+    assert!(with_checkpoints.unwrap() > tracked);
+}
@@ -0,0 +1,386 @@
+// Tests for the options that make the rewriter faster without changing the model it returns
+
+mod common;
+
+use std::collections::HashMap;
+use std::thread;
+use std::time::Duration;
+
+use conjure_core::solver::SolverFamily;
+use conjure_oxide::{
+    ast::*,
+    rule_engine::{
+        resolve_rule_sets, rewrite_model_with_options, IgnoredOption, RewriteOptions,
+        RewriteStatus, RuleProfile,
+    },
+    Metadata, Model,
+};
+
+use common::*;
+
+#[test]
+fn rewrite_memoizes_failed_attempts() {
+    let expr = Expression::And(Metadata::new(), vec![x_lt_y(), x_lt_y()]);
+    let attempts = |options: &RewriteOptions| {
+        let model = Model::new(HashMap::new(), expr.clone(), Default::default());
+        let outcome = rewrite_model_with_options(&model, &rule_sets("NoOp"), options).unwrap();
+        assert_eq!(outcome.model.constraints, expr);
+        let context = model.context.read().unwrap();
+        context.stats.rewriter_runs[0].rewriter_rule_application_attempts
+    };
+
+    assert_eq!(attempts(&RewriteOptions::new()), Some(7));
+    assert_eq!(
+        attempts(&RewriteOptions::new().memoize_failures(true)),
+        Some(4)
+    );
+}
+
+#[test]
+fn rewrite_keeps_clean_marks_per_rule_set() {
+    let both = [rule_sets("NoOp"), rule_sets("Failing")].concat();
+    let attempts = |model: &Model| {
+        let outcome = rewrite_model_with_options(model, &both, &RewriteOptions::new()).unwrap();
+        assert_eq!(outcome.model.constraints, x_lt_y());
+        let context = model.context.read().unwrap();
+        context
+            .stats
+            .rewriter_runs
+            .last()
+            .unwrap()
+            .rewriter_rule_application_attempts
+    };
+
+    let model = Model::new(HashMap::new(), x_lt_y(), Default::default());
+    assert_eq!(attempts(&model), Some(6));
+
+    // Only the rules in Failing need to be tried on expressions that NoOp has already visited
+    let model = Model::new(HashMap::new(), x_lt_y(), Default::default());
+    let outcome =
+        rewrite_model_with_options(&model, &rule_sets("NoOp"), &RewriteOptions::new()).unwrap();
+    assert_eq!(attempts(&outcome.model), Some(3));
+}
+
+#[test]
+fn rewrite_adapts_rule_order() {
+    let expr = Expression::And(Metadata::new(), vec![x_lt_y(); 4]);
+    let run = |options: &RewriteOptions| {
+        let model = Model::new(HashMap::new(), expr.clone(), Default::default());
+        let outcome = rewrite_model_with_options(&model, &rule_sets("Hot"), options).unwrap();
+        let context = model.context.read().unwrap();
+        let attempts = context.stats.rewriter_runs[0].rewriter_rule_application_attempts;
+        (outcome.model.constraints, attempts.unwrap())
+    };
+
+    let (expected, static_attempts) = run(&RewriteOptions::new());
+    let (constraints, adaptive_attempts) = run(&RewriteOptions::new().adaptive_rule_order(true));
+    assert_eq!(constraints, expected);
+    assert!(adaptive_attempts < static_attempts);
+}
+
+#[test]
+fn rewrite_orders_rules_by_profile() {
+    let expr = Expression::And(Metadata::new(), vec![x_lt_y(); 4]);
+    let run = |options: &RewriteOptions| {
+        let model = Model::new(HashMap::new(), expr.clone(), Default::default());
+        let outcome = rewrite_model_with_options(&model, &rule_sets("Hot"), options).unwrap();
+        let context = model.context.read().unwrap();
+        let attempts = context.stats.rewriter_runs[0].rewriter_rule_application_attempts;
+        (outcome, attempts.unwrap())
+    };
+
+    let (training, static_attempts) = run(&RewriteOptions::new().record_profile(true));
+    let profile = training.profile.unwrap();
+    assert_eq!(profile.successes("Lt", "hot_lt_to_gt"), 4);
+    assert_eq!(profile.successes("Lt", "cold_neq_to_eq"), 0);
+
+    let file = std::env::temp_dir().join("conjure_oxide_rule_profile_test.json");
+    profile.save(&file).unwrap();
+    let loaded = RuleProfile::load(&file).unwrap();
+    assert_eq!(loaded, profile);
+
+    let (outcome, guided_attempts) = run(&RewriteOptions::new().rule_profile(loaded));
+    assert_eq!(outcome.model.constraints, training.model.constraints);
+    assert!(outcome.profile.is_none());
+    assert!(guided_attempts < static_attempts);
+}
+
+#[test]
+fn rewrite_batches_independent_rewrites() {
+    let expr = Expression::And(Metadata::new(), vec![x_lt_y(); 4]);
+    let run = |options: &RewriteOptions| {
+        let model = Model::new(HashMap::new(), expr.clone(), Default::default());
+        let outcome = rewrite_model_with_options(&model, &rule_sets("Hot"), options).unwrap();
+        let context = model.context.read().unwrap();
+        let attempts = context.stats.rewriter_runs[0].rewriter_rule_application_attempts;
+        (outcome.model.constraints, attempts.unwrap())
+    };
+
+    let (expected, single_attempts) = run(&RewriteOptions::new());
+    let (constraints, batch_attempts) = run(&RewriteOptions::new().batch_rewrites(true));
+    assert_eq!(constraints, expected);
+    assert!(batch_attempts < single_attempts);
+}
+
+#[test]
+fn rewrite_caches_normal_forms() {
+    let expr = Expression::And(Metadata::new(), vec![x_lt_y(); 4]);
+    let run = |options: &RewriteOptions| {
+        let model = Model::new(HashMap::new(), expr.clone(), Default::default());
+        let outcome = rewrite_model_with_options(&model, &rule_sets("Pure"), options).unwrap();
+        let context = model.context.read().unwrap();
+        let attempts = context.stats.rewriter_runs[0].rewriter_rule_application_attempts;
+        (outcome.model.constraints, attempts.unwrap())
+    };
+
+    let (expected, uncached_attempts) = run(&RewriteOptions::new());
+    let (constraints, cached_attempts) = run(&RewriteOptions::new().cache_normal_forms(true));
+    assert_eq!(constraints, expected);
+    assert!(cached_attempts < uncached_attempts);
+}
+
+#[test]
+fn rewrite_times_out_while_normalising() {
+    // The rules never reach a normal form, so the only pass never ends by itself
+    let model = Model::new(HashMap::new(), x_lt_y(), Default::default());
+    let options = RewriteOptions::new()
+        .cache_normal_forms(true)
+        .timeout(Duration::from_millis(50));
+    let outcome = rewrite_model_with_options(&model, &rule_sets("PurePingPong"), &options).unwrap();
+    assert_eq!(outcome.status, RewriteStatus::Timeout);
+}
+
+#[test]
+fn rewrite_tries_pure_rules_in_parallel() {
+    let expr = Expression::And(Metadata::new(), vec![x_lt_y(); 4]);
+    let run = |options: &RewriteOptions| {
+        let model = Model::new(HashMap::new(), expr.clone(), Default::default());
+        rewrite_model_with_options(&model, &rule_sets("Parallel"), options)
+            .unwrap()
+            .model
+            .constraints
+    };
+
+    let expected = run(&RewriteOptions::new());
+    let constraints = run(&RewriteOptions::new().parallel_rule_trials(4));
+    assert_eq!(constraints, expected);
+
+    // Both rules apply to x < y, so the one with the higher priority must be chosen
+    let Expression::And(_, children) = &constraints else {
+        panic!("expected an And, got {}", constraints);
+    };
+    assert!(children
+        .iter()
+        .all(|child| matches!(child, Expression::Gt(_, _, _))));
+}
+
+#[test]
+fn rewrite_deterministically_ignores_slow_rules() {
+    let model = Model::new(HashMap::new(), x_lt_y(), Default::default());
+    let options = RewriteOptions::new()
+        .slow_rule_threshold(Duration::from_millis(1))
+        .deterministic(true);
+
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Slow"), &options).unwrap();
+
+    assert!(matches!(outcome.model.constraints, Expression::Gt(_, _, _)));
+    assert!(outcome.slow_rules.is_empty());
+    assert_eq!(
+        outcome.ignored_options,
+        vec![IgnoredOption::SlowRuleThreshold]
+    );
+}
+
+#[test]
+fn rewrite_raises_parallel_panics_in_priority_order() {
+    let model = Model::new(HashMap::new(), x_lt_y(), Default::default());
+    // Only the first applicable rule is tried in order, so the panicking rule is never reached
+    let options = RewriteOptions::new()
+        .adaptive_rule_order(true)
+        .parallel_rule_trials(2);
+
+    let outcome =
+        rewrite_model_with_options(&model, &rule_sets("ParallelPanic"), &options).unwrap();
+
+    assert!(matches!(outcome.model.constraints, Expression::Gt(_, _, _)));
+}
+
+#[test]
+fn rewrite_shares_passes_between_threads() {
+    let expr = Expression::And(
+        Metadata::new(),
+        vec![
+            Expression::And(Metadata::new(), vec![x_lt_y(); 3]),
+            Expression::And(Metadata::new(), vec![x_lt_y(); 40]),
+            x_lt_y(),
+        ],
+    );
+    let run = |options: &RewriteOptions| {
+        let model = Model::new(HashMap::new(), expr.clone(), Default::default());
+        let outcome = rewrite_model_with_options(&model, &rule_sets("Pure"), options).unwrap();
+        let context = model.context.read().unwrap();
+        let applications = context.stats.rewriter_runs[0].rewriter_rule_applications;
+        (outcome.model.constraints, applications.unwrap())
+    };
+
+    let (expected, expected_applications) = run(&RewriteOptions::new().batch_rewrites(true));
+    let (constraints, applications) =
+        run(&RewriteOptions::new().batch_rewrites(true).work_stealing(2));
+    assert_eq!(constraints, expected);
+    assert_eq!(applications, expected_applications);
+    assert_eq!(applications, 44);
+}
+
+#[test]
+fn rewrite_holds_constraints_in_arena() {
+    let a = || {
+        Box::new(Expression::Reference(
+            Metadata::new(),
+            Name::UserName(String::from("a")),
+        ))
+    };
+    let b = || Expression::Reference(Metadata::new(), Name::UserName(String::from("b")));
+    let int = |i| Expression::Constant(Metadata::new(), Constant::Int(i));
+    let expr = Expression::And(
+        Metadata::new(),
+        vec![
+            Expression::Leq(
+                Metadata::new(),
+                Box::new(Expression::Sum(
+                    Metadata::new(),
+                    vec![
+                        *a(),
+                        Expression::Sum(Metadata::new(), vec![b(), int(1), int(2)]),
+                    ],
+                )),
+                Box::new(int(10)),
+            ),
+            Expression::Not(
+                Metadata::new(),
+                Box::new(Expression::Not(
+                    Metadata::new(),
+                    Box::new(Expression::Lt(Metadata::new(), a(), Box::new(b()))),
+                )),
+            ),
+            sum_of_constants(),
+            Expression::Or(Metadata::new(), vec![x_lt_y()]),
+            // Adds a variable and top-level constraints
+            Expression::Leq(
+                Metadata::new(),
+                Box::new(Expression::Min(Metadata::new(), vec![*a(), b()])),
+                Box::new(int(3)),
+            ),
+        ],
+    );
+    let variables: HashMap<_, _> = ["a", "b"]
+        .into_iter()
+        .map(|name| {
+            let domain = Domain::IntDomain(vec![Range::Bounded(1, 5)]);
+            (
+                Name::UserName(name.to_string()),
+                DecisionVariable::new(domain),
+            )
+        })
+        .collect();
+    let rule_sets = resolve_rule_sets(SolverFamily::Minion, &vec!["Constant".to_string()]).unwrap();
+    let run = |options: &RewriteOptions| {
+        let model = Model::new(variables.clone(), expr.clone(), Default::default());
+        let outcome = rewrite_model_with_options(&model, &rule_sets, options).unwrap();
+        let context = model.context.read().unwrap();
+        let applications = context.stats.rewriter_runs[0].rewriter_rule_applications;
+        (outcome.model.constraints, applications.unwrap())
+    };
+
+    let (expected, expected_applications) = run(&RewriteOptions::new());
+    let (constraints, applications) = run(&RewriteOptions::new().arena(true));
+    assert_eq!(constraints, expected);
+    assert_eq!(applications, expected_applications);
+    assert!(applications > 0);
+}
+
+#[test]
+fn rewrite_revisits_clean_expressions_after_new_symbols() {
+    let reference = |name: &str| {
+        Box::new(Expression::Reference(
+            Metadata::new(),
+            Name::UserName(name.to_string()),
+        ))
+    };
+    let x = || reference("x");
+    let expr = Expression::And(
+        Metadata::new(),
+        vec![Expression::Eq(Metadata::new(), x(), x()), x_lt_y()],
+    );
+    let expected = Expression::And(
+        Metadata::new(),
+        vec![
+            Expression::Neq(Metadata::new(), x(), x()),
+            Expression::Gt(Metadata::new(), reference("y"), x()),
+        ],
+    );
+
+    for options in [RewriteOptions::new(), RewriteOptions::new().arena(true)] {
+        // The equality is marked clean before the variable it needs is added
+        let model = Model::new(HashMap::new(), expr.clone(), Default::default());
+        let outcome = rewrite_model_with_options(&model, &rule_sets("Aux"), &options).unwrap();
+        assert_eq!(outcome.model.constraints, expected);
+
+        // The marks left by the run are still valid in the next one
+        let attempts = |model: &Model| {
+            let context = model.context.read().unwrap();
+            let runs = &context.stats.rewriter_runs;
+            runs.last().unwrap().rewriter_rule_application_attempts
+        };
+        rewrite_model_with_options(&outcome.model, &rule_sets("Aux"), &options).unwrap();
+        assert_eq!(attempts(&outcome.model), Some(0));
+    }
+}
+
+#[test]
+fn rewrite_finishes_rewriting_a_model_left_by_a_run_that_stopped() {
+    let expr = Expression::And(Metadata::new(), vec![x_lt_y(); 3]);
+    let model = Model::new(HashMap::new(), expr, Default::default());
+    let options = RewriteOptions::new().max_rewrites(1);
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Pure"), &options).unwrap();
+    assert_eq!(outcome.status, RewriteStatus::BudgetExhausted);
+
+    // Nothing the stopped run marked is trusted, so the comparisons it did not get to are rewritten
+    let outcome =
+        rewrite_model_with_options(&outcome.model, &rule_sets("Pure"), &RewriteOptions::new())
+            .unwrap();
+    let Expression::And(_, children) = &outcome.model.constraints else {
+        panic!("expected an And, got {}", outcome.model.constraints);
+    };
+    assert!(children
+        .iter()
+        .all(|child| matches!(child, Expression::Gt(_, _, _))));
+}
+
+#[test]
+fn rewrite_limits_parallelism() {
+    let expr = Expression::And(Metadata::new(), vec![x_lt_y(); 4]);
+    let run = |options: &RewriteOptions| {
+        TRIAL_THREADS.lock().unwrap().clear();
+        let model = Model::new(HashMap::new(), expr.clone(), Default::default());
+        let constraints = rewrite_model_with_options(&model, &rule_sets("Threads"), options)
+            .unwrap()
+            .model
+            .constraints;
+        let threads = TRIAL_THREADS.lock().unwrap().clone();
+        let on_other_threads = threads.iter().any(|&id| id != thread::current().id());
+        (constraints, on_other_threads)
+    };
+
+    let options = RewriteOptions::new().parallel_rule_trials(4);
+    let (expected, on_other_threads) = run(&options);
+    assert!(on_other_threads);
+
+    // The constraints have 13 sub-expressions, so are too small to try rules on in parallel
+    let (constraints, on_other_threads) = run(&options.clone().parallel_min_size(14));
+    assert_eq!(constraints, expected);
+    assert!(!on_other_threads);
+
+    let (constraints, on_other_threads) = run(&options.clone().max_threads(1));
+    assert_eq!(constraints, expected);
+    assert!(!on_other_threads);
+}
@@ -0,0 +1,750 @@
+// Tests for the traces, reports and callbacks the rewriter produces
+
+mod common;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use conjure_core::metadata::{Provenance, Span};
+use conjure_oxide::{
+    ast::*,
+    rule_engine::{
+        event_channel, explain, replay, rewrite_model_with_options, AttemptOutcome, IgnoredOption,
+        PhasedTrace, Progress, ReductionEvent, ReductionObserver, ReplayErrorKind, RewriteOptions,
+        RewriteStatus, RuleCoverage, TraceFilter,
+    },
+    ApplicationError, Metadata, Model, Reduction, Rule,
+};
+
+use common::*;
+
+#[test]
+fn rewrite_reports_performance() {
+    let expr = Expression::And(Metadata::new(), vec![x_lt_y(); 4]);
+    let model = Model::new(HashMap::new(), expr, Default::default());
+
+    let outcome =
+        rewrite_model_with_options(&model, &rule_sets("Pure"), &RewriteOptions::new()).unwrap();
+    assert!(outcome.perf.is_none());
+
+    let options = RewriteOptions::new().perf_report(true);
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Pure"), &options).unwrap();
+    let perf = outcome.perf.unwrap();
+
+    let rule = &perf.rules["pure_lt_to_gt"];
+    assert_eq!(rule.successes, 4);
+    assert!(rule.attempts >= 4);
+    assert!(perf.search >= rule.time);
+    assert_eq!(
+        perf.total,
+        perf.setup + perf.search + perf.apply + perf.checks
+    );
+    assert!(perf.to_string().contains("pure_lt_to_gt"));
+}
+
+#[test]
+fn rewrite_reports_rule_statistics() {
+    let expr = Expression::And(Metadata::new(), vec![x_lt_y(); 4]);
+    let model = Model::new(HashMap::new(), expr, Default::default());
+    let gt = lt_to_gt(&x_lt_y(), &model).unwrap().new_expression;
+
+    let options = RewriteOptions::new().perf_report(true);
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Hot"), &options).unwrap();
+    let perf = outcome.perf.unwrap();
+
+    let hot = &perf.rules["hot_lt_to_gt"];
+    assert_eq!(hot.successes, 4);
+    assert_eq!(hot.nodes_produced, 4 * gt.size());
+
+    // No not-equals constraint is ever seen, so the rule is never tried, but is still reported
+    let cold = &perf.rules["cold_neq_to_eq"];
+    assert_eq!(cold.successes, 0);
+    assert_eq!(cold.nodes_produced, 0);
+    assert_eq!(perf.unused_rules(), ["cold_neq_to_eq"]);
+}
+
+#[test]
+fn rule_coverage_accumulates_over_runs() {
+    let corpus = [vec![x_lt_y()], vec![x_lt_y(), x_lt_y()]];
+    let options = RewriteOptions::new().perf_report(true);
+
+    let mut coverage = RuleCoverage::new();
+    for constraints in corpus {
+        let expr = Expression::And(Metadata::new(), constraints);
+        let model = Model::new(HashMap::new(), expr, Default::default());
+        let outcome = rewrite_model_with_options(&model, &rule_sets("Reach"), &options).unwrap();
+        coverage.record(&outcome.perf.unwrap());
+    }
+
+    assert_eq!(coverage.runs, 2);
+    let hits = &coverage.rules["reach_lt_to_gt"];
+    assert_eq!((hits.fires, hits.runs), (3, 2));
+    // No not-equals constraint is in the corpus
+    assert_eq!(coverage.never_fired(), ["reach_neq_to_eq"]);
+    assert_eq!(coverage.never_tried(), ["reach_neq_to_eq"]);
+    assert!(coverage
+        .to_string()
+        .starts_with("1 of 2 rules fired over 2 runs"));
+
+    let file = std::env::temp_dir().join("conjure_oxide_rule_coverage_test.json");
+    coverage.save(&file).unwrap();
+    let mut loaded = RuleCoverage::load(&file).unwrap();
+    assert_eq!(loaded, coverage);
+
+    loaded.merge(&coverage);
+    assert_eq!(loaded.runs, 4);
+    assert_eq!(loaded.rules["reach_lt_to_gt"].fires, 6);
+    assert_eq!(loaded.never_fired(), ["reach_neq_to_eq"]);
+}
+
+#[test]
+fn rewrite_traces_rewrites() {
+    // Wide enough for the rewrites to be shared between threads in work stealing mode
+    let pair = [
+        Expression::Not(Metadata::new(), Box::new(x_lt_y())),
+        x_lt_y(),
+    ];
+    let expr = Expression::And(Metadata::new(), [pair.as_slice(); 10].concat());
+    let expected_paths: Vec<Vec<usize>> = (0..10)
+        .flat_map(|i| [vec![2 * i, 0], vec![2 * i + 1]])
+        .collect();
+    let gt = pure_lt_to_gt(&x_lt_y(), &Model::new_empty(Default::default()))
+        .unwrap()
+        .new_expression;
+    let model = Model::new(HashMap::new(), expr, Default::default());
+
+    let outcome =
+        rewrite_model_with_options(&model, &rule_sets("Pure"), &RewriteOptions::new()).unwrap();
+    assert!(outcome.trace.is_none());
+
+    let traced = RewriteOptions::new().trace(true);
+    for options in [
+        traced.clone(),
+        traced.clone().arena(true),
+        traced.clone().batch_rewrites(true).work_stealing(2),
+    ] {
+        let outcome = rewrite_model_with_options(&model, &rule_sets("Pure"), &options).unwrap();
+        let trace = outcome.trace.unwrap();
+        let paths: Vec<_> = trace.iter().map(|step| step.path.clone()).collect();
+        assert_eq!(paths, expected_paths);
+        for step in trace {
+            assert_eq!(step.rule, "pure_lt_to_gt");
+            let changed = TreeEdit::Changed {
+                path: step.path.clone(),
+                expression: gt.clone(),
+            };
+            assert_eq!(step.edits, [changed]);
+        }
+    }
+}
+
+#[test]
+fn replay_repeats_traced_rewrites() {
+    let x = || {
+        Box::new(Expression::Reference(
+            Metadata::new(),
+            Name::UserName(String::from("x")),
+        ))
+    };
+    let expr = Expression::And(
+        Metadata::new(),
+        vec![Expression::Eq(Metadata::new(), x(), x()), x_lt_y()],
+    );
+    let model = Model::new(HashMap::new(), expr, Default::default());
+    let options = RewriteOptions::new().trace(true);
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Aux"), &options).unwrap();
+    let trace = outcome.trace.unwrap();
+
+    // The new variable is added again by the rule, so the equality can be rewritten
+    let replayed = replay(&model, &trace).unwrap();
+    assert_eq!(replayed.constraints, outcome.model.constraints);
+    assert_eq!(replayed.variables, outcome.model.variables);
+
+    // Steps out of order no longer apply
+    let reordered = [trace[1].clone(), trace[0].clone()];
+    let error = replay(&model, &reordered).unwrap_err();
+    assert_eq!((error.step, error.rule.as_str()), (0, "aux_eq_to_neq"));
+    assert!(matches!(error.kind, ReplayErrorKind::NotApplied(_)));
+
+    let mut renamed = trace.clone();
+    renamed[1].rule = String::from("no_such_rule");
+    let error = replay(&model, &renamed).unwrap_err();
+    assert_eq!(error.step, 1);
+    assert!(matches!(error.kind, ReplayErrorKind::UnknownRule));
+
+    let mut moved = trace.clone();
+    moved[0].path = vec![5];
+    let error = replay(&model, &moved).unwrap_err();
+    assert!(matches!(error.kind, ReplayErrorKind::MissingExpression));
+
+    // A rule that now rewrites differently from the recording is caught
+    let mut edited = trace.clone();
+    edited[0].edits.clear();
+    let error = replay(&model, &edited).unwrap_err();
+    assert!(matches!(error.kind, ReplayErrorKind::DifferentEdits { .. }));
+    assert!(error.source().is_some());
+}
+
+#[test]
+fn rewrite_reports_timeline_of_iterations() {
+    let expr = Expression::And(Metadata::new(), vec![x_lt_y(); 3]);
+    let model = Model::new(HashMap::new(), expr, Default::default());
+
+    let options = RewriteOptions::new().perf_report(true);
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Pure"), &options).unwrap();
+    assert!(outcome.perf.unwrap().timeline.is_empty());
+
+    for arena in [false, true] {
+        let options = RewriteOptions::new().perf_timeline(true).arena(arena);
+        let outcome = rewrite_model_with_options(&model, &rule_sets("Pure"), &options).unwrap();
+        let perf = outcome.perf.unwrap();
+
+        // One iteration per rewrite, and a last one finding nothing to rewrite
+        let rewrites: Vec<usize> = perf.timeline.iter().map(|t| t.rewrites).collect();
+        assert_eq!(rewrites, [1, 1, 1, 0]);
+        for timing in &perf.timeline {
+            assert_eq!(
+                timing.total,
+                timing.traversal + timing.rules + timing.rebuild + timing.apply + timing.checks
+            );
+        }
+        let rules: Duration = perf.timeline.iter().map(|t| t.rules).sum();
+        assert!(rules <= perf.rules["pure_lt_to_gt"].time);
+        assert_eq!(perf.slowest_iterations().len(), 4);
+    }
+}
+
+#[test]
+fn rewrite_counts_attempts_by_path() {
+    let expr = Expression::And(Metadata::new(), vec![x_lt_y(); 2]);
+    let model = Model::new(HashMap::new(), expr, Default::default());
+    let options = RewriteOptions::new()
+        .attempt_heatmap(true)
+        .perf_report(true);
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Pure"), &options).unwrap();
+
+    let heatmap = outcome.heatmap.unwrap();
+    let attempts: usize = outcome
+        .perf
+        .unwrap()
+        .rules
+        .values()
+        .map(|r| r.attempts)
+        .sum();
+    assert_eq!(heatmap.total(), attempts);
+    assert_eq!(heatmap.below(&[]), attempts);
+    assert!(heatmap.at(&[0]) > 0);
+    assert_eq!(
+        heatmap.below(&[0]) + heatmap.below(&[1]) + heatmap.at(&[]),
+        attempts
+    );
+    assert_eq!(heatmap.at(&[5]), 0);
+
+    let hottest = heatmap.hottest();
+    assert_eq!(hottest.len(), heatmap.attempts.len());
+    assert!(hottest.windows(2).all(|w| w[0].1 >= w[1].1));
+
+    // Nothing is counted unless asked for
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Pure"), &RewriteOptions::new());
+    assert!(outcome.unwrap().heatmap.is_none());
+}
+
+#[test]
+fn phased_trace_labels_runs_and_snapshots_checkpoints() {
+    let x_neq_y = Expression::Neq(
+        Metadata::new(),
+        Box::new(Expression::Reference(
+            Metadata::new(),
+            Name::UserName(String::from("x")),
+        )),
+        Box::new(Expression::Reference(
+            Metadata::new(),
+            Name::UserName(String::from("y")),
+        )),
+    );
+    let expr = Expression::And(Metadata::new(), vec![x_lt_y(), x_neq_y.clone()]);
+    let model = Model::new(HashMap::new(), expr.clone(), Default::default());
+    let options = RewriteOptions::new().trace(true);
+
+    let mut trace = PhasedTrace::new(&expr);
+    trace.checkpoint("start");
+    // Only rewrites less-than constraints
+    let first = rewrite_model_with_options(&model, &rule_sets("Effects"), &options).unwrap();
+    trace.add_phase("lt", first.trace.unwrap());
+    trace.checkpoint("after lt");
+    // Only rewrites not-equals constraints
+    let second = rewrite_model_with_options(&first.model, &rule_sets("Hot"), &options).unwrap();
+    trace.add_phase("neq", second.trace.unwrap());
+    trace.checkpoint("after neq");
+
+    assert_eq!(trace.steps.len(), 2);
+    assert_eq!(trace.phase_of(0), Some("lt"));
+    assert_eq!(trace.phase_of(1), Some("neq"));
+    assert_eq!(trace.phase_of(2), None);
+
+    assert_eq!(trace.snapshot("start"), Some(expr));
+    let gt = lt_to_gt(&x_lt_y(), &model).unwrap().new_expression;
+    assert_eq!(
+        trace.snapshot("after lt"),
+        Some(Expression::And(Metadata::new(), vec![gt, x_neq_y]))
+    );
+    assert_eq!(trace.snapshot("after neq"), Some(second.model.constraints));
+    assert_eq!(trace.snapshot("after flattening"), None);
+}
+
+#[test]
+fn rewrite_filters_and_samples_traces() {
+    let pair = [
+        Expression::Not(Metadata::new(), Box::new(x_lt_y())),
+        x_lt_y(),
+    ];
+    let expr = Expression::And(Metadata::new(), [pair.as_slice(); 10].concat());
+    let model = Model::new(HashMap::new(), expr, Default::default());
+    let traced_paths = |filter: TraceFilter| {
+        let options = RewriteOptions::new().trace(true).trace_filter(filter);
+        let outcome = rewrite_model_with_options(&model, &rule_sets("Pure"), &options).unwrap();
+        let trace = outcome.trace.unwrap();
+        trace.into_iter().map(|step| step.path).collect::<Vec<_>>()
+    };
+    let top_level = |indices: &[usize]| indices.iter().map(|&i| vec![i]).collect::<Vec<_>>();
+
+    assert_eq!(
+        traced_paths(TraceFilter::new().rules(["pure_lt_to_gt"])).len(),
+        20
+    );
+    assert!(traced_paths(TraceFilter::new().rules(["lt_to_gt"])).is_empty());
+    assert_eq!(
+        traced_paths(TraceFilter::new().path_prefix(vec![4])),
+        [vec![4, 0]]
+    );
+    assert_eq!(
+        traced_paths(TraceFilter::new().max_depth(1)),
+        top_level(&[1, 3, 5, 7, 9, 11, 13, 15, 17, 19])
+    );
+
+    // Sampling counts only the rewrites that pass the other filters
+    assert_eq!(
+        traced_paths(TraceFilter::new().max_depth(1).every(3)),
+        top_level(&[1, 7, 13, 19])
+    );
+    assert_eq!(
+        traced_paths(TraceFilter::new().matching(|step| step.path[0] < 4)),
+        [vec![0, 0], vec![1], vec![2, 0], vec![3]]
+    );
+}
+
+#[cfg(feature = "html-report")]
+#[test]
+fn rewrite_renders_html_reports() {
+    use conjure_oxide::html_report;
+
+    let expr = Expression::And(Metadata::new(), vec![x_lt_y(); 2]);
+    let model = Model::new(HashMap::new(), expr.clone(), Default::default());
+    let options = RewriteOptions::new().trace(true).perf_report(true);
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Pure"), &options).unwrap();
+
+    let html = html_report::to_html("x < y & <y>", &expr, &outcome);
+    assert!(html.starts_with("<!DOCTYPE html>"));
+    assert!(html.contains("<title>x &lt; y &amp; &lt;y&gt;</title>"));
+    assert!(html.contains("<td>pure_lt_to_gt</td><td>11</td><td>2</td><td>6</td>"));
+    assert_eq!(html.matches("<details class=\"step\">").count(), 2);
+    assert_eq!(html.matches("<h4>Before</h4>").count(), 2);
+    assert!(html.contains("<details open><summary>Gt</summary>"));
+    assert!(html.trim_end().ends_with("</html>"));
+
+    // Without a performance report, the rewrites are counted from the trace
+    let options = RewriteOptions::new().trace(true);
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Pure"), &options).unwrap();
+    let file = std::env::temp_dir().join("conjure_oxide_html_report_test.html");
+    html_report::save(&file, "Report", &expr, &outcome).unwrap();
+    let html = std::fs::read_to_string(&file).unwrap();
+    std::fs::remove_file(&file).unwrap();
+    assert!(html.contains("<tr><td>pure_lt_to_gt</td><td>2</td></tr>"));
+}
+
+#[cfg(feature = "json-traces")]
+#[test]
+fn rewrite_exports_traces_as_json() {
+    use conjure_oxide::rule_engine::TraceExport;
+
+    let expr = Expression::And(Metadata::new(), vec![x_lt_y(); 3]);
+    let model = Model::new(HashMap::new(), expr.clone(), Default::default());
+    let options = RewriteOptions::new().trace(true);
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Pure"), &options).unwrap();
+
+    let export = TraceExport::new(&expr, &outcome.trace.unwrap());
+    assert!(export.to_json().unwrap().contains("\"pure_lt_to_gt\""));
+
+    let file = std::env::temp_dir().join("conjure_oxide_trace_export_test.json");
+    export.save(&file).unwrap();
+    let loaded = TraceExport::load(&file).unwrap();
+    std::fs::remove_file(&file).unwrap();
+
+    // The rewritten constraints can be rebuilt from the loaded trace
+    assert_eq!(loaded.steps.len(), 3);
+    let mut constraints = loaded.constraints;
+    for step in &loaded.steps {
+        assert!(step.apply(&mut constraints));
+    }
+    assert_eq!(constraints, outcome.model.constraints);
+}
+
+/// Records the callbacks made to it, in order.
+struct RecordingObserver(Arc<Mutex<Vec<String>>>);
+
+impl RecordingObserver {
+    fn push(&self, event: String) {
+        if let Ok(mut events) = self.0.lock() {
+            events.push(event);
+        }
+    }
+}
+
+impl ReductionObserver for RecordingObserver {
+    fn on_attempt(&self, rule: &Rule, path: &[usize], applied: bool) {
+        self.push(format!("attempt {} {:?} {}", rule.name, path, applied));
+    }
+
+    fn on_applied(&self, rule: &Rule, path: &[usize], before: &Expression, reduction: &Reduction) {
+        assert_eq!(before, &x_lt_y());
+        assert_ne!(&reduction.new_expression, before);
+        self.push(format!("applied {} {:?}", rule.name, path));
+    }
+
+    fn on_iteration(&self, rules: &[&Rule], model: &Model) {
+        let names: Vec<_> = rules.iter().map(|rule| rule.name).collect();
+        self.push(format!("iteration {:?} {}", names, model.constraints));
+    }
+
+    fn on_fixpoint(&self, model: &Model) {
+        self.push(format!("fixpoint {}", model.constraints));
+    }
+}
+
+#[test]
+fn explain_reports_why_expressions_are_not_rewritten() {
+    let expr = Expression::And(Metadata::new(), vec![x_lt_y()]);
+    let model = Model::new(HashMap::new(), expr, Default::default());
+    let rule_sets = rule_sets("Explain");
+    let rewritten = rewrite_model_with_options(&model, &rule_sets, &RewriteOptions::new())
+        .unwrap()
+        .model;
+
+    let explanation = explain(&rewritten, &rule_sets, &[0]).unwrap().unwrap();
+    assert_eq!(
+        explanation.expression,
+        lt_to_gt(&x_lt_y(), &model).unwrap().new_expression
+    );
+
+    // Rules are listed in the order they are tried, highest priority first
+    let attempts: Vec<_> = explanation
+        .attempts
+        .iter()
+        .map(|attempt| (attempt.rule.as_str(), attempt.priority, &attempt.outcome))
+        .collect();
+    assert_eq!(
+        attempts,
+        [
+            ("explain_lt_to_gt", 100, &AttemptOutcome::NotTried),
+            (
+                "explain_gt_needs_aux",
+                50,
+                &AttemptOutcome::NotApplicable(Some(String::from("aux is not in the model")))
+            ),
+            (
+                "explain_gt_bound_error",
+                20,
+                &AttemptOutcome::Failed(ApplicationError::BoundError.to_string())
+            ),
+            (
+                "explain_neq_to_eq",
+                10,
+                &AttemptOutcome::NotApplicable(None)
+            ),
+        ]
+    );
+    assert!(explanation
+        .to_string()
+        .contains("explain_gt_needs_aux (priority 50): not applicable: aux is not in the model"));
+
+    // Explaining the original model shows the rule that would rewrite it
+    let before = explain(&model, &rule_sets, &[0]).unwrap().unwrap();
+    assert_eq!(before.attempts[0].outcome, AttemptOutcome::Applies);
+
+    assert!(explain(&rewritten, &rule_sets, &[1]).unwrap().is_none());
+}
+
+#[test]
+fn rewrite_reports_progress() {
+    let expr = Expression::And(Metadata::new(), vec![x_lt_y(); 4]);
+
+    for options in [RewriteOptions::new(), RewriteOptions::new().arena(true)] {
+        let model = Model::new(HashMap::new(), expr.clone(), Default::default());
+        let reports: Arc<Mutex<Vec<Progress>>> = Arc::default();
+        let recorded = reports.clone();
+        let options = options.on_progress(Duration::ZERO, move |progress| {
+            recorded.lock().unwrap().push(progress.clone())
+        });
+        rewrite_model_with_options(&model, &rule_sets("Hot"), &options).unwrap();
+
+        // Once after every rewrite, and once more at the end
+        let reports = reports.lock().unwrap();
+        let rewrites: Vec<usize> = reports.iter().map(|progress| progress.rewrites).collect();
+        assert_eq!(rewrites, [1, 2, 3, 4, 4]);
+        assert!(reports
+            .windows(2)
+            .all(|pair| pair[0].visited < pair[1].visited));
+
+        // Every expression is known to be fully rewritten by the end
+        let last = reports.last().unwrap();
+        assert_eq!(last.dirty, Some(0));
+        assert!(reports[0].dirty.unwrap() > 0);
+        assert!(last.to_string().starts_with("4 rewrites"));
+    }
+
+    // Reports are limited to one per interval, besides the last
+    let model = Model::new(HashMap::new(), expr, Default::default());
+    let count = Arc::new(Mutex::new(0));
+    let counted = count.clone();
+    let options = RewriteOptions::new().on_progress(Duration::from_secs(3600), move |_| {
+        *counted.lock().unwrap() += 1
+    });
+    rewrite_model_with_options(&model, &rule_sets("Hot"), &options).unwrap();
+    assert_eq!(*count.lock().unwrap(), 1);
+}
+
+#[test]
+fn rewrite_records_watches_in_trace() {
+    let x = || {
+        Box::new(Expression::Reference(
+            Metadata::new(),
+            Name::UserName(String::from("x")),
+        ))
+    };
+    let expr = Expression::And(
+        Metadata::new(),
+        vec![Expression::Eq(Metadata::new(), x(), x()), x_lt_y()],
+    );
+    let model = Model::new(HashMap::new(), expr, Default::default());
+
+    let options = RewriteOptions::new()
+        .trace(true)
+        .arena(true)
+        .watch("variables", |_, model| model.variables.len())
+        .watch("size", |constraints, _| constraints.size());
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Aux"), &options).unwrap();
+    let size = outcome.model.constraints.size().to_string();
+
+    let watches: Vec<_> = outcome
+        .trace
+        .unwrap()
+        .into_iter()
+        .map(|step| step.watches)
+        .collect();
+    let watch = |name: &str, value: &str| (String::from(name), String::from(value));
+    // The first rewrite adds the variable, and neither changes the size of the constraints
+    assert_eq!(
+        watches,
+        [
+            vec![watch("variables", "1"), watch("size", &size)],
+            vec![watch("variables", "1"), watch("size", &size)],
+        ]
+    );
+}
+
+#[test]
+fn rewrite_calls_observers() {
+    let expr = Expression::And(Metadata::new(), vec![x_lt_y(), x_lt_y()]);
+    let model = Model::new(HashMap::new(), expr, Default::default());
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let others = Arc::new(Mutex::new(Vec::new()));
+    let options = RewriteOptions::new()
+        .observer(RecordingObserver(events.clone()))
+        .observer(RecordingObserver(others.clone()))
+        .arena(true);
+
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Pure"), &options).unwrap();
+    assert_eq!(outcome.status, RewriteStatus::Fixpoint);
+    // Observers are called between rewrites, so the arena is not used
+    assert_eq!(outcome.ignored_options, vec![IgnoredOption::Arena]);
+
+    let events = events.lock().unwrap().clone();
+    assert_eq!(events, *others.lock().unwrap());
+    let applied: Vec<_> = events
+        .iter()
+        .filter(|event| event.starts_with("applied"))
+        .collect();
+    assert_eq!(
+        applied,
+        ["applied pure_lt_to_gt [0]", "applied pure_lt_to_gt [1]"]
+    );
+
+    // Each rewrite is attempted, applied, and then has its side-effects applied
+    let position = |event: &str| events.iter().position(|e| e == event).unwrap();
+    let iterations: Vec<_> = (0..events.len())
+        .filter(|&i| events[i].starts_with("iteration [\"pure_lt_to_gt\"]"))
+        .collect();
+    assert_eq!(iterations.len(), 2);
+    for (path, iteration) in ["[0]", "[1]"].into_iter().zip(iterations) {
+        let attempted = position(&format!("attempt pure_lt_to_gt {} true", path));
+        let applied = position(&format!("applied pure_lt_to_gt {}", path));
+        assert!(attempted < applied && applied < iteration);
+    }
+    assert_eq!(
+        events.last().unwrap(),
+        &format!("fixpoint {}", outcome.model.constraints)
+    );
+}
+
+#[test]
+fn rewrite_sends_events_to_another_thread() {
+    let expr = Expression::And(Metadata::new(), vec![x_lt_y(); 2]);
+    let model = Model::new(HashMap::new(), expr, Default::default());
+    let (events, receiver) = event_channel();
+
+    let rewriter = thread::spawn(move || {
+        let options = RewriteOptions::new().observer(events);
+        rewrite_model_with_options(&model, &rule_sets("Hot"), &options)
+            .map(|outcome| outcome.status)
+    });
+    // The receiver stops once the options holding the sender are dropped
+    let events: Vec<ReductionEvent> = receiver.iter().collect();
+    assert_eq!(rewriter.join().unwrap().unwrap(), RewriteStatus::Fixpoint);
+
+    let size = Expression::And(Metadata::new(), vec![x_lt_y(); 2]).size();
+    let gt = lt_to_gt(&x_lt_y(), &Model::new_empty(Default::default()))
+        .unwrap()
+        .new_expression;
+    let applied = |i| ReductionEvent::Applied {
+        rule: String::from("hot_lt_to_gt"),
+        path: vec![i],
+        before: Box::new(x_lt_y()),
+        after: Box::new(gt.clone()),
+        new_top: Box::new(Expression::Nothing),
+    };
+    let iteration = ReductionEvent::Iteration {
+        rules: vec![String::from("hot_lt_to_gt")],
+        size,
+    };
+    assert_eq!(
+        events,
+        [
+            applied(0),
+            iteration.clone(),
+            applied(1),
+            iteration,
+            ReductionEvent::Fixpoint
+        ]
+    );
+
+    // Attempts are only sent when asked for
+    let model = Model::new(HashMap::new(), x_lt_y(), Default::default());
+    let (events, receiver) = event_channel();
+    let options = RewriteOptions::new().observer(events.attempts(true));
+    rewrite_model_with_options(&model, &rule_sets("Hot"), &options).unwrap();
+    drop(options);
+    let attempted = receiver
+        .iter()
+        .filter(|event| matches!(event, ReductionEvent::Attempted { .. }))
+        .count();
+    assert!(attempted > 0);
+}
+
+#[test]
+fn rewrite_carries_spans_through_rewrites() {
+    let span = |start, end| Span { start, end };
+    let reference =
+        |name: &str| Expression::Reference(Metadata::new(), Name::UserName(name.into()));
+    // `x < y`, as parsed from the source
+    let expr = Expression::Lt(
+        Metadata::new(),
+        Box::new(reference("x").with_span(span(0, 1))),
+        Box::new(reference("y").with_span(span(4, 5))),
+    )
+    .with_span(span(0, 5));
+    let model = Model::new(HashMap::new(), expr, Default::default());
+
+    let outcome =
+        rewrite_model_with_options(&model, &rule_sets("Effects"), &RewriteOptions::new()).unwrap();
+    assert_eq!(outcome.model.constraints.span(), None);
+
+    let options = RewriteOptions::new().inherit_spans();
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Effects"), &options).unwrap();
+    let constraints = &outcome.model.constraints;
+    assert!(matches!(constraints, Expression::Gt(_, _, _)));
+    assert_eq!(constraints.span(), Some(span(0, 5)));
+    // The references were kept, along with their own spans
+    assert_eq!(constraints.at_path(&[0]).unwrap().span(), Some(span(4, 5)));
+    assert_eq!(constraints.at_path(&[1]).unwrap().span(), Some(span(0, 1)));
+}
+
+#[test]
+fn rewrite_records_provenance() {
+    let x = || {
+        Box::new(Expression::Reference(
+            Metadata::new(),
+            Name::UserName(String::from("x")),
+        ))
+    };
+    let expr = Expression::And(
+        Metadata::new(),
+        vec![Expression::Eq(Metadata::new(), x(), x()), x_lt_y()],
+    );
+    let model = Model::new(HashMap::new(), expr, Default::default());
+
+    let outcome =
+        rewrite_model_with_options(&model, &rule_sets("Aux"), &RewriteOptions::new()).unwrap();
+    assert!(outcome.model.constraints.provenance().is_none());
+    assert!(outcome
+        .model
+        .constraints
+        .child(0)
+        .unwrap()
+        .provenance()
+        .is_none());
+
+    for options in [RewriteOptions::new(), RewriteOptions::new().arena(true)] {
+        let outcome =
+            rewrite_model_with_options(&model, &rule_sets("Aux"), &options.provenance(true))
+                .unwrap();
+        let constraints = &outcome.model.constraints;
+        let provenance = |path: &[usize]| constraints.at_path(path).unwrap().provenance().cloned();
+
+        // The less-than constraint adds the variable the equality needs, so is rewritten first
+        assert_eq!(
+            provenance(&[1]),
+            Some(Provenance {
+                rule: String::from("aux_lt_to_gt"),
+                iteration: 0,
+            })
+        );
+        assert_eq!(
+            provenance(&[0]),
+            Some(Provenance {
+                rule: String::from("aux_eq_to_neq"),
+                iteration: 1,
+            })
+        );
+        // Sub-expressions kept by the rule, and the untouched root, are not marked
+        assert_eq!(provenance(&[1, 0]), None);
+        assert_eq!(provenance(&[]), None);
+    }
+
+    // A pass of batch mode is one iteration, however many rewrites it makes
+    let expr = Expression::And(Metadata::new(), vec![x_lt_y(), x_lt_y()]);
+    let model = Model::new(HashMap::new(), expr, Default::default());
+    let options = RewriteOptions::new().batch_rewrites(true).provenance(true);
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Pure"), &options).unwrap();
+    for child in 0..2 {
+        assert_eq!(
+            outcome.model.constraints.child(child).unwrap().provenance(),
+            Some(&Provenance {
+                rule: String::from("pure_lt_to_gt"),
+                iteration: 0,
+            })
+        );
+    }
+}
@@ -0,0 +1,366 @@
+// Tests for what rules can do besides rewriting an expression: take subtrees, change the model
+// directly, and keep state
+
+mod common;
+
+use std::any::TypeId;
+use std::collections::HashMap;
+
+use conjure_core::meta::{MetaInvalidation, MetaStore};
+use conjure_oxide::{
+    ast::*,
+    get_rule_by_name,
+    rule_engine::{
+        apply_to_model_copy, rewrite_model_with_options, Backtracking, NoOpPolicy, RewriteError,
+        RewriteOptions, RewriteStatus, RuleError, RuleErrorKind,
+    },
+    ApplicationError, ApplicationResult, Metadata, Model,
+};
+
+use common::*;
+
+#[test]
+fn rewrite_lets_rules_take_subtrees() {
+    let expr = Expression::Not(Metadata::new(), Box::new(x_lt_y()));
+    let model = Model::new(HashMap::new(), expr, Default::default());
+
+    let outcome =
+        rewrite_model_with_options(&model, &rule_sets("Subtree"), &RewriteOptions::new()).unwrap();
+    let Expression::Not(_, contents) = &outcome.model.constraints else {
+        panic!("expected a Not");
+    };
+    assert!(matches!(**contents, Expression::Gt(_, _, _)));
+
+    // Rules that take a subtree can still be applied to a borrowed expression
+    let rule = get_rule_by_name("subtree_lt_to_gt").unwrap();
+    let reduction = rule.apply(&x_lt_y(), &model).unwrap();
+    assert!(matches!(reduction.new_expression, Expression::Gt(_, _, _)));
+}
+
+#[test]
+fn rewrite_rejects_rules_that_take_subtrees_without_rewriting() {
+    let expr = Expression::Not(Metadata::new(), Box::new(x_lt_y()));
+    let model = Model::new(HashMap::new(), expr, Default::default());
+
+    let result = rewrite_model_with_options(&model, &rule_sets("Greedy"), &RewriteOptions::new());
+
+    match result {
+        Err(RewriteError::Rule(RuleError {
+            rule,
+            path,
+            kind: RuleErrorKind::TakenWithoutRewrite,
+            ..
+        })) => {
+            assert_eq!(rule, "greedy_lt");
+            assert_eq!(path, vec![0]);
+        }
+        other => panic!("expected a TakenWithoutRewrite error, got {:?}", other),
+    }
+}
+
+#[test]
+fn rewrite_skips_no_op_rules_that_take_subtrees() {
+    let expr = Expression::Not(Metadata::new(), Box::new(x_lt_y()));
+    let model = Model::new(HashMap::new(), expr, Default::default());
+
+    // The expression is put back, so the next rule can still be applied to it
+    let options = RewriteOptions::new().max_rewrites(10);
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Normalise"), &options).unwrap();
+    assert!(outcome.is_complete());
+    let Expression::Not(_, contents) = &outcome.model.constraints else {
+        panic!("expected a Not");
+    };
+    assert!(matches!(**contents, Expression::Gt(_, _, _)));
+
+    let options = options.on_no_op(NoOpPolicy::Error);
+    let result = rewrite_model_with_options(&model, &rule_sets("Normalise"), &options);
+    assert!(matches!(
+        result,
+        Err(RewriteError::Rule(RuleError {
+            kind: RuleErrorKind::NoOpRewrite,
+            ..
+        }))
+    ));
+}
+
+#[test]
+fn rules_can_change_the_model_directly() {
+    let model = Model::new(HashMap::new(), x_lt_y(), Default::default());
+
+    // The rule changes a copy of the model, and its changes are returned as symbols
+    let rule = get_rule_by_name("mut_model_lt_to_gt").unwrap();
+    let reduction = rule.apply(&x_lt_y(), &model).unwrap();
+    assert!(model.variables.is_empty());
+    assert_eq!(reduction.symbols.len(), 2);
+    assert!(reduction.symbols.contains_key(&aux()));
+
+    // Changes made by rules that do not apply are undone
+    let outcome =
+        rewrite_model_with_options(&model, &rule_sets("MutModel"), &RewriteOptions::new()).unwrap();
+    assert_eq!(outcome.status, RewriteStatus::Fixpoint);
+    let names: Vec<_> = outcome.model.variables.keys().cloned().collect();
+    assert_eq!(names.len(), 2);
+    assert!(names.contains(&aux()));
+    // Names made for a copy are not made again
+    assert!(names.contains(&Name::MachineName(1)));
+    assert_eq!(outcome.model.gensym(), Name::MachineName(2));
+
+    // Symbols removed from the copy cannot be removed by the reduction, so the rule fails
+    fn remove_aux(expr: &Expression, mdl: &mut Model) -> ApplicationResult {
+        mdl.variables.remove(&aux());
+        lt_to_gt(expr, mdl)
+    }
+    let result = apply_to_model_copy(&x_lt_y(), &outcome.model, remove_aux);
+    assert!(matches!(result, Err(ApplicationError::SymbolsRemoved(names)) if names == [aux()]));
+    assert!(outcome.model.variables.contains_key(&aux()));
+}
+
+#[test]
+fn rules_keep_their_own_state_in_the_meta_store() {
+    let expr = Expression::And(Metadata::new(), vec![x_lt_y(); 2]);
+    let model = Model::new(HashMap::new(), expr, Default::default());
+
+    let outcome =
+        rewrite_model_with_options(&model, &rule_sets("Meta"), &RewriteOptions::new()).unwrap();
+
+    assert_eq!(outcome.status, RewriteStatus::Fixpoint);
+    assert!(model.meta.is_empty());
+    assert_eq!(outcome.model.meta.len(), 2);
+    assert_eq!(outcome.model.meta.get::<LtRewrites>(), Some(&LtRewrites(2)));
+    assert_eq!(outcome.model.meta.get::<GtRewrites>(), Some(&GtRewrites(2)));
+}
+
+#[test]
+fn meta_listeners_say_which_rules_to_try_again() {
+    let truth = Expression::Constant(Metadata::new(), Constant::Bool(true));
+    let expr = Expression::And(
+        Metadata::new(),
+        vec![
+            x_lt_y(),
+            Expression::Not(Metadata::new(), Box::new(truth.clone())),
+        ],
+    );
+    let mut model = Model::new(HashMap::new(), expr, Default::default());
+    model.meta.listen::<Guard>();
+    let options = RewriteOptions::new().memoize_failures(true);
+
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Guarded"), &options).unwrap();
+
+    // `guarded_lt_to_gt` was found not to apply to `x < y` before the guard was opened
+    let Expression::And(_, constraints) = &outcome.model.constraints else {
+        panic!("Expected a conjunction");
+    };
+    assert!(matches!(constraints[0], Expression::Gt(_, _, _)));
+    assert_eq!(constraints[1], truth);
+
+    let mut changes = MetaStore::new();
+    changes.insert(Guard(true));
+    assert_eq!(
+        model.meta.invalidation(&changes),
+        MetaInvalidation::Rules(vec!["guarded_lt_to_gt"])
+    );
+    assert_eq!(
+        outcome.model.meta.invalidation(&changes),
+        MetaInvalidation::Nothing
+    );
+    assert_eq!(
+        MetaStore::new().invalidation(&changes),
+        MetaInvalidation::All
+    );
+}
+
+#[test]
+fn rewrite_puts_the_meta_of_binders_in_scope() {
+    let not = |expr| Expression::Not(Metadata::new(), Box::new(expr));
+    let expr = Expression::And(Metadata::new(), vec![x_lt_y(), not(x_lt_y())]);
+    let model = Model::new(HashMap::new(), expr, Default::default());
+    let options = RewriteOptions::new().binders(|expression, _| match expression {
+        Expression::Not(_, _) => {
+            let mut scope = MetaStore::new();
+            scope.insert(Negated);
+            Some(scope)
+        }
+        _ => None,
+    });
+
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Scoped"), &options).unwrap();
+
+    let Expression::And(_, constraints) = &outcome.model.constraints else {
+        panic!("Expected a conjunction");
+    };
+    assert_eq!(constraints[0], x_lt_y());
+    assert!(matches!(
+        &constraints[1],
+        Expression::Not(_, inner) if matches!(**inner, Expression::Gt(_, _, _))
+    ));
+    assert!(outcome.model.meta.is_empty());
+
+    // Without binders, the rule never applies
+    let outcome =
+        rewrite_model_with_options(&model, &rule_sets("Scoped"), &RewriteOptions::new()).unwrap();
+    assert_eq!(outcome.model.constraints, model.constraints);
+}
+
+#[test]
+fn rules_declare_the_meta_they_read_and_set() {
+    let rule = get_rule_by_name("unlocked_lt_to_gt").unwrap();
+    assert_eq!(rule.reads_meta.unwrap()[0](), TypeId::of::<Unlocked>());
+    assert!(rule.writes_meta.is_none());
+
+    // Rules are not tried without the meta they read
+    let mut model = Model::new(HashMap::new(), x_lt_y(), Default::default());
+    let outcome =
+        rewrite_model_with_options(&model, &rule_sets("Declared"), &RewriteOptions::new()).unwrap();
+    assert_eq!(outcome.model.constraints, x_lt_y());
+
+    // Rules tried on other threads are given the meta they read
+    model.meta.insert(Unlocked);
+    let options = RewriteOptions::new()
+        .parallel_rule_trials(2)
+        .parallel_min_size(1);
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Declared"), &options).unwrap();
+    assert!(matches!(outcome.model.constraints, Expression::Gt(_, _, _)));
+
+    let result =
+        rewrite_model_with_options(&model, &rule_sets("Undeclared"), &RewriteOptions::new());
+    let Err(RewriteError::Rule(RuleError {
+        kind: RuleErrorKind::UndeclaredMeta { type_name },
+        ..
+    })) = result
+    else {
+        panic!("Expected an undeclared meta error");
+    };
+    assert!(type_name.ends_with("GtRewrites"));
+}
+
+#[test]
+fn rewrite_keeps_derived_meta_up_to_date() {
+    let expr = Expression::And(Metadata::new(), vec![x_lt_y(); 3]);
+    let model = Model::new(HashMap::new(), expr, Default::default());
+    let options = RewriteOptions::new().derive_meta::<LtCount>();
+
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Derived"), &options).unwrap();
+
+    let Expression::And(_, constraints) = &outcome.model.constraints else {
+        panic!("Expected a conjunction");
+    };
+    assert!(matches!(constraints[0], Expression::Gt(_, _, _)));
+    assert!(matches!(constraints[1], Expression::Gt(_, _, _)));
+    assert_eq!(constraints[2], x_lt_y());
+    assert_eq!(outcome.model.meta.get::<LtCount>(), Some(&LtCount(1)));
+
+    // Without the derived meta, the rule is never tried
+    let outcome =
+        rewrite_model_with_options(&model, &rule_sets("Derived"), &RewriteOptions::new()).unwrap();
+    assert_eq!(outcome.model.constraints, model.constraints);
+}
+
+#[test]
+fn rewrite_records_meta_diffs_in_trace() {
+    let model = Model::new(HashMap::new(), x_lt_y(), Default::default());
+    let options = RewriteOptions::new().trace(true).diff_meta::<LtRewrites>();
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Meta"), &options).unwrap();
+
+    let diffs: Vec<_> = outcome
+        .trace
+        .unwrap()
+        .into_iter()
+        .map(|step| (step.rule, step.meta_diffs))
+        .collect();
+    let name = std::any::type_name::<LtRewrites>().to_string();
+    assert_eq!(
+        diffs,
+        vec![
+            (
+                String::from("meta_lt_to_gt"),
+                vec![(name, String::from("None -> LtRewrites(1)"))]
+            ),
+            (String::from("meta_gt_to_not_leq"), vec![]),
+        ]
+    );
+
+    let options = RewriteOptions::new().trace(true).diff_symbols();
+    let outcome = rewrite_model_with_options(&model, &rule_sets("MutModel"), &options).unwrap();
+    let trace = outcome.trace.unwrap();
+    let [(name, diff)] = trace[0].meta_diffs.as_slice() else {
+        panic!("Expected a diff of the symbols");
+    };
+    assert_eq!(name, "symbols");
+    assert!(diff.starts_with("added "));
+    assert!(diff.contains(&aux().to_string()));
+}
+
+/// Meta that is large and cannot be copied.
+struct Catalogue(Vec<usize>);
+
+#[test]
+fn meta_is_shared_with_copies_of_the_model_rather_than_copied() {
+    let expr = Expression::And(Metadata::new(), vec![x_lt_y(); 2]);
+    let mut model = Model::new(HashMap::new(), expr, Default::default());
+    model.meta.insert(Catalogue((0..1000).collect()));
+    model.meta.insert(GtRewrites(5));
+    let options = RewriteOptions::new().quarantine_after(1);
+
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Meta"), &options).unwrap();
+
+    assert_eq!(outcome.status, RewriteStatus::Fixpoint);
+    let catalogue = model.meta.get::<Catalogue>().unwrap();
+    assert_eq!(catalogue.0.len(), 1000);
+    assert!(std::ptr::eq(
+        catalogue,
+        outcome.model.meta.get::<Catalogue>().unwrap()
+    ));
+    // Values that are changed are copied first, leaving the model given to the rewriter as it was
+    assert_eq!(model.meta.get::<GtRewrites>(), Some(&GtRewrites(5)));
+    assert_eq!(outcome.model.meta.get::<GtRewrites>(), Some(&GtRewrites(7)));
+}
+
+#[test]
+fn backtracking_rewinds_checkpointed_meta() {
+    let journal = Journal::default();
+    let mut model = Model::new(HashMap::new(), x_lt_y(), Default::default());
+    model.meta.insert(journal.clone());
+    model.meta.checkpointed::<Journal>();
+    let needs_aux = Backtracking::new(|model| match model.variables.contains_key(&aux()) {
+        true => Ok(()),
+        false => Err(String::from("aux is not in the model")),
+    });
+    let options = RewriteOptions::new().backtracking(needs_aux);
+
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Journaled"), &options).unwrap();
+
+    assert_eq!(outcome.status, RewriteStatus::Fixpoint);
+    assert!(outcome.model.variables.contains_key(&aux()));
+    // Each rule was tried on `x < y`, then on `x > y` with `aux`. The rules tried on `x > y`
+    // without `aux`, the dead end, were forgotten on backing out of it
+    let tried = [
+        "journaled_lt_to_gt",
+        "journaled_lt_to_gt_with_aux",
+        "journaled_never",
+    ];
+    assert_eq!(journal.entries(), [tried, tried].concat());
+}
+
+#[test]
+fn rules_keep_scratch_state_for_one_run() {
+    let expr = Expression::And(Metadata::new(), vec![x_lt_y(); 3]);
+    let model = Model::new(HashMap::new(), expr, Default::default());
+
+    // Each run starts with a new scratch space, so has a budget of its own
+    for _ in 0..2 {
+        let outcome =
+            rewrite_model_with_options(&model, &rule_sets("Scratch"), &RewriteOptions::new())
+                .unwrap();
+
+        let Expression::And(_, constraints) = &outcome.model.constraints else {
+            panic!("Expected a conjunction");
+        };
+        let rewritten = constraints
+            .iter()
+            .filter(|constraint| matches!(constraint, Expression::Gt(_, _, _)))
+            .count();
+        assert_eq!(rewritten, 2);
+        assert!(model.scratch.is_empty());
+        assert!(outcome.model.scratch.is_empty());
+    }
+}
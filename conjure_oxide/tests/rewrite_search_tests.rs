@@ -0,0 +1,848 @@
+// Tests for how the rewriter chooses between the rewrites available, and for exploring them
+
+mod common;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use conjure_oxide::{
+    ast::*,
+    rule_engine::{
+        analyse, check_confluence, enumerate_rewrites, pareto_front, reduce_all_normal_forms,
+        replay, rewrite_model_with_options, rewrite_portfolio, Annealing, Backtracking, BeamSearch,
+        BestFirst, BudgetPolicy, ConfluenceCheck, CostGuided, DiscardReason, EngineError,
+        IgnoredOption, NormalFormLimits, ParetoSearch, Portfolio, RewriteChoice, RewriteError,
+        RewriteOptions, RewriteOutcome, RewriteStatus, Saturation, Stochastic, TieBreak,
+    },
+    Metadata, Model, Rule,
+};
+
+use common::*;
+
+#[test]
+fn reduce_all_normal_forms_finds_every_normal_form() {
+    let expr = Expression::And(Metadata::new(), vec![x_lt_y(); 2]);
+    let model = Model::new(HashMap::new(), expr, Default::default());
+    let limits = NormalFormLimits::new();
+
+    // Either constraint can be rewritten first, but both orders end in the same model
+    let forms = reduce_all_normal_forms(&model, &rule_sets("Pure"), &limits).unwrap();
+    assert_eq!(forms.states, 4);
+    assert!(forms.is_unique());
+    let gt = lt_to_gt(&x_lt_y(), &model).unwrap().new_expression;
+    assert_eq!(
+        forms.models[0].constraints,
+        Expression::And(Metadata::new(), vec![gt.clone(); 2])
+    );
+
+    // Whether `aux` is added depends on which rule rewrites the constraint
+    let model = Model::new(HashMap::new(), x_lt_y(), Default::default());
+    let forms = reduce_all_normal_forms(&model, &rule_sets("Effects"), &limits).unwrap();
+    assert!(forms.complete);
+    assert!(!forms.is_unique());
+    assert_eq!(forms.models.len(), 2);
+    assert!(forms.models.iter().all(|model| model.constraints == gt));
+    assert!(forms
+        .models
+        .iter()
+        .any(|model| model.variables.contains_key(&aux())));
+
+    // Rewriting that goes round in a cycle has no normal form
+    let forms = reduce_all_normal_forms(&model, &rule_sets("PingPong"), &limits).unwrap();
+    assert!(forms.complete);
+    assert_eq!(forms.states, 2);
+    assert!(forms.models.is_empty());
+
+    let forms =
+        reduce_all_normal_forms(&model, &rule_sets("Effects"), &limits.max_depth(0)).unwrap();
+    assert!(!forms.complete);
+    assert_eq!(forms.states, 1);
+    let forms =
+        reduce_all_normal_forms(&model, &rule_sets("Effects"), &limits.max_states(2)).unwrap();
+    assert!(!forms.complete);
+    assert_eq!(forms.models.len(), 1);
+}
+
+#[test]
+fn check_confluence_reports_inputs_with_divergent_normal_forms() {
+    let check = ConfluenceCheck::new().orders(8).seed(7);
+    let inputs = |n| {
+        (1..=n).map(|n| {
+            let expr = Expression::And(Metadata::new(), vec![x_lt_y(); n]);
+            Model::new(HashMap::new(), expr, Default::default())
+        })
+    };
+
+    let report = check_confluence(&rule_sets("Pure"), inputs(3), &check).unwrap();
+    assert!(report.is_confluent());
+    assert_eq!(report.inputs, 3);
+    assert_eq!(report.finished, 24);
+
+    // Whether `aux` is added depends on which rule rewrites the constraint
+    let input = Model::new(HashMap::new(), x_lt_y(), Default::default());
+    let report = check_confluence(&rule_sets("Effects"), [input.clone()], &check).unwrap();
+    assert!(!report.is_confluent());
+    let [non_confluent] = report.non_confluent.as_slice() else {
+        panic!("expected one non-confluent input");
+    };
+    assert_eq!(non_confluent.input.constraints, input.constraints);
+    assert_eq!(non_confluent.runs.len(), 2);
+    for run in &non_confluent.runs {
+        assert_eq!(run.trace.len(), 1);
+        let replayed = replay(&input, &run.trace).unwrap();
+        assert_eq!(replayed.constraints, run.normal_form.constraints);
+        assert_eq!(replayed.variables, run.normal_form.variables);
+    }
+    assert_ne!(
+        non_confluent.runs[0].normal_form.variables,
+        non_confluent.runs[1].normal_form.variables
+    );
+
+    // Rewriting that goes round in a cycle never reaches a normal form
+    let check = check.max_steps(10);
+    let report = check_confluence(&rule_sets("PingPong"), [input], &check).unwrap();
+    assert!(report.is_confluent());
+    assert_eq!(report.finished, 0);
+    assert_eq!(report.unfinished, 8);
+}
+
+#[test]
+fn rewrite_extracts_cheapest_expression_by_equality_saturation() {
+    let expr = Expression::And(Metadata::new(), vec![x_lt_y(); 2]);
+    let model = Model::new(HashMap::new(), expr, Default::default());
+    // Comparisons other than `>=` are expensive, so `not (x >= y)` is cheaper than `x < y`
+    let saturation = Saturation::new(|expr| match expr {
+        Expression::Lt(_, _, _) | Expression::Gt(_, _, _) => 10,
+        _ => 1,
+    });
+    let not_geq = saturate_lt_to_not_geq(&x_lt_y(), &model)
+        .unwrap()
+        .new_expression;
+
+    // Rewriting `<` to `>` and back again never ends by replacing expressions, but saturates
+    let options = RewriteOptions::new().equality_saturation(saturation.clone());
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Saturate"), &options).unwrap();
+    assert_eq!(outcome.status, RewriteStatus::Fixpoint);
+    assert_eq!(
+        outcome.model.constraints,
+        Expression::And(Metadata::new(), vec![not_geq; 2])
+    );
+
+    let options = RewriteOptions::new().equality_saturation(saturation.max_iterations(0));
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Saturate"), &options).unwrap();
+    assert_eq!(outcome.status, RewriteStatus::BudgetExhausted);
+    assert_eq!(outcome.model.constraints, model.constraints);
+
+    let options = RewriteOptions::new()
+        .equality_saturation(Saturation::smallest())
+        .max_rewrites(1)
+        .on_budget_exhausted(BudgetPolicy::Error);
+    let result = rewrite_model_with_options(&model, &rule_sets("Saturate"), &options);
+    assert!(matches!(
+        result,
+        Err(RewriteError::Engine(EngineError::BudgetExhausted { .. }))
+    ));
+
+    // Not all of these rules are pure, so the model is rewritten as usual
+    let options = RewriteOptions::new()
+        .equality_saturation(Saturation::smallest())
+        .max_rewrites(4);
+    let outcome = rewrite_model_with_options(&model, &rule_sets("PingPong"), &options).unwrap();
+    assert_eq!(outcome.status, RewriteStatus::BudgetExhausted);
+    assert_eq!(outcome.ignored_options, vec![IgnoredOption::Saturation]);
+}
+
+/// The number of sub-expressions of `expr`, with `<` counting as 3 and `>` as 5.
+fn comparison_cost(expr: &Expression) -> f64 {
+    let own = match expr {
+        Expression::Lt(_, _, _) => 3.0,
+        Expression::Gt(_, _, _) => 5.0,
+        _ => 1.0,
+    };
+    let mut children = 0.0;
+    let mut i = 0;
+    while let Some(child) = expr.child(i) {
+        children += comparison_cost(child);
+        i += 1;
+    }
+    own + children
+}
+
+#[test]
+fn rewrite_applies_cheapest_rewrite_by_cost() {
+    let expr = Expression::And(Metadata::new(), vec![x_lt_y(); 2]);
+    let model = Model::new(HashMap::new(), expr, Default::default());
+    let guide = CostGuided::new(comparison_cost);
+    let not_geq = saturate_lt_to_not_geq(&x_lt_y(), &model)
+        .unwrap()
+        .new_expression;
+
+    // Rewriting `<` to `>` costs more, so each `<` is rewritten to `not (x >= y)`
+    let options = RewriteOptions::new().cost_guided(guide.clone()).trace(true);
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Saturate"), &options).unwrap();
+    assert_eq!(outcome.status, RewriteStatus::Fixpoint);
+    assert_eq!(
+        outcome.model.constraints,
+        Expression::And(Metadata::new(), vec![not_geq; 2])
+    );
+    let trace = outcome.trace.unwrap();
+    assert_eq!(trace.len(), 2);
+    assert!(trace
+        .iter()
+        .all(|step| step.rule == "saturate_lt_to_not_geq"));
+    assert_eq!(
+        replay(&model, &trace).unwrap().constraints,
+        outcome.model.constraints
+    );
+
+    // `not (y <= x)` is cheaper than `x < y`, but only by way of the more costly `y > x`
+    let model = Model::new(HashMap::new(), x_lt_y(), Default::default());
+    let options = RewriteOptions::new().cost_guided(guide.clone());
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Costly"), &options).unwrap();
+    assert_eq!(outcome.model.constraints, model.constraints);
+
+    let options = RewriteOptions::new()
+        .cost_guided(guide.clone().allow_increase(true))
+        .trace(true);
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Costly"), &options).unwrap();
+    assert!(matches!(
+        &outcome.model.constraints,
+        Expression::Not(_, leq) if matches!(**leq, Expression::Leq(_, _, _))
+    ));
+    assert_eq!(outcome.trace.unwrap().len(), 2);
+
+    // Looking one rewrite ahead, `y > x` is judged by the cheaper `not (y <= x)` it leads to
+    let options = RewriteOptions::new()
+        .cost_guided(guide.lookahead(1))
+        .trace(true);
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Costly"), &options).unwrap();
+    assert_eq!(outcome.status, RewriteStatus::Fixpoint);
+    assert!(matches!(
+        &outcome.model.constraints,
+        Expression::Not(_, leq) if matches!(**leq, Expression::Leq(_, _, _))
+    ));
+    let trace = outcome.trace.unwrap();
+    assert_eq!(
+        trace
+            .iter()
+            .map(|step| step.rule.as_str())
+            .collect::<Vec<_>>(),
+        ["costly_lt_to_gt", "costly_gt_to_not_leq"]
+    );
+}
+
+#[test]
+fn rewrite_anneals_out_of_local_minima() {
+    let model = Model::new(HashMap::new(), x_lt_y(), Default::default());
+    let guide = CostGuided::new(comparison_cost);
+
+    // When hot, the more costly `y > x` is accepted on the way to `not (y <= x)`
+    let options = RewriteOptions::new()
+        .cost_guided(
+            guide
+                .clone()
+                .annealing(Annealing::new(3).temperature(1000.0)),
+        )
+        .trace(true);
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Costly"), &options).unwrap();
+    assert_eq!(outcome.status, RewriteStatus::Fixpoint);
+    assert_eq!(outcome.seed, Some(3));
+    assert!(matches!(
+        &outcome.model.constraints,
+        Expression::Not(_, leq) if matches!(**leq, Expression::Leq(_, _, _))
+    ));
+    let trace = outcome.trace.unwrap();
+    assert_eq!(trace.len(), 2);
+    assert_eq!(
+        replay(&model, &trace).unwrap().constraints,
+        outcome.model.constraints
+    );
+
+    // When cold, it is not
+    let options =
+        RewriteOptions::new().cost_guided(guide.annealing(Annealing::new(3).temperature(0.01)));
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Costly"), &options).unwrap();
+    assert_eq!(outcome.status, RewriteStatus::Fixpoint);
+    assert_eq!(outcome.model.constraints, model.constraints);
+}
+
+#[test]
+fn rewrite_portfolio_keeps_the_best_strategy() {
+    let model = Model::new(HashMap::new(), x_lt_y(), Default::default());
+
+    // The first strategy to reach a fixpoint wins
+    let portfolio = Portfolio::new(vec![
+        RewriteOptions::new().max_rewrites(1),
+        RewriteOptions::new(),
+    ]);
+    let result = rewrite_portfolio(&model, &rule_sets("Costly"), &portfolio).unwrap();
+    assert_eq!(result.strategy, 1);
+    assert_eq!(result.outcome.status, RewriteStatus::Fixpoint);
+    assert_eq!(
+        result.statuses,
+        [RewriteStatus::BudgetExhausted, RewriteStatus::Fixpoint]
+    );
+    assert_eq!(result.cost, None);
+
+    // If none does, the first that did not fail wins
+    let portfolio = Portfolio::new(vec![
+        RewriteOptions::new()
+            .max_rewrites(0)
+            .on_budget_exhausted(BudgetPolicy::Error),
+        RewriteOptions::new().max_rewrites(1),
+    ]);
+    let result = rewrite_portfolio(&model, &rule_sets("Costly"), &portfolio).unwrap();
+    assert_eq!(result.strategy, 1);
+    assert!(result.outcome.model.constraints.is_gt());
+    assert_eq!(
+        result.statuses,
+        [RewriteStatus::Error, RewriteStatus::BudgetExhausted]
+    );
+
+    // With a cost, the cheapest model wins, even if another strategy finished first
+    let portfolio = Portfolio::new(vec![
+        RewriteOptions::new().cost_guided(CostGuided::new(comparison_cost)),
+        RewriteOptions::new().max_rewrites(1),
+        RewriteOptions::new(),
+    ])
+    .cheapest(comparison_cost)
+    .timeout(Duration::from_secs(10));
+    let result = rewrite_portfolio(&model, &rule_sets("Costly"), &portfolio).unwrap();
+    assert_eq!(result.strategy, 2);
+    assert_eq!(result.cost, Some(4.0));
+    assert!(matches!(
+        &result.outcome.model.constraints,
+        Expression::Not(_, leq) if matches!(**leq, Expression::Leq(_, _, _))
+    ));
+
+    // If every strategy fails, the error of the first is returned
+    let portfolio = Portfolio::new(vec![RewriteOptions::new()
+        .max_rewrites(0)
+        .on_budget_exhausted(BudgetPolicy::Error)]);
+    let error = rewrite_portfolio(&model, &rule_sets("Costly"), &portfolio).unwrap_err();
+    assert!(matches!(
+        error,
+        RewriteError::Engine(EngineError::BudgetExhausted { .. })
+    ));
+}
+
+#[test]
+fn rewrite_backtracks_from_dead_ends() {
+    let model = Model::new(HashMap::new(), x_lt_y(), Default::default());
+    let needs_aux = Backtracking::new(|model| match model.variables.contains_key(&aux()) {
+        true => Ok(()),
+        false => Err(String::from("aux is not in the model")),
+    });
+
+    // `effects_lt_to_gt` applies first, but leaves a model without `aux`
+    let options = RewriteOptions::new()
+        .backtracking(needs_aux.clone())
+        .trace(true);
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Effects"), &options).unwrap();
+    assert_eq!(outcome.status, RewriteStatus::Fixpoint);
+    assert!(outcome.model.variables.contains_key(&aux()));
+    let trace = outcome.trace.unwrap();
+    assert_eq!(trace.len(), 1);
+    assert_eq!(trace[0].rule, "effects_lt_to_gt_with_aux");
+
+    let options = RewriteOptions::new().backtracking(needs_aux.max_backtracks(0));
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Effects"), &options).unwrap();
+    assert_eq!(outcome.status, RewriteStatus::BudgetExhausted);
+    assert!(!outcome.model.variables.contains_key(&aux()));
+
+    let never = Backtracking::new(|_| Err(String::from("never")));
+    let options = RewriteOptions::new().backtracking(never.check_every_rewrite(true));
+    match rewrite_model_with_options(&model, &rule_sets("Effects"), &options) {
+        Err(RewriteError::Engine(EngineError::DeadEnd {
+            dead_ends,
+            last_reason,
+        })) => {
+            // The model is checked before it is rewritten, so nothing else is
+            assert_eq!(dead_ends, 1);
+            assert_eq!(last_reason.as_deref(), Some("never"));
+        }
+        other => panic!("expected a dead end, got {other:?}"),
+    }
+
+    // Rewriting `<` to `>` and back again never reaches a model to check
+    let options = RewriteOptions::new().backtracking(Backtracking::new(|_| Ok(())));
+    let result = rewrite_model_with_options(&model, &rule_sets("PingPong"), &options);
+    assert!(matches!(
+        result,
+        Err(RewriteError::Engine(EngineError::DeadEnd {
+            dead_ends: 0,
+            last_reason: None,
+        }))
+    ));
+}
+
+#[test]
+fn rewrite_finds_cheapest_model_by_beam_search() {
+    let model = Model::new(HashMap::new(), x_lt_y(), Default::default());
+
+    // Cost-guided rewriting stops at `x < y`, but `not (y <= x)` is found by way of `y > x`
+    let options = RewriteOptions::new()
+        .beam_search(BeamSearch::new(comparison_cost).width(2))
+        .trace(true);
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Costly"), &options).unwrap();
+    assert_eq!(outcome.status, RewriteStatus::Fixpoint);
+    assert!(matches!(
+        &outcome.model.constraints,
+        Expression::Not(_, leq) if matches!(**leq, Expression::Leq(_, _, _))
+    ));
+    let trace = outcome.trace.unwrap();
+    assert_eq!(
+        trace
+            .iter()
+            .map(|step| step.rule.as_str())
+            .collect::<Vec<_>>(),
+        ["costly_lt_to_gt", "costly_gt_to_not_leq"]
+    );
+    assert_eq!(
+        replay(&model, &trace).unwrap().constraints,
+        outcome.model.constraints
+    );
+
+    // After one step, only the more costly `y > x` has been found
+    let options =
+        RewriteOptions::new().beam_search(BeamSearch::new(comparison_cost).width(2).steps(1));
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Costly"), &options).unwrap();
+    assert_eq!(outcome.status, RewriteStatus::BudgetExhausted);
+    assert_eq!(outcome.model.constraints, model.constraints);
+}
+
+#[test]
+fn rewrite_chooses_rewrites_at_random_from_seed() {
+    let model = Model::new(HashMap::new(), x_lt_y(), Default::default());
+
+    // The same seed makes the same choices
+    let options = RewriteOptions::new()
+        .stochastic(Stochastic::new(7))
+        .trace(true);
+    let first = rewrite_model_with_options(&model, &rule_sets("Effects"), &options).unwrap();
+    let second = rewrite_model_with_options(&model, &rule_sets("Effects"), &options).unwrap();
+    assert_eq!(first.status, RewriteStatus::Fixpoint);
+    assert_eq!(first.seed, Some(7));
+    assert!(first.model.constraints.is_gt());
+    assert_eq!(first.model.constraints, second.model.constraints);
+    let (first_trace, second_trace) = (first.trace.unwrap(), second.trace.unwrap());
+    assert_eq!(first_trace.len(), 1);
+    assert_eq!(first_trace[0].rule, second_trace[0].rule);
+    assert_eq!(
+        replay(&model, &first_trace).unwrap().constraints,
+        first.model.constraints
+    );
+
+    // A rule with no weight is never applied, whatever the seed
+    for seed in 0..8 {
+        let options =
+            RewriteOptions::new().stochastic(Stochastic::new(seed).weight("effects_lt_to_gt", 0.0));
+        let outcome = rewrite_model_with_options(&model, &rule_sets("Effects"), &options).unwrap();
+        assert!(outcome.model.variables.contains_key(&aux()));
+    }
+
+    // Rules that never reach a normal form are stopped by the budget
+    let options = RewriteOptions::new()
+        .stochastic(Stochastic::new(7))
+        .max_rewrites(10);
+    let outcome = rewrite_model_with_options(&model, &rule_sets("PingPong"), &options).unwrap();
+    assert_eq!(outcome.status, RewriteStatus::BudgetExhausted);
+    assert_eq!(outcome.seed, Some(7));
+}
+
+#[test]
+fn rewrite_selects_among_candidate_rewrites() {
+    let model = Model::new(HashMap::new(), x_lt_y(), Default::default());
+    let candidates = Arc::new(Mutex::new(Vec::<Vec<String>>::new()));
+    let seen = candidates.clone();
+    let options = RewriteOptions::new()
+        .trace(true)
+        .select_rewrite(move |rewrites| {
+            let mut seen = seen.lock().unwrap();
+            seen.push(rewrites.iter().map(|(rule, _)| rule.to_string()).collect());
+            rewrites
+                .iter()
+                .position(|(_, reduction)| !reduction.symbols.is_empty())
+        });
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Effects"), &options).unwrap();
+
+    // Every applicable rule is offered, in priority order
+    assert_eq!(
+        candidates.lock().unwrap()[0],
+        vec!["effects_lt_to_gt", "effects_lt_to_gt_with_aux"]
+    );
+    assert!(outcome.model.variables.contains_key(&aux()));
+    assert!(outcome.model.constraints.is_gt());
+    assert!(outcome.discarded_effects.is_empty());
+    assert_eq!(outcome.trace.unwrap()[0].rule, "effects_lt_to_gt_with_aux");
+
+    // Choosing none of them leaves the expression as it is
+    let options = RewriteOptions::new().select_rewrite(|_| None);
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Effects"), &options).unwrap();
+    assert_eq!(outcome.model.constraints, x_lt_y());
+    assert!(outcome.model.variables.is_empty());
+    assert_eq!(outcome.discarded_effects.len(), 1);
+    assert_eq!(outcome.discarded_effects[0].reason, DiscardReason::Declined);
+}
+
+#[test]
+fn rewrite_chooses_rewrites_with_context_and_can_stop() {
+    let expr = Expression::And(Metadata::new(), vec![x_lt_y(); 2]);
+    let model = Model::new(HashMap::new(), expr, Default::default());
+    let asked = Arc::new(Mutex::new(Vec::new()));
+    let seen = asked.clone();
+    let options = RewriteOptions::new().choose_rewrite(move |candidates| {
+        let mut seen = seen.lock().unwrap();
+        seen.push((
+            candidates.expression.clone(),
+            candidates.path.to_vec(),
+            candidates.model.variables.len(),
+        ));
+        assert_eq!(candidates.rewrites.len(), 2);
+        match seen.len() {
+            1 => RewriteChoice::Apply(1),
+            _ => RewriteChoice::Stop,
+        }
+    });
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Effects"), &options).unwrap();
+
+    // The first `<` is rewritten with `aux`, and rewriting stops when asked about the second
+    assert_eq!(
+        *asked.lock().unwrap(),
+        vec![(x_lt_y(), vec![0], 0), (x_lt_y(), vec![1], 1)]
+    );
+    assert_eq!(outcome.status, RewriteStatus::Cancelled);
+    assert!(outcome.model.variables.contains_key(&aux()));
+    let Expression::And(_, conjuncts) = &outcome.model.constraints else {
+        panic!("expected a conjunction, got {}", outcome.model.constraints);
+    };
+    assert!(conjuncts[0].is_gt());
+    assert_eq!(conjuncts[1], x_lt_y());
+
+    // Declining every rewrite leaves the model as it is, and reaches a fixpoint
+    let options = RewriteOptions::new().choose_rewrite(|_| RewriteChoice::Decline);
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Effects"), &options).unwrap();
+    assert_eq!(outcome.status, RewriteStatus::Fixpoint);
+    assert_eq!(outcome.model.constraints, model.constraints);
+    assert!(outcome
+        .discarded_effects
+        .iter()
+        .all(|discarded| discarded.reason == DiscardReason::Declined));
+}
+
+#[test]
+fn rewrite_reports_rules_of_the_same_priority_that_overlap() {
+    let expr = Expression::And(Metadata::new(), vec![x_lt_y(), x_lt_y()]);
+    let model = Model::new(HashMap::new(), expr, Default::default());
+    let options = RewriteOptions::new().report_ambiguities(true);
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Overlap"), &options).unwrap();
+    assert_eq!(outcome.status, RewriteStatus::Fixpoint);
+
+    // Rules of lower priority are not reported, as they would not be used either way
+    let mut ambiguities = outcome.ambiguities;
+    ambiguities.sort_by(|a, b| a.path.cmp(&b.path));
+    assert_eq!(ambiguities.len(), 2);
+    for (i, ambiguity) in ambiguities.iter().enumerate() {
+        assert_eq!(ambiguity.path, vec![i]);
+        assert_eq!(ambiguity.priority, 100);
+        let mut rules = ambiguity.rules.clone();
+        rules.sort();
+        assert_eq!(rules, ["overlap_lt_to_gt", "overlap_lt_to_not_geq"]);
+    }
+
+    // Rules of different priorities are not ambiguous
+    let options = RewriteOptions::new().report_ambiguities(true);
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Effects"), &options).unwrap();
+    assert!(outcome.ambiguities.is_empty());
+
+    // Nothing is reported unless asked for
+    let outcome =
+        rewrite_model_with_options(&model, &rule_sets("Overlap"), &RewriteOptions::new()).unwrap();
+    assert!(outcome.ambiguities.is_empty());
+}
+
+#[test]
+fn rewrite_records_choices_to_branch_from() {
+    let expr = Expression::And(Metadata::new(), vec![x_lt_y(), x_lt_y()]);
+    let model = Model::new(HashMap::new(), expr, Default::default());
+    let options = RewriteOptions::new().record_choices(true);
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Overlap"), &options).unwrap();
+    assert_eq!(outcome.status, RewriteStatus::Fixpoint);
+    let gt = outcome.model.constraints.at_path(&[0]).unwrap().clone();
+
+    // Each choice point holds the model as it was, with the earlier rewrites made
+    assert_eq!(outcome.choices.len(), 2);
+    for (i, choice) in outcome.choices.iter().enumerate() {
+        assert_eq!(choice.rewrites, i);
+        assert_eq!(choice.path, vec![i]);
+        assert_eq!(choice.chosen, "overlap_lt_to_gt");
+        let rules: Vec<_> = choice.alternatives.iter().map(|(rule, _)| rule).collect();
+        assert_eq!(rules, ["overlap_lt_to_not_geq", "overlap_lt_to_gt_late"]);
+    }
+    assert_eq!(
+        outcome.choices[1].model.constraints.at_path(&[0]),
+        Some(&gt)
+    );
+
+    // Branching makes the other rewrite instead, without making the rewrites before it again
+    let branch = outcome.choices[1].branch(0).unwrap();
+    assert_eq!(branch.constraints.at_path(&[0]), Some(&gt));
+    assert!(matches!(
+        branch.constraints.at_path(&[1]),
+        Some(Expression::Not(_, _))
+    ));
+    let branch = outcome.choices[0].branch(1).unwrap();
+    assert_eq!(branch.constraints.at_path(&[0]), Some(&gt));
+    assert_eq!(branch.constraints.at_path(&[1]), Some(&x_lt_y()));
+    assert!(outcome.choices[0].branch(2).is_none());
+
+    // Only rewrites where another rule applied are choices
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Pure"), &options).unwrap();
+    assert_eq!(outcome.status, RewriteStatus::Fixpoint);
+    assert!(outcome.choices.is_empty());
+    let outcome =
+        rewrite_model_with_options(&model, &rule_sets("Overlap"), &RewriteOptions::new()).unwrap();
+    assert!(outcome.choices.is_empty());
+}
+
+#[test]
+fn analyse_lists_every_available_rewrite() {
+    let expr = Expression::And(Metadata::new(), vec![x_lt_y(), x_lt_y()]);
+    let model = Model::new(HashMap::new(), expr.clone(), Default::default());
+    let redexes = analyse(&model, &rule_sets("Overlap")).unwrap();
+
+    // Every rule is listed at every expression it applies to, by expression, then by priority
+    assert_eq!(
+        redexes
+            .iter()
+            .map(|redex| (redex.path.clone(), redex.priority))
+            .collect::<Vec<_>>(),
+        [
+            (vec![0], 100),
+            (vec![0], 100),
+            (vec![0], 50),
+            (vec![1], 100),
+            (vec![1], 100),
+            (vec![1], 50),
+        ]
+    );
+    assert_eq!(redexes[2].rule, "overlap_lt_to_gt_late");
+    assert!(redexes[2].new_expression.is_gt());
+    assert_eq!(model.constraints, expr);
+
+    let redexes = analyse(&model, &rule_sets("Costly")).unwrap();
+    assert_eq!(redexes.len(), 2);
+    assert!(redexes.iter().all(|redex| redex.rule == "costly_lt_to_gt"));
+}
+
+#[test]
+fn rewrite_breaks_ties_between_rules_of_the_same_priority() {
+    let model = Model::new(HashMap::new(), x_lt_y(), Default::default());
+    let first_rule = |options: RewriteOptions| {
+        let outcome =
+            rewrite_model_with_options(&model, &rule_sets("Overlap"), &options.trace(true))
+                .unwrap();
+        outcome.trace.unwrap()[0].rule.clone()
+    };
+
+    // By default, rules of the same priority are tried by name
+    assert_eq!(first_rule(RewriteOptions::new()), "overlap_lt_to_gt");
+
+    // The order of the rules is decided by the tie-break given
+    let reversed = TieBreak::Custom(Arc::new(|a: &Rule, b: &Rule| b.name.cmp(a.name)));
+    assert_eq!(
+        first_rule(RewriteOptions::new().tie_break(reversed)),
+        "overlap_lt_to_not_geq"
+    );
+}
+
+#[test]
+fn enumerate_rewrites_lists_every_sequence_up_to_a_length() {
+    let expr = Expression::And(Metadata::new(), vec![x_lt_y(); 2]);
+    let model = Model::new(HashMap::new(), expr, Default::default());
+
+    // Either constraint can be rewritten first, and each order is a sequence of its own
+    let tree = enumerate_rewrites(&model, &rule_sets("Pure"), 5).unwrap();
+    assert_eq!(tree.branches.len(), 2);
+    assert_eq!(tree.size(), 5);
+    let sequences = tree.sequences();
+    assert_eq!(
+        sequences
+            .iter()
+            .map(|sequence| sequence
+                .iter()
+                .map(|step| step.path.clone())
+                .collect::<Vec<_>>())
+            .collect::<Vec<_>>(),
+        [vec![vec![0], vec![1]], vec![vec![1], vec![0]]]
+    );
+    let leaf = &tree.branches[0].tree.branches[0].tree;
+    assert!(leaf.is_normal_form());
+    for sequence in &sequences {
+        assert_eq!(
+            replay(&model, sequence).unwrap().constraints,
+            leaf.model.constraints
+        );
+    }
+
+    // Rules of every priority are tried, and sequences stop at the length allowed
+    let tree = enumerate_rewrites(&model, &rule_sets("Overlap"), 1).unwrap();
+    assert_eq!(tree.branches.len(), 6);
+    assert!(tree.branches.iter().all(|branch| branch.tree.truncated));
+    assert!(!tree.is_normal_form());
+
+    let model = Model::new(HashMap::new(), x_lt_y(), Default::default());
+    let tree = enumerate_rewrites(&model, &rule_sets("PingPong"), 3).unwrap();
+    let sequences = tree.sequences();
+    assert_eq!(sequences.len(), 1);
+    assert_eq!(sequences[0].len(), 3);
+    assert_eq!(tree.size(), 4);
+}
+
+#[test]
+fn pareto_front_keeps_every_trade_off_between_objectives() {
+    let model = Model::new(HashMap::new(), x_lt_y(), Default::default());
+    let objectives = |model: &Model| {
+        vec![
+            model.constraints.size() as f64,
+            comparison_cost(&model.constraints),
+        ]
+    };
+
+    // Only `not(y <= x)` is a normal form
+    let search = ParetoSearch::new(objectives);
+    let front = pareto_front(&model, &rule_sets("Costly"), &search).unwrap();
+    assert!(front.complete);
+    assert_eq!(front.states, 3);
+    assert_eq!(front.members.len(), 1);
+    assert_eq!(front.members[0].costs, [4.0, 4.0]);
+
+    // `x < y` is smaller, `not(y <= x)` is cheaper, and `y > x` is worse than `x < y` on both
+    let front = pareto_front(&model, &rule_sets("Costly"), &search.every_model(true)).unwrap();
+    let costs: Vec<_> = front.members.iter().map(|member| &member.costs).collect();
+    assert_eq!(costs, [&[3.0, 5.0], &[4.0, 4.0]]);
+    assert_eq!(front.members[0].model.constraints, x_lt_y());
+
+    // Normal forms with an auxiliary variable are dominated by the one without
+    let search = ParetoSearch::new(|model| vec![model.variables.len() as f64]);
+    let front = pareto_front(&model, &rule_sets("Effects"), &search).unwrap();
+    assert_eq!(front.members.len(), 1);
+    assert!(front.members[0].model.variables.is_empty());
+
+    let search = search.limits(NormalFormLimits::new().max_states(1));
+    let front = pareto_front(&model, &rule_sets("Effects"), &search).unwrap();
+    assert!(!front.complete);
+    assert!(front.members.is_empty());
+}
+
+#[test]
+fn rewrite_exhausts_each_priority_tier_before_the_next() {
+    let model = Model::new(HashMap::new(), x_lt_y(), Default::default());
+    let y_gt_x = lt_to_gt(&x_lt_y(), &model).unwrap().new_expression;
+    let expr = Expression::And(Metadata::new(), vec![x_lt_y(), y_gt_x]);
+    let model = Model::new(HashMap::new(), expr, Default::default());
+    let steps = |outcome: &RewriteOutcome| {
+        let trace = outcome.trace.as_ref().unwrap();
+        trace
+            .iter()
+            .map(|step| (step.rule.clone(), step.path.clone()))
+            .collect::<Vec<_>>()
+    };
+
+    // Without tiers, the first expression visited is rewritten first, whatever the priority
+    let options = RewriteOptions::new().trace(true);
+    let flat = rewrite_model_with_options(&model, &rule_sets("Tiered"), &options).unwrap();
+    assert_eq!(
+        steps(&flat),
+        [
+            (String::from("tiered_lt_to_gt"), vec![0]),
+            (String::from("tiered_gt_to_not_leq"), vec![0]),
+            (String::from("tiered_gt_to_not_leq"), vec![1]),
+        ]
+    );
+
+    // With tiers, the rule of priority 100 rewrites everything it can first, and is tried again
+    // after the rule of priority 10 makes something it can rewrite
+    let options = options.priority_tiers(&[100]);
+    let tiered = rewrite_model_with_options(&model, &rule_sets("Tiered"), &options).unwrap();
+    assert_eq!(tiered.status, RewriteStatus::Fixpoint);
+    assert_eq!(
+        steps(&tiered),
+        [
+            (String::from("tiered_gt_to_not_leq"), vec![1]),
+            (String::from("tiered_lt_to_gt"), vec![0]),
+            (String::from("tiered_gt_to_not_leq"), vec![0]),
+        ]
+    );
+    assert_eq!(tiered.model.constraints, flat.model.constraints);
+
+    // Boundaries above every rule leave a single tier
+    let options = RewriteOptions::new().trace(true).priority_tiers(&[200]);
+    let outcome = rewrite_model_with_options(&model, &rule_sets("Tiered"), &options).unwrap();
+    assert_eq!(steps(&outcome), steps(&flat));
+}
+
+#[test]
+fn rewrite_tries_every_tier_after_a_tiered_run_stops() {
+    let model = Model::new(HashMap::new(), x_lt_y(), Default::default());
+    let y_gt_x = lt_to_gt(&x_lt_y(), &model).unwrap().new_expression;
+    let expr = Expression::And(Metadata::new(), vec![x_lt_y(), y_gt_x]);
+    let model = Model::new(HashMap::new(), expr, Default::default());
+    let options = RewriteOptions::new().priority_tiers(&[100]).max_rewrites(1);
+    let stopped = rewrite_model_with_options(&model, &rule_sets("Tiered"), &options).unwrap();
+    assert_eq!(stopped.status, RewriteStatus::BudgetExhausted);
+
+    // `x < y` was only tried with the rules of the first tier before the run stopped
+    let flat = |model: &Model| {
+        rewrite_model_with_options(model, &rule_sets("Tiered"), &RewriteOptions::new())
+            .unwrap()
+            .model
+            .constraints
+    };
+    assert_eq!(flat(&stopped.model), flat(&model));
+}
+
+#[test]
+fn rewrite_searches_best_first_for_the_shortest_rewrites() {
+    let model = Model::new(HashMap::new(), x_lt_y(), Default::default());
+    let overlapping = [rule_sets("Overlap"), rule_sets("Tiered")].concat();
+    let rules = |outcome: &RewriteOutcome| {
+        let trace = outcome.trace.as_ref().unwrap();
+        trace
+            .iter()
+            .map(|step| step.rule.clone())
+            .collect::<Vec<_>>()
+    };
+
+    // The rewriter goes the long way round, through `y > x`
+    let options = RewriteOptions::new().trace(true);
+    let outcome = rewrite_model_with_options(&model, &overlapping, &options).unwrap();
+    assert_eq!(
+        rules(&outcome),
+        ["overlap_lt_to_gt", "tiered_gt_to_not_leq"]
+    );
+
+    let options = options.best_first(BestFirst::new(|_| 0.0));
+    let outcome = rewrite_model_with_options(&model, &overlapping, &options).unwrap();
+    assert_eq!(outcome.status, RewriteStatus::Fixpoint);
+    assert_eq!(rules(&outcome), ["overlap_lt_to_not_geq"]);
+
+    // Costlier rewrites can make the longer way cheaper
+    let search = BestFirst::new(comparison_cost).rule_cost("overlap_lt_to_not_geq", 5.0);
+    let options = RewriteOptions::new().trace(true).best_first(search);
+    let outcome = rewrite_model_with_options(&model, &overlapping, &options).unwrap();
+    assert_eq!(rules(&outcome).len(), 2);
+    assert!(matches!(outcome.model.constraints, Expression::Not(_, _)));
+
+    // Rules that only undo each other never reach a normal form
+    let options = RewriteOptions::new().best_first(BestFirst::new(|_| 0.0));
+    let error = rewrite_model_with_options(&model, &rule_sets("PingPong"), &options).unwrap_err();
+    assert!(matches!(
+        error,
+        RewriteError::Engine(EngineError::DeadEnd { .. })
+    ));
+    let options = options.max_rewrites(1);
+    let outcome = rewrite_model_with_options(&model, &rule_sets("PingPong"), &options).unwrap();
+    assert_eq!(outcome.status, RewriteStatus::BudgetExhausted);
+}
@@ -107,8 +107,8 @@ fn recursive_sum_of_constants() {
     assert_eq!(simplified_expression, correct_simplified_expression);
 }
 
-fn simplify_expression(expr: Expression) -> Expression {
-    match expr {
+fn simplify_expression(mut expr: Expression) -> Expression {
+    match &mut expr {
         Expression::Sum(_metadata, expressions) => {
             if let Some(result) =
                 evaluate_sum_of_constants(&Expression::Sum(Metadata::new(), expressions.clone()))
@@ -117,19 +117,22 @@ fn simplify_expression(expr: Expression) -> Expression {
             } else {
                 Expression::Sum(
                     Metadata::new(),
-                    expressions.into_iter().map(simplify_expression).collect(),
+                    std::mem::take(expressions)
+                        .into_iter()
+                        .map(simplify_expression)
+                        .collect(),
                 )
             }
         }
         Expression::Eq(_metadata, left, right) => Expression::Eq(
             Metadata::new(),
-            Box::new(simplify_expression(*left)),
-            Box::new(simplify_expression(*right)),
+            Box::new(simplify_expression(std::mem::take(&mut **left))),
+            Box::new(simplify_expression(std::mem::take(&mut **right))),
         ),
         Expression::Geq(_metadata, left, right) => Expression::Geq(
             Metadata::new(),
-            Box::new(simplify_expression(*left)),
-            Box::new(simplify_expression(*right)),
+            Box::new(simplify_expression(std::mem::take(&mut **left))),
+            Box::new(simplify_expression(std::mem::take(&mut **right))),
         ),
         _ => expr,
     }
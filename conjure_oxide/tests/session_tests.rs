@@ -0,0 +1,243 @@
+// Tests for stepping through rewriting with `ReductionSession`
+
+use conjure_oxide::{
+    ast::*,
+    get_rule_set_by_name, register_rule, register_rule_set,
+    rule_engine::{Breakpoint, ReductionSession, RewriteOptions},
+    ApplicationError, ApplicationResult, Metadata, Model, Reduction, RuleSet,
+};
+
+register_rule_set!("Session", 0, ());
+
+#[register_rule(("Session", 100))]
+fn session_lt_to_gt(expr: &Expression, _: &Model) -> ApplicationResult {
+    match expr {
+        Expression::Lt(_, a, b) => Ok(Reduction::pure(Expression::Gt(
+            Metadata::new(),
+            b.clone(),
+            a.clone(),
+        ))),
+        _ => Err(ApplicationError::RuleNotApplicable),
+    }
+}
+
+#[register_rule(("Session", 100))]
+fn session_neq_to_not_eq(expr: &Expression, _: &Model) -> ApplicationResult {
+    match expr {
+        Expression::Neq(_, a, b) => Ok(Reduction::pure(Expression::Not(
+            Metadata::new(),
+            Box::new(Expression::Eq(Metadata::new(), a.clone(), b.clone())),
+        ))),
+        _ => Err(ApplicationError::RuleNotApplicable),
+    }
+}
+
+#[register_rule(("Session", 100))]
+fn session_not_lt_to_top(expr: &Expression, _: &Model) -> ApplicationResult {
+    match expr {
+        Expression::Not(_, inner) if matches!(**inner, Expression::Lt(_, _, _)) => {
+            Ok(Reduction::with_top(
+                Expression::Constant(Metadata::new(), Constant::Bool(true)),
+                (**inner).clone(),
+            ))
+        }
+        _ => Err(ApplicationError::RuleNotApplicable),
+    }
+}
+
+fn reference(name: &str) -> Expression {
+    Expression::Reference(Metadata::new(), Name::UserName(String::from(name)))
+}
+
+fn x_lt_y() -> Expression {
+    Expression::Lt(
+        Metadata::new(),
+        Box::new(reference("x")),
+        Box::new(reference("y")),
+    )
+}
+
+fn y_gt_x() -> Expression {
+    Expression::Gt(
+        Metadata::new(),
+        Box::new(reference("y")),
+        Box::new(reference("x")),
+    )
+}
+
+fn x_neq_y() -> Expression {
+    Expression::Neq(
+        Metadata::new(),
+        Box::new(reference("x")),
+        Box::new(reference("y")),
+    )
+}
+
+fn session(constraints: Vec<Expression>) -> ReductionSession<'static> {
+    session_of(Expression::And(Metadata::new(), constraints))
+}
+
+fn session_of(constraints: Expression) -> ReductionSession<'static> {
+    let model = Model::new(Default::default(), constraints, Default::default());
+    let rule_sets: Vec<&RuleSet> = get_rule_set_by_name("Session").into_iter().collect();
+    ReductionSession::new(model, rule_sets, RewriteOptions::new())
+}
+
+fn and(constraints: Vec<Expression>) -> Expression {
+    Expression::And(Metadata::new(), constraints)
+}
+
+#[test]
+fn session_steps_one_rewrite_at_a_time() {
+    let mut session = session(vec![x_lt_y(); 3]);
+
+    for i in 0..3 {
+        let step = session.step().unwrap().unwrap();
+        assert_eq!(step.rule, "session_lt_to_gt");
+        assert_eq!(step.path, [i]);
+        let changed = TreeEdit::Changed {
+            path: vec![i],
+            expression: y_gt_x(),
+        };
+        assert_eq!(step.edits, [changed]);
+        assert!(!session.is_complete());
+
+        let mut expected = vec![y_gt_x(); i + 1];
+        expected.resize(3, x_lt_y());
+        assert_eq!(session.current_tree(), &and(expected));
+    }
+
+    assert!(session.step().unwrap().is_none());
+    assert!(session.is_complete());
+    assert_eq!(session.history().count(), 3);
+}
+
+#[test]
+fn session_undoes_rewrites() {
+    let mut session = session(vec![x_lt_y(); 2]);
+    assert!(session.undo().is_none());
+
+    session.step().unwrap();
+    session.step().unwrap();
+    assert!(session.step().unwrap().is_none());
+    assert!(session.is_complete());
+
+    let undone = session.undo().unwrap();
+    assert_eq!(undone.path, [1]);
+    assert!(!session.is_complete());
+    assert_eq!(session.current_tree(), &and(vec![y_gt_x(), x_lt_y()]));
+    assert_eq!(session.history().count(), 1);
+
+    // The undone rewrite is found again
+    let redone = session.step().unwrap().unwrap();
+    assert_eq!(redone.path, [1]);
+    assert_eq!(session.current_tree(), &and(vec![y_gt_x(); 2]));
+}
+
+#[test]
+fn session_keeps_the_model_when_a_step_fails() {
+    let constraints = and(vec![y_gt_x(), x_lt_y()]);
+    let model = Model::new(Default::default(), constraints.clone(), Default::default());
+    let rule_sets: Vec<&RuleSet> = get_rule_set_by_name("Session").into_iter().collect();
+    let options = RewriteOptions::new().check_invariant(|_| Err(String::from("never holds")));
+    let mut session = ReductionSession::new(model, rule_sets, options);
+
+    assert!(session.step().is_err());
+    assert_eq!(session.current_tree(), &constraints);
+    assert_eq!(session.history().count(), 0);
+}
+
+#[test]
+fn session_runs_until_predicate() {
+    let mut session = session(vec![x_lt_y(); 4]);
+
+    let step = session
+        .run_until(|step, _| step.path == [2])
+        .unwrap()
+        .unwrap();
+    assert_eq!(step.path, [2]);
+    assert_eq!(session.history().count(), 3);
+
+    let step = session
+        .run_until(|_, model| !model.constraints.to_string().contains("Lt"))
+        .unwrap()
+        .unwrap();
+    assert_eq!(step.path, [3]);
+
+    // Stops without a step once no rule applies
+    assert!(session.run_until(|_, _| false).unwrap().is_none());
+    assert!(session.is_complete());
+    assert_eq!(session.into_model().constraints, and(vec![y_gt_x(); 4]));
+}
+
+#[test]
+fn session_stops_before_rule_breakpoints() {
+    let mut session = session(vec![x_lt_y(), x_neq_y(), x_lt_y()]);
+    session.add_breakpoint(Breakpoint::rule("session_neq_to_not_eq"));
+
+    let step = session.run_to_breakpoint().unwrap().unwrap();
+    assert_eq!(step.rule, "session_neq_to_not_eq");
+    assert_eq!(step.path, [1]);
+    assert_eq!(
+        session.paused_at().map(|step| step.path.clone()),
+        Some(vec![1])
+    );
+
+    // Stopped before the rewrite, so the not-equals constraint is still there
+    assert_eq!(
+        session.current_tree(),
+        &and(vec![y_gt_x(), x_neq_y(), x_lt_y()])
+    );
+    assert_eq!(session.history().count(), 1);
+
+    // Resuming applies the rewrite it stopped before, then runs to the end
+    assert!(session.run_to_breakpoint().unwrap().is_none());
+    assert!(session.paused_at().is_none());
+    assert_eq!(session.history().count(), 3);
+    assert!(session.is_complete());
+}
+
+#[test]
+fn session_stops_before_node_breakpoints() {
+    let mut session = session(vec![x_neq_y(), x_lt_y(), x_lt_y()]);
+    session.add_breakpoint(Breakpoint::node(|expr| {
+        matches!(expr, Expression::Lt(_, _, _))
+    }));
+
+    for path in [1, 2] {
+        let step = session.run_to_breakpoint().unwrap().unwrap().clone();
+        assert_eq!(step.path, [path]);
+        assert_eq!(session.current_tree().at_path(&step.path), Some(&x_lt_y()));
+    }
+    assert_eq!(session.history().count(), 2);
+
+    // Stepping moves the session on from the breakpoint
+    session.step().unwrap();
+    assert!(session.paused_at().is_none());
+    session.clear_breakpoints();
+    assert!(session.run_to_breakpoint().unwrap().is_none());
+    assert_eq!(session.history().count(), 3);
+}
+
+#[test]
+fn session_undoes_new_top_level_constraints() {
+    let not_x_lt_y = Expression::Not(Metadata::new(), Box::new(x_lt_y()));
+    // The new constraint is added to the conjunction, or joined to the or in a new one
+    for constraints in [
+        and(vec![not_x_lt_y.clone(), x_lt_y()]),
+        Expression::Or(Metadata::new(), vec![not_x_lt_y.clone(), x_neq_y()]),
+    ] {
+        let mut session = session_of(constraints.clone());
+        let mut trees = vec![constraints];
+        while session.step().unwrap().is_some() {
+            trees.push(session.current_tree().clone());
+        }
+        assert_eq!(trees.len(), 4);
+
+        while session.undo().is_some() {
+            trees.pop();
+            assert_eq!(Some(session.current_tree()), trees.last());
+        }
+        assert_eq!(trees.len(), 1);
+    }
+}
@@ -0,0 +1,147 @@
+use std::time::Duration;
+
+use conjure_oxide::ast::*;
+use conjure_oxide::rule_engine::TraceStep;
+use conjure_oxide::viz::{
+    label, to_dot, to_dot_with, trace_to_dot, trace_to_text, trace_to_text_with,
+};
+use conjure_oxide::Metadata;
+
+fn reference(name: &str) -> Expression {
+    Expression::Reference(Metadata::new(), Name::UserName(String::from(name)))
+}
+
+fn lt(a: Expression, b: Expression) -> Expression {
+    Expression::Lt(Metadata::new(), Box::new(a), Box::new(b))
+}
+
+fn gt(a: Expression, b: Expression) -> Expression {
+    Expression::Gt(Metadata::new(), Box::new(a), Box::new(b))
+}
+
+#[test]
+fn to_dot_draws_every_sub_expression() {
+    let expr = Expression::And(
+        Metadata::new(),
+        vec![lt(reference("x"), reference("y")), reference("z")],
+    );
+    let dot = to_dot(&expr);
+
+    assert!(dot.starts_with("digraph {"));
+    assert!(dot.contains("n0 [label=\"And\"];"));
+    assert!(dot.contains("n1 [label=\"Lt\"];"));
+    assert!(dot.contains("n2 [label=\"UserName(x)\"];"));
+    assert!(dot.contains("n4 [label=\"UserName(z)\"];"));
+    for edge in ["n0 -> n1;", "n1 -> n2;", "n1 -> n3;", "n0 -> n4;"] {
+        assert!(dot.contains(edge), "missing edge {} in {}", edge, dot);
+    }
+    assert!(!dot.contains("fillcolor"));
+}
+
+#[test]
+fn to_dot_escapes_labels() {
+    let dot = to_dot_with(&reference("x"), |_| String::from("say \"hi\""));
+    assert!(dot.contains("n0 [label=\"say \\\"hi\\\"\"];"));
+}
+
+#[test]
+fn trace_to_dot_highlights_each_rewrite() {
+    let constraints = Expression::And(
+        Metadata::new(),
+        vec![reference("a"), lt(reference("x"), reference("y"))],
+    );
+    let trace = vec![TraceStep {
+        rule: String::from("lt_to_gt"),
+        path: vec![1],
+        edits: vec![TreeEdit::Changed {
+            path: vec![1],
+            expression: gt(reference("y"), reference("x")),
+        }],
+        new_top: reference("b"),
+        elapsed: Duration::ZERO,
+        watches: Vec::new(),
+        meta_diffs: Vec::new(),
+    }];
+
+    let graphs = trace_to_dot(&constraints, &trace);
+    assert_eq!(graphs.len(), 2);
+    assert_eq!(graphs[0], to_dot(&constraints));
+
+    // The rewritten expression replaces the old one, and the new top-level constraint is added
+    let graph = &graphs[1];
+    assert!(graph.contains("label=\"lt_to_gt at [1]\";"));
+    assert!(graph.contains("n0 [label=\"And\"];"));
+    assert!(graph.contains("n1 [label=\"UserName(a)\"];"));
+    assert!(graph.contains("n2 [label=\"Gt\", style=filled, fillcolor=yellow];"));
+    assert!(graph.contains("n3 [label=\"UserName(y)\", style=filled, fillcolor=yellow];"));
+    assert!(graph.contains("n5 [label=\"UserName(b)\"];"));
+    assert_eq!(graph.matches("fillcolor").count(), 3);
+}
+
+#[test]
+fn trace_to_dot_leaves_constraints_unchanged_by_steps_that_do_not_fit() {
+    let constraints = reference("a");
+    let trace = vec![TraceStep {
+        rule: String::from("lt_to_gt"),
+        path: vec![3],
+        edits: vec![TreeEdit::Changed {
+            path: vec![3],
+            expression: gt(reference("y"), reference("x")),
+        }],
+        new_top: Expression::Nothing,
+        elapsed: Duration::ZERO,
+        watches: Vec::new(),
+        meta_diffs: Vec::new(),
+    }];
+
+    let graphs = trace_to_dot(&constraints, &trace);
+    assert_eq!(graphs.len(), 2);
+    assert!(graphs[1].contains("n0 [label=\"UserName(a)\"];"));
+    assert!(!graphs[1].contains("fillcolor"));
+}
+
+#[test]
+fn trace_to_text_shows_each_rewrite() {
+    let constraints = Expression::And(
+        Metadata::new(),
+        vec![reference("a"), lt(reference("x"), reference("y"))],
+    );
+    let before = lt(reference("x"), reference("y"));
+    let after = gt(reference("y"), reference("x"));
+    let trace = vec![
+        TraceStep {
+            rule: String::from("lt_to_gt"),
+            path: vec![1],
+            edits: vec![TreeEdit::Changed {
+                path: vec![1],
+                expression: after.clone(),
+            }],
+            new_top: reference("b"),
+            elapsed: Duration::ZERO,
+            watches: Vec::new(),
+            meta_diffs: Vec::new(),
+        },
+        TraceStep {
+            rule: String::from("lt_to_gt"),
+            path: vec![3],
+            edits: Vec::new(),
+            new_top: Expression::Nothing,
+            elapsed: Duration::ZERO,
+            watches: Vec::new(),
+            meta_diffs: Vec::new(),
+        },
+    ];
+
+    let text = trace_to_text(&constraints, &trace, 1000);
+    let expected = format!(
+        "lt_to_gt @ [1]: {} ~> {}\n    + {}\nlt_to_gt @ [3]: does not fit the constraints\n",
+        before,
+        after,
+        reference("b")
+    );
+    assert_eq!(text, expected);
+
+    // Long expressions are cut short
+    let text = trace_to_text_with(&constraints, &trace[..1], 3, |expr| label(expr).repeat(2));
+    assert_eq!(text, "lt_to_gt @ [1]: Lt… ~> Gt…\n    + Us…\n");
+}
@@ -0,0 +1,235 @@
+use serde::{Deserialize, Serialize};
+
+use crate::ast::Expression;
+
+/// One change in a structural diff between two expressions, made by [`Expression::diff`].
+///
+/// Paths are the child indices leading from the root of the diffed expression, in the same order
+/// as [`Expression::child`], and refer to the expression as it is after the edits before them
+/// have been applied.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TreeEdit {
+    /// The sub-expression at `path` was replaced by `expression`.
+    Changed {
+        path: Vec<usize>,
+        expression: Expression,
+    },
+    /// `expression` was inserted into a list of sub-expressions, so that it is at `path`.
+    Inserted {
+        path: Vec<usize>,
+        expression: Expression,
+    },
+    /// The sub-expression at `path` was removed from a list of sub-expressions.
+    Deleted { path: Vec<usize> },
+}
+
+impl Expression {
+    /// The edits that turn this expression into `other`.
+    ///
+    /// Sub-expressions of the same variant are compared child by child, so only the parts that
+    /// differ are recorded. Where a list of sub-expressions, such as the operands of a
+    /// conjunction, has grown or shrunk, the children in common at either end are matched up,
+    /// and the rest are recorded as changed, inserted, or deleted. Metadata is not compared.
+    pub fn diff(&self, other: &Expression) -> Vec<TreeEdit> {
+        let mut edits = Vec::new();
+        diff_into(self, other, &mut Vec::new(), &mut edits);
+        edits
+    }
+
+    /// Applies `edits`, as returned by [`Expression::diff`], to this expression.
+    ///
+    /// # Returns
+    /// False if an edit's path is not in the expression, in which case the edits before it have
+    /// been applied and the rest have not.
+    pub fn apply_edits(&mut self, edits: &[TreeEdit]) -> bool {
+        edits.iter().all(|edit| apply_edit(self, edit))
+    }
+
+    /// The sub-expression at `path`, given as the child indices leading to it.
+    pub fn at_path(&self, path: &[usize]) -> Option<&Expression> {
+        path.iter()
+            .try_fold(self, |expression, &index| expression.child(index))
+    }
+}
+
+impl TreeEdit {
+    /// The path of the sub-expression the edit applies to.
+    pub fn path(&self) -> &[usize] {
+        match self {
+            TreeEdit::Changed { path, .. }
+            | TreeEdit::Inserted { path, .. }
+            | TreeEdit::Deleted { path } => path,
+        }
+    }
+
+    /// The path of the sub-expression the edit applies to, for example to make it relative to a
+    /// larger expression.
+    pub fn path_mut(&mut self) -> &mut Vec<usize> {
+        match self {
+            TreeEdit::Changed { path, .. }
+            | TreeEdit::Inserted { path, .. }
+            | TreeEdit::Deleted { path } => path,
+        }
+    }
+}
+
+fn diff_into(
+    before: &Expression,
+    after: &Expression,
+    path: &mut Vec<usize>,
+    edits: &mut Vec<TreeEdit>,
+) {
+    if before == after {
+        return;
+    }
+    let same_shape = match (before, after) {
+        (Expression::Constant(_, _), _) | (Expression::Reference(_, _), _) => false,
+        _ => before.variant_index() == after.variant_index(),
+    };
+    if !same_shape {
+        edits.push(TreeEdit::Changed {
+            path: path.clone(),
+            expression: after.clone(),
+        });
+        return;
+    }
+
+    match (operands(before), operands(after)) {
+        (Some(old), Some(new)) if old.len() != new.len() => {
+            diff_operands(old, new, path, edits);
+        }
+        _ => {
+            let mut i = 0;
+            while let (Some(old), Some(new)) = (before.child(i), after.child(i)) {
+                path.push(i);
+                diff_into(old, new, path, edits);
+                path.pop();
+                i += 1;
+            }
+            if before.child(i).is_some() || after.child(i).is_some() {
+                // Only lists of operands can differ in length, but do not rely on it
+                edits.push(TreeEdit::Changed {
+                    path: path.clone(),
+                    expression: after.clone(),
+                });
+            }
+        }
+    }
+}
+
+/// Diffs two lists of operands of different lengths, matching up the operands they have in common
+/// at either end.
+fn diff_operands(
+    old: &[Expression],
+    new: &[Expression],
+    path: &mut Vec<usize>,
+    edits: &mut Vec<TreeEdit>,
+) {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let old_middle = &old[prefix..old.len() - suffix];
+    let new_middle = &new[prefix..new.len() - suffix];
+    let paired = old_middle.len().min(new_middle.len());
+
+    for (i, (a, b)) in old_middle.iter().zip(new_middle).enumerate() {
+        path.push(prefix + i);
+        diff_into(a, b, path, edits);
+        path.pop();
+    }
+    for (i, expression) in new_middle[paired..].iter().enumerate() {
+        path.push(prefix + paired + i);
+        edits.push(TreeEdit::Inserted {
+            path: path.clone(),
+            expression: expression.clone(),
+        });
+        path.pop();
+    }
+    // Each deletion moves the operands after it down, so the same index is deleted repeatedly
+    for _ in paired..old_middle.len() {
+        path.push(prefix + paired);
+        edits.push(TreeEdit::Deleted { path: path.clone() });
+        path.pop();
+    }
+}
+
+fn apply_edit(expression: &mut Expression, edit: &TreeEdit) -> bool {
+    if let TreeEdit::Changed {
+        path,
+        expression: replacement,
+    } = edit
+    {
+        return edit_at(expression, path, &mut |target| {
+            *target = replacement.clone();
+            true
+        });
+    }
+
+    // Insertions and deletions are made in the list of operands of the parent
+    let Some((&index, parent_path)) = edit.path().split_last() else {
+        return false;
+    };
+    edit_at(expression, parent_path, &mut |parent| {
+        let Some(operands) = operands_mut(parent) else {
+            return false;
+        };
+        match edit {
+            TreeEdit::Inserted {
+                expression: inserted,
+                ..
+            } if index <= operands.len() => operands.insert(index, inserted.clone()),
+            TreeEdit::Deleted { .. } if index < operands.len() => {
+                operands.remove(index);
+            }
+            _ => return false,
+        }
+        parent.invalidate_annotations();
+        true
+    })
+}
+
+/// Calls `f` on the sub-expression at `path`, and invalidates the annotations of the
+/// sub-expressions above it if `f` changed it.
+fn edit_at(
+    expression: &mut Expression,
+    path: &[usize],
+    f: &mut dyn FnMut(&mut Expression) -> bool,
+) -> bool {
+    let Some((&index, rest)) = path.split_first() else {
+        return f(expression);
+    };
+    let edited = expression
+        .child_mut(index)
+        .is_some_and(|child| edit_at(child, rest, f));
+    if edited {
+        expression.invalidate_annotations();
+    }
+    edited
+}
+
+/// The sub-expressions of an expression whose only sub-expressions are a list of operands.
+fn operands(expression: &Expression) -> Option<&[Expression]> {
+    match expression {
+        Expression::Sum(_, exprs)
+        | Expression::Min(_, exprs)
+        | Expression::Or(_, exprs)
+        | Expression::And(_, exprs)
+        | Expression::AllDiff(_, exprs) => Some(exprs),
+        _ => None,
+    }
+}
+
+fn operands_mut(expression: &mut Expression) -> Option<&mut Vec<Expression>> {
+    match expression {
+        Expression::Sum(_, exprs)
+        | Expression::Min(_, exprs)
+        | Expression::Or(_, exprs)
+        | Expression::And(_, exprs)
+        | Expression::AllDiff(_, exprs) => Some(exprs),
+        _ => None,
+    }
+}
@@ -1,4 +1,7 @@
+use std::collections::hash_map::DefaultHasher;
 use std::fmt::{Display, Formatter};
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, OnceLock};
 
 use derive_is_enum_variant::is_enum_variant;
 use serde::{Deserialize, Serialize};
@@ -9,16 +12,17 @@ use uniplate_derive::Uniplate;
 
 use crate::ast::constants::Constant;
 use crate::ast::symbol_table::{Name, SymbolTable};
-use crate::metadata::Metadata;
+use crate::metadata::{Annotations, Metadata, Provenance, Span, Summaries, Summary};
 
 #[document_compatibility]
-#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, is_enum_variant, Uniplate)]
+#[derive(Debug, Default, Eq, Serialize, Deserialize, is_enum_variant, Uniplate)]
 #[non_exhaustive]
 pub enum Expression {
     /**
      * Represents an empty expression
      * NB: we only expect this at the top level of a model (if there is no constraints)
      */
+    #[default]
     Nothing,
 
     #[compatible(Minion, JsonInput)]
@@ -92,6 +96,60 @@ pub enum Expression {
 }
 
 impl Expression {
+    /// The names of the variants of `Expression`, in the order given by
+    /// [`Expression::variant_index`].
+    pub const VARIANT_NAMES: &'static [&'static str] = &[
+        "Nothing",
+        "Constant",
+        "Reference",
+        "Sum",
+        "Min",
+        "Not",
+        "Or",
+        "And",
+        "Eq",
+        "Neq",
+        "Geq",
+        "Leq",
+        "Gt",
+        "Lt",
+        "SumEq",
+        "SumGeq",
+        "SumLeq",
+        "Ineq",
+        "AllDiff",
+    ];
+
+    /// The position of this expression's variant in [`Expression::VARIANT_NAMES`].
+    pub fn variant_index(&self) -> usize {
+        match self {
+            Expression::Nothing => 0,
+            Expression::Constant(_, _) => 1,
+            Expression::Reference(_, _) => 2,
+            Expression::Sum(_, _) => 3,
+            Expression::Min(_, _) => 4,
+            Expression::Not(_, _) => 5,
+            Expression::Or(_, _) => 6,
+            Expression::And(_, _) => 7,
+            Expression::Eq(_, _, _) => 8,
+            Expression::Neq(_, _, _) => 9,
+            Expression::Geq(_, _, _) => 10,
+            Expression::Leq(_, _, _) => 11,
+            Expression::Gt(_, _, _) => 12,
+            Expression::Lt(_, _, _) => 13,
+            Expression::SumEq(_, _, _) => 14,
+            Expression::SumGeq(_, _, _) => 15,
+            Expression::SumLeq(_, _, _) => 16,
+            Expression::Ineq(_, _, _, _) => 17,
+            Expression::AllDiff(_, _) => 18,
+        }
+    }
+
+    /// The name of this expression's variant, e.g. `"Lt"`.
+    pub fn variant_name(&self) -> &'static str {
+        Self::VARIANT_NAMES[self.variant_index()]
+    }
+
     pub fn bounds(&self, vars: &SymbolTable) -> Option<(i32, i32)> {
         match self {
             Expression::Reference(_, name) => vars.get(name).and_then(|v| v.domain.min_max_i32()),
@@ -129,56 +187,17 @@ impl Expression {
     }
 
     pub fn is_clean(&self) -> bool {
-        match self {
-            Expression::Nothing => true,
-            Expression::Constant(metadata, _) => metadata.clean,
-            Expression::Reference(metadata, _) => metadata.clean,
-            Expression::Sum(metadata, exprs) => {
-                metadata.clean && exprs.iter().all(|e| e.is_clean())
-            }
-            Expression::Min(metadata, exprs) => {
-                metadata.clean && exprs.iter().all(|e| e.is_clean())
-            }
-            Expression::Not(metadata, expr) => metadata.clean && expr.is_clean(),
-            Expression::Or(metadata, exprs) => metadata.clean && exprs.iter().all(|e| e.is_clean()),
-            Expression::And(metadata, exprs) => {
-                metadata.clean && exprs.iter().all(|e| e.is_clean())
-            }
-            Expression::Eq(metadata, box1, box2) => {
-                metadata.clean && box1.is_clean() && box2.is_clean()
+        let mut stack = vec![self];
+        while let Some(expression) = stack.pop() {
+            if expression
+                .metadata()
+                .is_some_and(|metadata| !metadata.clean)
+            {
+                return false;
             }
-            Expression::Neq(metadata, box1, box2) => {
-                metadata.clean && box1.is_clean() && box2.is_clean()
-            }
-            Expression::Geq(metadata, box1, box2) => {
-                metadata.clean && box1.is_clean() && box2.is_clean()
-            }
-            Expression::Leq(metadata, box1, box2) => {
-                metadata.clean && box1.is_clean() && box2.is_clean()
-            }
-            Expression::Gt(metadata, box1, box2) => {
-                metadata.clean && box1.is_clean() && box2.is_clean()
-            }
-            Expression::Lt(metadata, box1, box2) => {
-                metadata.clean && box1.is_clean() && box2.is_clean()
-            }
-            Expression::SumGeq(metadata, box1, box2) => {
-                metadata.clean && box1.iter().all(|e| e.is_clean()) && box2.is_clean()
-            }
-            Expression::SumLeq(metadata, box1, box2) => {
-                metadata.clean && box1.iter().all(|e| e.is_clean()) && box2.is_clean()
-            }
-            Expression::Ineq(metadata, box1, box2, box3) => {
-                metadata.clean && box1.is_clean() && box2.is_clean() && box3.is_clean()
-            }
-            Expression::AllDiff(metadata, exprs) => {
-                metadata.clean && exprs.iter().all(|e| e.is_clean())
-            }
-            Expression::SumEq(metadata, exprs, expr) => {
-                metadata.clean && exprs.iter().all(|e| e.is_clean()) && expr.is_clean()
-            }
-            _ => false,
+            stack.extend((0..).map_while(|i| expression.child(i)));
         }
+        true
     }
 
     pub fn set_clean(&mut self, bool_value: bool) {
@@ -237,92 +256,811 @@ impl Expression {
                 metadata.clean = bool_value;
             }
         }
+        // Only the rewriter marks expressions clean for rule sets, as the marks belong to one of its
+        // generations
+        if let Some(cache) = self.metadata_mut().and_then(|m| m.cache.get_mut()) {
+            if !bool_value {
+                cache.clean_rule_sets = 0;
+                cache.clean_subtree_rule_sets = 0;
+            }
+        }
+    }
+
+    /// Returns true if none of the rule sets in the bitmask `rule_sets` apply to this expression
+    /// or any of its sub-expressions, going by the marks made in `generation`. See
+    /// [`Cache::clean_subtree_rule_sets`](crate::metadata::Cache::clean_subtree_rule_sets).
+    pub fn is_clean_for(&self, rule_sets: u64, generation: u64) -> bool {
+        self.clean_subtree_rule_sets(generation) & rule_sets == rule_sets
+    }
+
+    /// The rule sets known not to apply to this expression or any of its sub-expressions, going
+    /// by the marks made in `generation`.
+    fn clean_subtree_rule_sets(&self, generation: u64) -> u64 {
+        match self.metadata() {
+            Some(metadata) => match metadata.cache.get() {
+                Some(cache) if cache.clean_generation == generation => {
+                    cache.clean_subtree_rule_sets
+                }
+                _ => 0,
+            },
+            None => u64::MAX,
+        }
+    }
+
+    /// The rule sets known not to apply to this expression, not counting its sub-expressions,
+    /// going by the marks made in `generation`. See
+    /// [`Cache::clean_generation`](crate::metadata::Cache::clean_generation).
+    pub fn clean_rule_sets(&self, generation: u64) -> u64 {
+        match self.metadata() {
+            Some(metadata) => match metadata.cache.get() {
+                Some(cache) if cache.clean_generation == generation => cache.clean_rule_sets,
+                _ => 0,
+            },
+            None => u64::MAX,
+        }
+    }
+
+    /// Marks the rule sets in the bitmask `rule_sets` as not applying to this expression, not
+    /// counting its sub-expressions, in `generation`. Marks from other generations are dropped.
+    pub fn mark_clean_for(&mut self, rule_sets: u64, generation: u64) {
+        if let Some(metadata) = self.metadata_mut() {
+            let mut cache = metadata.cache.take().unwrap_or_default();
+            if cache.clean_generation != generation {
+                cache.clean_generation = generation;
+                cache.clean_rule_sets = 0;
+                cache.clean_subtree_rule_sets = 0;
+            }
+            cache.clean_rule_sets |= rule_sets;
+            metadata.cache = OnceLock::from(cache);
+        }
+    }
+
+    /// Marks this expression as clean throughout for the rule sets that, in `generation`, are
+    /// marked as not applying to it and to the whole of each of its children. Only the marks of
+    /// the children are looked at, not those of their sub-expressions.
+    pub fn refresh_subtree_clean_marks(&mut self, generation: u64) {
+        let clean = (0..)
+            .map_while(|i| self.child(i))
+            .fold(self.clean_rule_sets(generation), |clean, child| {
+                clean & child.clean_subtree_rule_sets(generation)
+            });
+        if let Some(cache) = self.metadata_mut().and_then(|m| m.cache.get_mut()) {
+            cache.clean_subtree_rule_sets = clean;
+        }
+    }
+
+    /// Marks this expression and all of its sub-expressions as dirty.
+    pub fn clear_clean_marks(&mut self) {
+        let mut stack = vec![self];
+        while let Some(expression) = stack.pop() {
+            if let Some(metadata) = expression.metadata_mut() {
+                metadata.clean = false;
+                if let Some(cache) = metadata.cache.get_mut() {
+                    cache.clean_rule_sets = 0;
+                    cache.clean_subtree_rule_sets = 0;
+                }
+            }
+            expression.for_each_sub_expression_mut(|e| stack.push(e));
+        }
+    }
+
+    /// Moves the direct sub-expressions onto the end of `buffer`, leaving `Expression::Nothing` in
+    /// their place. Put them back with [`Expression::restore_children`].
+    ///
+    /// Unlike [`Uniplate::children`], this neither clones the sub-expressions nor allocates, so a
+    /// traversal can reuse one buffer for every expression it visits.
+    pub fn take_children(&mut self, buffer: &mut Vec<Expression>) {
+        self.for_each_sub_expression_mut(|e| {
+            buffer.push(std::mem::replace(e, Expression::Nothing))
+        });
+    }
+
+    /// Moves the expressions in `buffer` from index `start` onwards back into this expression, as
+    /// taken by [`Expression::take_children`].
+    ///
+    /// The children may have changed since they were taken, so this expression's cached
+    /// annotations are dropped.
+    pub fn restore_children(&mut self, buffer: &mut Vec<Expression>, start: usize) {
+        self.invalidate_annotations();
+        self.restore_unchanged_children(buffer, start);
+    }
+
+    /// Moves the expressions in `buffer` from index `start` onwards back into this expression, as
+    /// [`Expression::restore_children`] does, but keeps this expression's cached annotations.
+    ///
+    /// Only use this if the children are the ones taken by [`Expression::take_children`], and
+    /// have not changed since.
+    pub fn restore_unchanged_children(&mut self, buffer: &mut Vec<Expression>, start: usize) {
+        let mut children = buffer.drain(start..);
+        self.for_each_sub_expression_mut(|e| {
+            if let Some(child) = children.next() {
+                *e = child;
+            }
+        });
+    }
+
+    /// Facts about this expression and its sub-expressions, such as its size.
+    ///
+    /// These are computed the first time they are needed, and cached in the metadata of this
+    /// expression and its sub-expressions, so later calls take constant time. The cache is not
+    /// kept when metadata is cloned. Code that replaces the children of an expression in place
+    /// must call [`Expression::invalidate_annotations`] on it and its ancestors.
+    pub fn annotations(&self) -> Annotations {
+        // Annotate the sub-expressions that have none cached first, deepest first, so that each
+        // expression is annotated from the cached annotations of its children without recursing
+        let has_annotations = |expression: &Expression| {
+            expression
+                .metadata()
+                .and_then(|metadata| metadata.cache.get())
+                .is_some_and(|cache| cache.annotations.get().is_some())
+        };
+        let mut stack = Vec::new();
+        if !has_annotations(self) {
+            stack.push((self, 0));
+        }
+        while let Some((expression, i)) = stack.pop() {
+            match expression.child(i) {
+                Some(child) => {
+                    stack.push((expression, i + 1));
+                    if !has_annotations(child) {
+                        stack.push((child, 0));
+                    }
+                }
+                None => {
+                    expression.annotate_node();
+                }
+            }
+        }
+        self.annotate_node()
+    }
+
+    /// The annotations of this expression, computed from those of its children if not cached.
+    fn annotate_node(&self) -> Annotations {
+        let compute = || {
+            let mut annotations = Annotations {
+                size: 1,
+                depth: 1,
+                contains_reference: matches!(self, Expression::Reference(_, _)),
+                hash: 0,
+                variants: 1 << self.variant_index(),
+            };
+            let children = self.sub_expressions();
+            let mut hasher = DefaultHasher::new();
+            self.hash_node(&mut hasher);
+            children.len().hash(&mut hasher);
+            for child in children {
+                let child = child.annotations();
+                annotations.size += child.size;
+                annotations.depth = annotations.depth.max(child.depth + 1);
+                annotations.contains_reference |= child.contains_reference;
+                child.hash.hash(&mut hasher);
+                annotations.variants |= child.variants;
+            }
+            annotations.hash = hasher.finish();
+            annotations
+        };
+        match self.metadata() {
+            Some(metadata) => *metadata
+                .cache
+                .get_or_init(Box::default)
+                .annotations
+                .get_or_init(compute),
+            None => compute(),
+        }
+    }
+
+    /// The number of expressions in this expression, including itself.
+    pub fn size(&self) -> usize {
+        self.annotations().size
+    }
+
+    /// The number of expressions on the longest path from this expression down to a leaf.
+    pub fn depth(&self) -> usize {
+        self.annotations().depth
+    }
+
+    /// Returns true if this expression or any sub-expression refers to a variable.
+    pub fn contains_reference(&self) -> bool {
+        self.annotations().contains_reference
+    }
+
+    /// A hash of this expression, ignoring metadata, which is cached along with the other
+    /// [`Expression::annotations`].
+    ///
+    /// This is not the same value as hashing the expression with [`Hash`], which always traverses
+    /// the whole expression.
+    pub fn subtree_hash(&self) -> u64 {
+        self.annotations().hash
+    }
+
+    /// The variants of this expression and its sub-expressions, as a bitmask over
+    /// [`Expression::VARIANT_NAMES`].
+    pub fn variants(&self) -> u64 {
+        self.annotations().variants
+    }
+
+    /// The [`Summary`] of type `S` of this expression and its sub-expressions.
+    ///
+    /// Like [`Expression::annotations`], summaries are computed the first time they are needed and
+    /// cached in the metadata of this expression and its sub-expressions, so later calls take
+    /// constant time. The cached summaries are dropped along with the annotations.
+    pub fn summary<S: Summary>(&self) -> Arc<S> {
+        // Summarise the sub-expressions that have no summary cached first, as for annotations
+        let has_summary = |expression: &Expression| {
+            expression
+                .metadata()
+                .and_then(|metadata| metadata.cache.get())
+                .is_some_and(|cache| cache.summaries.get::<S>().is_some())
+        };
+        let mut stack = Vec::new();
+        if !has_summary(self) {
+            stack.push((self, 0));
+        }
+        while let Some((expression, i)) = stack.pop() {
+            match expression.child(i) {
+                Some(child) => {
+                    stack.push((expression, i + 1));
+                    if !has_summary(child) {
+                        stack.push((child, 0));
+                    }
+                }
+                None => {
+                    expression.summarise_node::<S>();
+                }
+            }
+        }
+        self.summarise_node()
+    }
+
+    /// The [`Summary`] of type `S` of this expression, computed from those of its children if not
+    /// cached.
+    fn summarise_node<S: Summary>(&self) -> Arc<S> {
+        let compute = || {
+            let mut summary = S::of_node(self);
+            for child in self.sub_expressions() {
+                summary.combine(&child.summary::<S>());
+            }
+            Arc::new(summary)
+        };
+        match self.metadata() {
+            Some(metadata) => {
+                let summaries = &metadata.cache.get_or_init(Box::default).summaries;
+                match summaries.get::<S>() {
+                    Some(summary) => summary,
+                    None => summaries.insert(compute()),
+                }
+            }
+            None => compute(),
+        }
+    }
+
+    /// Drops the cached [`Expression::annotations`] and [`Expression::summary`] values of this
+    /// expression, but not of its sub-expressions, along with the other facts held about the
+    /// whole of it, such as where no rule set applies.
+    pub fn invalidate_annotations(&mut self) {
+        if let Some(metadata) = self.metadata_mut() {
+            if let Some(cache) = metadata.cache.get_mut() {
+                cache.annotations = OnceLock::new();
+                cache.summaries = Summaries::default();
+                cache.clean_subtree_rule_sets = 0;
+            }
+        }
+    }
+
+    pub fn metadata(&self) -> Option<&Metadata> {
+        match self {
+            Expression::Nothing => None,
+            Expression::Constant(metadata, _)
+            | Expression::Reference(metadata, _)
+            | Expression::Sum(metadata, _)
+            | Expression::Min(metadata, _)
+            | Expression::Not(metadata, _)
+            | Expression::Or(metadata, _)
+            | Expression::And(metadata, _)
+            | Expression::Eq(metadata, _, _)
+            | Expression::Neq(metadata, _, _)
+            | Expression::Geq(metadata, _, _)
+            | Expression::Leq(metadata, _, _)
+            | Expression::Gt(metadata, _, _)
+            | Expression::Lt(metadata, _, _)
+            | Expression::SumEq(metadata, _, _)
+            | Expression::SumGeq(metadata, _, _)
+            | Expression::SumLeq(metadata, _, _)
+            | Expression::Ineq(metadata, _, _, _)
+            | Expression::AllDiff(metadata, _) => Some(metadata),
+        }
+    }
+
+    /// The rule that produced this expression, if it was recorded. See
+    /// [`RewriteOptions::provenance`](crate::rule_engine::RewriteOptions::provenance).
+    pub fn provenance(&self) -> Option<&Provenance> {
+        self.metadata()
+            .and_then(|metadata| metadata.provenance.as_deref())
+    }
+
+    /// The part of the source this expression came from, if known.
+    ///
+    /// Spans are kept when metadata is cloned, so an expression a rule keeps from the one it
+    /// rewrote keeps its span. The rewriter gives spans to the expressions rules make if
+    /// [`RewriteOptions::span_policy`](crate::rule_engine::RewriteOptions::span_policy) is set.
+    pub fn span(&self) -> Option<Span> {
+        self.metadata()
+            .and_then(|metadata| metadata.span.as_deref().copied())
+    }
+
+    /// The same expression, marked as coming from `span` of the source, as a parser would mark
+    /// the expressions it makes.
+    pub fn with_span(mut self, span: Span) -> Self {
+        if let Some(metadata) = self.metadata_mut() {
+            metadata.span = Some(Box::new(span));
+        }
+        self
+    }
+
+    /// Gives a span to this expression, as decided by `policy` from `redex`, the span of the
+    /// expression a rule rewrote, and to its sub-expressions, unless it already has one.
+    /// Expressions that have a span are taken to have been kept from the source, along with their
+    /// sub-expressions, so are left as they are.
+    pub fn assign_spans(
+        &mut self,
+        redex: Option<Span>,
+        policy: &dyn Fn(Option<Span>, &Expression) -> Option<Span>,
+    ) {
+        let mut stack = vec![self];
+        while let Some(expression) = stack.pop() {
+            if expression
+                .metadata()
+                .is_none_or(|metadata| metadata.span.is_some())
+            {
+                continue;
+            }
+            let span = policy(redex, expression);
+            if let Some(metadata) = expression.metadata_mut() {
+                metadata.span = span.map(Box::new);
+            }
+            expression.for_each_sub_expression_mut(|e| stack.push(e));
+        }
+    }
+
+    pub fn metadata_mut(&mut self) -> Option<&mut Metadata> {
+        match self {
+            Expression::Nothing => None,
+            Expression::Constant(metadata, _)
+            | Expression::Reference(metadata, _)
+            | Expression::Sum(metadata, _)
+            | Expression::Min(metadata, _)
+            | Expression::Not(metadata, _)
+            | Expression::Or(metadata, _)
+            | Expression::And(metadata, _)
+            | Expression::Eq(metadata, _, _)
+            | Expression::Neq(metadata, _, _)
+            | Expression::Geq(metadata, _, _)
+            | Expression::Leq(metadata, _, _)
+            | Expression::Gt(metadata, _, _)
+            | Expression::Lt(metadata, _, _)
+            | Expression::SumEq(metadata, _, _)
+            | Expression::SumGeq(metadata, _, _)
+            | Expression::SumLeq(metadata, _, _)
+            | Expression::Ineq(metadata, _, _, _)
+            | Expression::AllDiff(metadata, _) => Some(metadata),
+        }
+    }
+
+    /// Hashes the variant of this expression and any fields that are not sub-expressions or
+    /// metadata.
+    fn hash_node(&self, hasher: &mut impl Hasher) {
+        std::mem::discriminant(self).hash(hasher);
+        match self {
+            Expression::Constant(_, constant) => constant.hash(hasher),
+            Expression::Reference(_, name) => name.hash(hasher),
+            _ => {}
+        }
+    }
+
+    /// The direct sub-expressions, by reference rather than cloned as by [`Uniplate::children`].
+    fn sub_expressions(&self) -> Vec<&Expression> {
+        match self {
+            Expression::Nothing | Expression::Constant(_, _) | Expression::Reference(_, _) => {
+                vec![]
+            }
+            Expression::Sum(_, exprs)
+            | Expression::Min(_, exprs)
+            | Expression::Or(_, exprs)
+            | Expression::And(_, exprs)
+            | Expression::AllDiff(_, exprs) => exprs.iter().collect(),
+            Expression::Not(_, expr) => vec![expr],
+            Expression::Eq(_, box1, box2)
+            | Expression::Neq(_, box1, box2)
+            | Expression::Geq(_, box1, box2)
+            | Expression::Leq(_, box1, box2)
+            | Expression::Gt(_, box1, box2)
+            | Expression::Lt(_, box1, box2) => vec![box1, box2],
+            Expression::SumEq(_, exprs, expr)
+            | Expression::SumGeq(_, exprs, expr)
+            | Expression::SumLeq(_, exprs, expr) => {
+                exprs.iter().chain(std::iter::once(expr.as_ref())).collect()
+            }
+            Expression::Ineq(_, box1, box2, box3) => vec![box1, box2, box3],
+        }
+    }
+
+    /// A copy of this expression, with `Expression::Nothing` in place of each direct
+    /// sub-expression, for [`Clone`] to fill in with [`Expression::restore_unchanged_children`].
+    fn clone_shell(&self) -> Expression {
+        let nothing = || Box::new(Expression::Nothing);
+        let nothings =
+            |exprs: &Vec<Expression>| exprs.iter().map(|_| Expression::Nothing).collect();
+        match self {
+            Expression::Nothing => Expression::Nothing,
+            Expression::Constant(metadata, constant) => {
+                Expression::Constant(metadata.clone(), constant.clone())
+            }
+            Expression::Reference(metadata, name) => {
+                Expression::Reference(metadata.clone(), name.clone())
+            }
+            Expression::Sum(metadata, exprs) => Expression::Sum(metadata.clone(), nothings(exprs)),
+            Expression::Min(metadata, exprs) => Expression::Min(metadata.clone(), nothings(exprs)),
+            Expression::Not(metadata, _) => Expression::Not(metadata.clone(), nothing()),
+            Expression::Or(metadata, exprs) => Expression::Or(metadata.clone(), nothings(exprs)),
+            Expression::And(metadata, exprs) => Expression::And(metadata.clone(), nothings(exprs)),
+            Expression::Eq(metadata, _, _) => {
+                Expression::Eq(metadata.clone(), nothing(), nothing())
+            }
+            Expression::Neq(metadata, _, _) => {
+                Expression::Neq(metadata.clone(), nothing(), nothing())
+            }
+            Expression::Geq(metadata, _, _) => {
+                Expression::Geq(metadata.clone(), nothing(), nothing())
+            }
+            Expression::Leq(metadata, _, _) => {
+                Expression::Leq(metadata.clone(), nothing(), nothing())
+            }
+            Expression::Gt(metadata, _, _) => {
+                Expression::Gt(metadata.clone(), nothing(), nothing())
+            }
+            Expression::Lt(metadata, _, _) => {
+                Expression::Lt(metadata.clone(), nothing(), nothing())
+            }
+            Expression::SumEq(metadata, exprs, _) => {
+                Expression::SumEq(metadata.clone(), nothings(exprs), nothing())
+            }
+            Expression::SumGeq(metadata, exprs, _) => {
+                Expression::SumGeq(metadata.clone(), nothings(exprs), nothing())
+            }
+            Expression::SumLeq(metadata, exprs, _) => {
+                Expression::SumLeq(metadata.clone(), nothings(exprs), nothing())
+            }
+            Expression::Ineq(metadata, _, _, _) => {
+                Expression::Ineq(metadata.clone(), nothing(), nothing(), nothing())
+            }
+            Expression::AllDiff(metadata, exprs) => {
+                Expression::AllDiff(metadata.clone(), nothings(exprs))
+            }
+        }
+    }
+
+    /// The direct sub-expression at `index`, in the same order as [`Uniplate::children`].
+    ///
+    /// Unlike [`Uniplate::children`], this does not allocate, so a traversal can visit the
+    /// sub-expressions of an expression it only borrows one at a time.
+    pub fn child(&self, index: usize) -> Option<&Expression> {
+        match self {
+            Expression::Nothing | Expression::Constant(_, _) | Expression::Reference(_, _) => None,
+            Expression::Sum(_, exprs)
+            | Expression::Min(_, exprs)
+            | Expression::Or(_, exprs)
+            | Expression::And(_, exprs)
+            | Expression::AllDiff(_, exprs) => exprs.get(index),
+            Expression::Not(_, expr) => (index == 0).then_some(&**expr),
+            Expression::Eq(_, box1, box2)
+            | Expression::Neq(_, box1, box2)
+            | Expression::Geq(_, box1, box2)
+            | Expression::Leq(_, box1, box2)
+            | Expression::Gt(_, box1, box2)
+            | Expression::Lt(_, box1, box2) => match index {
+                0 => Some(box1),
+                1 => Some(box2),
+                _ => None,
+            },
+            Expression::SumEq(_, exprs, expr)
+            | Expression::SumGeq(_, exprs, expr)
+            | Expression::SumLeq(_, exprs, expr) => match index.cmp(&exprs.len()) {
+                std::cmp::Ordering::Less => exprs.get(index),
+                std::cmp::Ordering::Equal => Some(expr),
+                std::cmp::Ordering::Greater => None,
+            },
+            Expression::Ineq(_, box1, box2, box3) => match index {
+                0 => Some(box1),
+                1 => Some(box2),
+                2 => Some(box3),
+                _ => None,
+            },
+        }
+    }
+
+    /// The direct sub-expression at `index`, as with [`Expression::child`], for changing in
+    /// place.
+    ///
+    /// The cached [`Expression::annotations`] of this expression are not invalidated, so callers
+    /// that change the sub-expression should call [`Expression::invalidate_annotations`].
+    pub fn child_mut(&mut self, index: usize) -> Option<&mut Expression> {
+        match self {
+            Expression::Nothing | Expression::Constant(_, _) | Expression::Reference(_, _) => None,
+            Expression::Sum(_, exprs)
+            | Expression::Min(_, exprs)
+            | Expression::Or(_, exprs)
+            | Expression::And(_, exprs)
+            | Expression::AllDiff(_, exprs) => exprs.get_mut(index),
+            Expression::Not(_, expr) => (index == 0).then_some(&mut **expr),
+            Expression::Eq(_, box1, box2)
+            | Expression::Neq(_, box1, box2)
+            | Expression::Geq(_, box1, box2)
+            | Expression::Leq(_, box1, box2)
+            | Expression::Gt(_, box1, box2)
+            | Expression::Lt(_, box1, box2) => match index {
+                0 => Some(box1),
+                1 => Some(box2),
+                _ => None,
+            },
+            Expression::SumEq(_, exprs, expr)
+            | Expression::SumGeq(_, exprs, expr)
+            | Expression::SumLeq(_, exprs, expr) => match index.cmp(&exprs.len()) {
+                std::cmp::Ordering::Less => exprs.get_mut(index),
+                std::cmp::Ordering::Equal => Some(expr),
+                std::cmp::Ordering::Greater => None,
+            },
+            Expression::Ineq(_, box1, box2, box3) => match index {
+                0 => Some(box1),
+                1 => Some(box2),
+                2 => Some(box3),
+                _ => None,
+            },
+        }
+    }
+
+    /// Calls `f` on each direct sub-expression, in the same order as [`Uniplate::children`].
+    fn for_each_sub_expression_mut<'a>(&'a mut self, mut f: impl FnMut(&'a mut Expression)) {
+        match self {
+            Expression::Nothing | Expression::Constant(_, _) | Expression::Reference(_, _) => {}
+            Expression::Sum(_, exprs)
+            | Expression::Min(_, exprs)
+            | Expression::Or(_, exprs)
+            | Expression::And(_, exprs)
+            | Expression::AllDiff(_, exprs) => exprs.iter_mut().for_each(f),
+            Expression::Not(_, expr) => f(expr),
+            Expression::Eq(_, box1, box2)
+            | Expression::Neq(_, box1, box2)
+            | Expression::Geq(_, box1, box2)
+            | Expression::Leq(_, box1, box2)
+            | Expression::Gt(_, box1, box2)
+            | Expression::Lt(_, box1, box2) => {
+                f(box1);
+                f(box2);
+            }
+            Expression::SumEq(_, exprs, expr)
+            | Expression::SumGeq(_, exprs, expr)
+            | Expression::SumLeq(_, exprs, expr) => {
+                exprs.iter_mut().for_each(&mut f);
+                f(expr);
+            }
+            Expression::Ineq(_, box1, box2, box3) => {
+                f(box1);
+                f(box2);
+                f(box3);
+            }
+        }
     }
 }
 
-fn display_expressions(expressions: &[Expression]) -> String {
-    if expressions.len() <= 3 {
-        format!(
-            "[{}]",
-            expressions
-                .iter()
-                .map(|e| e.to_string())
-                .collect::<Vec<String>>()
-                .join(", ")
-        )
-    } else {
-        format!(
-            "[{}..{}]",
-            expressions[0],
-            expressions[expressions.len() - 1]
-        )
+// `Debug` formatting and serialising an expression still recurse once per level of nesting, so
+// deep expressions should only be written out with `Display`.
+
+/// Expressions are dropped with an explicit stack, rather than by recursion as the compiler's drop
+/// glue would, so that deep expressions do not overflow the call stack.
+///
+/// Sub-expressions cannot be moved out of an expression by pattern, so take them with
+/// [`std::mem::take`] instead.
+impl Drop for Expression {
+    fn drop(&mut self) {
+        // Expressions whose children are all leaves are shallow enough to drop by recursion
+        if (0..)
+            .map_while(|i| self.child(i))
+            .all(|child| child.child(0).is_none())
+        {
+            return;
+        }
+        let mut stack = Vec::new();
+        self.take_children(&mut stack);
+        while let Some(mut expression) = stack.pop() {
+            expression.take_children(&mut stack);
+        }
     }
 }
 
-impl Display for Expression {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        match &self {
-            Expression::Constant(metadata, c) => write!(f, "Constant({}, {})", metadata, c),
-            Expression::Reference(metadata, name) => write!(f, "Reference({}, {})", metadata, name),
-            Expression::Nothing => write!(f, "Nothing"),
-            Expression::Sum(metadata, expressions) => {
-                write!(f, "Sum({}, {})", metadata, display_expressions(expressions))
+/// Expressions are cloned from the leaves up with an explicit stack, rather than by recursion as
+/// a derived `Clone` would, so that deep expressions do not overflow the call stack.
+impl Clone for Expression {
+    fn clone(&self) -> Self {
+        // The expressions being cloned, and the index of the next child of each to clone
+        let mut stack = vec![(self, 0)];
+        // The clones of the children of the expressions on the stack
+        let mut clones = Vec::new();
+        while let Some((expression, i)) = stack.pop() {
+            match expression.child(i) {
+                Some(child) => {
+                    stack.push((expression, i + 1));
+                    stack.push((child, 0));
+                }
+                None => {
+                    let mut clone = expression.clone_shell();
+                    let start = clones.len() - i;
+                    clone.restore_unchanged_children(&mut clones, start);
+                    clones.push(clone);
+                }
+            }
+        }
+        clones.pop().unwrap_or(Expression::Nothing)
+    }
+}
+
+/// Expressions are compared a pair of sub-expressions at a time with an explicit stack, rather than
+/// by recursion as a derived `PartialEq` would, so that deep expressions do not overflow the call
+/// stack.
+impl PartialEq for Expression {
+    fn eq(&self, other: &Self) -> bool {
+        let mut stack = vec![(self, other)];
+        while let Some((left, right)) = stack.pop() {
+            let same_node = match (left, right) {
+                (Expression::Constant(_, a), Expression::Constant(_, b)) => a == b,
+                (Expression::Reference(_, a), Expression::Reference(_, b)) => a == b,
+                _ => std::mem::discriminant(left) == std::mem::discriminant(right),
+            };
+            if !same_node || left.metadata() != right.metadata() {
+                return false;
             }
-            Expression::Not(metadata, expr_box) => {
-                write!(f, "Not({}, {})", metadata, expr_box.clone())
+            for i in 0.. {
+                match (left.child(i), right.child(i)) {
+                    (Some(a), Some(b)) => stack.push((a, b)),
+                    (None, None) => break,
+                    _ => return false,
+                }
             }
-            Expression::Or(metadata, expressions) => {
-                write!(f, "Not({}, {})", metadata, display_expressions(expressions))
+        }
+        true
+    }
+}
+
+/// Expressions are hashed a node at a time in pre-order with an explicit stack, rather than by
+/// recursion as a derived `Hash` would, so that deep expressions do not overflow the call stack.
+impl Hash for Expression {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let mut stack = vec![self];
+        while let Some(expression) = stack.pop() {
+            expression.hash_node(state);
+            let children = stack.len();
+            stack.extend((0..).map_while(|i| expression.child(i)));
+            stack[children..].reverse();
+        }
+    }
+}
+
+/// A part of the text of an expression, for [`Display`] to write out in order.
+enum DisplayPiece<'a> {
+    Text(String),
+    Expression(&'a Expression),
+    List(&'a [Expression]),
+}
+
+impl<'a> DisplayPiece<'a> {
+    /// Pushes the parts this piece is made of onto `pieces`, in the order they are written.
+    fn expand(self, pieces: &mut Vec<DisplayPiece<'a>>) {
+        use DisplayPiece::{Expression as Expr, List, Text};
+        let text = |s: &str| Text(s.to_owned());
+        match self {
+            Text(_) => pieces.push(self),
+            List(expressions) if expressions.len() <= 3 => {
+                pieces.push(text("["));
+                for (i, expression) in expressions.iter().enumerate() {
+                    if i > 0 {
+                        pieces.push(text(", "));
+                    }
+                    pieces.push(Expr(expression));
+                }
+                pieces.push(text("]"));
             }
-            Expression::And(metadata, expressions) => {
-                write!(f, "And({}, {})", metadata, display_expressions(expressions))
+            List(expressions) => pieces.extend([
+                text("["),
+                Expr(&expressions[0]),
+                text(".."),
+                Expr(&expressions[expressions.len() - 1]),
+                text("]"),
+            ]),
+            Expr(expression) => match expression {
+                Expression::Constant(metadata, c) => {
+                    pieces.push(Text(format!("Constant({}, {})", metadata, c)))
+                }
+                Expression::Reference(metadata, name) => {
+                    pieces.push(Text(format!("Reference({}, {})", metadata, name)))
+                }
+                Expression::Nothing => pieces.push(text("Nothing")),
+                Expression::Sum(metadata, expressions) => pieces.extend([
+                    Text(format!("Sum({}, ", metadata)),
+                    List(expressions),
+                    text(")"),
+                ]),
+                Expression::Not(metadata, expr_box) => pieces.extend([
+                    Text(format!("Not({}, ", metadata)),
+                    Expr(expr_box),
+                    text(")"),
+                ]),
+                Expression::Or(metadata, expressions) => pieces.extend([
+                    Text(format!("Not({}, ", metadata)),
+                    List(expressions),
+                    text(")"),
+                ]),
+                Expression::And(metadata, expressions) => pieces.extend([
+                    Text(format!("And({}, ", metadata)),
+                    List(expressions),
+                    text(")"),
+                ]),
+                Expression::Eq(metadata, box1, box2)
+                | Expression::Neq(metadata, box1, box2)
+                | Expression::Geq(metadata, box1, box2)
+                | Expression::Leq(metadata, box1, box2)
+                | Expression::Gt(metadata, box1, box2)
+                | Expression::Lt(metadata, box1, box2) => pieces.extend([
+                    Text(format!("{}({}, ", expression.variant_name(), metadata)),
+                    Expr(box1),
+                    text(", "),
+                    Expr(box2),
+                    text(")"),
+                ]),
+                Expression::SumGeq(metadata, box1, box2) => pieces.extend([
+                    Text(format!("SumGeq({}, ", metadata)),
+                    List(box1),
+                    text(". "),
+                    Expr(box2),
+                    text(")"),
+                ]),
+                Expression::SumLeq(metadata, box1, box2) => pieces.extend([
+                    Text(format!("SumLeq({}, ", metadata)),
+                    List(box1),
+                    text(", "),
+                    Expr(box2),
+                    text(")"),
+                ]),
+                Expression::Ineq(metadata, box1, box2, box3) => pieces.extend([
+                    Text(format!("Ineq({}, ", metadata)),
+                    Expr(box1),
+                    text(", "),
+                    Expr(box2),
+                    text(", "),
+                    Expr(box3),
+                    text(")"),
+                ]),
+                #[allow(unreachable_patterns)]
+                _ => pieces.push(text("Expression::Unknown")),
+            },
+        }
+    }
+}
+
+/// Expressions are written out a piece at a time with an explicit stack, rather than by recursion,
+/// so that deep expressions do not overflow the call stack.
+impl Display for Expression {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        // The pieces left to write, the next one last
+        let mut stack = vec![DisplayPiece::Expression(self)];
+        while let Some(piece) = stack.pop() {
+            match piece {
+                DisplayPiece::Text(text) => f.write_str(&text)?,
+                piece => {
+                    let start = stack.len();
+                    piece.expand(&mut stack);
+                    stack[start..].reverse();
+                }
             }
-            Expression::Eq(metadata, box1, box2) => {
-                write!(f, "Eq({}, {}, {})", metadata, box1.clone(), box2.clone())
-            }
-            Expression::Neq(metadata, box1, box2) => {
-                write!(f, "Neq({}, {}, {})", metadata, box1.clone(), box2.clone())
-            }
-            Expression::Geq(metadata, box1, box2) => {
-                write!(f, "Geq({}, {}, {})", metadata, box1.clone(), box2.clone())
-            }
-            Expression::Leq(metadata, box1, box2) => {
-                write!(f, "Leq({}, {}, {})", metadata, box1.clone(), box2.clone())
-            }
-            Expression::Gt(metadata, box1, box2) => {
-                write!(f, "Gt({}, {}, {})", metadata, box1.clone(), box2.clone())
-            }
-            Expression::Lt(metadata, box1, box2) => {
-                write!(f, "Lt({}, {}, {})", metadata, box1.clone(), box2.clone())
-            }
-            Expression::SumGeq(metadata, box1, box2) => {
-                write!(
-                    f,
-                    "SumGeq({}, {}. {})",
-                    metadata,
-                    display_expressions(box1),
-                    box2.clone()
-                )
-            }
-            Expression::SumLeq(metadata, box1, box2) => {
-                write!(
-                    f,
-                    "SumLeq({}, {}, {})",
-                    metadata,
-                    display_expressions(box1),
-                    box2.clone()
-                )
-            }
-            Expression::Ineq(metadata, box1, box2, box3) => write!(
-                f,
-                "Ineq({}, {}, {}, {})",
-                metadata,
-                box1.clone(),
-                box2.clone(),
-                box3.clone()
-            ),
-            #[allow(unreachable_patterns)]
-            _ => write!(f, "Expression::Unknown"),
         }
+        Ok(())
     }
 }
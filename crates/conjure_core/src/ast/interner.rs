@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+
+use uniplate::uniplate::Uniplate;
+
+use crate::ast::Expression;
+
+/// Identifies an expression stored in an [`ExpressionInterner`].
+///
+/// Two ids from the same interner are equal if and only if the expressions they were created
+/// from are structurally equal, ignoring metadata.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ExpressionId(usize);
+
+/// Stores each distinct sub-expression once, so that structurally equal expressions can be
+/// compared by id.
+///
+/// Each expression is stored as its outermost node, with its children replaced by
+/// `Expression::Nothing`, along with the ids of its children. Interning an expression costs a
+/// traversal of it; after that, comparing it with any other interned expression is O(1).
+///
+/// # Example
+/// ```rust
+/// use conjure_core::ast::{Constant, Expression, ExpressionInterner};
+/// use conjure_core::metadata::Metadata;
+///
+/// let one = Expression::Constant(Metadata::new(), Constant::Int(1));
+/// let sum = Expression::Sum(Metadata::new(), vec![one.clone(), one.clone()]);
+///
+/// let mut interner = ExpressionInterner::new();
+/// let sum_id = interner.intern(&sum);
+///
+/// assert_eq!(interner.children(sum_id)[0], interner.children(sum_id)[1]);
+/// assert_eq!(interner.len(), 2);
+/// assert_eq!(interner.get(sum_id), sum);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct ExpressionInterner {
+    ids: HashMap<(Expression, Vec<ExpressionId>), ExpressionId>,
+    nodes: Vec<(Expression, Vec<ExpressionId>)>,
+}
+
+impl ExpressionInterner {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Stores `expression` and all of its sub-expressions, and returns its id.
+    pub fn intern(&mut self, expression: &Expression) -> ExpressionId {
+        let children = expression.children();
+        let child_ids: Vec<ExpressionId> =
+            children.iter().map(|child| self.intern(child)).collect();
+
+        let mut node = expression
+            .with_children(vec![Expression::Nothing; children.len()])
+            .unwrap_or_else(|_| expression.clone());
+        node.set_clean(false);
+
+        let key = (node, child_ids);
+        if let Some(&id) = self.ids.get(&key) {
+            return id;
+        }
+        let id = ExpressionId(self.nodes.len());
+        self.nodes.push(key.clone());
+        self.ids.insert(key, id);
+        id
+    }
+
+    /// Returns the id of `expression` if it has already been interned.
+    pub fn find(&self, expression: &Expression) -> Option<ExpressionId> {
+        let children = expression.children();
+        let child_ids = children
+            .iter()
+            .map(|child| self.find(child))
+            .collect::<Option<Vec<_>>>()?;
+
+        let mut node = expression
+            .with_children(vec![Expression::Nothing; children.len()])
+            .unwrap_or_else(|_| expression.clone());
+        node.set_clean(false);
+
+        self.ids.get(&(node, child_ids)).copied()
+    }
+
+    /// Rebuilds the expression with the given id.
+    ///
+    /// # Panics
+    /// If `id` was not created by this interner.
+    pub fn get(&self, id: ExpressionId) -> Expression {
+        let (node, child_ids) = &self.nodes[id.0];
+        let children = child_ids.iter().map(|&child| self.get(child)).collect();
+        node.with_children(children)
+            .unwrap_or_else(|_| node.clone())
+    }
+
+    /// The ids of the direct children of the expression with the given id.
+    ///
+    /// # Panics
+    /// If `id` was not created by this interner.
+    pub fn children(&self, id: ExpressionId) -> &[ExpressionId] {
+        &self.nodes[id.0].1
+    }
+
+    /// The number of distinct expressions stored.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+}
@@ -1,13 +1,17 @@
 pub use constants::Constant;
+pub use diff::TreeEdit;
 pub use domains::Domain;
 pub use domains::Range;
 pub use expressions::Expression;
+pub use interner::{ExpressionId, ExpressionInterner};
 pub use symbol_table::Name;
 pub use symbol_table::SymbolTable;
 pub use variables::DecisionVariable;
 
 mod constants;
+mod diff;
 mod domains;
 mod expressions;
+mod interner;
 mod symbol_table;
 mod variables;
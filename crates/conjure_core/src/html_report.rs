@@ -0,0 +1,195 @@
+//! Rendering of a rewriter run as a standalone HTML report, for sharing with people who do not
+//! run the code.
+//!
+//! The report is a single file, with no scripts or external resources, holding the rule
+//! statistics, a timeline of the rewrites, and collapsible trees of each expression before and
+//! after it was rewritten. The statistics need
+//! [`RewriteOptions::perf_report`](crate::rule_engine::RewriteOptions::perf_report), and the
+//! timeline [`RewriteOptions::trace`](crate::rule_engine::RewriteOptions::trace); the sections
+//! are left out of runs without them.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::Path;
+
+use crate::ast::Expression;
+use crate::error::Result;
+use crate::rule_engine::RewriteOutcome;
+use crate::viz::label;
+
+const STYLE: &str = "
+body { font-family: sans-serif; margin: 2em; }
+table { border-collapse: collapse; }
+th, td { border: 1px solid #ccc; padding: 0.2em 0.6em; text-align: right; }
+th:first-child, td:first-child { text-align: left; }
+code { background: #f4f4f4; }
+.step > summary { cursor: pointer; margin: 0.3em 0; }
+.sides { display: flex; gap: 2em; margin-left: 1.5em; }
+.tree details, .tree .leaf { margin-left: 1.2em; }
+.tree > details { margin-left: 0; }
+";
+
+/// Renders the run that returned `outcome`, starting from `constraints`, as an HTML page titled
+/// `title`.
+///
+/// The timeline replays the trace on `constraints` as
+/// [`trace_to_text`](crate::viz::trace_to_text) does. A step that does not fit the constraints,
+/// because the trace is not of these constraints, leaves them unchanged, and is shown without its
+/// trees.
+pub fn to_html(title: &str, constraints: &Expression, outcome: &RewriteOutcome) -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<style>{}</style>\n</head>\n<body>",
+        escape(title),
+        STYLE
+    );
+    let _ = writeln!(out, "<h1>{}</h1>", escape(title));
+    let _ = writeln!(out, "<p>Status: {:?}</p>", outcome.status);
+    if let Some(error) = &outcome.error {
+        let _ = writeln!(out, "<p>Error: {}</p>", escape(&error.to_string()));
+    }
+
+    write_statistics(&mut out, outcome);
+    write_timeline(&mut out, constraints, outcome);
+
+    let _ = writeln!(out, "<h2>Rewritten constraints</h2>");
+    let _ = writeln!(
+        out,
+        "<p><code>{}</code></p>",
+        escape(&outcome.model.constraints.to_string())
+    );
+    write_tree(&mut out, &outcome.model.constraints);
+    out.push_str("</body>\n</html>\n");
+    out
+}
+
+/// Writes the report of the run that returned `outcome` to a file. See [`to_html`].
+pub fn save(
+    path: impl AsRef<Path>,
+    title: &str,
+    constraints: &Expression,
+    outcome: &RewriteOutcome,
+) -> Result<()> {
+    std::fs::write(path, to_html(title, constraints, outcome)).map_err(anyhow::Error::from)?;
+    Ok(())
+}
+
+/// Writes the table of rule statistics, from the performance report, or else counted from the
+/// trace.
+fn write_statistics(out: &mut String, outcome: &RewriteOutcome) {
+    if let Some(perf) = &outcome.perf {
+        let _ = writeln!(out, "<h2>Rules</h2>");
+        let _ = writeln!(
+            out,
+            "<p>Rewriting took {:?}: {:?} setting up, {:?} searching, {:?} applying, {:?} on checks.</p>",
+            perf.total, perf.setup, perf.search, perf.apply, perf.checks
+        );
+        let _ = writeln!(
+            out,
+            "<table>\n<tr><th>Rule</th><th>Attempts</th><th>Successes</th><th>Nodes</th><th>Time</th></tr>"
+        );
+        for (name, rule) in perf.slowest_rules() {
+            let _ = writeln!(
+                out,
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{:?}</td></tr>",
+                escape(name),
+                rule.attempts,
+                rule.successes,
+                rule.nodes_produced,
+                rule.time
+            );
+        }
+        out.push_str("</table>\n");
+    } else if let Some(trace) = &outcome.trace {
+        let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+        for step in trace {
+            *counts.entry(&step.rule).or_default() += 1;
+        }
+        let _ = writeln!(out, "<h2>Rules</h2>");
+        let _ = writeln!(out, "<table>\n<tr><th>Rule</th><th>Rewrites</th></tr>");
+        for (name, count) in counts {
+            let _ = writeln!(out, "<tr><td>{}</td><td>{}</td></tr>", escape(name), count);
+        }
+        out.push_str("</table>\n");
+    }
+}
+
+/// Writes the timeline of the rewrites in the trace, each with the trees of the expression
+/// before and after it.
+fn write_timeline(out: &mut String, constraints: &Expression, outcome: &RewriteOutcome) {
+    let Some(trace) = &outcome.trace else {
+        return;
+    };
+    let _ = writeln!(out, "<h2>Timeline</h2>");
+    let _ = writeln!(
+        out,
+        "<p>{} rewrites, starting from <code>{}</code></p>\n<ol>",
+        trace.len(),
+        escape(&constraints.to_string())
+    );
+    let mut current = constraints.clone();
+    for step in trace {
+        let _ = write!(
+            out,
+            "<li><details class=\"step\"><summary><b>{}</b> at {:?} ({:?})</summary>",
+            escape(&step.rule),
+            step.path,
+            step.elapsed
+        );
+        let mut next = current.clone();
+        let before = current.at_path(&step.path);
+        if let (Some(before), true) = (before, step.apply(&mut next)) {
+            let after = next.at_path(&step.path).unwrap_or(&Expression::Nothing);
+            out.push_str("<div class=\"sides\">\n<div><h4>Before</h4>");
+            let _ = write!(out, "<p><code>{}</code></p>", escape(&before.to_string()));
+            write_tree(out, before);
+            out.push_str("</div>\n<div><h4>After</h4>");
+            let _ = write!(out, "<p><code>{}</code></p>", escape(&after.to_string()));
+            write_tree(out, after);
+            out.push_str("</div>\n</div>\n");
+            if !step.new_top.is_nothing() {
+                let _ = writeln!(
+                    out,
+                    "<p>Added the top-level constraint <code>{}</code></p>",
+                    escape(&step.new_top.to_string())
+                );
+            }
+            current = next;
+        } else {
+            out.push_str("<p>Does not fit the constraints.</p>\n");
+        }
+        out.push_str("</details></li>\n");
+    }
+    out.push_str("</ol>\n");
+}
+
+/// Writes `expression` as a tree of nodes labelled by [`label`], each of which can be collapsed.
+fn write_tree(out: &mut String, expression: &Expression) {
+    out.push_str("<div class=\"tree\">");
+    write_node(out, expression);
+    out.push_str("</div>\n");
+}
+
+fn write_node(out: &mut String, expression: &Expression) {
+    let label = escape(&label(expression));
+    if expression.child(0).is_none() {
+        let _ = write!(out, "<div class=\"leaf\">{}</div>", label);
+        return;
+    }
+    let _ = write!(out, "<details open><summary>{}</summary>", label);
+    let mut i = 0;
+    while let Some(child) = expression.child(i) {
+        write_node(out, child);
+        i += 1;
+    }
+    out.push_str("</details>");
+}
+
+/// Escapes `text` for use in HTML.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
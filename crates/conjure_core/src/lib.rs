@@ -5,6 +5,9 @@ pub use model::Model;
 pub mod ast;
 pub mod context;
 pub mod error;
+#[cfg(feature = "html-report")]
+pub mod html_report;
+pub mod meta;
 pub mod metadata;
 pub mod model;
 pub mod parse;
@@ -12,3 +15,4 @@ pub mod rule_engine;
 pub mod rules;
 pub mod solver;
 pub mod stats;
+pub mod viz;
@@ -0,0 +1,660 @@
+//! State that groups of rules keep in the model alongside the symbol table, such as caches and
+//! statistics.
+
+use std::any::{type_name, Any, TypeId};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+
+use crate::ast::{Expression, SymbolTable};
+use crate::Model;
+
+/// A value that can be kept in a [`MetaStore`].
+///
+/// Implemented for every type that is `Send + Sync + 'static`. Values are never copied when the
+/// store is, so they need not be `Clone`, see [`MetaStore`].
+pub trait MetaValue: Any + Send + Sync {
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync>;
+    fn type_name(&self) -> &'static str;
+}
+
+impl<T: Any + Send + Sync> MetaValue for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+
+    fn type_name(&self) -> &'static str {
+        type_name::<T>()
+    }
+}
+
+/// What a change to a meta value may make out of date in the rewriter's records of where rules
+/// do not apply. See [`MetaListener`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MetaInvalidation {
+    /// Nothing, as no rule reads the value to decide whether it applies.
+    Nothing,
+    /// Only the records of the named rules.
+    Rules(Vec<&'static str>),
+    /// Every record, as for a change to a value without a listener.
+    All,
+}
+
+impl MetaInvalidation {
+    /// What both `self` and `other` make out of date.
+    pub fn and(self, other: MetaInvalidation) -> MetaInvalidation {
+        match (self, other) {
+            (MetaInvalidation::All, _) | (_, MetaInvalidation::All) => MetaInvalidation::All,
+            (MetaInvalidation::Nothing, other) | (other, MetaInvalidation::Nothing) => other,
+            (MetaInvalidation::Rules(mut rules), MetaInvalidation::Rules(more)) => {
+                rules.extend(more);
+                MetaInvalidation::Rules(rules)
+            }
+        }
+    }
+}
+
+/// A meta value that is told when a rewrite changes it, so that it can say which of the
+/// rewriter's records of where rules do not apply are now out of date.
+///
+/// By default, the rewriter forgets every record when a rewrite sets a meta value, as any rule
+/// may read it to decide whether it applies. Types that know which rules read them can implement
+/// this trait, and be listened for with [`MetaStore::listen`], so that only the records of those
+/// rules are forgotten.
+///
+/// # Example
+/// ```rust
+/// use conjure_core::meta::{MetaInvalidation, MetaListener, MetaStore};
+///
+/// #[derive(Clone)]
+/// struct Bounds(i32, i32);
+///
+/// impl MetaListener for Bounds {
+///     fn on_change(&self, _: Option<&Self>) -> MetaInvalidation {
+///         MetaInvalidation::Rules(vec!["tighten_bounds"])
+///     }
+/// }
+///
+/// let mut meta = MetaStore::new();
+/// meta.listen::<Bounds>();
+///
+/// let mut changes = MetaStore::new();
+/// changes.insert(Bounds(0, 10));
+/// assert_eq!(
+///     meta.invalidation(&changes),
+///     MetaInvalidation::Rules(vec!["tighten_bounds"])
+/// );
+/// ```
+pub trait MetaListener: MetaValue {
+    /// Called before a rewrite sets the value to `self`, with the value it replaces, if any.
+    fn on_change(&self, previous: Option<&Self>) -> MetaInvalidation;
+}
+
+/// The type of a meta value, as the function that returns its [`TypeId`], which unlike the
+/// `TypeId` itself can be written in a `static`. For example, `TypeId::of::<Stats>`.
+/// See [`Rule::reads_meta`](crate::rule_engine::Rule::reads_meta).
+pub type MetaKey = fn() -> TypeId;
+
+type Listener = fn(&dyn MetaValue, Option<&dyn MetaValue>) -> MetaInvalidation;
+
+type TakeCheckpoint = fn(&MetaStore) -> Option<Arc<dyn MetaValue>>;
+
+type Rewind = fn(&mut MetaStore, Option<&dyn MetaValue>);
+
+/// Calls [`MetaListener::on_change`] on values of type `K`.
+fn notify<K: MetaListener>(
+    value: &dyn MetaValue,
+    previous: Option<&dyn MetaValue>,
+) -> MetaInvalidation {
+    match value.as_any().downcast_ref::<K>() {
+        Some(value) => {
+            value.on_change(previous.and_then(|previous| previous.as_any().downcast_ref()))
+        }
+        None => MetaInvalidation::All,
+    }
+}
+
+/// A map from types to a value of each type, so that each group of rules can keep its own state
+/// in the model without every group agreeing on one type for all of it.
+///
+/// A group of rules keys its state by a type of its own, usually a struct holding it, and reads
+/// it with [`get`](MetaStore::get). Rules change it by returning a [`Reduction`] with
+/// [`with_meta`](crate::rule_engine::Reduction::with_meta), which sets it when the rewrite is
+/// applied, or by changing the model directly if they take it as `&mut Model`.
+///
+/// Cloning the store, as the engine does whenever it copies the model, shares its values with
+/// the clone rather than copying them, so values can be large, and need not be `Clone`. A value
+/// that is `Clone` is copied when it is borrowed mutably while shared, and only then. A value that
+/// is not is changed by setting a new one, for example with
+/// [`with_meta`](crate::rule_engine::Reduction::with_meta).
+///
+/// The store keeps track of the types set or borrowed mutably since it was made or cloned, so
+/// that only those are taken as the side-effects of a rule that changes the model directly.
+/// Changes to types that are [listened for](MetaStore::listen) only make the rewriter forget
+/// where the rules they name do not apply, see [`MetaListener`].
+///
+/// # Example
+/// ```rust
+/// use conjure_core::meta::MetaStore;
+///
+/// #[derive(Clone, Debug, Default, PartialEq)]
+/// struct Stats {
+///     rewrites: usize,
+/// }
+///
+/// let mut meta = MetaStore::new();
+/// assert_eq!(meta.get::<Stats>(), None);
+///
+/// meta.insert(Stats::default());
+/// meta.get_or_default::<Stats>().rewrites += 1;
+/// assert_eq!(meta.get::<Stats>(), Some(&Stats { rewrites: 1 }));
+/// ```
+///
+/// [`Reduction`]: crate::rule_engine::Reduction
+#[derive(Default)]
+pub struct MetaStore {
+    values: HashMap<TypeId, Arc<dyn MetaValue>>,
+    changed: HashSet<TypeId>,
+    listeners: HashMap<TypeId, Listener>,
+    checkpointed: HashMap<TypeId, (TakeCheckpoint, Rewind)>,
+}
+
+impl MetaStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The value of type `K`, if there is one.
+    pub fn get<K: MetaValue>(&self) -> Option<&K> {
+        self.values
+            .get(&TypeId::of::<K>())
+            .and_then(|value| value.as_ref().as_any().downcast_ref())
+    }
+
+    /// The value of type `K`, if there is one, to be changed. The value is copied first if it is
+    /// shared with a clone of this store.
+    pub fn get_mut<K: MetaValue + Clone>(&mut self) -> Option<&mut K> {
+        let key = TypeId::of::<K>();
+        let value = self.values.get_mut(&key)?;
+        self.changed.insert(key);
+        unshare::<K>(value)
+    }
+
+    /// The value of type `K`, set to its default first if there is none, to be changed. The value
+    /// is copied first if it is shared with a clone of this store.
+    #[allow(clippy::expect_used)]
+    pub fn get_or_default<K: MetaValue + Clone + Default>(&mut self) -> &mut K {
+        let key = TypeId::of::<K>();
+        self.changed.insert(key);
+        let value = self
+            .values
+            .entry(key)
+            .or_insert_with(|| Arc::new(K::default()));
+        unshare::<K>(value).expect("meta values are stored under their own type")
+    }
+
+    /// Sets the value of type `K`.
+    ///
+    /// # Returns
+    /// - The value it replaced, if there was one, which may be shared with clones of this store.
+    pub fn insert<K: MetaValue>(&mut self, value: K) -> Option<Arc<K>> {
+        let key = TypeId::of::<K>();
+        self.changed.insert(key);
+        self.values
+            .insert(key, Arc::new(value))
+            .and_then(|old| old.into_any().downcast().ok())
+    }
+
+    /// Removes the value of type `K`.
+    ///
+    /// # Returns
+    /// - The value, if there was one, which may be shared with clones of this store.
+    pub fn remove<K: MetaValue>(&mut self) -> Option<Arc<K>> {
+        let key = TypeId::of::<K>();
+        self.changed.remove(&key);
+        self.values
+            .remove(&key)
+            .and_then(|old| old.into_any().downcast().ok())
+    }
+
+    pub fn contains<K: MetaValue>(&self) -> bool {
+        self.values.contains_key(&TypeId::of::<K>())
+    }
+
+    /// Whether there is a value of the type given by `key`.
+    pub fn contains_key(&self, key: MetaKey) -> bool {
+        self.values.contains_key(&key())
+    }
+
+    /// The type of each value, with its name.
+    pub fn types(&self) -> impl Iterator<Item = (TypeId, &'static str)> + '_ {
+        self.values
+            .iter()
+            .map(|(key, value)| (*key, value.as_ref().type_name()))
+    }
+
+    /// A store of the values of the types given by `keys`, shared with this store, along with
+    /// every listener.
+    pub(crate) fn copy_of(&self, keys: impl IntoIterator<Item = MetaKey>) -> MetaStore {
+        let mut copy = MetaStore {
+            listeners: self.listeners.clone(),
+            ..MetaStore::default()
+        };
+        for key in keys {
+            if let Some(value) = self.values.get(&key()) {
+                copy.values.insert(key(), value.clone());
+            }
+        }
+        copy
+    }
+
+    /// The number of types that have a value.
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Sets each value in `other`, replacing the value of the same type in this store, and
+    /// listens for the types `other` listens for.
+    pub fn extend(&mut self, other: MetaStore) {
+        self.changed.extend(other.values.keys().copied());
+        self.values.extend(other.values);
+        self.listeners.extend(other.listeners);
+        self.checkpointed.extend(other.checkpointed);
+    }
+
+    /// Sets each value in `scope`, replacing the value of the same type in this store, without
+    /// counting them as changed. Returns the values they replaced, to be put back with
+    /// [`leave_scope`](MetaStore::leave_scope).
+    pub(crate) fn enter_scope(&mut self, scope: MetaStore) -> MetaScope {
+        MetaScope {
+            replaced: scope
+                .values
+                .into_iter()
+                .map(|(key, value)| (key, self.values.insert(key, value)))
+                .collect(),
+        }
+    }
+
+    /// Puts back the values replaced by [`enter_scope`](MetaStore::enter_scope).
+    pub(crate) fn leave_scope(&mut self, scope: MetaScope) {
+        for (key, value) in scope.replaced {
+            match value {
+                Some(value) => self.values.insert(key, value),
+                None => self.values.remove(&key),
+            };
+        }
+    }
+
+    /// Calls [`MetaListener::on_change`] whenever [`invalidation`](MetaStore::invalidation) is
+    /// asked about a change to the value of type `K`.
+    pub fn listen<K: MetaListener>(&mut self) {
+        self.listeners.insert(TypeId::of::<K>(), notify::<K>);
+    }
+
+    /// Includes the value of type `K` in every [`checkpoint`](MetaStore::checkpoint) of this store
+    /// and its clones.
+    pub fn checkpointed<K: Checkpointable>(&mut self) {
+        self.checkpointed.insert(
+            TypeId::of::<K>(),
+            (take_checkpoint::<K>, rewind_checkpoint::<K>),
+        );
+    }
+
+    /// A checkpoint of the values of the types that are
+    /// [checkpointed](MetaStore::checkpointed), to be given to [`rewind`](MetaStore::rewind)
+    /// later.
+    pub fn checkpoint(&self) -> MetaCheckpoint {
+        MetaCheckpoint {
+            values: self
+                .checkpointed
+                .values()
+                .map(|(take, rewind)| (*rewind, take(self)))
+                .collect(),
+        }
+    }
+
+    /// Puts the values in `checkpoint` back as they were when it was taken, and removes the values
+    /// of the types it has none of.
+    pub fn rewind(&mut self, checkpoint: &MetaCheckpoint) {
+        for (rewind, value) in &checkpoint.values {
+            rewind(self, value.as_deref());
+        }
+    }
+
+    /// What setting the values in `changes` would make out of date, as said by the listeners of
+    /// this store or of `changes`.
+    pub fn invalidation(&self, changes: &MetaStore) -> MetaInvalidation {
+        changes
+            .values
+            .iter()
+            .map(
+                |(key, value)| match self.listeners.get(key).or(changes.listeners.get(key)) {
+                    Some(listener) => listener(
+                        value.as_ref(),
+                        self.values.get(key).map(|previous| previous.as_ref()),
+                    ),
+                    None => MetaInvalidation::All,
+                },
+            )
+            .fold(MetaInvalidation::Nothing, MetaInvalidation::and)
+    }
+
+    /// Sets each value in `other` that this store has no value of the same type for.
+    pub(crate) fn extend_missing(&mut self, other: MetaStore) {
+        for (key, value) in other.values {
+            if let std::collections::hash_map::Entry::Vacant(entry) = self.values.entry(key) {
+                self.changed.insert(key);
+                entry.insert(value);
+            }
+        }
+    }
+
+    /// Removes the values set or borrowed mutably since this store was made or cloned.
+    ///
+    /// # Returns
+    /// - A store of the removed values.
+    pub(crate) fn take_changed(&mut self) -> MetaStore {
+        let mut changed = MetaStore::new();
+        for key in std::mem::take(&mut self.changed) {
+            if let Some(value) = self.values.remove(&key) {
+                changed.changed.insert(key);
+                changed.values.insert(key, value);
+            }
+        }
+        changed
+    }
+}
+
+/// Shares the values and clones the listeners, but not the record of which values were changed.
+impl Clone for MetaStore {
+    fn clone(&self) -> Self {
+        Self {
+            values: self.values.clone(),
+            changed: HashSet::new(),
+            listeners: self.listeners.clone(),
+            checkpointed: self.checkpointed.clone(),
+        }
+    }
+}
+
+impl Debug for MetaStore {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_set()
+            .entries(self.values.values().map(|value| value.as_ref().type_name()))
+            .finish()
+    }
+}
+
+/// Borrows `value`, a value of type `K`, mutably, copying it first if it is shared.
+fn unshare<K: MetaValue + Clone>(value: &mut Arc<dyn MetaValue>) -> Option<&mut K> {
+    if Arc::get_mut(value).is_none() {
+        let copy = value.as_ref().as_any().downcast_ref::<K>()?.clone();
+        *value = Arc::new(copy);
+    }
+    Arc::get_mut(value)?.as_any_mut().downcast_mut()
+}
+
+/// Meta that the search modes rewind when they go back to a model they reached before, such as
+/// when [backtracking](crate::rule_engine::RewriteOptions::backtracking) from a dead end, once it
+/// is [checkpointed](MetaStore::checkpointed).
+///
+/// Each model a search keeps has its own [`MetaStore`], so values set by rewrites are already
+/// those of the model the search goes back to. This is for values whose state is not kept in
+/// the value itself, such as a handle to a cache shared by every copy of the model, which rules
+/// change as they are tried. By default, a checkpoint is a clone, and rewinding replaces the value
+/// with it. Such values override both methods to copy and put back the state they share.
+///
+/// # Example
+/// ```rust
+/// use std::sync::{Arc, Mutex};
+///
+/// use conjure_core::meta::{Checkpointable, MetaStore};
+///
+/// /// The expressions tried so far, shared by every copy of the model.
+/// #[derive(Clone, Default)]
+/// struct Tried(Arc<Mutex<Vec<String>>>);
+///
+/// impl Checkpointable for Tried {
+///     fn checkpoint(&self) -> Self {
+///         Tried(Arc::new(Mutex::new(self.0.lock().unwrap().clone())))
+///     }
+///
+///     fn rewind(&mut self, checkpoint: Self) {
+///         *self.0.lock().unwrap() = checkpoint.0.lock().unwrap().clone();
+///     }
+/// }
+///
+/// let mut meta = MetaStore::new();
+/// meta.checkpointed::<Tried>();
+/// meta.insert(Tried::default());
+/// let checkpoint = meta.checkpoint();
+///
+/// let copy = meta.clone();
+/// copy.get::<Tried>().unwrap().0.lock().unwrap().push(String::from("x < y"));
+///
+/// meta.rewind(&checkpoint);
+/// assert!(copy.get::<Tried>().unwrap().0.lock().unwrap().is_empty());
+/// ```
+pub trait Checkpointable: MetaValue + Clone {
+    /// A copy of the value, to be given to [`rewind`](Checkpointable::rewind) later.
+    fn checkpoint(&self) -> Self {
+        self.clone()
+    }
+
+    /// Puts the value back as it was when `checkpoint` was taken.
+    fn rewind(&mut self, checkpoint: Self) {
+        *self = checkpoint;
+    }
+}
+
+/// The values replaced by [`MetaStore::enter_scope`].
+pub(crate) struct MetaScope {
+    replaced: Vec<(TypeId, Option<Arc<dyn MetaValue>>)>,
+}
+
+/// The values of a [`MetaStore`] as they were at some point, taken by
+/// [`MetaStore::checkpoint`].
+#[derive(Clone, Default)]
+pub struct MetaCheckpoint {
+    values: Vec<(Rewind, Option<Arc<dyn MetaValue>>)>,
+}
+
+/// Calls [`Checkpointable::checkpoint`] on the value of type `K`.
+fn take_checkpoint<K: Checkpointable>(store: &MetaStore) -> Option<Arc<dyn MetaValue>> {
+    let value: Arc<dyn MetaValue> = Arc::new(store.get::<K>()?.checkpoint());
+    Some(value)
+}
+
+/// Calls [`Checkpointable::rewind`] on the value of type `K`, or sets it to `checkpoint` if
+/// there is none, or removes it if `checkpoint` is None.
+fn rewind_checkpoint<K: Checkpointable>(store: &mut MetaStore, checkpoint: Option<&dyn MetaValue>) {
+    let Some(checkpoint) =
+        checkpoint.and_then(|checkpoint| checkpoint.as_any().downcast_ref::<K>())
+    else {
+        store.remove::<K>();
+        return;
+    };
+    match store.get_mut::<K>() {
+        Some(value) => value.rewind(checkpoint.clone()),
+        None => {
+            store.insert(checkpoint.clone());
+        }
+    }
+}
+
+/// Meta worked out from the model, such as the number of times each variable is used, which the
+/// rewriter keeps up to date after every rewrite, so that rules can look it up rather than each
+/// working it out from the constraints. See
+/// [`RewriteOptions::derive_meta`](crate::rule_engine::RewriteOptions::derive_meta).
+///
+/// # Example
+/// ```rust
+/// use std::collections::HashMap;
+///
+/// use conjure_core::ast::{Expression, Name};
+/// use conjure_core::meta::{DerivedMeta, MetaInvalidation};
+/// use conjure_core::Model;
+/// use uniplate::uniplate::Uniplate;
+///
+/// /// The number of times each variable is used in the constraints.
+/// #[derive(Clone, Default)]
+/// struct UseCounts(HashMap<Name, usize>);
+///
+/// impl UseCounts {
+///     fn count(&mut self, expression: &Expression, by: isize) {
+///         for node in expression.universe() {
+///             if let Expression::Reference(_, name) = &node {
+///                 let count = self.0.entry(name.clone()).or_default();
+///                 *count = count.saturating_add_signed(by);
+///             }
+///         }
+///     }
+/// }
+///
+/// impl DerivedMeta for UseCounts {
+///     fn derive(model: &Model) -> Self {
+///         let mut counts = UseCounts::default();
+///         counts.count(&model.constraints, 1);
+///         counts
+///     }
+///
+///     fn update(&mut self, before: &Expression, after: &Expression, _: &Model) -> MetaInvalidation {
+///         self.count(before, -1);
+///         self.count(after, 1);
+///         MetaInvalidation::Rules(vec!["remove_unused_variables"])
+///     }
+/// }
+/// ```
+pub trait DerivedMeta: MetaValue + Sized {
+    /// Works out the value from the whole of `model`.
+    fn derive(model: &Model) -> Self;
+
+    /// Brings the value up to date after a rewrite of `before` to `after`, where `model` is the
+    /// model after the rewrite. A top-level constraint added by a rewrite is given as a rewrite
+    /// of [`Expression::Nothing`].
+    ///
+    /// Only called when the value is not shared with a copy of the model, such as the one given
+    /// to the rewriter. Otherwise, the value is worked out from the whole model instead, so that
+    /// it need not be `Clone`.
+    ///
+    /// By default, works out the value from the whole of `model` again, and makes every record of
+    /// where rules do not apply out of date.
+    ///
+    /// # Returns
+    /// What the change to the value makes out of date, see [`MetaListener`].
+    fn update(
+        &mut self,
+        before: &Expression,
+        after: &Expression,
+        model: &Model,
+    ) -> MetaInvalidation {
+        let _ = (before, after);
+        *self = Self::derive(model);
+        MetaInvalidation::All
+    }
+}
+
+/// Brings a type of [`DerivedMeta`] in the model's meta up to date after `rewrites`, each an
+/// expression and what it was rewritten to, or works it out from scratch if it is not there.
+pub type DerivedMetaUpdate = fn(&mut Model, &[(Expression, Expression)]) -> MetaInvalidation;
+
+/// The [`DerivedMetaUpdate`] of `K`.
+pub(crate) fn update_derived<K: DerivedMeta>(
+    model: &mut Model,
+    rewrites: &[(Expression, Expression)],
+) -> MetaInvalidation {
+    let (value, invalidation) = match model.meta.remove::<K>().map(Arc::try_unwrap) {
+        Some(Ok(mut value)) => {
+            let invalidation = rewrites
+                .iter()
+                .map(|(before, after)| value.update(before, after, model))
+                .fold(MetaInvalidation::Nothing, MetaInvalidation::and);
+            (value, invalidation)
+        }
+        Some(Err(_)) | None => (K::derive(model), MetaInvalidation::All),
+    };
+    model.meta.insert(value);
+    invalidation
+}
+
+/// Meta whose changes can be described, to be recorded in the trace. See
+/// [`RewriteOptions::diff_meta`](crate::rule_engine::RewriteOptions::diff_meta).
+///
+/// # Example
+/// ```rust
+/// use conjure_core::meta::DiffMeta;
+///
+/// #[derive(Clone)]
+/// struct Stats {
+///     rewrites: usize,
+/// }
+///
+/// impl DiffMeta for Stats {
+///     fn diff(&self, previous: Option<&Self>) -> Option<String> {
+///         let before = previous.map_or(0, |previous| previous.rewrites);
+///         (self.rewrites != before).then(|| format!("rewrites: {} -> {}", before, self.rewrites))
+///     }
+/// }
+/// ```
+pub trait DiffMeta: MetaValue {
+    /// Describes how the value changed from `previous`, or from nothing if there was none.
+    ///
+    /// # Returns
+    /// - The description.
+    /// - None if the value did not change.
+    fn diff(&self, previous: Option<&Self>) -> Option<String>;
+}
+
+/// Lists the symbols added and the symbols whose domains changed.
+impl DiffMeta for SymbolTable {
+    fn diff(&self, previous: Option<&Self>) -> Option<String> {
+        let mut added = Vec::new();
+        let mut changed = Vec::new();
+        for (name, variable) in self {
+            match previous.and_then(|previous| previous.get(name)) {
+                None => added.push(format!("{}: {}", name, variable)),
+                Some(old) if old != variable => {
+                    changed.push(format!("{}: {} -> {}", name, old, variable))
+                }
+                Some(_) => {}
+            }
+        }
+        // Symbol tables are unordered, so the changes are sorted to be the same every run
+        added.sort();
+        changed.sort();
+        let parts: Vec<String> = [("added", added), ("changed", changed)]
+            .into_iter()
+            .filter(|(_, names)| !names.is_empty())
+            .map(|(kind, names)| format!("{} {}", kind, names.join(", ")))
+            .collect();
+        (!parts.is_empty()).then(|| parts.join("; "))
+    }
+}
+
+/// Describes how part of a model changed from the first model given to the second, or returns
+/// None if it did not. See [`DiffMeta`].
+pub type MetaDiff = fn(&Model, &Model) -> Option<String>;
+
+/// The [`MetaDiff`] of the value of type `K` in the models' meta.
+pub(crate) fn diff_meta_value<K: DiffMeta>(before: &Model, after: &Model) -> Option<String> {
+    after.meta.get::<K>()?.diff(before.meta.get::<K>())
+}
+
+/// The [`MetaDiff`] of the models' symbol tables.
+pub(crate) fn diff_symbols(before: &Model, after: &Model) -> Option<String> {
+    after.variables.diff(Some(&before.variables))
+}
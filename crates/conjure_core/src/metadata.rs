@@ -1,18 +1,237 @@
+use std::any::{Any, TypeId};
 use std::fmt::{Debug, Display};
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, OnceLock, PoisonError, RwLock};
 
+use derivative::Derivative;
 use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
+#[derive(Eq, Deserialize, Serialize, Default, Derivative)]
+#[derivative(Clone, Debug, PartialEq)]
 pub struct Metadata {
     pub clean: bool,
+    /// The state the rewriter keeps about the expression, allocated the first time any is needed
+    /// so that an expression without any takes little more space than the `clean` flag.
+    ///
+    /// This is a cache, so it is not serialised or compared.
+    #[serde(skip)]
+    #[derivative(
+        Clone(clone_with = "clone_cache"),
+        Debug = "ignore",
+        PartialEq = "ignore"
+    )]
+    pub cache: OnceLock<Box<Cache>>,
+    /// The rule that produced the expression, if it was produced while rewriting with
+    /// [`RewriteOptions::provenance`](crate::rule_engine::RewriteOptions::provenance) set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[derivative(PartialEq = "ignore")]
+    pub provenance: Option<Box<Provenance>>,
+    /// The part of the source the expression came from, if known. See
+    /// [`Expression::span`](crate::ast::Expression::span).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[derivative(PartialEq = "ignore")]
+    pub span: Option<Box<Span>>,
 }
 
 impl Metadata {
     pub fn new() -> Metadata {
-        Metadata { clean: false }
+        Metadata {
+            clean: false,
+            cache: OnceLock::new(),
+            provenance: None,
+            span: None,
+        }
     }
 }
 
+/// The state the rewriter keeps in the [`Metadata`] of an expression.
+#[derive(Default, PartialEq, Eq)]
+pub struct Cache {
+    /// The rule sets that are known not to apply to this expression (not counting its
+    /// sub-expressions), as a bitmask. Bit `i` stands for the `i`th registered rule set, as
+    /// returned by [`get_rule_sets`](crate::rule_engine::get_rule_sets). If more than 64 rule
+    /// sets are registered, bit `i` instead stands for the `i`th rule set of the run that made the
+    /// mark, and the marks are not kept for later runs.
+    pub clean_rule_sets: u64,
+    /// The rule sets that are known not to apply to this expression or any of its
+    /// sub-expressions, as a bitmask like `clean_rule_sets`. The rewriter checks this before
+    /// visiting a sub-expression, so it need not look at the marks below it.
+    ///
+    /// Dropped along with the annotations whenever the sub-expressions may have changed.
+    pub clean_subtree_rule_sets: u64,
+    /// The generation of the rewriter in which `clean_rule_sets` and `clean_subtree_rule_sets`
+    /// were last marked. Marks from any other generation are stale, and count as no marks at all,
+    /// so the rewriter can forget every mark at once by starting a new generation rather than by
+    /// visiting every expression. Each run of the rewriter starts from a generation no run has
+    /// started before, unless it carries on from the marks of a run that reached a fixpoint, see
+    /// [`Model::clean_generation`](crate::Model::clean_generation). No run starts generation 0,
+    /// so the marks in a new cache count for nothing.
+    pub clean_generation: u64,
+    /// Facts about the expression and its sub-expressions, computed when first needed. See
+    /// [`Expression::annotations`](crate::ast::Expression::annotations).
+    pub annotations: OnceLock<Annotations>,
+    /// The [`Summary`] values of the expression that have been computed so far. See
+    /// [`Expression::summary`](crate::ast::Expression::summary).
+    pub summaries: Summaries,
+}
+
+/// Facts about an expression and its sub-expressions, cached in its [`Metadata`] so that they
+/// can be looked up without traversing the expression.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Annotations {
+    /// The number of expressions, including the expression itself.
+    pub size: usize,
+    /// The number of expressions on the longest path from the expression down to a leaf,
+    /// including both ends.
+    pub depth: usize,
+    /// Whether the expression or any sub-expression is a reference to a variable.
+    pub contains_reference: bool,
+    /// A hash of the expression, ignoring metadata. Equal expressions have equal hashes.
+    ///
+    /// Computed from the hashes of the sub-expressions, so after a rewrite only the expressions
+    /// above the rewritten one are hashed again.
+    pub hash: u64,
+    /// The variants of the expression and its sub-expressions, as a bitmask. Bit `i` stands for
+    /// the variant named `Expression::VARIANT_NAMES[i]`.
+    pub variants: u64,
+}
+
+/// A fact about an expression and its sub-expressions that is made by combining the same fact
+/// about each sub-expression, such as the set of variables the expression refers to.
+///
+/// Summaries are computed by [`Expression::summary`](crate::ast::Expression::summary) and cached
+/// in the [`Metadata`] of each expression, alongside its [`Annotations`]. After a rewrite, the
+/// rewriter drops the cached annotations of the expressions above the rewritten one, and their
+/// summaries go with them, so only those summaries are combined again. Every other expression
+/// answers from its cache in constant time.
+///
+/// # Example
+/// ```rust
+/// use std::collections::BTreeSet;
+/// use conjure_core::ast::{Expression, Name};
+/// use conjure_core::metadata::{Metadata, Summary};
+///
+/// #[derive(Default)]
+/// struct Referenced(BTreeSet<Name>);
+///
+/// impl Summary for Referenced {
+///     fn of_node(expression: &Expression) -> Self {
+///         match expression {
+///             Expression::Reference(_, name) => Referenced(BTreeSet::from([name.clone()])),
+///             _ => Referenced::default(),
+///         }
+///     }
+///
+///     fn combine(&mut self, child: &Self) {
+///         self.0.extend(child.0.iter().cloned());
+///     }
+/// }
+///
+/// let x = Expression::Reference(Metadata::new(), Name::UserName(String::from("x")));
+/// let expr = Expression::Sum(Metadata::new(), vec![x.clone(), x]);
+/// assert_eq!(expr.summary::<Referenced>().0.len(), 1);
+/// ```
+pub trait Summary: Send + Sync + 'static {
+    /// The summary of `expression` alone, not counting its sub-expressions.
+    fn of_node(expression: &crate::ast::Expression) -> Self;
+
+    /// Adds the summary of a sub-expression to this summary.
+    fn combine(&mut self, child: &Self);
+}
+
+/// The cached [`Summary`] values of an expression, by type.
+///
+/// The cache does not take part in comparisons, so any two are equal.
+#[derive(Default)]
+pub struct Summaries(RwLock<Vec<(TypeId, Arc<dyn Any + Send + Sync>)>>);
+
+impl Summaries {
+    /// The cached summary of type `S`, if there is one.
+    pub fn get<S: Summary>(&self) -> Option<Arc<S>> {
+        let summaries = self.0.read().unwrap_or_else(PoisonError::into_inner);
+        summaries
+            .iter()
+            .find(|(id, _)| *id == TypeId::of::<S>())
+            .and_then(|(_, summary)| summary.clone().downcast().ok())
+    }
+
+    /// Caches `summary`, unless a summary of the same type was cached first, in which case that
+    /// one is kept and returned instead.
+    pub fn insert<S: Summary>(&self, summary: Arc<S>) -> Arc<S> {
+        let mut summaries = self.0.write().unwrap_or_else(PoisonError::into_inner);
+        if let Some(existing) = summaries
+            .iter()
+            .find(|(id, _)| *id == TypeId::of::<S>())
+            .and_then(|(_, summary)| summary.clone().downcast().ok())
+        {
+            return existing;
+        }
+        summaries.push((TypeId::of::<S>(), summary.clone()));
+        summary
+    }
+
+    /// Returns true if no summaries are cached.
+    pub fn is_empty(&self) -> bool {
+        self.0
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .is_empty()
+    }
+}
+
+impl PartialEq for Summaries {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for Summaries {}
+
+/// Where an expression came from: the rule that produced it, and when.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Provenance {
+    /// The name of the rule.
+    pub rule: String,
+    /// The iteration of the rewriter in which the rule was applied, counting from 0. An
+    /// iteration can make more than one rewrite, see
+    /// [`RewriteOptions::batch_rewrites`](crate::rule_engine::RewriteOptions::batch_rewrites).
+    pub iteration: usize,
+}
+
+/// A part of the source a model was parsed from, such as an Essence file, as a range of byte
+/// offsets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}..{}", self.start, self.end)
+    }
+}
+
+// Rules often build a new expression from the metadata of an old one, so only the clean marks are
+// kept when metadata is cloned, and the cache is only allocated for them if there are any.
+fn clone_cache(cache: &OnceLock<Box<Cache>>) -> OnceLock<Box<Cache>> {
+    match cache.get() {
+        Some(cache) if cache.clean_rule_sets != 0 => OnceLock::from(Box::new(Cache {
+            clean_rule_sets: cache.clean_rule_sets,
+            clean_subtree_rule_sets: cache.clean_subtree_rule_sets,
+            clean_generation: cache.clean_generation,
+            ..Cache::default()
+        })),
+        _ => OnceLock::new(),
+    }
+}
+
+// Metadata does not contribute to the hash of an expression, so that expressions that differ only
+// in their clean flags hash to the same value.
+impl Hash for Metadata {
+    fn hash<H: Hasher>(&self, _: &mut H) {}
+}
+
 impl Display for Metadata {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "Metadata")
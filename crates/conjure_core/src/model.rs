@@ -8,7 +8,9 @@ use serde_with::serde_as;
 
 use crate::ast::{DecisionVariable, Domain, Expression, Name, SymbolTable};
 use crate::context::Context;
+use crate::meta::MetaStore;
 use crate::metadata::Metadata;
+use crate::rule_engine::Scratch;
 
 #[serde_as]
 #[derive(Derivative, Clone, Debug, Serialize, Deserialize)]
@@ -20,6 +22,22 @@ pub struct Model {
     #[serde(skip)]
     #[derivative(PartialEq = "ignore")]
     pub context: Arc<RwLock<Context<'static>>>,
+    /// State kept by groups of rules, such as caches. See [`MetaStore`].
+    #[serde(skip)]
+    #[derivative(PartialEq = "ignore")]
+    pub meta: MetaStore,
+    /// State kept by rules for one run of the rewriter. See [`Scratch`].
+    #[serde(skip)]
+    #[derivative(PartialEq = "ignore")]
+    pub scratch: Scratch,
+    /// The generation of the clean marks left on the constraints by the last run of the
+    /// rewriter, so that the next run can go by them. Only set if that run reached a fixpoint
+    /// having tried every rule wherever it marked an expression clean; otherwise the next run
+    /// starts from a new generation. See
+    /// [`Cache::clean_generation`](crate::metadata::Cache::clean_generation).
+    #[serde(skip)]
+    #[derivative(PartialEq = "ignore")]
+    pub clean_generation: Option<u64>,
     next_var: RefCell<i32>,
 }
 
@@ -33,6 +51,9 @@ impl Model {
             variables,
             constraints,
             context,
+            meta: MetaStore::new(),
+            scratch: Scratch::default(),
+            clean_generation: None,
             next_var: RefCell::new(0),
         }
     }
@@ -92,6 +113,14 @@ impl Model {
         self.set_constraints(constraints);
     }
 
+    /// Makes sure that the names [`Model::gensym`] made for `copy`, a copy of this model, are not
+    /// made again for this one.
+    pub(crate) fn reserve_names(&self, copy: &Model) {
+        let next = *copy.next_var.borrow();
+        let mut next_var = self.next_var.borrow_mut();
+        *next_var = (*next_var).max(next);
+    }
+
     /// Returns an arbitrary variable name that is not in the model.
     pub fn gensym(&self) -> Name {
         let num = *self.next_var.borrow();
@@ -0,0 +1,46 @@
+use crate::ast::Expression;
+use crate::rule_engine::normal_forms::candidates;
+use crate::rule_engine::resolve_rules::{get_rule_priorities, get_rules_vec};
+use crate::rule_engine::{RewriteError, RuleSet};
+use crate::Model;
+
+/// A rewrite that a rule could make of one expression in the constraints, returned by
+/// [`analyse`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct Redex {
+    pub rule: String,
+    pub priority: u8,
+    /// The child indices leading from the root of the constraints to the expression.
+    pub path: Vec<usize>,
+    /// The expression the rule would replace the expression with.
+    pub new_expression: Expression,
+}
+
+/// Lists every rewrite that a rule in `rule_sets` could make of an expression in the constraints
+/// of `model`, without making any of them. Tools can use this to show which rewrites are
+/// available at each expression.
+///
+/// Every rule is tried on every expression, so rewrites are listed even where a rule of higher
+/// priority would be used instead. Rules that return an error, or that return the expression
+/// unchanged, are treated as not applying. Rules are only given the model to read, but panics in
+/// rules are not caught.
+///
+/// # Returns
+/// - The rewrites, by expression in pre-order, then in the order the rewriter tries the rules.
+/// - A `RewriteError` if the rule sets could not be resolved.
+pub fn analyse<'a>(
+    model: &Model,
+    rule_sets: &Vec<&'a RuleSet<'a>>,
+) -> Result<Vec<Redex>, RewriteError> {
+    let rule_priorities = get_rule_priorities(rule_sets)?;
+    let rules = get_rules_vec(&rule_priorities);
+    Ok(candidates(model, &rules)
+        .into_iter()
+        .map(|candidate| Redex {
+            rule: candidate.rule.name.to_string(),
+            priority: rule_priorities[candidate.rule],
+            path: candidate.path,
+            new_expression: candidate.reduction.new_expression,
+        })
+        .collect())
+}
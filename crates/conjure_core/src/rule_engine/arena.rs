@@ -0,0 +1,270 @@
+use std::ops::Range;
+
+use crate::ast::Expression;
+use crate::metadata::Metadata;
+
+/// The index of a node in an [`Arena`].
+pub(super) type NodeId = usize;
+
+/// An expression held as a flat list of nodes, each of which refers to its children by index.
+///
+/// Each node holds its expression with the children taken out, as by
+/// [`Expression::take_children`], along with facts about the sub-expression rooted at it that
+/// would otherwise need a traversal to find. Rewriting a node leaves its old descendants in
+/// place, unreachable, until the arena is compacted.
+pub(super) struct Arena {
+    nodes: Vec<Node>,
+    /// The children of every node, as ranges of this list.
+    edges: Vec<NodeId>,
+    root: NodeId,
+    /// The number of nodes and edges no longer reachable from the root.
+    garbage: usize,
+    /// The generation of the clean marks on the shells, see
+    /// [`Cache::clean_generation`](crate::metadata::Cache::clean_generation).
+    generation: u64,
+}
+
+struct Node {
+    /// The expression, with `Expression::Nothing` in place of its children.
+    shell: Expression,
+    children: Range<usize>,
+    /// The variants of the expression and its sub-expressions, as a bitmask over
+    /// [`Expression::VARIANT_NAMES`].
+    variants: u64,
+    /// The rule sets known not to apply to the expression or any of its sub-expressions, as of
+    /// `clean_generation`.
+    clean_rule_sets: u64,
+    clean_generation: u64,
+}
+
+impl Arena {
+    pub(super) fn new(expression: Expression) -> Self {
+        Self::with_generation(expression, 0)
+    }
+
+    /// An arena holding `expression`, going by the clean marks made on it in `generation`.
+    pub(super) fn with_generation(expression: Expression, generation: u64) -> Self {
+        let mut arena = Arena {
+            nodes: Vec::new(),
+            edges: Vec::new(),
+            root: 0,
+            garbage: 0,
+            generation,
+        };
+        arena.root = arena.add(expression);
+        arena
+    }
+
+    pub(super) fn root(&self) -> NodeId {
+        self.root
+    }
+
+    /// The expression at `id`, with `Expression::Nothing` in place of its children.
+    pub(super) fn shell(&self, id: NodeId) -> &Expression {
+        &self.nodes[id].shell
+    }
+
+    pub(super) fn shell_mut(&mut self, id: NodeId) -> &mut Expression {
+        &mut self.nodes[id].shell
+    }
+
+    pub(super) fn children(&self, id: NodeId) -> &[NodeId] {
+        &self.edges[self.nodes[id].children.clone()]
+    }
+
+    /// The variants of the expression at `id` and its sub-expressions, as a bitmask over
+    /// [`Expression::VARIANT_NAMES`].
+    pub(super) fn variants(&self, id: NodeId) -> u64 {
+        self.nodes[id].variants
+    }
+
+    /// Returns true if none of the rule sets in the bitmask `rule_sets` apply to the expression at
+    /// `id` or any of its sub-expressions, as of the last call to [`Arena::refresh`] on it in the
+    /// current generation.
+    pub(super) fn is_clean_for(&self, id: NodeId, rule_sets: u64) -> bool {
+        self.clean_rule_sets(id) & rule_sets == rule_sets
+    }
+
+    /// Starts a new generation of clean marks, so that every expression in the arena is dirty,
+    /// without visiting them.
+    pub(super) fn set_generation(&mut self, generation: u64) {
+        self.generation = generation;
+    }
+
+    /// Recomputes the facts held about the sub-expression at `id` from its shell and its
+    /// children. Call this after changing the shell, or the facts held about a child.
+    pub(super) fn refresh(&mut self, id: NodeId) {
+        let node = &self.nodes[id];
+        let mut variants = 1 << node.shell.variant_index();
+        let mut clean_rule_sets = node.shell.clean_rule_sets(self.generation);
+        for &child in &self.edges[node.children.clone()] {
+            variants |= self.nodes[child].variants;
+            clean_rule_sets &= self.clean_rule_sets(child);
+        }
+        let node = &mut self.nodes[id];
+        node.variants = variants;
+        node.clean_rule_sets = clean_rule_sets;
+        node.clean_generation = self.generation;
+    }
+
+    /// Rebuilds the expression at `id`, cloning the shells of it and its sub-expressions.
+    pub(super) fn to_expression(&self, id: NodeId) -> Expression {
+        let mut built = Vec::new();
+        let mut stack = vec![(id, false)];
+        while let Some((id, children_built)) = stack.pop() {
+            let node = &self.nodes[id];
+            if children_built {
+                let mut expression = node.shell.clone();
+                let start = built.len() - node.children.len();
+                expression.restore_children(&mut built, start);
+                built.push(expression);
+            } else {
+                stack.push((id, true));
+                // Pushed in reverse, so the children are built in order
+                for &child in self.edges[node.children.clone()].iter().rev() {
+                    stack.push((child, false));
+                }
+            }
+        }
+        built.pop().unwrap_or(Expression::Nothing)
+    }
+
+    /// The rule sets known not to apply to the expression at `id` or any of its sub-expressions,
+    /// or none if they were worked out in an earlier generation.
+    fn clean_rule_sets(&self, id: NodeId) -> u64 {
+        let node = &self.nodes[id];
+        match node.clean_generation == self.generation {
+            true => node.clean_rule_sets,
+            false => 0,
+        }
+    }
+
+    /// Converts the arena back into an expression, moving the shells rather than cloning them.
+    /// The sub-expressions known to be clean throughout are marked as such.
+    pub(super) fn into_expression(mut self) -> Expression {
+        let mut built = Vec::new();
+        let mut stack = vec![(self.root, false)];
+        while let Some((id, children_built)) = stack.pop() {
+            if children_built {
+                let node = &mut self.nodes[id];
+                let mut expression = std::mem::replace(&mut node.shell, Expression::Nothing);
+                let start = built.len() - node.children.len();
+                expression.restore_children(&mut built, start);
+                expression.refresh_subtree_clean_marks(self.generation);
+                built.push(expression);
+            } else {
+                stack.push((id, true));
+                for &child in self.edges[self.nodes[id].children.clone()].iter().rev() {
+                    stack.push((child, false));
+                }
+            }
+        }
+        built.pop().unwrap_or(Expression::Nothing)
+    }
+
+    /// Replaces the expression at `id` with `expression`, then refreshes `ancestors`, which must
+    /// be the ancestors of `id`, innermost last.
+    ///
+    /// The arena is compacted if most of it is no longer reachable, so the ids of any other nodes
+    /// are no longer valid after this.
+    pub(super) fn replace(&mut self, id: NodeId, expression: Expression, ancestors: &[NodeId]) {
+        // The old descendants of the node become unreachable
+        let mut stack = vec![id];
+        while let Some(id) = stack.pop() {
+            let children = self.nodes[id].children.clone();
+            self.garbage += children.len() * 2;
+            stack.extend_from_slice(&self.edges[children]);
+        }
+
+        self.fill(id, expression);
+        for &ancestor in ancestors.iter().rev() {
+            self.refresh(ancestor);
+        }
+
+        if self.garbage > self.nodes.len() + self.edges.len() {
+            let generation = self.generation;
+            let expression = std::mem::replace(self, Arena::new(Expression::Nothing));
+            *self = Arena::with_generation(expression.into_expression(), generation);
+        }
+    }
+
+    /// Adds a top-level constraint, joining it to the root with `And`.
+    ///
+    /// See [`Reduction::apply`](crate::rule_engine::Reduction::apply).
+    pub(super) fn add_top(&mut self, expression: Expression) {
+        let top = self.add(expression);
+        let root = self.root;
+        let old_children = self.nodes[root].children.clone();
+        if let Expression::And(_, children) = &mut self.nodes[root].shell {
+            // Avoid creating a nested conjunction
+            children.push(Expression::Nothing);
+            self.nodes[root].shell.invalidate_annotations();
+            let start = self.edges.len();
+            self.edges.extend_from_within(old_children.clone());
+            self.edges.push(top);
+            self.garbage += old_children.len();
+            self.nodes[root].children = start..self.edges.len();
+            self.refresh(root);
+        } else {
+            let start = self.edges.len();
+            self.edges.extend([root, top]);
+            self.root = self.nodes.len();
+            self.nodes.push(Node {
+                shell: Expression::And(
+                    Metadata::new(),
+                    vec![Expression::Nothing, Expression::Nothing],
+                ),
+                children: start..self.edges.len(),
+                variants: 0,
+                clean_rule_sets: 0,
+                clean_generation: 0,
+            });
+            self.refresh(self.root);
+        }
+    }
+
+    /// Adds `expression` to the arena as a new node, returning its id.
+    fn add(&mut self, expression: Expression) -> NodeId {
+        let id = self.nodes.len();
+        self.nodes.push(Node {
+            shell: Expression::Nothing,
+            children: 0..0,
+            variants: 0,
+            clean_rule_sets: 0,
+            clean_generation: 0,
+        });
+        self.fill(id, expression);
+        id
+    }
+
+    /// Puts `expression` at `id`, adding new nodes for its sub-expressions.
+    fn fill(&mut self, id: NodeId, expression: Expression) {
+        self.nodes[id].shell = expression;
+        // Nodes are split into shell and children breadth-first, so each node is filled in
+        // before its children, and refreshed after them
+        let mut filled = vec![id];
+        let mut next = 0;
+        let mut children = Vec::new();
+        while let Some(&id) = filled.get(next) {
+            next += 1;
+            self.nodes[id].shell.take_children(&mut children);
+            let start = self.edges.len();
+            for child in children.drain(..) {
+                let child_id = self.nodes.len();
+                self.nodes.push(Node {
+                    shell: child,
+                    children: 0..0,
+                    variants: 0,
+                    clean_rule_sets: 0,
+                    clean_generation: 0,
+                });
+                self.edges.push(child_id);
+                filled.push(child_id);
+            }
+            self.nodes[id].children = start..self.edges.len();
+        }
+        for &id in filled.iter().rev() {
+            self.refresh(id);
+        }
+    }
+}
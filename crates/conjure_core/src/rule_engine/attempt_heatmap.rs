@@ -0,0 +1,69 @@
+use std::collections::HashMap;
+
+/// How many rules were tried at each position in the constraints, returned in
+/// [`RewriteOutcome::heatmap`](crate::rule_engine::RewriteOutcome::heatmap) if
+/// [`RewriteOptions::attempt_heatmap`](crate::rule_engine::RewriteOptions::attempt_heatmap) is
+/// set.
+///
+/// Positions are paths of child indices from the root of the constraints, as they were when the
+/// rules were tried. Rewrites that change the shape of the tree, such as adding top-level
+/// constraints or flattening, can move an expression, in which case the attempts made before the
+/// move are counted at its old position.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AttemptHeatmap {
+    /// The number of rules tried at each path.
+    pub attempts: HashMap<Vec<usize>, usize>,
+}
+
+impl AttemptHeatmap {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// The number of rules tried on the expression at `path`.
+    pub fn at(&self, path: &[usize]) -> usize {
+        self.attempts.get(path).copied().unwrap_or(0)
+    }
+
+    /// The number of rules tried on the expression at `path` and its sub-expressions.
+    pub fn below(&self, path: &[usize]) -> usize {
+        self.attempts
+            .iter()
+            .filter(|(p, _)| p.starts_with(path))
+            .map(|(_, count)| count)
+            .sum()
+    }
+
+    /// The number of rules tried in total.
+    pub fn total(&self) -> usize {
+        self.attempts.values().sum()
+    }
+
+    /// Every path at which rules were tried, with the number tried there, most first. Paths with
+    /// the same number are in order.
+    pub fn hottest(&self) -> Vec<(&[usize], usize)> {
+        let mut paths: Vec<_> = self
+            .attempts
+            .iter()
+            .map(|(path, &count)| (path.as_slice(), count))
+            .collect();
+        paths.sort_by(|(a, m), (b, n)| n.cmp(m).then(a.cmp(b)));
+        paths
+    }
+
+    pub(super) fn record(&mut self, path: &[usize]) {
+        match self.attempts.get_mut(path) {
+            Some(count) => *count += 1,
+            None => {
+                self.attempts.insert(path.to_vec(), 1);
+            }
+        }
+    }
+
+    /// Adds the attempts counted in `other` to this heatmap.
+    pub fn merge(&mut self, other: &AttemptHeatmap) {
+        for (path, count) in &other.attempts {
+            *self.attempts.entry(path.clone()).or_default() += count;
+        }
+    }
+}
@@ -0,0 +1,256 @@
+use std::collections::HashSet;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Instant;
+
+use derivative::Derivative;
+
+use crate::meta::MetaCheckpoint;
+use crate::rule_engine::normal_forms::{candidates, state_hash, Candidate};
+use crate::rule_engine::rewrite::budget_exhausted;
+use crate::rule_engine::{
+    BudgetPolicy, EngineError, RewriteError, RewriteOptions, RewriteOutcome, RewriteStatus, Rule,
+};
+use crate::stats::RewriterStats;
+use crate::Model;
+
+/// Decides whether a model is a dead end, such as one a solver rejects. Returns the reason if it
+/// is. See [`Backtracking`].
+pub type DeadEndCheck = Arc<dyn Fn(&Model) -> Result<(), String> + Send + Sync>;
+
+/// Settings for rewriting with backtracking, set with [`RewriteOptions::backtracking`].
+///
+/// Rewrites are chosen as the rewriter would choose them, but every other rewrite that could have
+/// been made instead is remembered as a choice point. When `dead_end` rejects a model, rewriting
+/// goes back to the most recent choice point with a rewrite left to try, and tries it instead.
+///
+/// Each choice point keeps its model, so going back to it undoes the rewrites made since. Meta
+/// values that are [checkpointed](crate::meta::MetaStore::checkpointed) are rewound as well, see
+/// [`Checkpointable`](crate::meta::Checkpointable).
+///
+/// # Example
+/// ```rust
+/// use conjure_core::rule_engine::{Backtracking, RewriteOptions};
+///
+/// let options = RewriteOptions::new().backtracking(
+///     Backtracking::new(|model| match model.variables.len() {
+///         0 => Ok(()),
+///         _ => Err(String::from("auxiliary variables are not supported")),
+///     })
+///     .max_backtracks(100),
+/// );
+/// ```
+#[derive(Clone, Derivative)]
+#[derivative(Debug)]
+pub struct Backtracking {
+    #[derivative(Debug = "ignore")]
+    pub dead_end: DeadEndCheck,
+    /// Whether to check every model reached, rather than only those no rule applies to, so that
+    /// dead ends are found before rewriting goes further down them.
+    pub check_every_rewrite: bool,
+    /// The maximum number of dead ends to back out of.
+    pub max_backtracks: Option<usize>,
+}
+
+impl Backtracking {
+    /// Back out of the models `dead_end` rejects.
+    pub fn new(dead_end: impl Fn(&Model) -> Result<(), String> + Send + Sync + 'static) -> Self {
+        Self {
+            dead_end: Arc::new(dead_end),
+            check_every_rewrite: false,
+            max_backtracks: None,
+        }
+    }
+
+    /// Check every model reached, not only those no rule applies to.
+    pub fn check_every_rewrite(self, check_every_rewrite: bool) -> Self {
+        Self {
+            check_every_rewrite,
+            ..self
+        }
+    }
+
+    /// Stop after backing out of `max_backtracks` dead ends.
+    pub fn max_backtracks(self, max_backtracks: usize) -> Self {
+        Self {
+            max_backtracks: Some(max_backtracks),
+            ..self
+        }
+    }
+}
+
+/// A model rewriting went through, with the rewrites of it not yet tried, in the order they are
+/// to be tried.
+struct ChoicePoint<'r> {
+    model: Model,
+    /// The checkpointed meta of the model, as it was when its rewrites were found.
+    checkpoint: MetaCheckpoint,
+    untried: std::vec::IntoIter<Candidate<'r>>,
+    /// The length of the trace when the model was reached.
+    trace_len: usize,
+}
+
+/// Rewrites `model`, backing out of dead ends. See [`RewriteOptions::backtracking`].
+pub(super) fn rewrite_backtracking(
+    model: &Model,
+    rules: &[&Rule],
+    backtracking: &Backtracking,
+    options: &RewriteOptions,
+) -> Result<RewriteOutcome, RewriteError> {
+    let start = Instant::now();
+    let mut choice_points: Vec<ChoicePoint> = Vec::new();
+    // Models already reached are either on the way to the current one, or only led to dead ends
+    let mut seen = HashSet::from([state_hash(model)]);
+    let mut trace = options.trace.then(Vec::new);
+    let mut rewrites = 0;
+    let mut dead_ends = 0;
+    let mut last_reason = None;
+    let mut status = RewriteStatus::Fixpoint;
+    let mut error = None;
+
+    // The model most recently reached, and whether it is still to be rewritten
+    let mut latest = model.clone();
+    let mut reached = true;
+    if backtracking.check_every_rewrite {
+        if let Err(reason) = (backtracking.dead_end)(model) {
+            dead_ends += 1;
+            last_reason = Some(reason);
+            reached = false;
+        }
+    }
+    let new_model = loop {
+        if budget_exhausted(options, rewrites, 0)
+            || backtracking
+                .max_backtracks
+                .is_some_and(|max| dead_ends > max)
+        {
+            status = RewriteStatus::BudgetExhausted;
+            break latest;
+        }
+        if options
+            .timeout
+            .is_some_and(|timeout| start.elapsed() >= timeout)
+        {
+            status = RewriteStatus::Timeout;
+            break latest;
+        }
+        if options
+            .cancel
+            .as_ref()
+            .is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+        {
+            status = RewriteStatus::Cancelled;
+            break latest;
+        }
+
+        if std::mem::take(&mut reached) {
+            let found = candidates(&latest, rules);
+            if !found.is_empty() {
+                choice_points.push(ChoicePoint {
+                    checkpoint: latest.meta.checkpoint(),
+                    model: latest.clone(),
+                    untried: found.into_iter(),
+                    trace_len: trace.as_ref().map_or(0, Vec::len),
+                });
+                continue;
+            }
+            // If every rewrite is checked, this model already has been
+            let checked = match backtracking.check_every_rewrite {
+                true => Ok(()),
+                false => (backtracking.dead_end)(&latest),
+            };
+            match checked {
+                Ok(()) => break latest,
+                Err(reason) => {
+                    log::debug!(target: "file", "Backtracking from a dead end: {}", reason);
+                    dead_ends += 1;
+                    last_reason = Some(reason);
+                    continue;
+                }
+            }
+        }
+
+        // Try the next rewrite from the most recent choice point that has one left
+        let Some(choice_point) = choice_points.last_mut() else {
+            error = Some(
+                EngineError::DeadEnd {
+                    dead_ends,
+                    last_reason,
+                }
+                .into(),
+            );
+            break model.clone();
+        };
+        let Some(candidate) = choice_point.untried.next() else {
+            choice_points.pop();
+            continue;
+        };
+        choice_point.model.meta.rewind(&choice_point.checkpoint);
+        let step = trace
+            .is_some()
+            .then(|| candidate.trace_step(&choice_point.model.constraints));
+        let next = candidate.apply(&choice_point.model);
+        if !seen.insert(state_hash(&next)) {
+            continue;
+        }
+        rewrites += 1;
+        if backtracking.check_every_rewrite {
+            if let Err(reason) = (backtracking.dead_end)(&next) {
+                log::debug!(target: "file", "Backtracking from a dead end: {}", reason);
+                dead_ends += 1;
+                last_reason = Some(reason);
+                continue;
+            }
+        }
+        if let (Some(trace), Some(step)) = (&mut trace, step) {
+            trace.truncate(choice_point.trace_len);
+            trace.push(step);
+        }
+        latest = next;
+        reached = true;
+    };
+
+    if let Ok(mut context) = model.context.write() {
+        context.stats.add_rewriter_run(RewriterStats {
+            is_optimization_enabled: None,
+            rewriter_run_time: Some(start.elapsed()),
+            rewriter_rule_application_attempts: None,
+            rewriter_rule_applications: Some(rewrites),
+            rewriter_peak_memory: None,
+        });
+    }
+
+    if status == RewriteStatus::BudgetExhausted {
+        log::warn!(target: "file", "Backtracking rewriter stopped after {} rewrites and {} dead ends", rewrites, dead_ends);
+        if options.on_budget_exhausted == BudgetPolicy::Error {
+            error = Some(
+                EngineError::BudgetExhausted {
+                    rewrites,
+                    attempts: 0,
+                }
+                .into(),
+            );
+        }
+    }
+    match error {
+        Some(error) if !options.partial_on_error => return Err(error),
+        Some(_) => status = RewriteStatus::Error,
+        None => {}
+    }
+    Ok(RewriteOutcome {
+        model: new_model,
+        status,
+        slow_rules: Vec::new(),
+        discarded_effects: Vec::new(),
+        quarantined: Vec::new(),
+        error,
+        perf: None,
+        profile: None,
+        heatmap: None,
+        trace,
+        seed: None,
+        ambiguities: Vec::new(),
+        choices: Vec::new(),
+        ignored_options: Vec::new(),
+    })
+}
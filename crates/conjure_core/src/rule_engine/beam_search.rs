@@ -0,0 +1,217 @@
+use std::collections::HashSet;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Instant;
+
+use derivative::Derivative;
+
+use crate::ast::Expression;
+use crate::meta::MetaCheckpoint;
+use crate::rule_engine::normal_forms::{candidates, state_hash};
+use crate::rule_engine::rewrite::budget_exhausted;
+use crate::rule_engine::{
+    BudgetPolicy, CostFunction, EngineError, RewriteError, RewriteOptions, RewriteOutcome,
+    RewriteStatus, Rule, TraceStep,
+};
+use crate::stats::RewriterStats;
+use crate::Model;
+
+/// Settings for rewriting by beam search, set with [`RewriteOptions::beam_search`].
+///
+/// A beam of the `width` cheapest models under `cost` is kept. At each step, every model in the
+/// beam is rewritten in every way any rule can rewrite any expression in its constraints, and the
+/// `width` cheapest of the models made form the next beam. After `steps` steps, or once no model
+/// in the beam can be rewritten, the cheapest model seen is returned.
+///
+/// Meta values that are [checkpointed](crate::meta::MetaStore::checkpointed) are rewound to
+/// those of each model in the beam before it is rewritten, and to those of the model returned,
+/// see [`Checkpointable`](crate::meta::Checkpointable).
+///
+/// # Example
+/// ```rust
+/// use conjure_core::rule_engine::{BeamSearch, RewriteOptions};
+///
+/// let options = RewriteOptions::new().beam_search(
+///     BeamSearch::new(|constraints| constraints.size() as f64)
+///         .width(4)
+///         .steps(20),
+/// );
+/// ```
+#[derive(Clone, Derivative)]
+#[derivative(Debug)]
+pub struct BeamSearch {
+    #[derivative(Debug = "ignore")]
+    pub cost: CostFunction,
+    /// The number of models kept at each step.
+    pub width: usize,
+    /// The number of steps to take.
+    pub steps: usize,
+}
+
+impl BeamSearch {
+    /// Keep the models of least `cost`, 8 at a time, for up to 100 steps.
+    pub fn new(cost: impl Fn(&Expression) -> f64 + Send + Sync + 'static) -> Self {
+        Self {
+            cost: Arc::new(cost),
+            width: 8,
+            steps: 100,
+        }
+    }
+
+    /// Keep `width` models at each step.
+    pub fn width(self, width: usize) -> Self {
+        Self { width, ..self }
+    }
+
+    /// Take up to `steps` steps.
+    pub fn steps(self, steps: usize) -> Self {
+        Self { steps, ..self }
+    }
+}
+
+/// A model in the beam.
+struct Beam {
+    model: Model,
+    /// The checkpointed meta of the model, as it was when the model was reached.
+    checkpoint: MetaCheckpoint,
+    cost: f64,
+    /// The rewrites that reach the model, if a trace is being recorded.
+    trace: Vec<TraceStep>,
+}
+
+/// Rewrites `model` by beam search. See [`RewriteOptions::beam_search`].
+pub(super) fn rewrite_beam_search(
+    model: &Model,
+    rules: &[&Rule],
+    search: &BeamSearch,
+    options: &RewriteOptions,
+) -> Result<RewriteOutcome, RewriteError> {
+    let start = Instant::now();
+    let first = Beam {
+        checkpoint: model.meta.checkpoint(),
+        model: model.clone(),
+        cost: (search.cost)(&model.constraints),
+        trace: Vec::new(),
+    };
+    // The cheapest model seen, as a copy of its beam
+    let mut best = (
+        first.model.clone(),
+        first.cost,
+        Vec::new(),
+        first.checkpoint.clone(),
+    );
+    let mut beam = vec![first];
+    let mut seen = HashSet::from([state_hash(model)]);
+    let mut rewrites = 0;
+    let mut steps = 0;
+    let mut status = RewriteStatus::Fixpoint;
+    while !beam.is_empty() {
+        if steps >= search.steps || budget_exhausted(options, rewrites, 0) {
+            status = RewriteStatus::BudgetExhausted;
+            break;
+        }
+        if options
+            .timeout
+            .is_some_and(|timeout| start.elapsed() >= timeout)
+        {
+            status = RewriteStatus::Timeout;
+            break;
+        }
+        if options
+            .cancel
+            .as_ref()
+            .is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+        {
+            status = RewriteStatus::Cancelled;
+            break;
+        }
+
+        // Models reached from more than one model in the beam are only kept once
+        let mut next = Vec::new();
+        for parent in &mut beam {
+            parent.model.meta.rewind(&parent.checkpoint);
+            for candidate in candidates(&parent.model, rules) {
+                let step = options
+                    .trace
+                    .then(|| candidate.trace_step(&parent.model.constraints));
+                let model = candidate.apply(&parent.model);
+                if !seen.insert(state_hash(&model)) {
+                    continue;
+                }
+                rewrites += 1;
+                let mut trace = Vec::new();
+                if let Some(step) = step {
+                    trace.clone_from(&parent.trace);
+                    trace.push(step);
+                }
+                let cost = (search.cost)(&model.constraints);
+                next.push(Beam {
+                    checkpoint: model.meta.checkpoint(),
+                    model,
+                    cost,
+                    trace,
+                });
+            }
+        }
+        // Sorting is stable, so ties go to the model found first
+        next.sort_by(|a, b| a.cost.total_cmp(&b.cost));
+        next.truncate(search.width);
+        if let Some(cheapest) = next.first().filter(|cheapest| cheapest.cost < best.1) {
+            best = (
+                cheapest.model.clone(),
+                cheapest.cost,
+                cheapest.trace.clone(),
+                cheapest.checkpoint.clone(),
+            );
+        }
+        beam = next;
+        steps += 1;
+    }
+    let (mut new_model, _, trace, checkpoint) = best;
+    new_model.meta.rewind(&checkpoint);
+
+    if let Ok(mut context) = model.context.write() {
+        context.stats.add_rewriter_run(RewriterStats {
+            is_optimization_enabled: None,
+            rewriter_run_time: Some(start.elapsed()),
+            rewriter_rule_application_attempts: None,
+            rewriter_rule_applications: Some(rewrites),
+            rewriter_peak_memory: None,
+        });
+    }
+
+    let mut error = None;
+    if status == RewriteStatus::BudgetExhausted {
+        log::warn!(target: "file", "Beam search stopped after {} steps and {} rewrites", steps, rewrites);
+        if options.on_budget_exhausted == BudgetPolicy::Error {
+            error = Some(
+                EngineError::BudgetExhausted {
+                    rewrites,
+                    attempts: 0,
+                }
+                .into(),
+            );
+        }
+    }
+    match error {
+        Some(error) if !options.partial_on_error => return Err(error),
+        Some(_) => status = RewriteStatus::Error,
+        None => {}
+    }
+    Ok(RewriteOutcome {
+        model: new_model,
+        status,
+        slow_rules: Vec::new(),
+        discarded_effects: Vec::new(),
+        quarantined: Vec::new(),
+        error,
+        perf: None,
+        profile: None,
+        heatmap: None,
+        trace: options.trace.then_some(trace),
+        seed: None,
+        ambiguities: Vec::new(),
+        choices: Vec::new(),
+        ignored_options: Vec::new(),
+    })
+}
@@ -0,0 +1,252 @@
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Instant;
+
+use derivative::Derivative;
+
+use crate::ast::Expression;
+use crate::rule_engine::normal_forms::{candidates, state_hash};
+use crate::rule_engine::rewrite::budget_exhausted;
+use crate::rule_engine::{
+    BudgetPolicy, CostFunction, EngineError, RewriteError, RewriteOptions, RewriteOutcome,
+    RewriteStatus, Rule, TraceStep,
+};
+use crate::stats::RewriterStats;
+use crate::Model;
+
+/// Settings for rewriting by best-first search, set with [`RewriteOptions::best_first`].
+///
+/// Models are explored in order of the cost of the rewrites that reach them, plus `heuristic`'s
+/// estimate of the cost of the rewrites left to reach a normal form, as in A* search. The first
+/// normal form explored is returned. If the heuristic never overestimates, no normal form can be
+/// reached by rewrites that cost less in total.
+///
+/// # Example
+/// ```rust
+/// use conjure_core::rule_engine::{BestFirst, RewriteOptions};
+///
+/// // Every rewrite costs 1, so the normal form reached by the fewest rewrites is returned
+/// let options = RewriteOptions::new()
+///     .best_first(BestFirst::new(|_| 0.0).rule_cost("expensive_rule", 10.0))
+///     .max_rewrites(10000);
+/// ```
+#[derive(Clone, Derivative)]
+#[derivative(Debug)]
+pub struct BestFirst {
+    /// Estimates the cost of the rewrites left to reach a normal form from the constraints.
+    #[derivative(Debug = "ignore")]
+    pub heuristic: CostFunction,
+    /// The cost of a rewrite by each rule, by name. Rewrites by rules not given a cost cost 1.
+    pub rule_costs: HashMap<String, f64>,
+}
+
+impl BestFirst {
+    /// Search with `heuristic`, where every rewrite costs 1.
+    pub fn new(heuristic: impl Fn(&Expression) -> f64 + Send + Sync + 'static) -> Self {
+        Self {
+            heuristic: Arc::new(heuristic),
+            rule_costs: HashMap::new(),
+        }
+    }
+
+    /// Count each rewrite by the rule named `rule` as costing `cost`, rather than 1. Costs below 0
+    /// are treated as 0.
+    pub fn rule_cost(mut self, rule: &str, cost: f64) -> Self {
+        self.rule_costs.insert(rule.to_string(), cost);
+        self
+    }
+
+    fn cost_of(&self, rule: &str) -> f64 {
+        self.rule_costs.get(rule).copied().unwrap_or(1.0).max(0.0)
+    }
+}
+
+/// A model reached by the search, and not yet explored.
+struct Open {
+    model: Model,
+    /// The cost of the rewrites that reach the model.
+    cost: f64,
+    /// The heuristic's estimate of the cost left.
+    estimate: f64,
+    /// The order the model was reached in, so that ties go to the model reached first.
+    order: usize,
+    /// The rewrites that reach the model, if a trace is being recorded.
+    trace: Vec<TraceStep>,
+}
+
+impl Open {
+    fn priority(&self) -> f64 {
+        self.cost + self.estimate
+    }
+}
+
+impl PartialEq for Open {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == CmpOrdering::Equal
+    }
+}
+
+impl Eq for Open {}
+
+impl PartialOrd for Open {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Open {
+    // Reversed, so that the heap gives the model of least priority, reached first
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        other
+            .priority()
+            .total_cmp(&self.priority())
+            .then(other.order.cmp(&self.order))
+    }
+}
+
+/// Rewrites `model` by best-first search. See [`RewriteOptions::best_first`].
+pub(super) fn rewrite_best_first(
+    model: &Model,
+    rules: &[&Rule],
+    search: &BestFirst,
+    options: &RewriteOptions,
+) -> Result<RewriteOutcome, RewriteError> {
+    let start = Instant::now();
+    let mut open = BinaryHeap::from([Open {
+        model: model.clone(),
+        cost: 0.0,
+        estimate: (search.heuristic)(&model.constraints),
+        order: 0,
+        trace: Vec::new(),
+    }]);
+    // The least cost each model has been reached at, and the models already explored
+    let mut costs = HashMap::from([(state_hash(model), 0.0)]);
+    let mut closed = HashSet::new();
+    // The model explored with the least estimate, returned if no normal form is reached
+    let mut closest: Option<(Model, f64, Vec<TraceStep>)> = None;
+    let mut rewrites = 0;
+    let mut explored = 0;
+    let mut status = RewriteStatus::Fixpoint;
+    let mut error = None;
+    let (new_model, trace) = loop {
+        if budget_exhausted(options, rewrites, 0) {
+            status = RewriteStatus::BudgetExhausted;
+        } else if options
+            .timeout
+            .is_some_and(|timeout| start.elapsed() >= timeout)
+        {
+            status = RewriteStatus::Timeout;
+        } else if options
+            .cancel
+            .as_ref()
+            .is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+        {
+            status = RewriteStatus::Cancelled;
+        }
+        if status != RewriteStatus::Fixpoint {
+            break closest.map_or((model.clone(), Vec::new()), |(model, _, trace)| {
+                (model, trace)
+            });
+        }
+
+        let Some(node) = open.pop() else {
+            // Every model reached was explored, and none was a normal form
+            error = Some(
+                EngineError::DeadEnd {
+                    dead_ends: 0,
+                    last_reason: None,
+                }
+                .into(),
+            );
+            break (model.clone(), Vec::new());
+        };
+        // A model may be reached again at less cost before it is explored, so it can be queued
+        // more than once
+        if !closed.insert(state_hash(&node.model)) {
+            continue;
+        }
+        explored += 1;
+        let found = candidates(&node.model, rules);
+        if found.is_empty() {
+            break (node.model, node.trace);
+        }
+        if closest
+            .as_ref()
+            .is_none_or(|(_, estimate, _)| node.estimate < *estimate)
+        {
+            closest = Some((node.model.clone(), node.estimate, node.trace.clone()));
+        }
+
+        for candidate in found {
+            let cost = node.cost + search.cost_of(candidate.rule.name);
+            let step = options
+                .trace
+                .then(|| candidate.trace_step(&node.model.constraints));
+            let next = candidate.apply(&node.model);
+            let hash = state_hash(&next);
+            if closed.contains(&hash) || costs.get(&hash).is_some_and(|&known| known <= cost) {
+                continue;
+            }
+            costs.insert(hash, cost);
+            rewrites += 1;
+            let mut trace = Vec::new();
+            if let Some(step) = step {
+                trace.clone_from(&node.trace);
+                trace.push(step);
+            }
+            open.push(Open {
+                estimate: (search.heuristic)(&next.constraints),
+                model: next,
+                cost,
+                order: rewrites,
+                trace,
+            });
+        }
+    };
+
+    if let Ok(mut context) = model.context.write() {
+        context.stats.add_rewriter_run(RewriterStats {
+            is_optimization_enabled: None,
+            rewriter_run_time: Some(start.elapsed()),
+            rewriter_rule_application_attempts: None,
+            rewriter_rule_applications: Some(rewrites),
+            rewriter_peak_memory: None,
+        });
+    }
+
+    if status == RewriteStatus::BudgetExhausted {
+        log::warn!(target: "file", "Best-first search stopped after exploring {} models and making {} rewrites", explored, rewrites);
+        if options.on_budget_exhausted == BudgetPolicy::Error {
+            error = Some(
+                EngineError::BudgetExhausted {
+                    rewrites,
+                    attempts: 0,
+                }
+                .into(),
+            );
+        }
+    }
+    match error {
+        Some(error) if !options.partial_on_error => return Err(error),
+        Some(_) => status = RewriteStatus::Error,
+        None => {}
+    }
+    Ok(RewriteOutcome {
+        model: new_model,
+        status,
+        slow_rules: Vec::new(),
+        discarded_effects: Vec::new(),
+        quarantined: Vec::new(),
+        error,
+        perf: None,
+        profile: None,
+        heatmap: None,
+        trace: options.trace.then_some(trace),
+        seed: None,
+        ambiguities: Vec::new(),
+        choices: Vec::new(),
+        ignored_options: Vec::new(),
+    })
+}
@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+
+use crate::rule_engine::normal_forms::{candidates, state_hash};
+use crate::rule_engine::random::SplitMix64;
+use crate::rule_engine::resolve_rules::{get_rule_priorities, get_rules_vec};
+use crate::rule_engine::{RewriteError, Rule, RuleSet, TraceStep};
+use crate::Model;
+
+/// How [`check_confluence`] reduces each input.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConfluenceCheck {
+    /// The number of rewrite orders to reduce each input under.
+    pub orders: usize,
+    /// The seed the orders are chosen from, so that a check can be run again exactly.
+    pub seed: u64,
+    /// The maximum number of rewrites in each order. Orders that reach it are left out of the
+    /// comparison, and counted in [`ConfluenceReport::unfinished`].
+    pub max_steps: Option<usize>,
+}
+
+impl Default for ConfluenceCheck {
+    fn default() -> Self {
+        Self {
+            orders: 16,
+            seed: 0,
+            max_steps: Some(1000),
+        }
+    }
+}
+
+impl ConfluenceCheck {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Reduce each input under `orders` rewrite orders.
+    pub fn orders(self, orders: usize) -> Self {
+        Self { orders, ..self }
+    }
+
+    /// Choose the rewrite orders from `seed`.
+    pub fn seed(self, seed: u64) -> Self {
+        Self { seed, ..self }
+    }
+
+    /// Stop each order after `max_steps` rewrites.
+    pub fn max_steps(self, max_steps: usize) -> Self {
+        Self {
+            max_steps: Some(max_steps),
+            ..self
+        }
+    }
+}
+
+/// The result of [`check_confluence`].
+#[derive(Clone, Debug)]
+pub struct ConfluenceReport {
+    /// The number of inputs checked.
+    pub inputs: usize,
+    /// The number of orders that reached a normal form, over every input.
+    pub finished: usize,
+    /// The number of orders stopped by [`ConfluenceCheck::max_steps`] before reaching a normal
+    /// form, over every input.
+    pub unfinished: usize,
+    /// The inputs with more than one normal form, in the order they were checked.
+    pub non_confluent: Vec<NonConfluentInput>,
+}
+
+impl ConfluenceReport {
+    /// Whether every input reached the same normal form under every order that finished.
+    pub fn is_confluent(&self) -> bool {
+        self.non_confluent.is_empty()
+    }
+}
+
+/// An input that reached different normal forms under different rewrite orders.
+#[derive(Clone, Debug)]
+pub struct NonConfluentInput {
+    pub input: Model,
+    /// One run for each distinct normal form, in the order they were found.
+    pub runs: Vec<ConfluenceRun>,
+}
+
+/// A reduction of an input under one rewrite order.
+#[derive(Clone, Debug)]
+pub struct ConfluenceRun {
+    /// The seed the order was chosen from.
+    pub seed: u64,
+    /// The rewrites made, in order. Rules are not timed, so every step has an `elapsed` of zero.
+    pub trace: Vec<TraceStep>,
+    pub normal_form: Model,
+}
+
+/// Reduces each model in `inputs` under many rewrite orders, and reports the inputs that reach
+/// more than one normal form, which show that the rules in `rule_sets` are not confluent.
+///
+/// Each order is a random walk: from each model, one rewrite is chosen at random from every
+/// rewrite any rule makes of any expression in the constraints, regardless of priority, until no
+/// rule applies. Unlike [`reduce_all_normal_forms`](crate::rule_engine::reduce_all_normal_forms),
+/// this does not explore every order, so it scales to many inputs, such as small trees made by a
+/// generator, but can miss orders that lead elsewhere. Normal forms are told apart by their
+/// constraints and the names of their symbols.
+///
+/// The trace of each run can be printed with
+/// [`viz::trace_to_text`](crate::viz::trace_to_text), starting from the constraints of the input,
+/// or checked with [`replay`](crate::rule_engine::replay).
+///
+/// Rules that return an error, or that return the expression unchanged, are treated as not
+/// applying. Panics in rules are not caught.
+///
+/// # Returns
+/// - Which inputs reached more than one normal form, with a run reaching each.
+/// - A `RewriteError` if the rule sets could not be resolved.
+pub fn check_confluence<'a>(
+    rule_sets: &Vec<&'a RuleSet<'a>>,
+    inputs: impl IntoIterator<Item = Model>,
+    check: &ConfluenceCheck,
+) -> Result<ConfluenceReport, RewriteError> {
+    let rule_priorities = get_rule_priorities(rule_sets)?;
+    let rules = get_rules_vec(&rule_priorities);
+
+    let mut report = ConfluenceReport {
+        inputs: 0,
+        finished: 0,
+        unfinished: 0,
+        non_confluent: Vec::new(),
+    };
+    for input in inputs {
+        let mut runs: Vec<ConfluenceRun> = Vec::new();
+        let mut normal_forms = HashMap::new();
+        for order in 0..check.orders {
+            let seed = check
+                .seed
+                .wrapping_add((report.inputs * check.orders + order) as u64);
+            let Some(run) = random_walk(&input, &rules, seed, check.max_steps) else {
+                report.unfinished += 1;
+                continue;
+            };
+            report.finished += 1;
+            normal_forms
+                .entry(state_hash(&run.normal_form))
+                .or_insert_with(|| runs.push(run));
+        }
+        if runs.len() > 1 {
+            report.non_confluent.push(NonConfluentInput { input, runs });
+        }
+        report.inputs += 1;
+    }
+    Ok(report)
+}
+
+/// Reduces `model` by rewrites chosen at random from `seed`.
+///
+/// # Returns
+/// None if `max_steps` rewrites were made without reaching a normal form.
+fn random_walk(
+    model: &Model,
+    rules: &[&Rule],
+    seed: u64,
+    max_steps: Option<usize>,
+) -> Option<ConfluenceRun> {
+    let mut random = SplitMix64::new(seed);
+    let mut model = model.clone();
+    let mut trace = Vec::new();
+    loop {
+        let mut candidates = candidates(&model, rules);
+        if candidates.is_empty() {
+            return Some(ConfluenceRun {
+                seed,
+                trace,
+                normal_form: model,
+            });
+        }
+        if max_steps.is_some_and(|max| trace.len() >= max) {
+            return None;
+        }
+
+        let chosen = candidates.swap_remove(random.below(candidates.len()));
+        trace.push(chosen.trace_step(&model.constraints));
+        model = chosen.apply(&model);
+    }
+}
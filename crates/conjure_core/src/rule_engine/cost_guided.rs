@@ -0,0 +1,338 @@
+use std::collections::HashSet;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Instant;
+
+use derivative::Derivative;
+
+use crate::ast::Expression;
+use crate::rule_engine::normal_forms::{candidates, state_hash};
+use crate::rule_engine::random::SplitMix64;
+use crate::rule_engine::rewrite::budget_exhausted;
+use crate::rule_engine::{
+    BudgetPolicy, EngineError, RewriteError, RewriteOptions, RewriteOutcome, RewriteStatus, Rule,
+};
+use crate::stats::RewriterStats;
+use crate::Model;
+
+/// The cost of the constraints of a model. See [`CostGuided`].
+pub type CostFunction = Arc<dyn Fn(&Expression) -> f64 + Send + Sync>;
+
+/// Settings for rewriting by cost, set with [`RewriteOptions::cost_guided`].
+///
+/// At each step, every rewrite any rule makes of any expression in the constraints is found, and
+/// the one that leaves the constraints cheapest under `cost` is applied. Rewriting stops at a
+/// model that no rewrite makes cheaper, which need not be a normal form.
+///
+/// With [`annealing`](Self::annealing), rewrites are instead chosen at random, and ones that make
+/// the constraints more costly are sometimes applied, so that rewriting can escape a model that
+/// no single rewrite makes cheaper.
+///
+/// With [`lookahead`](Self::lookahead), rewrites are instead judged by the cheapest constraints
+/// they lead to within a number of further rewrites, so that a rewrite that looks costly can be
+/// chosen for the simplifications it opens up.
+///
+/// # Example
+/// ```rust
+/// use conjure_core::rule_engine::{CostGuided, RewriteOptions};
+///
+/// let options = RewriteOptions::new()
+///     .cost_guided(CostGuided::new(|constraints| constraints.size() as f64));
+/// ```
+#[derive(Clone, Derivative)]
+#[derivative(Debug)]
+pub struct CostGuided {
+    #[derivative(Debug = "ignore")]
+    pub cost: CostFunction,
+    /// Whether to apply the cheapest rewrite even if it makes the constraints more costly, so that
+    /// rewriting can climb out of a local minimum.
+    pub allow_increase: bool,
+    /// How to choose rewrites by simulated annealing, rather than taking the cheapest, if set.
+    pub annealing: Option<Annealing>,
+    /// The number of further rewrites to look through when judging a rewrite, or 0 to judge it
+    /// only by the constraints it leaves.
+    pub lookahead: usize,
+}
+
+impl CostGuided {
+    /// Choose rewrites by the `cost` of the constraints they leave.
+    pub fn new(cost: impl Fn(&Expression) -> f64 + Send + Sync + 'static) -> Self {
+        Self {
+            cost: Arc::new(cost),
+            allow_increase: false,
+            annealing: None,
+            lookahead: 0,
+        }
+    }
+
+    /// Apply the cheapest rewrite even if it makes the constraints more costly. Models already
+    /// seen are never returned to, and the cheapest model seen is returned.
+    pub fn allow_increase(self, allow_increase: bool) -> Self {
+        Self {
+            allow_increase,
+            ..self
+        }
+    }
+
+    /// Choose rewrites by simulated annealing, as set out in `annealing`. The cheapest model seen
+    /// is returned.
+    pub fn annealing(self, annealing: Annealing) -> Self {
+        Self {
+            annealing: Some(annealing),
+            ..self
+        }
+    }
+
+    /// Judge each rewrite by the cheapest constraints reached from it by up to `steps` further
+    /// rewrites, and apply the one with the cheapest outlook, even if it makes the constraints
+    /// more costly for now. Rewriting stops once no rewrite has an outlook cheaper than the
+    /// constraints as they are, and the cheapest model seen is returned.
+    ///
+    /// Every way of making the further rewrites is tried, so the work grows exponentially with
+    /// `steps`. The rewrites looked through are not applied, and do not count towards
+    /// `max_rewrites`. Not used with [`annealing`](Self::annealing).
+    pub fn lookahead(self, steps: usize) -> Self {
+        Self {
+            lookahead: steps,
+            ..self
+        }
+    }
+}
+
+/// A schedule for simulated annealing, set with [`CostGuided::annealing`].
+///
+/// At each step, a rewrite is chosen at random from every rewrite any rule makes of any expression
+/// in the constraints. It is applied if it makes the constraints no more costly, and otherwise with
+/// a chance of `e^(-increase / temperature)`. The temperature starts at `temperature`, and is
+/// multiplied by `cooling` after every step, so that rewrites that make the constraints more costly
+/// grow less likely. Rewriting stops once the temperature falls below `min_temperature`.
+///
+/// # Example
+/// ```rust
+/// use conjure_core::rule_engine::{Annealing, CostGuided, RewriteOptions};
+///
+/// let options = RewriteOptions::new().cost_guided(
+///     CostGuided::new(|constraints| constraints.size() as f64)
+///         .annealing(Annealing::new(42).temperature(10.0).cooling(0.9)),
+/// );
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct Annealing {
+    /// The seed rewrites are chosen from, and chosen to be applied from.
+    pub seed: u64,
+    /// The temperature to start at.
+    pub temperature: f64,
+    /// The factor the temperature is multiplied by after every step. Must be less than 1.
+    pub cooling: f64,
+    /// The temperature to stop at.
+    pub min_temperature: f64,
+}
+
+impl Annealing {
+    /// Start at a temperature of 1, and cool by 5% every step, down to 0.001.
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            temperature: 1.0,
+            cooling: 0.95,
+            min_temperature: 0.001,
+        }
+    }
+
+    /// Start at `temperature`.
+    pub fn temperature(self, temperature: f64) -> Self {
+        Self {
+            temperature,
+            ..self
+        }
+    }
+
+    /// Multiply the temperature by `cooling` after every step.
+    pub fn cooling(self, cooling: f64) -> Self {
+        Self { cooling, ..self }
+    }
+
+    /// Stop once the temperature falls below `min_temperature`.
+    pub fn min_temperature(self, min_temperature: f64) -> Self {
+        Self {
+            min_temperature,
+            ..self
+        }
+    }
+}
+
+/// Rewrites `model` by the cheapest rewrite at each step. See [`RewriteOptions::cost_guided`].
+pub(super) fn rewrite_cost_guided(
+    model: &Model,
+    rules: &[&Rule],
+    guide: &CostGuided,
+    options: &RewriteOptions,
+) -> Result<RewriteOutcome, RewriteError> {
+    let start = Instant::now();
+    let mut current = model.clone();
+    let mut current_cost = (guide.cost)(&current.constraints);
+    // The cheapest model seen, its cost, and the length of the trace that reaches it
+    let mut best = (current.clone(), current_cost, 0);
+    let mut seen = HashSet::from([state_hash(&current)]);
+    // The random numbers and temperature to anneal with, if annealing
+    let mut annealing = guide.annealing.as_ref().map(|annealing| {
+        let random = SplitMix64::new(annealing.seed);
+        (annealing, random, annealing.temperature)
+    });
+    let climbs = guide.allow_increase || annealing.is_some() || guide.lookahead > 0;
+    let mut trace = options.trace.then(Vec::new);
+    let mut rewrites = 0;
+    let mut status = RewriteStatus::Fixpoint;
+    loop {
+        if budget_exhausted(options, rewrites, 0) {
+            status = RewriteStatus::BudgetExhausted;
+            break;
+        }
+        if options
+            .timeout
+            .is_some_and(|timeout| start.elapsed() >= timeout)
+        {
+            status = RewriteStatus::Timeout;
+            break;
+        }
+        if options
+            .cancel
+            .as_ref()
+            .is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+        {
+            status = RewriteStatus::Cancelled;
+            break;
+        }
+
+        let (cost, next, step) = match &mut annealing {
+            Some((schedule, random, temperature)) => {
+                if *temperature < schedule.min_temperature {
+                    break;
+                }
+                let mut found = candidates(&current, rules);
+                if found.is_empty() {
+                    break;
+                }
+                let candidate = found.swap_remove(random.below(found.len()));
+                let step = trace
+                    .is_some()
+                    .then(|| candidate.trace_step(&current.constraints));
+                let next = candidate.apply(&current);
+                let cost = (guide.cost)(&next.constraints);
+                let accepted = cost <= current_cost
+                    || random.unit() < (-(cost - current_cost) / *temperature).exp();
+                *temperature *= schedule.cooling;
+                if !accepted {
+                    continue;
+                }
+                (cost, next, step)
+            }
+            None => {
+                // Ties go to the first rewrite found, by expression in pre-order, then by priority
+                let mut cheapest = None;
+                for candidate in candidates(&current, rules) {
+                    let step = trace
+                        .is_some()
+                        .then(|| candidate.trace_step(&current.constraints));
+                    let next = candidate.apply(&current);
+                    if seen.contains(&state_hash(&next)) {
+                        continue;
+                    }
+                    let cost = (guide.cost)(&next.constraints);
+                    let outlook = outlook(&next, cost, rules, guide, guide.lookahead);
+                    if cheapest
+                        .as_ref()
+                        .is_none_or(|(cheapest, _, _, _)| outlook < *cheapest)
+                    {
+                        cheapest = Some((outlook, cost, next, step));
+                    }
+                }
+                let Some((outlook, cost, next, step)) = cheapest else {
+                    break;
+                };
+                if outlook > current_cost && !guide.allow_increase {
+                    break;
+                }
+                seen.insert(state_hash(&next));
+                (cost, next, step)
+            }
+        };
+
+        if let (Some(trace), Some(step)) = (&mut trace, step) {
+            trace.push(step);
+        }
+        rewrites += 1;
+        current = next;
+        current_cost = cost;
+        // Without increases, the cost never goes up, so the latest model is among the cheapest
+        if current_cost < best.1 || !climbs {
+            best = (current.clone(), current_cost, rewrites);
+        }
+    }
+
+    // Only the rewrites that reach the model returned are kept in the trace
+    let (new_model, _, best_rewrites) = best;
+    if let Some(trace) = &mut trace {
+        trace.truncate(best_rewrites);
+    }
+
+    if let Ok(mut context) = model.context.write() {
+        context.stats.add_rewriter_run(RewriterStats {
+            is_optimization_enabled: None,
+            rewriter_run_time: Some(start.elapsed()),
+            rewriter_rule_application_attempts: None,
+            rewriter_rule_applications: Some(rewrites),
+            rewriter_peak_memory: None,
+        });
+    }
+
+    let mut error = None;
+    if status == RewriteStatus::BudgetExhausted {
+        log::warn!(target: "file", "Cost-guided rewriting stopped after {} rewrites", rewrites);
+        if options.on_budget_exhausted == BudgetPolicy::Error {
+            error = Some(
+                EngineError::BudgetExhausted {
+                    rewrites,
+                    attempts: 0,
+                }
+                .into(),
+            );
+        }
+    }
+    match error {
+        Some(error) if !options.partial_on_error => return Err(error),
+        Some(_) => status = RewriteStatus::Error,
+        None => {}
+    }
+    Ok(RewriteOutcome {
+        model: new_model,
+        status,
+        slow_rules: Vec::new(),
+        discarded_effects: Vec::new(),
+        quarantined: Vec::new(),
+        error,
+        perf: None,
+        profile: None,
+        heatmap: None,
+        trace,
+        seed: guide.annealing.as_ref().map(|annealing| annealing.seed),
+        ambiguities: Vec::new(),
+        choices: Vec::new(),
+        ignored_options: Vec::new(),
+    })
+}
+
+/// The cost of the cheapest constraints reached from `model`, whose constraints cost `cost`, by up
+/// to `steps` rewrites. See [`CostGuided::lookahead`].
+fn outlook(model: &Model, cost: f64, rules: &[&Rule], guide: &CostGuided, steps: usize) -> f64 {
+    if steps == 0 {
+        return cost;
+    }
+    candidates(model, rules)
+        .into_iter()
+        .fold(cost, |cheapest, candidate| {
+            let next = candidate.apply(model);
+            let next_cost = (guide.cost)(&next.constraints);
+            cheapest.min(outlook(&next, next_cost, rules, guide, steps - 1))
+        })
+}
@@ -0,0 +1,146 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Display;
+use std::sync::Arc;
+
+/// Called when the rewriter appears to be diverging. Returns whether to keep rewriting.
+pub type DivergenceCallback = Arc<dyn Fn(&DivergenceWarning) -> DivergenceAction + Send + Sync>;
+
+/// Heuristics used to warn when rewriting appears not to terminate.
+///
+/// The rewriter looks at a sliding window of the most recent rewrites. After a warning is raised,
+/// the window is cleared, so a diverging run raises at most one warning per `window` rewrites.
+#[derive(Clone, Debug)]
+pub struct DivergenceMonitor {
+    /// The number of recent rewrites to look at.
+    pub window: usize,
+    /// Warn if the constraints grew by at least this factor over the window, without ever
+    /// shrinking.
+    pub max_growth: f64,
+    /// Warn if a single rule made at least this fraction of the rewrites in the window.
+    pub max_rule_share: f64,
+}
+
+impl Default for DivergenceMonitor {
+    fn default() -> Self {
+        Self {
+            window: 100,
+            max_growth: 2.0,
+            max_rule_share: 0.9,
+        }
+    }
+}
+
+/// Raised by a [`DivergenceMonitor`] when rewriting appears not to terminate.
+#[derive(Clone, Debug)]
+pub struct DivergenceWarning {
+    /// The number of rewrites applied so far.
+    pub rewrites: usize,
+    pub reason: DivergenceReason,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DivergenceReason {
+    /// The constraints have grown steadily, from `from` expressions to `to` expressions.
+    Growing { from: usize, to: usize },
+    /// The same rule made `count` of the recent rewrites.
+    RepeatedRule { rule: String, count: usize },
+}
+
+/// What the rewriter should do after a [`DivergenceWarning`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DivergenceAction {
+    /// Keep rewriting.
+    #[default]
+    Continue,
+    /// Stop rewriting, with the status [`RewriteStatus::Cancelled`](crate::rule_engine::RewriteStatus::Cancelled).
+    Stop,
+}
+
+impl Display for DivergenceWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.reason {
+            DivergenceReason::Growing { from, to } => write!(
+                f,
+                "Rewriting may not terminate: constraints grew from {} to {} expressions (after {} rewrites)",
+                from, to, self.rewrites
+            ),
+            DivergenceReason::RepeatedRule { rule, count } => write!(
+                f,
+                "Rewriting may not terminate: rule {} made {} of the last rewrites (after {} rewrites)",
+                rule, count, self.rewrites
+            ),
+        }
+    }
+}
+
+/// The recent history used by a [`DivergenceMonitor`].
+pub(super) struct DivergenceTracker<'a> {
+    monitor: &'a DivergenceMonitor,
+    /// The size of the constraints and the rule applied, for each recent rewrite.
+    recent: VecDeque<(usize, &'a str)>,
+}
+
+impl<'a> DivergenceTracker<'a> {
+    pub(super) fn new(monitor: &'a DivergenceMonitor) -> Self {
+        Self {
+            monitor,
+            recent: VecDeque::new(),
+        }
+    }
+
+    /// Records a rewrite, and returns a warning if the recent rewrites look divergent.
+    pub(super) fn record(
+        &mut self,
+        rewrites: usize,
+        size: usize,
+        rule: &'a str,
+    ) -> Option<DivergenceWarning> {
+        self.recent.push_back((size, rule));
+        if self.recent.len() > self.monitor.window {
+            self.recent.pop_front();
+        }
+        if self.recent.len() < self.monitor.window.max(2) {
+            return None;
+        }
+
+        let reason = self.check_growth().or_else(|| self.check_repetition())?;
+        self.recent.clear();
+        Some(DivergenceWarning { rewrites, reason })
+    }
+
+    fn check_growth(&self) -> Option<DivergenceReason> {
+        let (from, _) = *self.recent.front()?;
+        let (to, _) = *self.recent.back()?;
+        let monotonic = self
+            .recent
+            .iter()
+            .zip(self.recent.iter().skip(1))
+            .all(|((a, _), (b, _))| a <= b);
+
+        if monotonic && to as f64 >= from as f64 * self.monitor.max_growth {
+            Some(DivergenceReason::Growing { from, to })
+        } else {
+            None
+        }
+    }
+
+    fn check_repetition(&self) -> Option<DivergenceReason> {
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for (_, rule) in &self.recent {
+            *counts.entry(rule).or_insert(0) += 1;
+        }
+        // Break ties by name, so that the reported rule does not depend on hash order
+        let (rule, count) = counts
+            .into_iter()
+            .max_by(|(a, m), (b, n)| m.cmp(n).then(b.cmp(a)))?;
+
+        if count as f64 >= self.recent.len() as f64 * self.monitor.max_rule_share {
+            Some(DivergenceReason::RepeatedRule {
+                rule: rule.to_string(),
+                count,
+            })
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+
+use uniplate::uniplate::Uniplate;
+
+use crate::ast::Expression;
+
+/// The index of an equivalence class in an [`EGraph`].
+pub(super) type ClassId = usize;
+
+/// An expression with `Expression::Nothing` in place of its children, and the classes its
+/// children may be taken from.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(super) struct ENode {
+    pub shell: Expression,
+    pub children: Vec<ClassId>,
+}
+
+/// Expressions grouped into classes of expressions known to be equal, such that each class holds
+/// every expression made by taking the children of one of its nodes from the classes they point
+/// to.
+///
+/// Adding an expression, as with [`ExpressionInterner`](crate::ast::ExpressionInterner), stores
+/// each distinct sub-expression once. Merging two classes never removes an expression, so that
+/// any of the expressions a class was ever known to equal can be taken from it.
+#[derive(Debug, Default)]
+pub(super) struct EGraph {
+    /// The class each class was merged into, or itself if it is canonical.
+    parents: Vec<ClassId>,
+    /// The nodes of each canonical class. Empty for classes merged into another.
+    nodes: Vec<Vec<ENode>>,
+    /// The class of each node, with its children canonical as of the last rebuild.
+    memo: HashMap<ENode, ClassId>,
+    /// Whether classes have been merged since the last rebuild.
+    dirty: bool,
+}
+
+impl EGraph {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// The canonical class of `class`.
+    pub fn find(&self, mut class: ClassId) -> ClassId {
+        while self.parents[class] != class {
+            class = self.parents[class];
+        }
+        class
+    }
+
+    /// Adds `expression` and its sub-expressions, and returns the class of `expression`.
+    pub fn add(&mut self, expression: &Expression) -> ClassId {
+        let children = expression.children();
+        let children = children.iter().map(|child| self.add(child)).collect();
+        let mut shell = expression
+            .with_children(vec![Expression::Nothing; expression.children().len()])
+            .unwrap_or_else(|_| expression.clone());
+        shell.set_clean(false);
+        self.add_node(ENode { shell, children })
+    }
+
+    fn add_node(&mut self, mut node: ENode) -> ClassId {
+        self.canonicalise(&mut node);
+        if let Some(&class) = self.memo.get(&node) {
+            return self.find(class);
+        }
+        let class = self.parents.len();
+        self.parents.push(class);
+        self.nodes.push(vec![node.clone()]);
+        self.memo.insert(node, class);
+        class
+    }
+
+    /// Records that the expressions of classes `a` and `b` are equal.
+    ///
+    /// # Returns
+    /// False if they were already known to be.
+    pub fn merge(&mut self, a: ClassId, b: ClassId) -> bool {
+        let (a, b) = (self.find(a), self.find(b));
+        if a == b {
+            return false;
+        }
+        // The older class is kept, so that the class of the constraints stays the smallest id
+        let (kept, merged) = (a.min(b), a.max(b));
+        self.parents[merged] = kept;
+        let nodes = std::mem::take(&mut self.nodes[merged]);
+        self.nodes[kept].extend(nodes);
+        self.dirty = true;
+        true
+    }
+
+    /// Merges the classes of nodes that have become equal through merging their children, until
+    /// no two classes hold the same node.
+    #[allow(clippy::mutable_key_type)] // Metadata, which caches annotations, is not hashed
+    pub fn rebuild(&mut self) {
+        while std::mem::take(&mut self.dirty) {
+            let mut memo: HashMap<ENode, ClassId> = HashMap::new();
+            let mut equal = Vec::new();
+            for class in self.classes() {
+                let mut nodes = std::mem::take(&mut self.nodes[class]);
+                nodes.retain_mut(|node| {
+                    self.canonicalise(node);
+                    match memo.get(node) {
+                        // A node held by two classes is kept by the one they are merged into
+                        Some(&other) => {
+                            if other != class {
+                                equal.push((class, other));
+                            }
+                            false
+                        }
+                        None => {
+                            memo.insert(node.clone(), class);
+                            true
+                        }
+                    }
+                });
+                self.nodes[class] = nodes;
+            }
+            self.memo = memo;
+            for (a, b) in equal {
+                self.merge(a, b);
+            }
+        }
+    }
+
+    fn canonicalise(&self, node: &mut ENode) {
+        for child in &mut node.children {
+            *child = self.find(*child);
+        }
+    }
+
+    /// The canonical classes, oldest first.
+    pub fn classes(&self) -> Vec<ClassId> {
+        (0..self.parents.len())
+            .filter(|&class| self.parents[class] == class)
+            .collect()
+    }
+
+    /// The nodes of the canonical class `class`.
+    pub fn nodes(&self, class: ClassId) -> &[ENode] {
+        &self.nodes[class]
+    }
+
+    /// The number of distinct nodes held.
+    pub fn len(&self) -> usize {
+        self.memo.len()
+    }
+
+    /// The cheapest expression of each canonical class, and its cost, where the cost of an
+    /// expression is the sum of `cost` over its sub-expressions, each given with
+    /// `Expression::Nothing` in place of its children.
+    ///
+    /// Classes with no expression of finite cost, which can only happen if every node in them has
+    /// a child of the same class, are left out.
+    pub fn cheapest(&self, cost: &dyn Fn(&Expression) -> u64) -> HashMap<ClassId, (u64, &ENode)> {
+        let mut best: HashMap<ClassId, (u64, &ENode)> = HashMap::new();
+        let node_costs: HashMap<ClassId, Vec<u64>> = self
+            .classes()
+            .into_iter()
+            .map(|class| {
+                let costs = self.nodes(class).iter().map(|node| cost(&node.shell));
+                (class, costs.collect())
+            })
+            .collect();
+        // Costs only go down, so this stops once every class has its cheapest node
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for class in self.classes() {
+                for (node, &cost) in self.nodes(class).iter().zip(&node_costs[&class]) {
+                    let total = node.children.iter().try_fold(cost, |total, child| {
+                        best.get(&self.find(*child))
+                            .map(|(cost, _)| total.saturating_add(*cost))
+                    });
+                    let Some(total) = total else {
+                        continue;
+                    };
+                    if best.get(&class).is_none_or(|(cost, _)| total < *cost) {
+                        best.insert(class, (total, node));
+                        changed = true;
+                    }
+                }
+            }
+        }
+        best
+    }
+}
@@ -0,0 +1,108 @@
+use std::sync::mpsc::{self, Receiver, Sender};
+
+use crate::ast::Expression;
+use crate::rule_engine::{Reduction, ReductionObserver, Rule};
+use crate::Model;
+
+/// Something that happened while rewriting, sent by an [`EventSender`].
+///
+/// Events own their data, so they can be handled on another thread while rewriting goes on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ReductionEvent {
+    /// `rule` was tried on the expression at `path`. Only sent if [`EventSender::attempts`] is
+    /// set.
+    Attempted {
+        rule: String,
+        path: Vec<usize>,
+        applied: bool,
+    },
+    /// `rule` was chosen to rewrite `before`, the expression at `path`, into `after`, adding
+    /// `new_top` to the top-level constraints unless it is [`Expression::Nothing`].
+    Applied {
+        rule: String,
+        path: Vec<usize>,
+        before: Box<Expression>,
+        after: Box<Expression>,
+        new_top: Box<Expression>,
+    },
+    /// The rewrites made by `rules`, and their side-effects, were applied, leaving constraints of
+    /// `size` expressions.
+    Iteration { rules: Vec<String>, size: usize },
+    /// No more rules can be applied.
+    Fixpoint,
+}
+
+/// A [`ReductionObserver`] that sends a [`ReductionEvent`] over a channel for each callback, so
+/// that another thread can follow rewriting as it happens, for example to show it live.
+///
+/// Made with [`event_channel`], and registered with
+/// [`RewriteOptions::observer`](crate::rule_engine::RewriteOptions::observer). Events are sent
+/// without waiting for them to be received, and are dropped once the receiver is.
+#[derive(Clone, Debug)]
+pub struct EventSender {
+    sender: Sender<ReductionEvent>,
+    attempts: bool,
+}
+
+/// Makes a channel of [`ReductionEvent`]s: an [`EventSender`] to register as an observer, and
+/// the receiver to read the events from.
+///
+/// The receiver keeps waiting for events for as long as the options holding the sender exist, so
+/// drop them once rewriting stops to end iteration over the receiver.
+pub fn event_channel() -> (EventSender, Receiver<ReductionEvent>) {
+    let (sender, receiver) = mpsc::channel();
+    (EventSender::new(sender), receiver)
+}
+
+impl EventSender {
+    pub fn new(sender: Sender<ReductionEvent>) -> Self {
+        Self {
+            sender,
+            attempts: false,
+        }
+    }
+
+    /// Whether to send a [`ReductionEvent::Attempted`] for every rule tried. There are many more
+    /// attempts than rewrites, so these are not sent by default.
+    pub fn attempts(self, attempts: bool) -> Self {
+        Self { attempts, ..self }
+    }
+
+    fn send(&self, event: ReductionEvent) {
+        // Nobody is listening once the receiver is dropped, which is not an error for rewriting
+        let _ = self.sender.send(event);
+    }
+}
+
+impl ReductionObserver for EventSender {
+    fn on_attempt(&self, rule: &Rule, path: &[usize], applied: bool) {
+        if self.attempts {
+            self.send(ReductionEvent::Attempted {
+                rule: rule.name.to_string(),
+                path: path.to_vec(),
+                applied,
+            });
+        }
+    }
+
+    fn on_applied(&self, rule: &Rule, path: &[usize], before: &Expression, reduction: &Reduction) {
+        self.send(ReductionEvent::Applied {
+            rule: rule.name.to_string(),
+            path: path.to_vec(),
+            before: Box::new(before.clone()),
+            after: Box::new(reduction.new_expression.clone()),
+            new_top: Box::new(reduction.new_top.clone()),
+        });
+    }
+
+    fn on_iteration(&self, rules: &[&Rule], model: &Model) {
+        self.send(ReductionEvent::Iteration {
+            rules: rules.iter().map(|rule| rule.name.to_string()).collect(),
+            size: model.constraints.size(),
+        });
+    }
+
+    fn on_fixpoint(&self, _model: &Model) {
+        self.send(ReductionEvent::Fixpoint);
+    }
+}
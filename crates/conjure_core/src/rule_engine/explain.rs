@@ -0,0 +1,121 @@
+use std::fmt::Display;
+
+use crate::ast::Expression;
+use crate::rule_engine::reachability::Reachability;
+use crate::rule_engine::resolve_rules::{get_rule_priorities, get_rules_vec};
+use crate::rule_engine::{ApplicationError, RewriteError, RuleSet};
+use crate::Model;
+
+/// What each rule makes of one expression, returned by [`explain`].
+#[derive(Clone, Debug)]
+pub struct Explanation {
+    /// The child indices leading from the root of the constraints to the expression.
+    pub path: Vec<usize>,
+    pub expression: Expression,
+    /// Every rule in the rule sets, in the order the rewriter tries them.
+    pub attempts: Vec<RuleAttempt>,
+}
+
+/// What a single rule makes of the expression in an [`Explanation`].
+#[derive(Clone, Debug)]
+pub struct RuleAttempt {
+    pub rule: String,
+    pub priority: u8,
+    pub outcome: AttemptOutcome,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AttemptOutcome {
+    /// The rule is declared not to apply to this variant of expression, so the rewriter never
+    /// tries it.
+    NotTried,
+    /// The rule does not apply, for the reason it gave with
+    /// [`ApplicationError::NotApplicable`], if any.
+    NotApplicable(Option<String>),
+    /// The rule returned an error, which the rewriter handles according to
+    /// [`RewriteOptions::on_rule_error`](crate::rule_engine::RewriteOptions::on_rule_error).
+    Failed(String),
+    /// The rule applies, but returns the expression unchanged, which the rewriter handles
+    /// according to [`RewriteOptions::on_no_op`](crate::rule_engine::RewriteOptions::on_no_op).
+    Unchanged,
+    /// The rule applies, so rewriting stopped before it could.
+    Applies,
+}
+
+/// Explains why the expression at `path` in the constraints of `model` is not rewritten further,
+/// by trying each rule in `rule_sets` on it in the order the rewriter would.
+///
+/// This is meant for the model returned by rewriting, to answer why an expression was left as it
+/// is. The rules are tried again rather than recorded while rewriting, so rules must give the same
+/// result for the same expression and model, as the rewriter already expects.
+///
+/// # Returns
+/// - What each rule makes of the expression, or None if there is no expression at `path`.
+/// - A `RewriteError` if the rule sets could not be resolved.
+pub fn explain<'a>(
+    model: &Model,
+    rule_sets: &Vec<&'a RuleSet<'a>>,
+    path: &[usize],
+) -> Result<Option<Explanation>, RewriteError> {
+    let Some(expression) = model.constraints.at_path(path) else {
+        return Ok(None);
+    };
+    let rule_priorities = get_rule_priorities(rule_sets)?;
+    let attempts = get_rules_vec(&rule_priorities)
+        .into_iter()
+        .map(|rule| {
+            let outcome = match Reachability::declared_to_apply(rule, expression) {
+                false => AttemptOutcome::NotTried,
+                true => match rule.apply(expression, model) {
+                    Ok(reduction)
+                        if reduction.new_expression == *expression
+                            && !reduction.has_side_effects() =>
+                    {
+                        AttemptOutcome::Unchanged
+                    }
+                    Ok(_) => AttemptOutcome::Applies,
+                    Err(ApplicationError::RuleNotApplicable) => AttemptOutcome::NotApplicable(None),
+                    Err(ApplicationError::NotApplicable(reason)) => {
+                        AttemptOutcome::NotApplicable(Some(reason))
+                    }
+                    Err(e) => AttemptOutcome::Failed(e.to_string()),
+                },
+            };
+            RuleAttempt {
+                rule: rule.name.to_string(),
+                priority: rule_priorities[rule],
+                outcome,
+            }
+        })
+        .collect();
+
+    Ok(Some(Explanation {
+        path: path.to_vec(),
+        expression: expression.clone(),
+        attempts,
+    }))
+}
+
+impl Display for Explanation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Expression at {:?}: {}", self.path, self.expression)?;
+        for attempt in &self.attempts {
+            let outcome = match &attempt.outcome {
+                AttemptOutcome::NotTried => String::from("not tried on this kind of expression"),
+                AttemptOutcome::NotApplicable(None) => String::from("not applicable"),
+                AttemptOutcome::NotApplicable(Some(reason)) => {
+                    format!("not applicable: {}", reason)
+                }
+                AttemptOutcome::Failed(message) => format!("failed: {}", message),
+                AttemptOutcome::Unchanged => String::from("returns the expression unchanged"),
+                AttemptOutcome::Applies => String::from("applies"),
+            };
+            writeln!(
+                f,
+                "  {} (priority {}): {}",
+                attempt.rule, attempt.priority, outcome
+            )?;
+        }
+        Ok(())
+    }
+}
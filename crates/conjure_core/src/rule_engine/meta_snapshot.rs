@@ -0,0 +1,67 @@
+use crate::ast::{Expression, SymbolTable};
+use crate::Model;
+
+/// State that a rewrite can change alongside the constraints, such as the symbol table, and that
+/// can be put back as it was if the rewrite is undone.
+///
+/// By default, a snapshot is a clone, and restoring it replaces the state with the clone. Types
+/// that can be snapshotted more cheaply, for example by leaving out the parts that rewrites do
+/// not change, can override both methods.
+///
+/// The engine snapshots the [`Model`] before each rewrite that it may have to undo, such as when
+/// [`quarantine_after`](crate::rule_engine::RewriteOptions::quarantine_after) is set and the
+/// rewrite breaks an invariant. The changes of a rule that takes `&mut Model`, which become the
+/// side-effects of its reduction, are then undone along with the rest of the rewrite.
+///
+/// # Example
+/// ```rust
+/// use conjure_core::ast::{DecisionVariable, Domain, Name, Range};
+/// use conjure_core::rule_engine::MetaSnapshot;
+/// use conjure_core::Model;
+///
+/// let mut model = Model::new_empty(Default::default());
+/// let snapshot = model.snapshot();
+///
+/// let domain = Domain::IntDomain(vec![Range::Bounded(0, 1)]);
+/// model.add_variable(model.gensym(), DecisionVariable::new(domain));
+///
+/// model.restore(snapshot);
+/// assert!(model.variables.is_empty());
+/// // Names that have been made are not made again
+/// assert_eq!(model.gensym(), Name::MachineName(1));
+/// ```
+pub trait MetaSnapshot: Clone {
+    /// A copy of the state, to be given to [`restore`](MetaSnapshot::restore) later.
+    fn snapshot(&self) -> Self {
+        self.clone()
+    }
+
+    /// Puts the state back as it was when `snapshot` was taken.
+    fn restore(&mut self, snapshot: Self) {
+        *self = snapshot;
+    }
+}
+
+impl MetaSnapshot for SymbolTable {}
+
+/// Snapshots the symbol table and [`meta`](Model::meta) of the model, without its constraints,
+/// which the engine keeps itself. Restoring a snapshot does not make the names made since by
+/// [`Model::gensym`] again.
+impl MetaSnapshot for Model {
+    fn snapshot(&self) -> Self {
+        let mut snapshot = Model::new(
+            self.variables.clone(),
+            Expression::Nothing,
+            self.context.clone(),
+        );
+        snapshot.meta = self.meta.clone();
+        snapshot.reserve_names(self);
+        snapshot
+    }
+
+    fn restore(&mut self, snapshot: Self) {
+        self.reserve_names(&snapshot);
+        self.variables = snapshot.variables;
+        self.meta = snapshot.meta;
+    }
+}
@@ -17,7 +17,9 @@ pub use linkme::distributed_slice;
 ///
 /// <hr>
 ///
-/// Functions must have the signature `fn(&Expr) -> ApplicationResult`.
+/// Functions must have the signature `fn(&Expr, &Model) -> ApplicationResult`, or
+/// `fn(&mut Subtree, &Model) -> ApplicationResult` to take the expression rather than clone it
+/// (see [`Subtree`]).
 /// The created rule will have the same name as the function.
 ///
 /// Intermediary static variables are created to allow for the decentralized registry, with the prefix `CONJURE_GEN_`.
@@ -63,17 +65,107 @@ pub use conjure_macros::register_rule;
 /// ```
 #[doc(inline)]
 pub use conjure_macros::register_rule_set;
-pub use resolve_rules::{get_rule_priorities, get_rules_vec, resolve_rule_sets};
-pub use rewrite::{rewrite_model, RewriteError};
-pub use rule::{ApplicationError, ApplicationResult, Reduction, Rule};
+pub use analyse::{analyse, Redex};
+pub use attempt_heatmap::AttemptHeatmap;
+pub use backtracking::{Backtracking, DeadEndCheck};
+pub use beam_search::BeamSearch;
+pub use best_first::BestFirst;
+pub use confluence::{
+    check_confluence, ConfluenceCheck, ConfluenceReport, ConfluenceRun, NonConfluentInput,
+};
+pub use cost_guided::{Annealing, CostFunction, CostGuided};
+pub use divergence::{
+    DivergenceAction, DivergenceCallback, DivergenceMonitor, DivergenceReason, DivergenceWarning,
+};
+pub use events::{event_channel, EventSender, ReductionEvent};
+pub use explain::{explain, AttemptOutcome, Explanation, RuleAttempt};
+pub use meta_snapshot::MetaSnapshot;
+pub use normal_forms::{
+    enumerate_rewrites, reduce_all_normal_forms, NormalFormLimits, NormalForms, RewriteBranch,
+    RewriteTree,
+};
+pub use observer::ReductionObserver;
+pub use pareto::{pareto_front, Objectives, ParetoFront, ParetoMember, ParetoSearch};
+pub use perf_report::{IterationTiming, PerfReport, RulePerf};
+pub use phased_trace::{PhasedTrace, TraceCheckpoint, TracePhase};
+pub use portfolio::{rewrite_portfolio, Portfolio, PortfolioOutcome};
+pub use progress::{Progress, ProgressCallback};
+pub use replay::{replay, ReplayError, ReplayErrorKind};
+pub use repro::ReproBundle;
+pub use resolve_rules::{
+    get_rule_priorities, get_rules_vec, get_rules_vec_with, resolve_rule_sets, RuleComparator,
+    TieBreak,
+};
+pub use rewrite::{
+    rewrite_model, rewrite_model_with_options, ChoicePoint, DiscardReason, DiscardedEffects,
+    IgnoredOption, QuarantinedRule, RewriteOutcome, RewriteStatus, RuleAmbiguity, SlowRule,
+    TraceStep,
+};
+pub use rewrite_error::{
+    Checkpoint, EngineError, ErrorCategory, RewriteError, RuleError, RuleErrorKind,
+};
+pub use rewrite_options::{
+    Binder, BudgetPolicy, CandidateRewrites, InvariantCheck, NoOpPolicy, NodeLabeler,
+    RewriteChoice, RewriteChooser, RewriteOptions, RewriteSelector, RuleErrorPolicy,
+    SearchStrategy, SpanPolicy, SpawnFailurePolicy, Watch,
+};
+pub use rule::{apply_to_model_copy, ApplicationError, ApplicationResult, Reduction, Rule};
+pub use rule_coverage::{RuleCoverage, RuleHits};
+pub use rule_profile::RuleProfile;
 pub use rule_set::RuleSet;
+pub use saturation::{NodeCost, Saturation};
+pub use scratch::Scratch;
+pub use session::{Breakpoint, ReductionSession};
+pub use shared_tree::SharedTree;
+pub use stochastic::Stochastic;
+pub use subtree::Subtree;
+#[cfg(feature = "json-traces")]
+pub use trace_export::TraceExport;
+pub use trace_filter::{TraceFilter, TracePredicate};
 
 use crate::solver::SolverFamily;
 
+mod analyse;
+mod arena;
+mod attempt_heatmap;
+mod backtracking;
+mod beam_search;
+mod best_first;
+mod confluence;
+mod cost_guided;
+mod divergence;
+mod egraph;
+mod events;
+mod explain;
+mod meta_snapshot;
+mod normal_forms;
+mod observer;
+mod pareto;
+mod perf_report;
+mod phased_trace;
+mod portfolio;
+mod progress;
+mod random;
+mod reachability;
+mod replay;
+mod repro;
 mod resolve_rules;
 mod rewrite;
+mod rewrite_error;
+mod rewrite_options;
 mod rule;
+mod rule_coverage;
+mod rule_profile;
 mod rule_set;
+mod saturation;
+mod scratch;
+mod session;
+mod shared_tree;
+mod stochastic;
+mod subtree;
+#[cfg(feature = "json-traces")]
+mod trace_export;
+mod trace_filter;
 
 #[doc(hidden)]
 #[distributed_slice]
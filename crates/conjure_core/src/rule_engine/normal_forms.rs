@@ -0,0 +1,343 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::time::Duration;
+
+use crate::ast::{Expression, TreeEdit};
+use crate::rule_engine::reachability::Reachability;
+use crate::rule_engine::resolve_rules::{get_rule_priorities, get_rules_vec};
+use crate::rule_engine::{Reduction, RewriteError, Rule, RuleSet, TraceStep};
+use crate::Model;
+
+/// Bounds on the search made by [`reduce_all_normal_forms`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NormalFormLimits {
+    /// The maximum number of distinct models to explore.
+    pub max_states: Option<usize>,
+    /// The maximum number of rewrites from the model the search starts from.
+    pub max_depth: Option<usize>,
+}
+
+impl NormalFormLimits {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Stop exploring once `max_states` distinct models have been explored.
+    pub fn max_states(self, max_states: usize) -> Self {
+        Self {
+            max_states: Some(max_states),
+            ..self
+        }
+    }
+
+    /// Do not explore models more than `max_depth` rewrites away from the model the search starts
+    /// from.
+    pub fn max_depth(self, max_depth: usize) -> Self {
+        Self {
+            max_depth: Some(max_depth),
+            ..self
+        }
+    }
+}
+
+/// The normal forms found by [`reduce_all_normal_forms`].
+#[derive(Clone, Debug)]
+pub struct NormalForms {
+    /// The distinct normal forms, in the order they were found.
+    pub models: Vec<Model>,
+    /// The number of distinct models explored, including the normal forms.
+    pub states: usize,
+    /// Whether every model that can be reached was explored. If not, because a limit was
+    /// reached, there may be normal forms that were not found.
+    pub complete: bool,
+}
+
+impl NormalForms {
+    /// Whether every way of rewriting the model leads to the same normal form, as it does when
+    /// the rule sets are confluent. False if the search was not complete.
+    pub fn is_unique(&self) -> bool {
+        self.complete && self.models.len() == 1
+    }
+}
+
+/// Finds every normal form of `model` under the rules in `rule_sets`, by making every choice of
+/// rewrite the rules allow, rather than only the one the rewriter makes.
+///
+/// From each model, every rule is tried on every expression in the constraints, regardless of
+/// priority, and each rewrite it makes leads to a new model. A model that no rule rewrites is a
+/// normal form. Models are told apart by the hash of their constraints and the names of their
+/// symbols, so each is explored once, even when rewriting goes round in a cycle. If there is more
+/// than one normal form, the rule sets are not confluent.
+///
+/// The number of models to explore can grow exponentially with the number of expressions that
+/// rules apply to, and without bounds if rules keep growing the constraints, so set `limits` for
+/// any but small models.
+///
+/// Rules that return an error, or that return the expression unchanged, are treated as not
+/// applying. Panics in rules are not caught.
+///
+/// # Returns
+/// - The distinct normal forms found, and whether the search was complete.
+/// - A `RewriteError` if the rule sets could not be resolved.
+pub fn reduce_all_normal_forms<'a>(
+    model: &Model,
+    rule_sets: &Vec<&'a RuleSet<'a>>,
+    limits: &NormalFormLimits,
+) -> Result<NormalForms, RewriteError> {
+    let rule_priorities = get_rule_priorities(rule_sets)?;
+    let rules = get_rules_vec(&rule_priorities);
+
+    let mut normal_forms = NormalForms {
+        models: Vec::new(),
+        states: 0,
+        complete: true,
+    };
+    let mut seen = HashSet::from([state_hash(model)]);
+    // Explored breadth first, so that models are found at the fewest rewrites from the start
+    let mut queue = VecDeque::from([(model.clone(), 0)]);
+    while let Some((model, depth)) = queue.pop_front() {
+        if limits
+            .max_states
+            .is_some_and(|max| normal_forms.states >= max)
+        {
+            normal_forms.complete = false;
+            break;
+        }
+        normal_forms.states += 1;
+
+        let next = rewrites_of(&model, &rules);
+        if next.is_empty() {
+            normal_forms.models.push(model);
+            continue;
+        }
+        if limits.max_depth.is_some_and(|max| depth >= max) {
+            normal_forms.complete = false;
+            continue;
+        }
+        for next in next {
+            if seen.insert(state_hash(&next)) {
+                queue.push_back((next, depth + 1));
+            }
+        }
+    }
+    Ok(normal_forms)
+}
+
+/// Every sequence of rewrites of a model, up to a bounded length, as a tree of choices. Returned by
+/// [`enumerate_rewrites`].
+#[derive(Clone, Debug)]
+pub struct RewriteTree {
+    pub model: Model,
+    /// Each rewrite of the model, and the tree of rewrites that follow it, by expression in
+    /// pre-order, then in the order the rewriter tries the rules.
+    pub branches: Vec<RewriteBranch>,
+    /// Whether the model can still be rewritten, but was not, as the sequence reaching it is as
+    /// long as allowed.
+    pub truncated: bool,
+}
+
+/// One choice of rewrite in a [`RewriteTree`].
+#[derive(Clone, Debug)]
+pub struct RewriteBranch {
+    /// The rewrite, as the trace would record it. Rules are not timed, so its `elapsed` is zero.
+    pub step: TraceStep,
+    /// The rewrites of the model it makes.
+    pub tree: RewriteTree,
+}
+
+impl RewriteTree {
+    /// Whether no rule rewrites the model.
+    pub fn is_normal_form(&self) -> bool {
+        self.branches.is_empty() && !self.truncated
+    }
+
+    /// Every sequence of rewrites from the root to a model with no branches, in depth-first order.
+    /// Each can be applied to the root model again with [`replay`](crate::rule_engine::replay).
+    pub fn sequences(&self) -> Vec<Vec<TraceStep>> {
+        if self.branches.is_empty() {
+            return vec![Vec::new()];
+        }
+        let mut sequences = Vec::new();
+        for branch in &self.branches {
+            for mut rest in branch.tree.sequences() {
+                rest.insert(0, branch.step.clone());
+                sequences.push(rest);
+            }
+        }
+        sequences
+    }
+
+    /// The number of models in the tree, including the root.
+    pub fn size(&self) -> usize {
+        1 + self
+            .branches
+            .iter()
+            .map(|branch| branch.tree.size())
+            .sum::<usize>()
+    }
+}
+
+/// Enumerates every sequence of up to `max_length` rewrites that the rules in `rule_sets` can
+/// make of `model`, as a tree of the choices made at each step.
+///
+/// Unlike [`reduce_all_normal_forms`], rewrites that lead to the same model are kept apart, so
+/// every order of making the same rewrites appears, which is what is wanted when studying how
+/// rules interact on small inputs. Every rule is tried on every expression, regardless of
+/// priority. The tree grows exponentially with `max_length`, so keep it and the model small.
+///
+/// Rules that return an error, or that return the expression unchanged, are treated as not
+/// applying. Panics in rules are not caught.
+///
+/// # Returns
+/// - The tree of rewrites, rooted at `model`.
+/// - A `RewriteError` if the rule sets could not be resolved.
+pub fn enumerate_rewrites<'a>(
+    model: &Model,
+    rule_sets: &Vec<&'a RuleSet<'a>>,
+    max_length: usize,
+) -> Result<RewriteTree, RewriteError> {
+    let rule_priorities = get_rule_priorities(rule_sets)?;
+    let rules = get_rules_vec(&rule_priorities);
+    Ok(rewrite_tree(model.clone(), &rules, max_length))
+}
+
+/// The tree of rewrites of `model` by `rules`, up to `remaining` rewrites long.
+fn rewrite_tree(model: Model, rules: &[&Rule], remaining: usize) -> RewriteTree {
+    let found = candidates(&model, rules);
+    if remaining == 0 {
+        return RewriteTree {
+            model,
+            branches: Vec::new(),
+            truncated: !found.is_empty(),
+        };
+    }
+    let branches = found
+        .into_iter()
+        .map(|candidate| {
+            let step = candidate.trace_step(&model.constraints);
+            let next = candidate.apply(&model);
+            RewriteBranch {
+                step,
+                tree: rewrite_tree(next, rules, remaining - 1),
+            }
+        })
+        .collect();
+    RewriteTree {
+        model,
+        branches,
+        truncated: false,
+    }
+}
+
+/// Every model made by applying one of `rules` to one expression in the constraints of `model`.
+fn rewrites_of(model: &Model, rules: &[&Rule]) -> Vec<Model> {
+    candidates(model, rules)
+        .into_iter()
+        .map(|candidate| candidate.apply(model))
+        .collect()
+}
+
+/// A rewrite of one expression in the constraints of a model, found by [`candidates`].
+pub(super) struct Candidate<'r> {
+    pub rule: &'r Rule<'r>,
+    /// The child indices leading from the root of the constraints to the expression.
+    pub path: Vec<usize>,
+    pub reduction: Reduction,
+}
+
+impl Candidate<'_> {
+    /// The trace step recording the rewrite, given the constraints it was found in. Rules are not
+    /// timed, so the step has an `elapsed` of zero.
+    pub fn trace_step(&self, constraints: &Expression) -> TraceStep {
+        let mut edits = match constraints.at_path(&self.path) {
+            Some(before) => before.diff(&self.reduction.new_expression),
+            None => Vec::new(),
+        };
+        for edit in &mut edits {
+            edit.path_mut().splice(0..0, self.path.iter().copied());
+        }
+        TraceStep {
+            rule: self.rule.name.to_string(),
+            path: self.path.clone(),
+            edits,
+            new_top: self.reduction.new_top.clone(),
+            elapsed: Duration::ZERO,
+            watches: Vec::new(),
+            meta_diffs: Vec::new(),
+        }
+    }
+
+    /// The model made by applying the rewrite to `model`, the model it was found in.
+    pub fn apply(mut self, model: &Model) -> Model {
+        let mut next = model.clone();
+        let edit = TreeEdit::Changed {
+            path: self.path,
+            expression: std::mem::replace(&mut self.reduction.new_expression, Expression::Nothing),
+        };
+        // The path was found in these constraints, so the edit fits them
+        next.constraints.apply_edits(&[edit]);
+        self.reduction.new_expression =
+            std::mem::replace(&mut next.constraints, Expression::Nothing);
+        self.reduction.apply(&mut next);
+        next
+    }
+}
+
+/// Every rewrite that one of `rules` makes of one expression in the constraints of `model`, by
+/// expression in pre-order, then by rule in the order given.
+pub(super) fn candidates<'r>(model: &Model, rules: &[&'r Rule<'r>]) -> Vec<Candidate<'r>> {
+    let mut paths = Vec::new();
+    collect_paths(&model.constraints, &mut Vec::new(), &mut paths);
+
+    let mut candidates = Vec::new();
+    for path in paths {
+        let Some(expression) = model.constraints.at_path(&path) else {
+            continue;
+        };
+        for &rule in rules {
+            if !Reachability::declared_to_apply(rule, expression) {
+                continue;
+            }
+            let Ok(reduction) = rule.apply(expression, model) else {
+                continue;
+            };
+            if reduction.new_expression == *expression && !reduction.has_side_effects() {
+                continue;
+            }
+            candidates.push(Candidate {
+                rule,
+                path: path.clone(),
+                reduction,
+            });
+        }
+    }
+    candidates
+}
+
+/// Adds the paths of `expression`, at `path`, and of its sub-expressions, to `paths`, in
+/// pre-order.
+fn collect_paths(expression: &Expression, path: &mut Vec<usize>, paths: &mut Vec<Vec<usize>>) {
+    paths.push(path.clone());
+    let mut i = 0;
+    while let Some(child) = expression.child(i) {
+        path.push(i);
+        collect_paths(child, path, paths);
+        path.pop();
+        i += 1;
+    }
+}
+
+/// Tells models apart by their constraints and the names of their symbols.
+pub(super) fn state_hash(model: &Model) -> u64 {
+    let mut names: Vec<String> = model
+        .variables
+        .keys()
+        .map(|name| name.to_string())
+        .collect();
+    names.sort();
+    let mut hasher = DefaultHasher::new();
+    model.constraints.subtree_hash().hash(&mut hasher);
+    names.hash(&mut hasher);
+    hasher.finish()
+}
@@ -0,0 +1,42 @@
+use crate::ast::Expression;
+use crate::rule_engine::{Reduction, Rule};
+use crate::Model;
+
+/// Callbacks made by the rewriter as it runs, registered with
+/// [`RewriteOptions::observer`](crate::rule_engine::RewriteOptions::observer).
+///
+/// This lets tools such as GUIs and loggers follow rewriting as it happens. Every method does
+/// nothing by default, so an observer need only implement the callbacks it uses.
+///
+/// Rules may be tried on several threads at once, so the callbacks take `&self`, and an observer
+/// that keeps state should use interior mutability. Rewriting waits for each callback to return;
+/// to handle the callbacks on another thread instead, use an [`EventSender`](crate::rule_engine::EventSender).
+pub trait ReductionObserver: Send + Sync {
+    /// Called after `rule` is tried on the expression at `path`, with whether it applied.
+    ///
+    /// The path gives the index of each sub-expression on the way from the root of the
+    /// constraints to the expression.
+    fn on_attempt(&self, _rule: &Rule, _path: &[usize], _applied: bool) {}
+
+    /// Called when `rule` is chosen to rewrite `before`, the expression at `path`, into the
+    /// expression in `reduction`.
+    ///
+    /// A rewrite that breaks a later check, such as
+    /// [`RewriteOptions::max_size`](crate::rule_engine::RewriteOptions::max_size), is reported
+    /// here before it is undone or reported as an error.
+    fn on_applied(
+        &self,
+        _rule: &Rule,
+        _path: &[usize],
+        _before: &Expression,
+        _reduction: &Reduction,
+    ) {
+    }
+
+    /// Called once the rewrites made by `rules`, and their side-effects such as new top-level
+    /// constraints and symbols, have been applied to `model`.
+    fn on_iteration(&self, _rules: &[&Rule], _model: &Model) {}
+
+    /// Called when no more rules can be applied to `model`.
+    fn on_fixpoint(&self, _model: &Model) {}
+}
@@ -0,0 +1,161 @@
+use std::collections::{HashSet, VecDeque};
+use std::sync::Arc;
+
+use derivative::Derivative;
+
+use crate::rule_engine::normal_forms::{candidates, state_hash};
+use crate::rule_engine::resolve_rules::{get_rule_priorities, get_rules_vec};
+use crate::rule_engine::{NormalFormLimits, RewriteError, RuleSet};
+use crate::Model;
+
+/// The costs of a model under several objectives, such as the size of its constraints and the
+/// number of auxiliary variables, each to be made as small as possible. See [`ParetoSearch`].
+pub type Objectives = Arc<dyn Fn(&Model) -> Vec<f64> + Send + Sync>;
+
+/// Settings for [`pareto_front`].
+///
+/// # Example
+/// ```rust
+/// use conjure_core::rule_engine::{NormalFormLimits, ParetoSearch};
+///
+/// let search = ParetoSearch::new(|model| {
+///     vec![model.constraints.size() as f64, model.variables.len() as f64]
+/// })
+/// .limits(NormalFormLimits::new().max_states(1000));
+/// ```
+#[derive(Clone, Derivative)]
+#[derivative(Debug)]
+pub struct ParetoSearch {
+    #[derivative(Debug = "ignore")]
+    pub objectives: Objectives,
+    pub limits: NormalFormLimits,
+    /// Whether every model reached may be on the front, rather than only normal forms.
+    pub every_model: bool,
+}
+
+impl ParetoSearch {
+    /// Find the normal forms that are best under `objectives`, with no limits on the search.
+    pub fn new(objectives: impl Fn(&Model) -> Vec<f64> + Send + Sync + 'static) -> Self {
+        Self {
+            objectives: Arc::new(objectives),
+            limits: NormalFormLimits::new(),
+            every_model: false,
+        }
+    }
+
+    /// Bound the search by `limits`.
+    pub fn limits(self, limits: NormalFormLimits) -> Self {
+        Self { limits, ..self }
+    }
+
+    /// Let any model reached be on the front, not only normal forms, so that rewriting can stop
+    /// part way when that is better.
+    pub fn every_model(self, every_model: bool) -> Self {
+        Self {
+            every_model,
+            ..self
+        }
+    }
+}
+
+/// A model on a [`ParetoFront`], with its costs.
+#[derive(Clone, Debug)]
+pub struct ParetoMember {
+    pub model: Model,
+    /// The cost of the model under each objective, in order.
+    pub costs: Vec<f64>,
+}
+
+/// The models found by [`pareto_front`] that no other model found is better than.
+#[derive(Clone, Debug)]
+pub struct ParetoFront {
+    /// The models on the front, in the order they were found.
+    pub members: Vec<ParetoMember>,
+    /// The number of distinct models explored.
+    pub states: usize,
+    /// Whether every model that can be reached was explored. If not, because a limit was
+    /// reached, models found later could have pushed members off the front.
+    pub complete: bool,
+}
+
+/// Whether costs `a` dominate costs `b`: they are no worse under every objective, and better
+/// under at least one.
+fn dominates(a: &[f64], b: &[f64]) -> bool {
+    a.iter().zip(b).all(|(a, b)| a <= b) && a.iter().zip(b).any(|(a, b)| a < b)
+}
+
+/// Finds the normal forms of `model` under the rules in `rule_sets` that are best under several
+/// objectives at once: those that no other normal form found is at least as good as under every
+/// objective and better under one. Where objectives conflict, such as the size of the constraints
+/// against the number of auxiliary variables, this gives every trade-off the rules allow, rather
+/// than a single model.
+///
+/// Models are explored as in [`reduce_all_normal_forms`](crate::rule_engine::reduce_all_normal_forms),
+/// making every choice of rewrite the rules allow, regardless of priority, and exploring each
+/// distinct model once. The number of models to explore can grow exponentially, so set
+/// [`ParetoSearch::limits`] for any but small models. Models whose costs are equal under every
+/// objective are all kept.
+///
+/// Rules that return an error, or that return the expression unchanged, are treated as not
+/// applying. Panics in rules are not caught.
+///
+/// # Returns
+/// - The models on the front, with their costs, and whether the search was complete.
+/// - A `RewriteError` if the rule sets could not be resolved.
+pub fn pareto_front<'a>(
+    model: &Model,
+    rule_sets: &Vec<&'a RuleSet<'a>>,
+    search: &ParetoSearch,
+) -> Result<ParetoFront, RewriteError> {
+    let rule_priorities = get_rule_priorities(rule_sets)?;
+    let rules = get_rules_vec(&rule_priorities);
+
+    let mut front = ParetoFront {
+        members: Vec::new(),
+        states: 0,
+        complete: true,
+    };
+    let mut seen = HashSet::from([state_hash(model)]);
+    let mut queue = VecDeque::from([(model.clone(), 0)]);
+    while let Some((model, depth)) = queue.pop_front() {
+        if search
+            .limits
+            .max_states
+            .is_some_and(|max| front.states >= max)
+        {
+            front.complete = false;
+            break;
+        }
+        front.states += 1;
+
+        let found = candidates(&model, &rules);
+        let normal = found.is_empty();
+        if !normal && search.limits.max_depth.is_some_and(|max| depth >= max) {
+            front.complete = false;
+        } else {
+            for candidate in found {
+                let next = candidate.apply(&model);
+                if seen.insert(state_hash(&next)) {
+                    queue.push_back((next, depth + 1));
+                }
+            }
+        }
+
+        if !normal && !search.every_model {
+            continue;
+        }
+        let costs = (search.objectives)(&model);
+        if front
+            .members
+            .iter()
+            .any(|member| dominates(&member.costs, &costs))
+        {
+            continue;
+        }
+        front
+            .members
+            .retain(|member| !dominates(&costs, &member.costs));
+        front.members.push(ParetoMember { model, costs });
+    }
+    Ok(front)
+}
@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::time::Duration;
+
+/// Where the time spent rewriting a model went, returned in
+/// [`RewriteOutcome::perf`](crate::rule_engine::RewriteOutcome::perf) if
+/// [`RewriteOptions::perf_report`](crate::rule_engine::RewriteOptions::perf_report) or
+/// [`RewriteOptions::perf_timeline`](crate::rule_engine::RewriteOptions::perf_timeline) is set.
+///
+/// The phases add up to `total`. Time spent trying rules is part of `search`.
+#[derive(Clone, Debug, Default)]
+pub struct PerfReport {
+    /// How often each rule was tried and applied, and how long it took, by rule name. Every rule
+    /// in the rule sets has an entry, including rules that were never tried.
+    pub rules: HashMap<String, RulePerf>,
+    /// Time spent resolving the rules and setting up the rewriter.
+    pub setup: Duration,
+    /// Time spent looking for rewrites: visiting expressions and trying rules on them.
+    pub search: Duration,
+    /// Time spent applying rewrites to the model.
+    pub apply: Duration,
+    /// Time spent on everything else between rewrites, such as size limits, invariants, cycle
+    /// detection, and checkpoints.
+    pub checks: Duration,
+    /// The wall-clock time of the whole run.
+    pub total: Duration,
+    /// Where the time spent on each iteration went, in order, if
+    /// [`RewriteOptions::perf_timeline`](crate::rule_engine::RewriteOptions::perf_timeline) is
+    /// set.
+    pub timeline: Vec<IterationTiming>,
+}
+
+/// Where the time spent on a single iteration of the rewriter went: finding rewrites, and then
+/// applying them and checking the result.
+///
+/// The parts add up to `total`. Rules are single functions, so the time spent deciding whether a
+/// rule applies cannot be told apart from the time spent rewriting, and both are in `rules`.
+/// Rules tried on other threads are timed there, so with
+/// [`RewriteOptions::rule_threads`](crate::rule_engine::RewriteOptions::rule_threads) or work
+/// stealing, `rules` can take up all of the search, and `traversal` is then zero.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IterationTiming {
+    /// The number of rewrites made in the iteration. The last iteration of a run that reached a
+    /// fixpoint makes none.
+    pub rewrites: usize,
+    /// Time spent visiting expressions looking for rewrites, other than trying rules on them.
+    pub traversal: Duration,
+    /// Time spent trying rules.
+    pub rules: Duration,
+    /// Time spent putting the rewrites back into the tree of constraints.
+    pub rebuild: Duration,
+    /// Time spent applying side-effects, such as new symbols, to the model.
+    pub apply: Duration,
+    /// Time spent on everything else, such as size limits, invariants and checkpoints.
+    pub checks: Duration,
+    /// The wall-clock time of the whole iteration.
+    pub total: Duration,
+}
+
+/// How often a single rule was tried and applied, and how long it took.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RulePerf {
+    /// The number of expressions the rule was tried on.
+    pub attempts: usize,
+    /// The number of expressions the rule applied to. Not every application is used: when more
+    /// than one rule applies to an expression, only the first is.
+    pub successes: usize,
+    /// The number of expressions in the rewrites the rule returned when it applied, including any
+    /// new top-level constraints.
+    pub nodes_produced: usize,
+    /// The time spent trying the rule, in total. For rules tried in parallel, this is the time
+    /// spent on other threads.
+    pub time: Duration,
+}
+
+impl PerfReport {
+    /// The rules that were tried, slowest first.
+    pub fn slowest_rules(&self) -> Vec<(&str, &RulePerf)> {
+        let mut rules: Vec<_> = self
+            .rules
+            .iter()
+            .map(|(name, perf)| (name.as_str(), perf))
+            .collect();
+        rules.sort_by(|(a, m), (b, n)| n.time.cmp(&m.time).then(a.cmp(b)));
+        rules
+    }
+
+    /// The slowest iterations, with their indices in `timeline`, slowest first.
+    pub fn slowest_iterations(&self) -> Vec<(usize, &IterationTiming)> {
+        let mut iterations: Vec<_> = self.timeline.iter().enumerate().collect();
+        iterations.sort_by(|(a, m), (b, n)| n.total.cmp(&m.total).then(a.cmp(b)));
+        iterations
+    }
+
+    /// The names of the rules that never applied, in alphabetical order. Over runs on
+    /// representative models, these are candidates for removal from the rule sets; see
+    /// [`RuleCoverage`](crate::rule_engine::RuleCoverage) to combine the reports of many runs.
+    pub fn unused_rules(&self) -> Vec<&str> {
+        let mut rules: Vec<_> = self
+            .rules
+            .iter()
+            .filter(|(_, perf)| perf.successes == 0)
+            .map(|(name, _)| name.as_str())
+            .collect();
+        rules.sort();
+        rules
+    }
+}
+
+impl Display for PerfReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Rewriting took {:?}: {:?} setting up, {:?} searching, {:?} applying, {:?} on checks",
+            self.total, self.setup, self.search, self.apply, self.checks
+        )?;
+        writeln!(
+            f,
+            "{:<40} {:>10} {:>10} {:>10} {:>14}",
+            "Rule", "Attempts", "Successes", "Nodes", "Time"
+        )?;
+        for (name, perf) in self.slowest_rules() {
+            writeln!(
+                f,
+                "{:<40} {:>10} {:>10} {:>10} {:>14}",
+                name,
+                perf.attempts,
+                perf.successes,
+                perf.nodes_produced,
+                format!("{:?}", perf.time)
+            )?;
+        }
+        Ok(())
+    }
+}
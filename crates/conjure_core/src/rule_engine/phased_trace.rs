@@ -0,0 +1,96 @@
+use std::ops::Range;
+
+use crate::ast::Expression;
+use crate::rule_engine::TraceStep;
+
+/// The traces of several rewriter runs, one after the other, labelled with the name of each run's
+/// phase, along with named checkpoints between them.
+///
+/// Rewriting in stages, such as evaluating constants before flattening, takes one run per stage,
+/// each with its own trace. Collecting them here lets tooling show the whole of the rewriting, and
+/// the constraints as they were at a checkpoint such as "after evaluation", without diffing the
+/// traces by hand.
+#[derive(Clone, Debug)]
+pub struct PhasedTrace {
+    /// The constraints before the first step.
+    pub constraints: Expression,
+    /// The steps of every phase, in order.
+    pub steps: Vec<TraceStep>,
+    pub phases: Vec<TracePhase>,
+    pub checkpoints: Vec<TraceCheckpoint>,
+}
+
+/// A named section of a [`PhasedTrace`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TracePhase {
+    pub name: String,
+    /// The indices of the steps made in the phase.
+    pub steps: Range<usize>,
+}
+
+/// A named point in a [`PhasedTrace`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceCheckpoint {
+    pub name: String,
+    /// The number of steps made before the checkpoint.
+    pub step: usize,
+}
+
+impl PhasedTrace {
+    /// Starts a trace of rewriting `constraints`.
+    pub fn new(constraints: &Expression) -> Self {
+        Self {
+            constraints: constraints.clone(),
+            steps: Vec::new(),
+            phases: Vec::new(),
+            checkpoints: Vec::new(),
+        }
+    }
+
+    /// Adds the steps of a phase named `name`, a trace returned in
+    /// [`RewriteOutcome::trace`](crate::rule_engine::RewriteOutcome::trace) of rewriting the
+    /// constraints as the phases before it left them.
+    pub fn add_phase(
+        &mut self,
+        name: impl Into<String>,
+        steps: impl IntoIterator<Item = TraceStep>,
+    ) {
+        let start = self.steps.len();
+        self.steps.extend(steps);
+        self.phases.push(TracePhase {
+            name: name.into(),
+            steps: start..self.steps.len(),
+        });
+    }
+
+    /// Adds a checkpoint named `name` after the steps added so far.
+    pub fn checkpoint(&mut self, name: impl Into<String>) {
+        self.checkpoints.push(TraceCheckpoint {
+            name: name.into(),
+            step: self.steps.len(),
+        });
+    }
+
+    /// The name of the phase that made the step at index `step`.
+    pub fn phase_of(&self, step: usize) -> Option<&str> {
+        self.phases
+            .iter()
+            .find(|phase| phase.steps.contains(&step))
+            .map(|phase| phase.name.as_str())
+    }
+
+    /// The constraints as they were at the first checkpoint named `name`, rebuilt by applying the
+    /// steps before it with [`TraceStep::apply`].
+    ///
+    /// # Returns
+    /// None if there is no such checkpoint, or if a step does not fit the constraints, because a
+    /// phase's trace is not of the constraints the phases before it left.
+    pub fn snapshot(&self, name: &str) -> Option<Expression> {
+        let checkpoint = self.checkpoints.iter().find(|c| c.name == name)?;
+        let mut constraints = self.constraints.clone();
+        self.steps[..checkpoint.step]
+            .iter()
+            .all(|step| step.apply(&mut constraints))
+            .then_some(constraints)
+    }
+}
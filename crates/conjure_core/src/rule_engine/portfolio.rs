@@ -0,0 +1,190 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+use derivative::Derivative;
+
+use crate::ast::Expression;
+use crate::rule_engine::{
+    rewrite_model_with_options, CostFunction, RewriteError, RewriteOptions, RewriteOutcome,
+    RewriteStatus, RuleSet,
+};
+use crate::Model;
+
+/// Several ways of rewriting the same model, to run at once with [`rewrite_portfolio`].
+///
+/// Each strategy is a set of [`RewriteOptions`], which may differ in anything from the order
+/// rules are tried in to the engine mode or seed. Without a cost, the first strategy to reach a
+/// fixpoint wins, and the others are cancelled. With one, every strategy runs to the end, and the
+/// cheapest model wins.
+///
+/// # Example
+/// ```rust
+/// use std::time::Duration;
+/// use conjure_core::rule_engine::{Portfolio, RewriteOptions, Stochastic};
+///
+/// let portfolio = Portfolio::new(vec![
+///     RewriteOptions::new(),
+///     RewriteOptions::new().stochastic(Stochastic::new(1)).max_rewrites(1000),
+///     RewriteOptions::new().stochastic(Stochastic::new(2)).max_rewrites(1000),
+/// ])
+/// .cheapest(|constraints| constraints.size() as f64)
+/// .timeout(Duration::from_secs(10));
+/// ```
+#[derive(Clone, Derivative)]
+#[derivative(Debug)]
+pub struct Portfolio {
+    pub strategies: Vec<RewriteOptions>,
+    /// The cost of the constraints of each model made, to keep the cheapest by, if set.
+    #[derivative(Debug = "ignore")]
+    pub cost: Option<CostFunction>,
+    /// The maximum wall-clock time to spend on each strategy.
+    pub timeout: Option<Duration>,
+}
+
+impl Portfolio {
+    /// Run each of `strategies`, keeping the first to reach a fixpoint.
+    pub fn new(strategies: Vec<RewriteOptions>) -> Self {
+        Self {
+            strategies,
+            cost: None,
+            timeout: None,
+        }
+    }
+
+    /// Run every strategy to the end, keeping the model of least `cost`.
+    pub fn cheapest(self, cost: impl Fn(&Expression) -> f64 + Send + Sync + 'static) -> Self {
+        Self {
+            cost: Some(Arc::new(cost)),
+            ..self
+        }
+    }
+
+    /// Stop each strategy after `timeout`, if it does not have a shorter timeout of its own.
+    pub fn timeout(self, timeout: Duration) -> Self {
+        Self {
+            timeout: Some(timeout),
+            ..self
+        }
+    }
+}
+
+/// The outcome of the strategy that won a [`Portfolio`], returned by [`rewrite_portfolio`].
+#[derive(Debug)]
+pub struct PortfolioOutcome {
+    /// The index of the strategy in [`Portfolio::strategies`].
+    pub strategy: usize,
+    pub outcome: RewriteOutcome,
+    /// The cost of the constraints of the model, if [`Portfolio::cheapest`] is set.
+    pub cost: Option<f64>,
+    /// How each strategy stopped, by index, with [`RewriteStatus::Error`] for those that failed.
+    pub statuses: Vec<RewriteStatus>,
+}
+
+/// Rewrites copies of `model` with each strategy in `portfolio` at once, each on its own thread,
+/// and keeps the best result: the first to reach a fixpoint, or the cheapest if
+/// [`Portfolio::cheapest`] is set.
+///
+/// The cancel flag of each strategy is replaced by one shared by the portfolio, which is set once
+/// a strategy reaches a fixpoint, unless a cost is set. If no strategy reaches a fixpoint, the
+/// first strategy, in the order given, that did not fail wins. Ties in cost also go to the first
+/// strategy. If a thread cannot be spawned, its strategy is run on the calling thread once the
+/// others have started.
+///
+/// # Returns
+/// - The outcome of the strategy that won.
+/// - The error of the first strategy, if every strategy failed. A portfolio with no strategies
+///   is rewritten with the default options.
+pub fn rewrite_portfolio<'a>(
+    model: &Model,
+    rule_sets: &Vec<&'a RuleSet<'a>>,
+    portfolio: &Portfolio,
+) -> Result<PortfolioOutcome, RewriteError> {
+    let cancel = Arc::new(AtomicBool::new(false));
+    let defaults = [RewriteOptions::default()];
+    let strategies = match portfolio.strategies.is_empty() {
+        true => &defaults[..],
+        false => &portfolio.strategies[..],
+    };
+    let strategies: Vec<RewriteOptions> = strategies
+        .iter()
+        .map(|strategy| {
+            let mut options = strategy.clone();
+            options.cancel = Some(cancel.clone());
+            options.timeout = match (options.timeout, portfolio.timeout) {
+                (Some(own), Some(shared)) => Some(own.min(shared)),
+                (own, shared) => own.or(shared),
+            };
+            options
+        })
+        .collect();
+
+    // Results are sent as each strategy stops, so they are received in the order they stopped
+    let (sender, receiver) = mpsc::channel();
+    // Models cannot be shared between threads, so each strategy rewrites its own copy
+    let run = |strategy: usize, copy: Model| {
+        let result = rewrite_model_with_options(&copy, rule_sets, &strategies[strategy]);
+        let fixpoint = matches!(&result, Ok(outcome) if outcome.status == RewriteStatus::Fixpoint);
+        if fixpoint && portfolio.cost.is_none() {
+            cancel.store(true, Ordering::Relaxed);
+        }
+        // The receiver outlives every thread
+        let _ = sender.send((strategy, result));
+    };
+    thread::scope(|scope| {
+        let run = &run;
+        let mut unstarted = Vec::new();
+        for strategy in 0..strategies.len() {
+            let copy = model.clone();
+            let spawned = thread::Builder::new().spawn_scoped(scope, move || run(strategy, copy));
+            if let Err(e) = spawned {
+                log::warn!(target: "file", "Could not spawn a thread for strategy {} of the portfolio, so it will be run on this thread: {}", strategy, e);
+                unstarted.push(strategy);
+            }
+        }
+        for strategy in unstarted {
+            run(strategy, model.clone());
+        }
+    });
+    drop(sender);
+
+    let mut received: Vec<_> = receiver.into_iter().collect();
+    let first_fixpoint = received.iter().find_map(|(strategy, result)| {
+        let fixpoint = matches!(result, Ok(outcome) if outcome.status == RewriteStatus::Fixpoint);
+        fixpoint.then_some(*strategy)
+    });
+    received.sort_by_key(|(strategy, _)| *strategy);
+    let mut results: Vec<_> = received.into_iter().map(|(_, result)| result).collect();
+
+    let statuses = results
+        .iter()
+        .map(|result| match result {
+            Ok(outcome) => outcome.status,
+            Err(_) => RewriteStatus::Error,
+        })
+        .collect();
+    let costs: Vec<Option<f64>> = results
+        .iter()
+        .map(|result| {
+            let cost = portfolio.cost.as_ref()?;
+            Some(cost(&result.as_ref().ok()?.model.constraints))
+        })
+        .collect();
+    let winner = match &portfolio.cost {
+        Some(_) => (0..costs.len())
+            .filter(|&strategy| costs[strategy].is_some())
+            .min_by(|&a, &b| costs[a].unwrap_or(0.0).total_cmp(&costs[b].unwrap_or(0.0))),
+        None => first_fixpoint.or_else(|| results.iter().position(Result::is_ok)),
+    };
+
+    // With no winner, every strategy failed
+    let strategy = winner.unwrap_or(0);
+    let outcome = results.swap_remove(strategy)?;
+    Ok(PortfolioOutcome {
+        strategy,
+        outcome,
+        cost: costs[strategy],
+        statuses,
+    })
+}
@@ -0,0 +1,38 @@
+use std::fmt::Display;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Called with the progress of a rewriter run. See [`RewriteOptions::on_progress`](crate::rule_engine::RewriteOptions::on_progress).
+pub type ProgressCallback = Arc<dyn Fn(&Progress) + Send + Sync>;
+
+/// How far a rewriter run has got, reported while it runs so that frontends can show that it is
+/// still working.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Progress {
+    /// The number of rewrites applied so far.
+    pub rewrites: usize,
+    /// The number of times the rewriter has tried rules on an expression so far.
+    pub visited: usize,
+    /// An estimate of the number of expressions left to try rules on: those not yet known to be
+    /// fully rewritten. Rewrites can add more, so this can go up as well as down.
+    ///
+    /// None if the rewriter does not keep track of which expressions are fully rewritten, as when
+    /// its optimisations are disabled.
+    pub dirty: Option<usize>,
+    /// The time since rewriting started.
+    pub elapsed: Duration,
+}
+
+impl Display for Progress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} rewrites, {} expressions visited",
+            self.rewrites, self.visited
+        )?;
+        if let Some(dirty) = self.dirty {
+            write!(f, ", about {} left", dirty)?;
+        }
+        write!(f, " ({:.2?})", self.elapsed)
+    }
+}
@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+
+use crate::ast::Expression;
+use crate::metadata::Metadata;
+use crate::rule_engine::Rule;
+
+/// Which rules can ever apply to a model, and to which variants of expression, worked out from
+/// the variants each rule is declared to apply to and produce.
+///
+/// Starting from the variants in the model, a rule is reachable if it applies to a reachable
+/// variant, and the variants it produces are then reachable too. A rule with no declared
+/// variants applies to, or produces, every variant.
+#[derive(Clone)]
+pub(super) struct Reachability<'a> {
+    /// The variants that a reachable rule applies to, as a bitmask over
+    /// [`Expression::VARIANT_NAMES`]. No rule can ever change an expression none of whose
+    /// sub-expressions are of these variants.
+    pub(super) matchable: u64,
+    /// The variants each rule applies to. Unreachable rules apply to none.
+    rule_variants: HashMap<&'a str, u64>,
+}
+
+impl<'a> Reachability<'a> {
+    pub(super) fn analyse(rules: &[&'a Rule<'a>], constraints: &Expression) -> Self {
+        let applies_to: Vec<u64> = rules
+            .iter()
+            .map(|rule| variant_mask(rule, rule.applies_to))
+            .collect();
+        let produces: Vec<u64> = rules
+            .iter()
+            .map(|rule| variant_mask(rule, rule.produces))
+            .collect();
+
+        // The rewriter itself joins new top-level constraints to the model with `And`
+        let mut reachable_variants = constraints.variants()
+            | 1 << Expression::And(Metadata::new(), Vec::new()).variant_index();
+        let mut reachable = vec![false; rules.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for i in 0..rules.len() {
+                if !reachable[i] && applies_to[i] & reachable_variants != 0 {
+                    reachable[i] = true;
+                    reachable_variants |= produces[i];
+                    changed = true;
+                }
+            }
+        }
+
+        let mut matchable = 0;
+        let mut rule_variants = HashMap::new();
+        for (i, rule) in rules.iter().enumerate() {
+            if reachable[i] {
+                matchable |= applies_to[i];
+                rule_variants.insert(rule.name, applies_to[i]);
+            } else {
+                log::trace!(target: "file", "Rule {} can never apply to this model", rule);
+                rule_variants.insert(rule.name, 0);
+            }
+        }
+
+        Self {
+            matchable,
+            rule_variants,
+        }
+    }
+
+    /// A reachability that assumes every rule can apply to every variant.
+    pub(super) fn everything() -> Self {
+        Self {
+            matchable: u64::MAX,
+            rule_variants: HashMap::new(),
+        }
+    }
+
+    /// Whether `rule` can apply to `expression`, going only by its variant.
+    pub(super) fn may_apply(&self, rule: &Rule, expression: &Expression) -> bool {
+        self.may_apply_to_variant(rule, expression.variant_index())
+    }
+
+    /// Whether `rule` can apply to expressions of the variant at `index` in
+    /// [`Expression::VARIANT_NAMES`].
+    pub(super) fn may_apply_to_variant(&self, rule: &Rule, index: usize) -> bool {
+        match self.rule_variants.get(rule.name) {
+            Some(variants) => variants & (1 << index) != 0,
+            None => true,
+        }
+    }
+
+    /// Whether `rule` is declared to apply to expressions of the variant of `expression`, whether or
+    /// not it is reachable.
+    pub(super) fn declared_to_apply(rule: &Rule, expression: &Expression) -> bool {
+        variant_mask(rule, rule.applies_to) & (1 << expression.variant_index()) != 0
+    }
+
+    /// Whether some rule may change `expression` or one of its sub-expressions.
+    pub(super) fn may_change(&self, expression: &Expression) -> bool {
+        expression.variants() & self.matchable != 0
+    }
+}
+
+/// Converts a list of variant names to a bitmask over [`Expression::VARIANT_NAMES`]. No list, or
+/// a list with an unknown name, stands for every variant.
+fn variant_mask(rule: &Rule, variants: Option<&[&str]>) -> u64 {
+    let Some(variants) = variants else {
+        return u64::MAX;
+    };
+    let mut mask = 0;
+    for variant in variants {
+        match Expression::VARIANT_NAMES
+            .iter()
+            .position(|name| name == variant)
+        {
+            Some(i) => mask |= 1 << i,
+            None => {
+                log::warn!(target: "file", "Rule {} refers to unknown expression variant {}, assuming it may be any variant", rule, variant);
+                return u64::MAX;
+            }
+        }
+    }
+    mask
+}
@@ -0,0 +1,112 @@
+use thiserror::Error;
+
+use crate::ast::{Expression, TreeEdit};
+use crate::rule_engine::{get_rule_by_name, ApplicationError, TraceStep};
+use crate::Model;
+
+/// A recorded rewrite could not be replayed by [`replay`].
+///
+/// The cause is available as [`ReplayError::kind`], and as the error's
+/// [`source`](std::error::Error::source).
+#[derive(Debug, Error)]
+#[error("Could not replay step {step}, rule {rule} on the expression at {path:?}")]
+pub struct ReplayError {
+    /// The index of the step in the trace.
+    pub step: usize,
+    pub rule: String,
+    /// The child indices leading from the root of the constraints to the expression the rule was
+    /// applied to.
+    pub path: Vec<usize>,
+    #[source]
+    pub kind: ReplayErrorKind,
+}
+
+/// Why a recorded rewrite could not be replayed.
+#[derive(Debug, Error)]
+pub enum ReplayErrorKind {
+    /// No rule with the recorded name is registered.
+    #[error("No rule with this name is registered")]
+    UnknownRule,
+
+    /// The constraints have no expression at the recorded path.
+    #[error("There is no expression at this path")]
+    MissingExpression,
+
+    /// The rule did not apply to the expression.
+    #[error("The rule did not apply: {0}")]
+    NotApplied(#[source] ApplicationError),
+
+    /// The rule rewrote the expression differently from the recording.
+    #[error("The rule made edits {actual:?} rather than the recorded {expected:?}")]
+    DifferentEdits {
+        expected: Vec<TreeEdit>,
+        actual: Vec<TreeEdit>,
+    },
+
+    /// The rule added a different top-level constraint from the recording.
+    #[error(
+        "The rule added the top-level constraint {actual} rather than the recorded {expected}"
+    )]
+    DifferentNewTop {
+        expected: Box<Expression>,
+        actual: Box<Expression>,
+    },
+}
+
+/// Applies the rewrites recorded in `trace` to `model` again, in order, checking that each rule
+/// still makes the same rewrite it did when it was recorded.
+///
+/// Each rule is looked up by name, and applied to the expression at the recorded path, without
+/// trying any other rule. This turns a trace of a run, for example one attached to a bug report,
+/// into a deterministic check: `model` must be equal to the model the traced run started from.
+/// Side-effects, such as new symbols, are not recorded, so they are applied as the rules make them
+/// now.
+///
+/// Panics in rules are not caught, so that a panicking rule can be debugged as usual.
+///
+/// # Returns
+/// - The model after every rewrite in `trace`.
+/// - A `ReplayError` for the first step that could not be replayed.
+pub fn replay(model: &Model, trace: &[TraceStep]) -> Result<Model, ReplayError> {
+    let mut model = model.clone();
+    for (i, step) in trace.iter().enumerate() {
+        let error = |kind| ReplayError {
+            step: i,
+            rule: step.rule.clone(),
+            path: step.path.clone(),
+            kind,
+        };
+        let rule =
+            get_rule_by_name(&step.rule).ok_or_else(|| error(ReplayErrorKind::UnknownRule))?;
+        let expression = model
+            .constraints
+            .at_path(&step.path)
+            .ok_or_else(|| error(ReplayErrorKind::MissingExpression))?;
+        let mut reduction = rule
+            .apply(expression, &model)
+            .map_err(|e| error(ReplayErrorKind::NotApplied(e)))?;
+
+        let mut edits = expression.diff(&reduction.new_expression);
+        for edit in &mut edits {
+            edit.path_mut().splice(0..0, step.path.iter().copied());
+        }
+        if edits != step.edits {
+            return Err(error(ReplayErrorKind::DifferentEdits {
+                expected: step.edits.clone(),
+                actual: edits,
+            }));
+        }
+        if reduction.new_top != step.new_top {
+            return Err(error(ReplayErrorKind::DifferentNewTop {
+                expected: Box::new(step.new_top.clone()),
+                actual: Box::new(reduction.new_top),
+            }));
+        }
+
+        // The edits were made from the expression at the path, so they fit the constraints
+        model.constraints.apply_edits(&edits);
+        reduction.new_expression = std::mem::replace(&mut model.constraints, Expression::Nothing);
+        reduction.apply(&mut model);
+    }
+    Ok(model)
+}
@@ -0,0 +1,97 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+use uniplate::uniplate::Uniplate;
+
+use crate::ast::{Expression, SymbolTable};
+use crate::error::Result;
+use crate::rule_engine::{get_rule_by_name, ApplicationResult, RewriteOptions, RuleError, RuleSet};
+use crate::Model;
+
+/// Everything needed to reproduce a [`RuleError`] without the original input.
+///
+/// Captured when [`RewriteOptions::capture_repro`](crate::rule_engine::RewriteOptions::capture_repro)
+/// is set, and attached to the error as [`RuleError::repro`].
+#[serde_as]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReproBundle {
+    /// The rule that failed.
+    pub rule: String,
+    /// The child indices leading from the root of the constraints to `expression`.
+    pub path: Vec<usize>,
+    /// The expression the rule was applied to.
+    pub expression: Expression,
+    /// The constraints when the rule was applied.
+    pub constraints: Expression,
+    /// The symbol table when the rule was applied.
+    #[serde_as(as = "Vec<(_, _)>")]
+    pub variables: SymbolTable,
+    /// The names of the rule sets being applied.
+    pub rule_sets: Vec<String>,
+    /// The options the rewriter was run with, for reference.
+    pub options: String,
+}
+
+impl ReproBundle {
+    pub(super) fn capture(
+        error: &RuleError,
+        model: &Model,
+        rule_sets: &[&RuleSet],
+        options: &RewriteOptions,
+    ) -> Self {
+        Self {
+            rule: error.rule.clone(),
+            path: error.path.clone(),
+            expression: subexpression_at(&model.constraints, &error.path),
+            constraints: model.constraints.clone(),
+            variables: model.variables.clone(),
+            rule_sets: rule_sets.iter().map(|rs| rs.name.to_string()).collect(),
+            options: format!("{:?}", options),
+        }
+    }
+
+    /// Writes the bundle to a file as JSON.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let file = File::create(path).map_err(anyhow::Error::from)?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+
+    /// Reads a bundle written by [`ReproBundle::save`].
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let file = File::open(path).map_err(anyhow::Error::from)?;
+        Ok(serde_json::from_reader(BufReader::new(file))?)
+    }
+
+    /// Applies the failing rule to the captured expression again.
+    ///
+    /// Panics are not caught, so that a panicking rule can be debugged as usual.
+    ///
+    /// # Returns
+    /// - The result of applying the rule.
+    /// - None if no rule with the bundle's rule name is registered.
+    pub fn replay(&self) -> Option<ApplicationResult> {
+        let rule = get_rule_by_name(&self.rule)?;
+        let model = Model::new(
+            self.variables.clone(),
+            self.constraints.clone(),
+            Default::default(),
+        );
+        Some(rule.apply(&self.expression, &model))
+    }
+}
+
+/// Follows `path` down from `expression`, stopping early if the path does not exist.
+fn subexpression_at(expression: &Expression, path: &[usize]) -> Expression {
+    let mut current = expression.clone();
+    for &i in path {
+        match current.children().into_iter().nth(i) {
+            Some(child) => current = child,
+            None => break,
+        }
+    }
+    current
+}
@@ -1,9 +1,14 @@
+use std::cmp::Ordering;
 use std::collections::{HashMap, HashSet};
 use std::fmt::Display;
+use std::sync::Arc;
 
+use derivative::Derivative;
 use thiserror::Error;
 
-use crate::rule_engine::{get_rule_set_by_name, get_rule_sets_for_solver_family, Rule, RuleSet};
+use crate::rule_engine::{
+    get_rule_set_by_name, get_rule_sets_for_solver_family, get_rules, Rule, RuleSet,
+};
 use crate::solver::SolverFamily;
 
 #[derive(Debug, Error)]
@@ -140,6 +145,62 @@ pub fn rule_cmp<'a>(
     b_priority.cmp(&a_priority)
 }
 
+/// Compares two rules of the same priority. See [`TieBreak::Custom`].
+pub type RuleComparator = Arc<dyn Fn(&Rule, &Rule) -> Ordering + Send + Sync>;
+
+/// How rules of the same priority are ordered, set with
+/// [`RewriteOptions::tie_break`](crate::rule_engine::RewriteOptions::tie_break).
+///
+/// Of the rules of the same priority that apply to an expression, the first in this order is
+/// used, so the order decides the rewrites made whenever rules overlap.
+#[derive(Clone, Default, Derivative)]
+#[derivative(Debug)]
+pub enum TieBreak {
+    /// By name, in lexicographic order.
+    #[default]
+    Name,
+    /// In the order the rules were registered, as returned by [`get_rules`]. This is the order
+    /// the linker placed them in, which is the same on every run of a build, but may change when
+    /// rules are added or the program is built differently.
+    Registration,
+    /// By the given comparison, with rules it ranks equally ordered by name.
+    Custom(#[derivative(Debug = "ignore")] RuleComparator),
+}
+
+/// Get a final ordering of rules based on their priorities, with rules of the same priority
+/// ordered by `tie_break`.
+///
+/// # Arguments
+/// - `rule_priorities` The priorities of the rules.
+/// - `tie_break` How to order rules of the same priority.
+///
+/// # Returns
+/// - A list of rules sorted by their priorities, then by `tie_break`.
+pub fn get_rules_vec_with<'a>(
+    rule_priorities: &HashMap<&'a Rule<'a>, u8>,
+    tie_break: &TieBreak,
+) -> Vec<&'a Rule<'a>> {
+    let mut rules = get_rules_vec(rule_priorities);
+    let priority = |rule: &Rule<'a>| rule_priorities.get(rule).copied().unwrap_or(0);
+    // The sort is stable, so rules the tie-break ranks equally stay ordered by name
+    match tie_break {
+        TieBreak::Name => {}
+        TieBreak::Registration => {
+            let registered: HashMap<&str, usize> = get_rules()
+                .into_iter()
+                .enumerate()
+                .map(|(index, rule)| (rule.name, index))
+                .collect();
+            let index = |rule: &Rule| registered.get(rule.name).copied().unwrap_or(usize::MAX);
+            rules.sort_by(|a, b| priority(b).cmp(&priority(a)).then(index(a).cmp(&index(b))));
+        }
+        TieBreak::Custom(compare) => {
+            rules.sort_by(|a, b| priority(b).cmp(&priority(a)).then(compare(a, b)));
+        }
+    }
+    rules
+}
+
 /// Get a final ordering of rules based on their priorities and names.
 ///
 /// # Arguments
@@ -152,3 +213,62 @@ pub fn get_rules_vec<'a>(rule_priorities: &HashMap<&'a Rule<'a>, u8>) -> Vec<&'a
     rules.sort_by(|a, b| rule_cmp(a, b, rule_priorities));
     rules
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::Expression;
+    use crate::rule_engine::{ApplicationError, ApplicationResult};
+    use crate::Model;
+
+    fn not_applicable(_: &Expression, _: &Model) -> ApplicationResult {
+        Err(ApplicationError::RuleNotApplicable)
+    }
+
+    fn names(rules: Vec<&Rule>) -> Vec<String> {
+        rules.iter().map(|rule| rule.name.to_string()).collect()
+    }
+
+    #[test]
+    fn rules_of_the_same_priority_are_ordered_by_tie_break() {
+        let a = Rule::new("a", not_applicable, &[]);
+        let b = Rule::new("b", not_applicable, &[]);
+        let c = Rule::new("c", not_applicable, &[]);
+        let rule_priorities = HashMap::from([(&a, 10), (&b, 10), (&c, 20)]);
+
+        // By default, rules of the same priority are ordered by name
+        assert_eq!(names(get_rules_vec(&rule_priorities)), ["c", "a", "b"]);
+        assert_eq!(
+            names(get_rules_vec_with(&rule_priorities, &TieBreak::Name)),
+            ["c", "a", "b"]
+        );
+
+        // A custom comparison decides between them, but not over priority
+        let b_first = TieBreak::Custom(Arc::new(|a: &Rule, _: &Rule| match a.name {
+            "b" => Ordering::Less,
+            _ => Ordering::Greater,
+        }));
+        assert_eq!(
+            names(get_rules_vec_with(&rule_priorities, &b_first)),
+            ["c", "b", "a"]
+        );
+
+        // Rules the comparison ranks equally stay ordered by name
+        let equal = TieBreak::Custom(Arc::new(|_: &Rule, _: &Rule| Ordering::Equal));
+        assert_eq!(
+            names(get_rules_vec_with(&rule_priorities, &equal)),
+            ["c", "a", "b"]
+        );
+    }
+
+    #[test]
+    fn rules_of_the_same_priority_can_be_ordered_by_registration() {
+        let registered = get_rules();
+        let mut rules: Vec<&Rule> = registered.iter().copied().take(3).collect();
+        rules.reverse();
+        let rule_priorities: HashMap<&Rule, u8> = rules.iter().map(|&rule| (rule, 10)).collect();
+
+        let ordered = get_rules_vec_with(&rule_priorities, &TieBreak::Registration);
+        assert_eq!(names(ordered), names(registered[..rules.len()].to_vec()));
+    }
+}
@@ -1,42 +1,321 @@
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::fmt::Display;
+use std::io;
+use std::ops::Range;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use thiserror::Error;
-
+use crate::meta::{MetaInvalidation, MetaKey, MetaScope};
+use crate::metadata::{Metadata, Provenance, Span};
+use crate::rule_engine::arena::{Arena, NodeId};
+use crate::rule_engine::backtracking::rewrite_backtracking;
+use crate::rule_engine::beam_search::rewrite_beam_search;
+use crate::rule_engine::best_first::rewrite_best_first;
+use crate::rule_engine::cost_guided::rewrite_cost_guided;
+use crate::rule_engine::divergence::DivergenceTracker;
+use crate::rule_engine::meta_snapshot::MetaSnapshot;
+use crate::rule_engine::perf_report::{IterationTiming, PerfReport, RulePerf};
+use crate::rule_engine::reachability::Reachability;
+use crate::rule_engine::saturation::saturate;
+use crate::rule_engine::stochastic::rewrite_stochastic;
 use crate::stats::RewriterStats;
-use uniplate::uniplate::Uniplate;
 
-use crate::rule_engine::{Reduction, Rule, RuleSet};
+use crate::rule_engine::{
+    get_rule_sets, ApplicationError, ApplicationResult, AttemptHeatmap, BudgetPolicy,
+    CandidateRewrites, Checkpoint, DivergenceAction, EngineError, NoOpPolicy, Progress, Reduction,
+    ReproBundle, RewriteChoice, RewriteError, RewriteOptions, Rule, RuleError, RuleErrorKind,
+    RuleErrorPolicy, RuleProfile, RuleSet, Scratch, SearchStrategy, SpawnFailurePolicy, Subtree,
+};
 use crate::{
-    ast::Expression,
-    rule_engine::resolve_rules::{
-        get_rule_priorities, get_rules_vec, ResolveRulesError as ResolveError,
-    },
+    ast::{DecisionVariable, Expression, Name, TreeEdit},
+    rule_engine::resolve_rules::{get_rule_priorities, get_rules_vec_with},
     Model,
 };
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 struct RuleResult<'a> {
     rule: &'a Rule<'a>,
     reduction: Reduction,
+    /// The time the rule took to apply.
+    elapsed: Duration,
+}
+
+/// Whether the rewriter ran to completion.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RewriteStatus {
+    /// No more rules can be applied to the model.
+    Fixpoint,
+    /// The rewriter stopped early because it ran out of budget.
+    BudgetExhausted,
+    /// The rewriter stopped early because its timeout was reached.
+    Timeout,
+    /// The rewriter stopped early because it was cancelled through `RewriteOptions::cancel`, or by
+    /// the `RewriteOptions::on_divergence` callback.
+    Cancelled,
+    /// The rewriter stopped early with the error in [`RewriteOutcome::error`].
+    Error,
+}
+
+/// The model returned by [`rewrite_model_with_options`], along with whether it is fully rewritten.
+#[derive(Debug)]
+pub struct RewriteOutcome {
+    pub model: Model,
+    pub status: RewriteStatus,
+    /// Rule applications that took longer than `RewriteOptions::slow_rule_threshold`.
+    pub slow_rules: Vec<SlowRule>,
+    /// Rule applications with side-effects that the rewriter did not keep.
+    pub discarded_effects: Vec<DiscardedEffects>,
+    /// Rules disabled part way through rewriting, if `RewriteOptions::quarantine_after` is set.
+    pub quarantined: Vec<QuarantinedRule>,
+    /// The error that stopped the rewriter, if `RewriteOptions::partial_on_error` is set.
+    pub error: Option<RewriteError>,
+    /// Where the time spent rewriting went, if `RewriteOptions::perf_report` is set.
+    pub perf: Option<PerfReport>,
+    /// The rules that applied to each variant of expression, if `RewriteOptions::record_profile`
+    /// is set.
+    pub profile: Option<RuleProfile>,
+    /// The number of rules tried at each position in the constraints, if
+    /// `RewriteOptions::attempt_heatmap` is set.
+    pub heatmap: Option<AttemptHeatmap>,
+    /// Every rewrite applied to the model, in order, if `RewriteOptions::trace` is set.
+    pub trace: Option<Vec<TraceStep>>,
+    /// The seed the rewrites were chosen from, if `RewriteOptions::stochastic` is set, or
+    /// `RewriteOptions::cost_guided` is set to anneal.
+    pub seed: Option<u64>,
+    /// The expressions more than one rule of the same priority applied to, if
+    /// `RewriteOptions::report_ambiguities` is set.
+    pub ambiguities: Vec<RuleAmbiguity>,
+    /// The rewrites not made at each step where more than one rule applied, if
+    /// `RewriteOptions::record_choices` is set.
+    pub choices: Vec<ChoicePoint>,
+    /// The options that could not be used with the other options or with the rules given, and
+    /// that the rewriter went without.
+    pub ignored_options: Vec<IgnoredOption>,
+}
+
+/// A rule application that took longer than `RewriteOptions::slow_rule_threshold`, and so was
+/// treated as not applicable.
+#[derive(Clone, Debug)]
+pub struct SlowRule {
+    pub rule: String,
+    /// The child indices leading from the root of the constraints to the expression.
+    pub path: Vec<usize>,
+    pub elapsed: Duration,
+}
+
+/// A rule application that added symbols or a top-level constraint, or set meta, which the
+/// rewriter discarded along with the rewrite.
+///
+/// Side-effects are only kept if the rewrite is, so a rule that makes them without deciding
+/// whether it should apply first may lose them silently. See
+/// [`RewriteOptions::warn_on_discarded_effects`](crate::rule_engine::RewriteOptions::warn_on_discarded_effects).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DiscardedEffects {
+    pub rule: String,
+    /// The child indices leading from the root of the constraints to the expression.
+    pub path: Vec<usize>,
+    /// The number of symbols the rule added.
+    pub symbols: usize,
+    /// Whether the rule added a top-level constraint.
+    pub new_top: bool,
+    /// The number of values the rule set in the model's [`MetaStore`](crate::meta::MetaStore).
+    pub meta: usize,
+    pub reason: DiscardReason,
 }
 
-#[derive(Debug, Error)]
-pub enum RewriteError {
-    ResolveRulesError(ResolveError),
+/// Why the rewriter discarded a rule application.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiscardReason {
+    /// The rule took longer than `RewriteOptions::slow_rule_threshold`.
+    TooSlow,
+    /// An earlier rule applied to the same expression, and only the first is used.
+    Superseded,
+    /// The rewrite broke a check, such as `RewriteOptions::max_size`, and was undone.
+    Undone,
+    /// `RewriteOptions::select_rewrite` or `RewriteOptions::choose_rewrite` chose none of the
+    /// rewrites found for the expression.
+    Declined,
 }
 
-impl Display for RewriteError {
+/// An option the rewriter went without, as it could not be used with the other options or with
+/// the rules given. The model is rewritten as if the option was not set.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IgnoredOption {
+    /// `RewriteOptions::cache_normal_forms`, as not all rules are pure, choices are recorded,
+    /// rules are tiered, or there are binders or derived meta.
+    CacheNormalForms,
+    /// `RewriteOptions::saturation`, as not all rules are pure.
+    Saturation,
+    /// `RewriteOptions::work_stealing_threads`, as rewrites are not batched, not all rules are
+    /// pure, rewrites are chosen or recorded, or there are binders or derived meta.
+    WorkStealing,
+    /// `RewriteOptions::arena`, as the other options need the constraints between rewrites.
+    Arena,
+    /// `RewriteOptions::slow_rule_threshold`, as slow rules are found by timing them, which is
+    /// not deterministic.
+    SlowRuleThreshold,
+}
+
+impl Display for IgnoredOption {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            RewriteError::ResolveRulesError(e) => write!(f, "Error resolving rules: {}", e),
+        let reason = match self {
+            IgnoredOption::CacheNormalForms => "Not all rules are pure, choices are recorded, rules are tiered, or there are binders or derived meta, so normal forms will not be cached",
+            IgnoredOption::Saturation => "Not all rules are pure, so equality saturation will not be used",
+            IgnoredOption::WorkStealing => "Work stealing needs batch_rewrites, pure rules, no choose_rewrite, no record_choices, no binders, and no derived meta, so each pass will be made on one thread",
+            IgnoredOption::Arena => "The options given need the constraints between rewrites, so they will not be held in an arena",
+            IgnoredOption::SlowRuleThreshold => "Slow rules are found by timing them, so are not discarded when rewriting deterministically",
+        };
+        write!(f, "{reason}")
+    }
+}
+
+impl Display for DiscardedEffects {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reason = match self.reason {
+            DiscardReason::TooSlow => "it was too slow",
+            DiscardReason::Superseded => "an earlier rule applied",
+            DiscardReason::Undone => "the rewrite was undone",
+            DiscardReason::Declined => "no rewrite was selected",
+        };
+        write!(
+            f,
+            "Discarded {} symbols, {} top-level constraints and {} meta values added by rule {} at {:?}, as {}",
+            self.symbols,
+            usize::from(self.new_top),
+            self.meta,
+            self.rule,
+            self.path,
+            reason
+        )
+    }
+}
+
+/// An expression that more than one rule of the same priority applied to, recorded if
+/// `RewriteOptions::report_ambiguities` is set.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RuleAmbiguity {
+    /// The child indices leading from the root of the constraints to the expression.
+    pub path: Vec<usize>,
+    /// The expression, as it is shown in logs.
+    pub expression: String,
+    /// The priority of the rules, which is the highest of the rules that applied.
+    pub priority: u8,
+    /// The rules of that priority that applied, in the order they were tried. Only the first is
+    /// used, unless `RewriteOptions::select_rewrite` or `RewriteOptions::choose_rewrite` is set.
+    pub rules: Vec<String>,
+}
+
+/// A rewrite the rewriter made where other rules also applied, recorded with the rewrites it did
+/// not make if `RewriteOptions::record_choices` is set.
+#[derive(Clone, Debug)]
+pub struct ChoicePoint {
+    /// The number of rewrites made before this one.
+    pub rewrites: usize,
+    /// The model as it was before the rewrite.
+    pub model: Model,
+    /// The child indices leading from the root of the constraints to the rewritten expression.
+    pub path: Vec<usize>,
+    /// The rule whose rewrite was made.
+    pub chosen: String,
+    /// The rules whose rewrites were not made, with their rewrites, in the order they were tried.
+    pub alternatives: Vec<(String, Reduction)>,
+}
+
+impl ChoicePoint {
+    /// The model made by applying the rewrite at `alternative` in `alternatives` instead, which
+    /// can be rewritten further to see where the other choice leads.
+    ///
+    /// # Returns
+    /// - The model, with the side-effects of the rewrite applied.
+    /// - None if there is no such alternative.
+    pub fn branch(&self, alternative: usize) -> Option<Model> {
+        let (_, reduction) = self.alternatives.get(alternative)?;
+        let mut reduction = reduction.clone();
+        let mut model = self.model.clone();
+        let edit = TreeEdit::Changed {
+            path: self.path.clone(),
+            expression: std::mem::replace(&mut reduction.new_expression, Expression::Nothing),
+        };
+        // The rewrite was found at this path in these constraints, so the edit fits them
+        model.constraints.apply_edits(&[edit]);
+        reduction.new_expression = std::mem::replace(&mut model.constraints, Expression::Nothing);
+        reduction.apply(&mut model);
+        Some(model)
+    }
+}
+
+/// A rule that was disabled after failing more than `RewriteOptions::quarantine_after` times.
+#[derive(Debug)]
+pub struct QuarantinedRule {
+    pub rule: String,
+    pub failures: usize,
+    /// The error that caused the rule to be quarantined.
+    pub error: RuleError,
+}
+
+/// A rewrite applied to the model, recorded if `RewriteOptions::trace` is set.
+///
+/// A trace can be applied to the model again, checking that each rule still makes the same
+/// rewrite, with [`replay`](crate::rule_engine::replay).
+///
+/// With the `json-traces` feature, traces can be saved as JSON with
+/// [`TraceExport`](crate::rule_engine::TraceExport).
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "json-traces", derive(serde::Serialize, serde::Deserialize))]
+pub struct TraceStep {
+    pub rule: String,
+    /// The child indices leading from the root of the constraints to the rewritten expression.
+    pub path: Vec<usize>,
+    /// The edits the rule made to the expression, with paths from the root of the constraints,
+    /// rather than copies of the expression before and after.
+    pub edits: Vec<TreeEdit>,
+    /// The new top-level constraint added by the rule, or [`Expression::Nothing`] if it added
+    /// none.
+    pub new_top: Expression,
+    /// The time the rule took to apply.
+    pub elapsed: Duration,
+    /// The values of the [`RewriteOptions::watch`]es after the rewrite, by name, or nothing if no
+    /// watches are registered.
+    pub watches: Vec<(String, String)>,
+    /// How the parts of the model registered with [`RewriteOptions::diff_meta`] and
+    /// [`RewriteOptions::diff_symbols`] changed with the rewrite, by name, leaving out those that
+    /// did not.
+    pub meta_diffs: Vec<(String, String)>,
+}
+
+impl TraceStep {
+    /// Applies the rewrite to `constraints`, the constraints as they were before it, adding any
+    /// new top-level constraint as [`Reduction::apply`] does.
+    ///
+    /// # Returns
+    /// False if the rewrite does not fit `constraints`, in which case they may have been partly
+    /// changed.
+    pub fn apply(&self, constraints: &mut Expression) -> bool {
+        if !constraints.apply_edits(&self.edits) {
+            return false;
         }
+        if !self.new_top.is_nothing() {
+            if let Expression::And(_, exprs) = constraints {
+                exprs.push(self.new_top.clone());
+                constraints.invalidate_annotations();
+            } else {
+                let old = std::mem::replace(constraints, Expression::Nothing);
+                *constraints = Expression::And(Metadata::new(), vec![old, self.new_top.clone()]);
+            }
+        }
+        true
     }
 }
 
-impl From<ResolveError> for RewriteError {
-    fn from(error: ResolveError) -> Self {
-        RewriteError::ResolveRulesError(error)
+impl RewriteOutcome {
+    /// Returns true if no more rules can be applied to the model.
+    pub fn is_complete(&self) -> bool {
+        self.status == RewriteStatus::Fixpoint
     }
 }
 
@@ -62,123 +341,2899 @@ pub fn rewrite_model<'a>(
     model: &Model,
     rule_sets: &Vec<&'a RuleSet<'a>>,
 ) -> Result<Model, RewriteError> {
+    rewrite_model_with_options(model, rule_sets, &RewriteOptions::default())
+        .map(|outcome| outcome.model)
+}
+
+/// Rewrites the model by applying the rules to all constraints, subject to the given options.
+///
+/// Limits on the number of rewrites and on running time are checked between rewrites, so the
+/// returned model is always the result of a whole number of rule applications.
+///
+/// # Returns
+/// - The rewritten model, and whether rewriting ran to completion.
+/// - `EngineError::BudgetExhausted` if a limit was reached and `BudgetPolicy::Error` is set.
+/// - Another `RewriteError` if a check enabled in `options` fails.
+///
+/// If `options.partial_on_error` is set, errors raised while rewriting are instead returned in
+/// [`RewriteOutcome::error`], along with the model as it was when rewriting stopped.
+///
+/// With the `tracing` feature, the run and each iteration are `tracing` spans, and every rule
+/// attempt, rule application, and application of side-effects is an event, with the rule names
+/// and the paths of the expressions involved.
+///
+/// With the `metrics` feature, the run is recorded through the `metrics` facade, to whichever
+/// recorder is installed:
+/// - `conjure_rewriter_runs_total`, a counter labelled with the `status` of the run.
+/// - `conjure_rewriter_run_duration_seconds`, a histogram of the wall-clock time of each run.
+/// - `conjure_rewriter_iterations_total`, a counter of iterations that made rewrites.
+/// - `conjure_rewriter_rewrites_total`, a counter of rewrites applied, labelled with the `rule`.
+/// - `conjure_rewriter_rule_duration_seconds`, a histogram of the time taken by each attempt to
+///   apply a rule, labelled with the `rule`.
+///
+/// Rules are given a new [`Scratch`] space for the run, which is dropped at the end of it.
+pub fn rewrite_model_with_options<'a>(
+    model: &Model,
+    rule_sets: &Vec<&'a RuleSet<'a>>,
+    options: &RewriteOptions,
+) -> Result<RewriteOutcome, RewriteError> {
+    rewrite_owned_model(model.clone(), rule_sets, options)
+}
+
+/// Rewrites `model` as [`rewrite_model_with_options`] does, but takes the model rather than
+/// copying it.
+pub(super) fn rewrite_owned_model<'a>(
+    model: Model,
+    rule_sets: &Vec<&'a RuleSet<'a>>,
+    options: &RewriteOptions,
+) -> Result<RewriteOutcome, RewriteError> {
+    let mut outcome = rewrite_in_scratch(model, rule_sets, options)?;
+    outcome.model.scratch = Scratch::default();
+    Ok(outcome)
+}
+
+/// Rewrites `model` as [`rewrite_model_with_options`] does, with a new [`Scratch`] space, which
+/// the returned model keeps.
+fn rewrite_in_scratch<'a>(
+    model: Model,
+    rule_sets: &Vec<&'a RuleSet<'a>>,
+    options: &RewriteOptions,
+) -> Result<RewriteOutcome, RewriteError> {
+    #[cfg(feature = "tracing")]
+    let _run_span = tracing::info_span!(
+        "rewrite_model",
+        rule_sets = ?rule_sets.iter().map(|rule_set| rule_set.name).collect::<Vec<_>>()
+    )
+    .entered();
+    options.validate()?;
+    let setup_start = Instant::now();
     let rule_priorities = get_rule_priorities(rule_sets)?;
-    let rules = get_rules_vec(&rule_priorities);
-    let mut new_model = model.clone();
-    let mut stats = RewriterStats {
-        is_optimization_enabled: Some(!optimizations_disabled()),
-        rewriter_run_time: None,
-        rewriter_rule_application_attempts: Some(0),
-        rewriter_rule_applications: Some(0),
+    let rules = get_rules_vec_with(&rule_priorities, &options.tie_break);
+    let priorities = rule_priorities
+        .iter()
+        .map(|(rule, &priority)| (rule.name, priority))
+        .collect();
+    let mut new_model = model;
+    new_model.scratch = Scratch::default();
+    // Only the run that reaches a fixpoint leaves its marks for the next one
+    let clean_generation = new_model.clean_generation.take();
+
+    // Clean marks are kept per rule set, so that an expression left clean by an earlier run is only
+    // revisited by the rules of rule sets that run did not apply
+    let bits = rule_set_bits(rule_sets);
+    let clean_mask = bits
+        .as_ref()
+        .map(|(bits, _)| bits.values().fold(0, |mask, bit| mask | bit));
+    // Marks made with bits of this run's own are not understood by other runs
+    let shared_bits = bits.as_ref().is_some_and(|(_, shared)| *shared);
+    let clean_generation = clean_generation.filter(|_| shared_bits);
+    let rule_masks = rules
+        .iter()
+        .map(|rule| {
+            let mask = rule
+                .rule_sets
+                .iter()
+                .filter_map(|(name, _)| bits.as_ref()?.0.get(name))
+                .fold(0, |mask, bit| mask | bit);
+            (rule.name, mask)
+        })
+        .collect();
+
+    let mut ignored_options = Vec::new();
+    let all_pure = rules.iter().all(|rule| rule.pure);
+    if let Some(saturation) = &options.saturation {
+        if all_pure {
+            return saturate(&new_model, &rules, saturation, options);
+        }
+        ignored_options.push(IgnoredOption::Saturation);
+    }
+    let outcome = match &options.strategy {
+        SearchStrategy::FirstFound => None,
+        SearchStrategy::CostGuided(guide) => {
+            Some(rewrite_cost_guided(&new_model, &rules, guide, options))
+        }
+        SearchStrategy::Backtracking(backtracking) => Some(rewrite_backtracking(
+            &new_model,
+            &rules,
+            backtracking,
+            options,
+        )),
+        SearchStrategy::BeamSearch(search) => {
+            Some(rewrite_beam_search(&new_model, &rules, search, options))
+        }
+        SearchStrategy::Stochastic(stochastic) => {
+            Some(rewrite_stochastic(&new_model, &rules, stochastic, options))
+        }
+        SearchStrategy::BestFirst(search) => {
+            Some(rewrite_best_first(&new_model, &rules, search, options))
+        }
+    };
+    if let Some(outcome) = outcome {
+        for ignored in &ignored_options {
+            log::warn!(target: "file", "{ignored}");
+        }
+        return outcome.map(|outcome| RewriteOutcome {
+            ignored_options,
+            ..outcome
+        });
+    }
+    let use_normal_forms = options.cache_normal_forms
+        && all_pure
+        && !options.record_choices
+        && options.priority_tiers.is_empty()
+        && options.binders.is_none()
+        && options.derived_meta.is_empty();
+    if options.cache_normal_forms && !use_normal_forms {
+        ignored_options.push(IgnoredOption::CacheNormalForms);
+    }
+    let use_work_stealing = options.work_stealing_threads > 1
+        && options.batch_rewrites
+        && all_pure
+        && options.rewrite_chooser.is_none()
+        && !options.record_choices
+        && options.binders.is_none()
+        && options.derived_meta.is_empty();
+    if options.work_stealing_threads > 1 && !use_work_stealing {
+        ignored_options.push(IgnoredOption::WorkStealing);
+    }
+    let use_arena = options.arena && arena_supported(options);
+    if options.arena && !use_arena {
+        ignored_options.push(IgnoredOption::Arena);
+    }
+    if options.deterministic && options.slow_rule_threshold.is_some() {
+        ignored_options.push(IgnoredOption::SlowRuleThreshold);
+    }
+    for ignored in &ignored_options {
+        log::warn!(target: "file", "{ignored}");
+    }
+
+    let max_threads = options.max_threads.unwrap_or(usize::MAX);
+
+    let reachability = match optimizations_disabled() {
+        true => Reachability::everything(),
+        false => Reachability::analyse(&rules, &new_model.constraints),
+    };
+
+    // Rules that are never tried are still reported, so that unused rules can be found
+    let rule_perf = (options.perf_report || options.perf_timeline).then(|| {
+        rules
+            .iter()
+            .map(|rule| (rule.name, RulePerf::default()))
+            .collect()
+    });
+
+    let mut rewriter = Rewriter {
+        rules,
+        options,
+        // Check if optimizations are disabled
+        apply_optimizations: !optimizations_disabled() && clean_mask.is_some(),
+        clean_mask: clean_mask.unwrap_or(0),
+        generation: clean_generation.unwrap_or_else(new_generation),
+        tier: 0,
+        rule_masks,
+        path: Vec::new(),
+        span: None,
+        rewrites: 0,
+        iterations: 0,
+        visited: 0,
+        traced: 0,
+        size: match tracks_size(options) {
+            true => new_model.constraints.size(),
+            false => 0,
+        },
+        slow_rules: Vec::new(),
+        discarded_effects: Vec::new(),
+        failures: HashMap::new(),
+        quarantined: Vec::new(),
+        failed_attempts: HashSet::new(),
+        priorities,
+        hit_rates: HashMap::new(),
+        reordered_at: 0,
+        normal_forms: use_normal_forms.then(HashMap::new),
+        reachability,
+        rule_perf,
+        profile: options.record_profile.then(RuleProfile::new),
+        timeline: options.perf_timeline.then(Vec::new),
+        rule_time: Duration::ZERO,
+        rebuild_time: Duration::ZERO,
+        heatmap: options.attempt_heatmap.then(AttemptHeatmap::new),
+        trace: options.trace.then(Vec::new),
+        dispatch: None,
+        rule_threads: options.rule_threads.min(max_threads),
+        work_stealing_threads: match use_work_stealing {
+            true => options.work_stealing_threads.min(max_threads),
+            false => 0,
+        },
+        arena: None,
+        worker_models: Vec::new(),
+        frames: Vec::new(),
+        scratch: Vec::new(),
+        rewritten: Vec::new(),
+        stats: RewriterStats {
+            is_optimization_enabled: Some(!optimizations_disabled()),
+            rewriter_run_time: None,
+            rewriter_rule_application_attempts: Some(0),
+            rewriter_rule_applications: Some(0),
+            rewriter_peak_memory: None,
+        },
+        held_bytes: 0,
+        peak_memory: options.track_memory.then_some(0),
+        start: setup_start,
+        stopped: false,
+        partial_marks: false,
+        ambiguities: Vec::new(),
+        choices: Vec::new(),
     };
 
-    // Check if optimizations are disabled
-    let apply_optimizations = !optimizations_disabled();
+    rewriter.build_dispatch();
 
-    let start = std::time::Instant::now();
+    // The input model is the first checkpoint, and the one returned on errors that leave no model
+    // known to be good
+    let mut checkpoint =
+        (options.checkpoint_interval.is_some() || options.partial_on_error).then(|| Checkpoint {
+            constraints: new_model.constraints.clone(),
+            variables: new_model.variables.clone(),
+            rewrites: 0,
+        });
 
-    while let Some(step) = rewrite_iteration(
-        &new_model.constraints,
-        &new_model,
-        &rules,
-        apply_optimizations,
-        &mut stats,
-    ) {
-        step.apply(&mut new_model); // Apply side-effects (e.g. symbol table updates)
+    if use_arena {
+        let constraints = std::mem::replace(&mut new_model.constraints, Expression::Nothing);
+        rewriter.arena = Some(Arena::with_generation(constraints, rewriter.generation));
     }
+
+    let start = Instant::now();
+    rewriter.start = start;
+    let setup_time = start - setup_start;
+    let mut search_time = Duration::ZERO;
+    let mut apply_time = Duration::ZERO;
+
+    let mut status = RewriteStatus::Fixpoint;
+    let mut error = None;
+
+    // Used for cycle detection: the rules applied so far, and the first time each state was seen
+    let mut applied_rules: Vec<&str> = Vec::new();
+    let mut seen_states: HashMap<u64, usize> = HashMap::new();
+    if options.detect_cycles {
+        seen_states.insert(new_model.constraints.subtree_hash(), 0);
+    }
+
+    let mut divergence = options
+        .divergence_monitor
+        .as_ref()
+        .map(DivergenceTracker::new);
+
+    let mut last_progress = start;
+
+    for update in &options.derived_meta {
+        update(&mut new_model, &[]);
+    }
+
+    loop {
+        #[cfg(feature = "tracing")]
+        let _iteration_span =
+            tracing::debug_span!("rewrite_iteration", rewrites = rewriter.rewrites).entered();
+        if budget_exhausted(options, rewriter.rewrites, rewriter.attempts()) {
+            status = RewriteStatus::BudgetExhausted;
+            break;
+        }
+        if options
+            .timeout
+            .is_some_and(|timeout| start.elapsed() >= timeout)
+        {
+            status = RewriteStatus::Timeout;
+            break;
+        }
+        if options
+            .cancel
+            .as_ref()
+            .is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+        {
+            status = RewriteStatus::Cancelled;
+            break;
+        }
+
+        // Keep the model as it was, so that a rule can be quarantined without its rewrite, the
+        // rewrite can be reproduced, or the model can be returned without it
+        let previous = match (options.quarantine_after.is_some()
+            || options.capture_repro
+            || options.partial_on_error)
+            && (options.max_size.is_some() || options.invariant.is_some())
+            && rewriter.arena.is_none()
+        {
+            true => Some((new_model.constraints.clone(), new_model.snapshot())),
+            false => None,
+        };
+
+        if options.track_memory {
+            let model_bytes =
+                expression_bytes(rewriter.size) + symbol_bytes(new_model.variables.len());
+            let previous_bytes = match previous {
+                Some(_) => model_bytes,
+                None => 0,
+            };
+            let checkpoint_bytes = checkpoint.as_ref().map_or(0, |checkpoint| {
+                expression_bytes(checkpoint.constraints.size())
+                    + symbol_bytes(checkpoint.variables.len())
+            });
+            let worker_bytes =
+                symbol_bytes(new_model.variables.len()) * rewriter.worker_models.len();
+            let cache_bytes = rewriter.normal_forms.as_ref().map_or(0, |cache| {
+                cache
+                    .values()
+                    .map(|(source, normal)| expression_bytes(source.size() + normal.size()))
+                    .sum()
+            });
+            rewriter.held_bytes =
+                model_bytes + previous_bytes + checkpoint_bytes + worker_bytes + cache_bytes;
+            rewriter.observe_memory(0);
+        }
+
+        // Keep the symbol table and meta as they were, to record how the rewrite changed them
+        let meta_before = match rewriter.trace.is_some() && !options.meta_diffs.is_empty() {
+            true => Some(new_model.snapshot()),
+            false => None,
+        };
+
+        let size_before = rewriter.size;
+        let trace_before = rewriter.trace.as_ref().map_or(0, Vec::len);
+        let choices_before = rewriter.choices.len();
+        let search_start = Instant::now();
+        let result = rewriter.rewrite_iteration(&mut new_model);
+        let search = search_start.elapsed();
+        search_time += search;
+        match result {
+            Ok(Some(mut step)) => {
+                let apply_start = Instant::now();
+                let rule = step.last_rule();
+
+                // The rewrite has already been made, in place or in the arena, so only its
+                // side-effects are left to apply
+                step.reduction.new_expression =
+                    std::mem::replace(&mut new_model.constraints, Expression::Nothing);
+                if tracks_size(options) {
+                    rewriter.size += added_top_size(&step.reduction);
+                }
+                #[cfg(feature = "tracing")]
+                tracing::debug!(
+                    rules = ?step.rules.iter().map(|rule| rule.name).collect::<Vec<_>>(),
+                    symbols = step.reduction.symbols.len(),
+                    new_top = !step.reduction.new_top.is_nothing(),
+                    "applying side-effects"
+                );
+                let symbols_added = step.reduction.symbols.len();
+                let top_added = !step.reduction.new_top.is_nothing();
+                let meta_set = step.reduction.meta.len();
+                // Rules may look up the new symbols or meta, so may now apply where they did not
+                // before
+                let invalidation = match (symbols_added, meta_set) {
+                    (0, 0) => MetaInvalidation::Nothing,
+                    (0, _) => new_model.meta.invalidation(&step.reduction.meta),
+                    _ => MetaInvalidation::All,
+                };
+                step.reduction.apply(&mut new_model); // Apply side-effects (e.g. symbol table updates)
+                if symbols_added > 0 || meta_set > 0 {
+                    rewriter.worker_models.clear();
+                }
+                // Counted before the checks, so that errors are attributed to the rewrite that
+                // caused them
+                rewriter.rewrites += step.rules.len();
+                rewriter.iterations += 1;
+
+                let mut failure = None;
+                if let Some(limit) = options.max_size {
+                    if rewriter.size > limit {
+                        failure = Some(RuleErrorKind::SizeLimitExceeded {
+                            size: rewriter.size,
+                            limit,
+                        });
+                    }
+                }
+                if let (None, Some(invariant)) = (&failure, &options.invariant) {
+                    if let Err(message) = invariant(&new_model) {
+                        let shown = match &options.labeler {
+                            Some(labeler) => labeler(&new_model.constraints),
+                            None => new_model.constraints.to_string(),
+                        };
+                        failure = Some(RuleErrorKind::InvariantViolated {
+                            message,
+                            constraints: Box::new(new_model.constraints.clone()),
+                            shown: shown.into(),
+                        });
+                    }
+                }
+
+                if let Some(kind) = failure {
+                    let mut rule_error = rewriter.rule_error(rule, kind);
+                    if let Some((constraints, meta)) = previous {
+                        // The snapshot of a model is the model without its constraints
+                        let mut previous = meta;
+                        previous.constraints = constraints;
+                        if options.quarantine_after.is_none() && options.capture_repro {
+                            rule_error.repro = Some(Box::new(ReproBundle::capture(
+                                &rule_error,
+                                &previous,
+                                rule_sets,
+                                options,
+                            )));
+                        }
+                        if options.quarantine_after.is_some() || options.partial_on_error {
+                            if symbols_added > 0 || top_added || meta_set > 0 {
+                                rewriter.record_discarded(DiscardedEffects {
+                                    rule: rule_error.rule.clone(),
+                                    path: rule_error.path.clone(),
+                                    symbols: symbols_added,
+                                    new_top: top_added,
+                                    meta: meta_set,
+                                    reason: DiscardReason::Undone,
+                                });
+                            }
+                            new_model.constraints = std::mem::take(&mut previous.constraints);
+                            new_model.restore(previous);
+                            rewriter.worker_models.clear();
+                            rewriter.rewritten.clear();
+                            rewriter.size = size_before;
+                            rewriter.truncate_trace(trace_before);
+                            rewriter.choices.truncate(choices_before);
+                            rewriter.rewrites -= step.rules.len();
+                            rewriter.iterations -= 1;
+                            if options.quarantine_after.is_some() {
+                                rewriter.record_failure(rule_error);
+                                continue;
+                            }
+                        }
+                    } else if let (true, Some(checkpoint)) = (options.partial_on_error, &checkpoint)
+                    {
+                        // The constraints before the rewrite were not kept, as they were held in
+                        // an arena, so the last model known to be good is the checkpoint
+                        new_model.constraints = checkpoint.constraints.clone();
+                        new_model.variables = checkpoint.variables.clone();
+                        rewriter.arena = None;
+                    }
+                    error = Some(rule_error.into());
+                    break;
+                }
+
+                // The rewrite is kept, so only now is anything told about it
+                #[cfg(feature = "metrics")]
+                {
+                    metrics::counter!("conjure_rewriter_iterations_total").increment(1);
+                    for rule in &step.rules {
+                        metrics::counter!("conjure_rewriter_rewrites_total", "rule" => rule.name.to_string())
+                            .increment(1);
+                    }
+                }
+                let rewritten = std::mem::take(&mut rewriter.rewritten);
+                let invalidation = options
+                    .derived_meta
+                    .iter()
+                    .map(|update| update(&mut new_model, &rewritten))
+                    .fold(invalidation, MetaInvalidation::and);
+                rewriter.invalidate(invalidation);
+                if rewriter.tier > 0 {
+                    // The rewrite may have made something a higher tier can rewrite
+                    rewriter.set_tier(0);
+                }
+                if options.adaptive_rule_order {
+                    rewriter.reorder_rules();
+                }
+                let apply = apply_start.elapsed();
+                apply_time += apply;
+                for observer in &options.observers {
+                    observer.on_iteration(&step.rules, &new_model);
+                }
+                // Steps traced in this iteration, if any, are at the end of the trace
+                let traced = rewriter
+                    .trace
+                    .as_mut()
+                    .filter(|trace| trace.len() > trace_before);
+                if let Some(last) = traced.and_then(|trace| trace.last_mut()) {
+                    last.watches = options
+                        .watches
+                        .iter()
+                        .map(|(name, watch)| {
+                            (name.clone(), watch(&new_model.constraints, &new_model))
+                        })
+                        .collect();
+                    if let Some(before) = &meta_before {
+                        last.meta_diffs = options
+                            .meta_diffs
+                            .iter()
+                            .filter_map(|(name, diff)| {
+                                Some((name.clone(), diff(before, &new_model)?))
+                            })
+                            .collect();
+                    }
+                }
+
+                if options.detect_cycles {
+                    applied_rules.extend(step.rules.iter().map(|rule| rule.name));
+                    let hash = new_model.constraints.subtree_hash();
+                    if let Some(&first_seen) = seen_states.get(&hash) {
+                        let rules = applied_rules[first_seen..]
+                            .iter()
+                            .map(|name| name.to_string())
+                            .collect();
+                        error = Some(EngineError::CycleDetected { rules }.into());
+                        break;
+                    }
+                    seen_states.insert(hash, rewriter.rewrites);
+                }
+
+                if let Some(tracker) = &mut divergence {
+                    let mut warning = None;
+                    for rule in &step.rules {
+                        warning =
+                            warning.or(tracker.record(rewriter.rewrites, rewriter.size, rule.name));
+                    }
+                    if let Some(warning) = warning {
+                        log::warn!(target: "file", "{}", warning);
+                        let action = match &options.on_divergence {
+                            Some(callback) => callback(&warning),
+                            None => DivergenceAction::Continue,
+                        };
+                        if action == DivergenceAction::Stop {
+                            status = RewriteStatus::Cancelled;
+                            break;
+                        }
+                    }
+                }
+
+                if let (Some(interval), Some(last)) = (options.checkpoint_interval, &checkpoint) {
+                    if rewriter.rewrites - last.rewrites >= interval {
+                        checkpoint = Some(Checkpoint {
+                            constraints: new_model.constraints.clone(),
+                            variables: new_model.variables.clone(),
+                            rewrites: rewriter.rewrites,
+                        });
+                    }
+                }
+
+                if let Some(callback) = &options.on_progress {
+                    if last_progress.elapsed() >= options.progress_interval {
+                        callback(&rewriter.progress(&new_model, start));
+                        last_progress = Instant::now();
+                    }
+                }
+                rewriter.record_timing(step.rules.len(), search_start, search, apply);
+            }
+            Ok(None) => {
+                rewriter.record_timing(0, search_start, search, Duration::ZERO);
+                if rewriter.stopped {
+                    status = RewriteStatus::Cancelled;
+                    break;
+                }
+                // A pass cut short by the timeout or cancellation is not a fixpoint, and the
+                // checks at the top of the loop stop the run
+                if rewriter.interrupted() {
+                    continue;
+                }
+                // The next tier is only tried once no rule of this one applies anywhere
+                if rewriter.tier < options.priority_tiers.len() {
+                    rewriter.set_tier(rewriter.tier + 1);
+                    continue;
+                }
+                for observer in &options.observers {
+                    observer.on_fixpoint(&new_model);
+                }
+                break;
+            }
+            Err(RewriteError::Rule(rule_error)) if options.quarantine_after.is_some() => {
+                rewriter.size = size_before;
+                rewriter.truncate_trace(trace_before);
+                rewriter.choices.truncate(choices_before);
+                rewriter.record_failure(rule_error);
+            }
+            Err(e) => {
+                // Errors raised during an iteration leave the model as it was before it
+                rewriter.truncate_trace(trace_before);
+                rewriter.choices.truncate(choices_before);
+                error = Some(e);
+                break;
+            }
+        }
+    }
+    if let Some(callback) = &options.on_progress {
+        callback(&rewriter.progress(&new_model, start));
+    }
+    if let Some(arena) = rewriter.arena.take() {
+        new_model.constraints = arena.into_expression();
+    }
+    if status == RewriteStatus::Fixpoint
+        && error.is_none()
+        && rewriter.apply_optimizations
+        && !rewriter.partial_marks
+        && shared_bits
+    {
+        new_model.clean_generation = Some(rewriter.generation);
+    }
+    let rewrites = rewriter.rewrites;
+    let attempts = rewriter.attempts();
+    let slow_rules = rewriter.slow_rules;
+    let discarded_effects = rewriter.discarded_effects;
+    let quarantined = rewriter.quarantined;
+    let ambiguities = rewriter.ambiguities;
+    let choices = rewriter.choices;
+    let perf = rewriter.rule_perf.map(|rules| {
+        let loop_time = start.elapsed();
+        PerfReport {
+            rules: rules
+                .into_iter()
+                .map(|(rule, perf)| (rule.to_string(), perf))
+                .collect(),
+            setup: setup_time,
+            search: search_time,
+            apply: apply_time,
+            checks: loop_time.saturating_sub(search_time + apply_time),
+            total: setup_time + loop_time,
+            timeline: rewriter.timeline.take().unwrap_or_default(),
+        }
+    });
+    let profile = rewriter.profile;
+    let heatmap = rewriter.heatmap;
+    let trace = rewriter.trace;
+    let mut stats = rewriter.stats;
+    stats.rewriter_peak_memory = rewriter.peak_memory;
     stats.rewriter_run_time = Some(start.elapsed());
-    model.context.write().unwrap().stats.add_rewriter_run(stats);
-    Ok(new_model)
+    new_model
+        .context
+        .write()
+        .unwrap()
+        .stats
+        .add_rewriter_run(stats);
+
+    #[cfg(feature = "tracing")]
+    tracing::info!(status = ?status, rewrites, attempts, "rewriting stopped");
+    match status {
+        RewriteStatus::BudgetExhausted => {
+            log::warn!(target: "file", "Rewrite budget exhausted after {} rewrites and {} rule attempts", rewrites, attempts);
+            if options.on_budget_exhausted == BudgetPolicy::Error {
+                error = Some(EngineError::BudgetExhausted { rewrites, attempts }.into());
+            }
+        }
+        RewriteStatus::Timeout => {
+            log::warn!(target: "file", "Rewriter timed out after {} rewrites", rewrites);
+        }
+        RewriteStatus::Cancelled => {
+            log::warn!(target: "file", "Rewriter cancelled after {} rewrites", rewrites);
+        }
+        RewriteStatus::Fixpoint | RewriteStatus::Error => {}
+    }
+
+    #[cfg(feature = "metrics")]
+    {
+        let status = match error {
+            Some(_) => RewriteStatus::Error,
+            None => status,
+        };
+        metrics::counter!("conjure_rewriter_runs_total", "status" => format!("{:?}", status))
+            .increment(1);
+        metrics::histogram!("conjure_rewriter_run_duration_seconds")
+            .record(setup_time + start.elapsed());
+    }
+
+    if let Some(mut error) = error {
+        if let RewriteError::Rule(rule_error) = &mut error {
+            rule_error.checkpoint = checkpoint.map(Box::new);
+            // Errors raised during an iteration leave the model as the rule saw it
+            if options.capture_repro && rule_error.repro.is_none() {
+                rule_error.repro = Some(Box::new(ReproBundle::capture(
+                    rule_error, &new_model, rule_sets, options,
+                )));
+            }
+        }
+        if !options.partial_on_error {
+            return Err(error);
+        }
+
+        log::warn!(target: "file", "Rewriter stopped after {} rewrites: {}", rewrites, error);
+        return Ok(RewriteOutcome {
+            model: new_model,
+            status: RewriteStatus::Error,
+            slow_rules,
+            discarded_effects,
+            quarantined,
+            error: Some(error),
+            perf,
+            profile,
+            heatmap,
+            trace,
+            seed: None,
+            ambiguities,
+            choices,
+            ignored_options,
+        });
+    }
+
+    Ok(RewriteOutcome {
+        model: new_model,
+        status,
+        slow_rules,
+        discarded_effects,
+        quarantined,
+        error: None,
+        perf,
+        profile,
+        heatmap,
+        trace,
+        seed: None,
+        ambiguities,
+        choices,
+        ignored_options,
+    })
 }
 
+/// The approximate number of bytes held by an expression of `size` sub-expressions, not counting
+/// heap data such as names. See [`RewriteOptions::track_memory`].
+fn expression_bytes(size: usize) -> usize {
+    size * std::mem::size_of::<Expression>()
+}
+
+/// The approximate number of bytes held by a symbol table of `symbols` symbols, not counting heap
+/// data such as domains.
+fn symbol_bytes(symbols: usize) -> usize {
+    symbols * (std::mem::size_of::<Name>() + std::mem::size_of::<DecisionVariable>())
+}
+
+/// The approximate number of bytes held by the expressions and symbols in `reduction`.
+fn reduction_bytes(reduction: &Reduction) -> usize {
+    expression_bytes(reduction.new_expression.size())
+        + expression_bytes(reduction.new_top.size())
+        + symbol_bytes(reduction.symbols.len())
+}
+
+/// The number of expressions that applying `reduction` adds to the top of the constraints.
+///
+/// See [`Reduction::apply`].
+fn added_top_size(reduction: &Reduction) -> usize {
+    match (&reduction.new_top, &reduction.new_expression) {
+        (Expression::Nothing, _) => 0,
+        (new_top, Expression::And(_, _)) => new_top.size(),
+        // A new conjunction is created to hold the new top-level constraint
+        (new_top, _) => new_top.size() + 1,
+    }
+}
+
+/// Returns true if the constraints can be held in an [`Arena`] with `options`, which is only the
+/// case if nothing needs the whole of the constraints between rewrites, and no other kind of pass
+/// is used. See [`RewriteOptions::arena`].
+fn arena_supported(options: &RewriteOptions) -> bool {
+    options.invariant.is_none()
+        && !options.detect_cycles
+        && options.checkpoint_interval.is_none()
+        && !options.capture_repro
+        && options.quarantine_after.is_none()
+        && !options.batch_rewrites
+        && !options.cache_normal_forms
+        && options.work_stealing_threads <= 1
+        && options.observers.is_empty()
+        && options.watches.is_empty()
+        && !options.record_choices
+        && options.binders.is_none()
+        && options.derived_meta.is_empty()
+}
+
+/// Returns true if the rewriter needs to keep track of the size of the constraints.
+fn tracks_size(options: &RewriteOptions) -> bool {
+    options.max_size.is_some() || options.divergence_monitor.is_some() || options.track_memory
+}
+
+/// The bit standing for each of `rule_sets` in
+/// [`Cache::clean_rule_sets`](crate::metadata::Cache::clean_rule_sets), and whether the bits are
+/// shared by every run.
+///
+/// Each rule set has the bit of its position among the registered rule sets, as returned by
+/// [`get_rule_sets`], so that a run can go by the marks of an earlier run that applied other rule
+/// sets. If there are too many registered rule sets for that, the bits are numbered over
+/// `rule_sets` alone, and only mean something to this run.
+///
 /// # Returns
-/// - Some(<new_expression>) after applying the first applicable rule to `expr` or a sub-expression.
-/// - None if no rule is applicable to the expression or any sub-expression.
-fn rewrite_iteration<'a>(
-    expression: &'a Expression,
-    model: &'a Model,
-    rules: &'a Vec<&'a Rule<'a>>,
-    apply_optimizations: bool,
-    stats: &mut RewriterStats,
-) -> Option<Reduction> {
-    if apply_optimizations && expression.is_clean() {
-        // Skip processing this expression if it's clean
+/// - None if there are more rule sets in `rule_sets` than bits to track them with.
+fn rule_set_bits<'a>(rule_sets: &[&'a RuleSet<'a>]) -> Option<(HashMap<&'a str, u64>, bool)> {
+    let registered = get_rule_sets();
+    let positions: Vec<Option<usize>> = rule_sets
+        .iter()
+        .map(|rule_set| registered.iter().position(|r| r.name == rule_set.name))
+        .collect();
+    if positions
+        .iter()
+        .all(|position| position.is_some_and(|i| i < 64))
+    {
+        let bits = rule_sets
+            .iter()
+            .zip(positions)
+            .filter_map(|(rule_set, i)| Some((rule_set.name, 1u64 << i?)))
+            .collect();
+        return Some((bits, true));
+    }
+    if rule_sets.len() > 64 {
+        log::warn!(target: "file", "Clean expressions cannot be tracked for {} rule sets at once, only for up to 64, so every expression will be revisited after each rewrite", rule_sets.len());
         return None;
     }
+    log::debug!(target: "file", "More than 64 rule sets are registered, so clean expressions are tracked for this run only");
+    let bits = rule_sets
+        .iter()
+        .enumerate()
+        .map(|(i, rule_set)| (rule_set.name, 1u64 << i))
+        .collect();
+    Some((bits, false))
+}
+
+/// A generation of clean marks that no rewriter has started before, so that no expression holds
+/// marks from it. Generation 0 is never started, so marks not made by a rewriter count for
+/// nothing. See [`Cache::clean_generation`](crate::metadata::Cache::clean_generation).
+fn new_generation() -> u64 {
+    static GENERATIONS: AtomicU64 = AtomicU64::new(1);
+    GENERATIONS.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Returns true if either of the rewrite limits in `options` has been reached.
+pub(super) fn budget_exhausted(options: &RewriteOptions, rewrites: usize, attempts: usize) -> bool {
+    options.max_rewrites.is_some_and(|max| rewrites >= max)
+        || options.max_rule_attempts.is_some_and(|max| attempts >= max)
+}
+
+/// State shared by every iteration of a single rewriter run.
+struct Rewriter<'r, 'o> {
+    rules: Vec<&'r Rule<'r>>,
+    options: &'o RewriteOptions,
+    apply_optimizations: bool,
+    /// The rule sets being applied, as a bitmask of [`rule_set_bits`].
+    clean_mask: u64,
+    /// The generation of the clean marks, see
+    /// [`Cache::clean_generation`](crate::metadata::Cache::clean_generation). A new
+    /// generation is started whenever rules may apply where they did not before, which makes
+    /// every expression dirty without visiting it. A run starts from a new generation, unless the
+    /// model holds the marks of a run that reached a fixpoint, see [`Model::clean_generation`].
+    generation: u64,
+    /// The rule sets each rule is applied as part of, as a bitmask of [`rule_set_bits`].
+    rule_masks: HashMap<&'r str, u64>,
+    /// The child indices leading from the root to the expression currently being rewritten.
+    path: Vec<usize>,
+    /// The span of the expression currently being rewritten, kept in case a rule takes it, see
+    /// [`RewriteOptions::span_policy`].
+    span: Option<Span>,
+    /// The number of rewrites applied so far.
+    rewrites: usize,
+    /// The number of iterations that have applied rewrites so far.
+    iterations: usize,
+    /// The number of times rules have been tried on an expression so far.
+    visited: usize,
+    /// The number of rewrites that passed `options.trace_filter`, for sampling.
+    traced: usize,
+    /// The number of expressions in the constraints, tracked only if [`tracks_size`] is true.
+    size: usize,
+    slow_rules: Vec<SlowRule>,
+    discarded_effects: Vec<DiscardedEffects>,
+    /// The number of times each rule has failed, tracked only if `options.quarantine_after` is set.
+    failures: HashMap<&'r str, usize>,
+    quarantined: Vec<QuarantinedRule>,
+    /// Rules known not to apply to expressions with the given hash, tracked only if
+    /// `options.memoize_failures` is set.
+    failed_attempts: HashSet<(&'r str, u64)>,
+    priorities: HashMap<&'r str, u8>,
+    /// The number of times each rule has applied, and has been tried, tracked only if
+    /// `options.adaptive_rule_order` is set.
+    hit_rates: HashMap<&'r str, (usize, usize)>,
+    /// The number of rule attempts made when the rules were last reordered by hit rate.
+    reordered_at: usize,
+    /// Each expression normalised so far, with its normal form, by the hash of the expression.
+    /// Different expressions may share a hash, so an entry is only reused for an expression equal
+    /// to the one it was made from. Only used if `options.cache_normal_forms` is set and every
+    /// rule is pure.
+    normal_forms: Option<HashMap<u64, (Expression, Expression)>>,
+    /// Which rules can apply to which variants of expression.
+    reachability: Reachability<'r>,
+    /// How often each rule was tried and applied, and how long it took, if
+    /// `options.perf_report` is set.
+    rule_perf: Option<HashMap<&'r str, RulePerf>>,
+    /// The rules that applied to each variant of expression, if `options.record_profile` is set.
+    profile: Option<RuleProfile>,
+    /// Where the time spent on each iteration went, if `options.perf_timeline` is set.
+    timeline: Option<Vec<IterationTiming>>,
+    /// The time spent trying rules, and putting rewrites back into the tree, since the last
+    /// iteration was timed. Only tracked if `options.perf_timeline` is set.
+    rule_time: Duration,
+    rebuild_time: Duration,
+    /// The number of rules tried at each path, if `options.attempt_heatmap` is set.
+    heatmap: Option<AttemptHeatmap>,
+    /// The rules to try on each variant of expression, in order, by the position of the variant
+    /// in [`Expression::VARIANT_NAMES`]. Built from `options.rule_profile` by
+    /// [`Rewriter::build_dispatch`], if it is set.
+    dispatch: Option<Vec<Vec<&'r Rule<'r>>>>,
+    /// Every rewrite applied so far, if `options.trace` is set.
+    trace: Option<Vec<TraceStep>>,
+    /// The number of threads on which to try pure rules, see [`Rewriter::try_in_parallel`].
+    rule_threads: usize,
+    /// The number of threads to share each pass between, or 0 if passes are made on one thread.
+    /// See [`Rewriter::shared_pass`].
+    work_stealing_threads: usize,
+    /// The constraints, if they are held in an arena while rewriting, see
+    /// [`Rewriter::arena_iteration`]. The constraints in the model are then left empty until
+    /// rewriting stops.
+    arena: Option<Arena>,
+    /// Copies of the model used to try rules on other threads, see [`Rewriter::try_in_parallel`].
+    worker_models: Vec<Model>,
+    /// Buffers reused by every pass, see [`Rewriter::commit`].
+    frames: Vec<Frame>,
+    scratch: Vec<Expression>,
+    /// Each expression rewritten in this iteration and what it was rewritten to, kept only if
+    /// `options.derived_meta` is not empty.
+    rewritten: Vec<(Expression, Expression)>,
+    stats: RewriterStats,
+    /// The approximate number of bytes held between iterations for copies of the constraints and
+    /// symbol table, tracked only if `options.track_memory` is set.
+    held_bytes: usize,
+    /// The most memory held at once so far, tracked only if `options.track_memory` is set.
+    peak_memory: Option<usize>,
+    /// The tier of `options.priority_tiers` whose rules are being tried, from 0 for the highest.
+    tier: usize,
+    /// When rewriting started, after setting up, from which `options.timeout` is measured.
+    start: Instant,
+    /// Whether `options.rewrite_chooser` chose to stop rewriting. No more rules are tried once it
+    /// has.
+    stopped: bool,
+    /// Whether an expression may have been marked clean without every rule being tried on it, as
+    /// a rule was skipped for its meta, declined, too slow or quarantined. The marks are kept for
+    /// the rest of the run, but are not left for the next one.
+    partial_marks: bool,
+    /// The expressions more than one rule of the same priority applied to, recorded only if
+    /// `options.report_ambiguities` is set.
+    ambiguities: Vec<RuleAmbiguity>,
+    /// The rewrites not made at each step where more than one rule applied, if
+    /// `options.record_choices` is set.
+    choices: Vec<ChoicePoint>,
+}
 
-    // Mark the expression as clean - will be marked dirty if any rule is applied
-    let mut expression = expression.clone();
-    if apply_optimizations {
-        expression.set_clean(true);
+impl<'r, 'o> Rewriter<'r, 'o> {
+    /// The number of rule applications attempted so far.
+    fn attempts(&self) -> usize {
+        self.stats.rewriter_rule_application_attempts.unwrap_or(0)
     }
 
-    let rule_results = apply_all_rules(&expression, model, rules, stats);
-    if let Some(mut new) = choose_rewrite(&rule_results) {
-        // If a rule is applied, mark the expression as dirty
-        if apply_optimizations {
-            new.new_expression.set_clean(false);
+    /// Whether `options.timeout` has been reached, or rewriting has been cancelled through
+    /// `options.cancel`. The run stops before its next iteration, so this is only checked within
+    /// an iteration that may take a long time.
+    fn interrupted(&self) -> bool {
+        self.options
+            .timeout
+            .is_some_and(|timeout| self.start.elapsed() >= timeout)
+            || self
+                .options
+                .cancel
+                .as_ref()
+                .is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+    }
+
+    /// Records the timing of an iteration that started at `start`, spent `search` looking for
+    /// `rewrites` rewrites, and `apply` applying them, if `options.perf_timeline` is set.
+    fn record_timing(
+        &mut self,
+        rewrites: usize,
+        start: Instant,
+        search: Duration,
+        apply: Duration,
+    ) {
+        // Rules tried, and rewrites rebuilt, on other threads may take longer than the search did
+        let rebuild = std::mem::take(&mut self.rebuild_time).min(search);
+        let rules = std::mem::take(&mut self.rule_time).min(search - rebuild);
+        let Some(timeline) = &mut self.timeline else {
+            return;
+        };
+        let total = start.elapsed();
+        timeline.push(IterationTiming {
+            rewrites,
+            traversal: search - rebuild - rules,
+            rules,
+            rebuild,
+            apply,
+            checks: total.saturating_sub(search + apply),
+            total,
+        });
+    }
+
+    /// Shows `expression` in logs, with `options.labeler` if it is set.
+    fn log_label(&self, expression: &Expression) -> String {
+        match &self.options.labeler {
+            Some(labeler) => labeler(expression),
+            None => expression.to_string(),
         }
-        return Some(new);
     }
 
-    let mut sub = expression.children();
-    for i in 0..sub.len() {
-        if let Some(red) = rewrite_iteration(&sub[i], model, rules, apply_optimizations, stats) {
-            sub[i] = red.new_expression;
-            // If child is dirty, make this expression dirty
+    /// How far the run that started at `start` has got, for `options.on_progress`.
+    fn progress(&self, model: &Model, start: Instant) -> Progress {
+        Progress {
+            rewrites: self.rewrites,
+            visited: self.visited,
+            dirty: self.apply_optimizations.then(|| self.dirty(model)),
+            elapsed: start.elapsed(),
+        }
+    }
 
-            if apply_optimizations && !sub[i].is_clean() {
-                expression.set_clean(false);
+    /// The number of expressions in the constraints not marked clean for every rule set.
+    ///
+    /// In the arena, sub-expressions that are clean throughout are skipped. Elsewhere that cannot
+    /// be known without visiting them, so every expression is visited.
+    fn dirty(&self, model: &Model) -> usize {
+        let is_dirty = |expression: &Expression| {
+            expression.clean_rule_sets(self.generation) & self.clean_mask != self.clean_mask
+        };
+        let mut dirty = 0;
+        match &self.arena {
+            Some(arena) => {
+                let mut stack = vec![arena.root()];
+                while let Some(id) = stack.pop() {
+                    if arena.is_clean_for(id, self.clean_mask) {
+                        continue;
+                    }
+                    dirty += usize::from(is_dirty(arena.shell(id)));
+                    stack.extend_from_slice(arena.children(id));
+                }
             }
-            if let Ok(res) = expression.with_children(sub.clone()) {
-                return Some(Reduction::new(res, red.new_top, red.symbols));
+            None => {
+                let mut stack = vec![&model.constraints];
+                while let Some(expression) = stack.pop() {
+                    dirty += usize::from(is_dirty(expression));
+                    stack.extend((0..).map_while(|i| expression.child(i)));
+                }
             }
         }
+        dirty
     }
-    None // No rules applicable to this branch of the expression
-}
 
-/// # Returns
-/// - A list of RuleResults after applying all rules to `expression`.
-/// - An empty list if no rules are applicable.
-fn apply_all_rules<'a>(
-    expression: &'a Expression,
-    model: &'a Model,
-    rules: &'a Vec<&'a Rule<'a>>,
-    stats: &mut RewriterStats,
-) -> Vec<RuleResult<'a>> {
-    let mut results = Vec::new();
-    for rule in rules {
-        match rule.apply(expression, model) {
-            Ok(red) => {
-                log::trace!(target: "file", "Rule applied: {:?}, to Expression: {:?}, resulting in: {:?}", rule, expression, red.new_expression);
-                stats.rewriter_rule_application_attempts =
-                    Some(stats.rewriter_rule_application_attempts.unwrap() + 1);
-                stats.rewriter_rule_applications =
-                    Some(stats.rewriter_rule_applications.unwrap() + 1);
-                results.push(RuleResult {
-                    rule,
-                    reduction: red,
+    /// Records that rewriting currently holds `pending` bytes on top of the copies counted in
+    /// `held_bytes`.
+    fn observe_memory(&mut self, pending: usize) {
+        if let Some(peak) = &mut self.peak_memory {
+            *peak = (*peak).max(self.held_bytes + pending);
+        }
+    }
+
+    /// Forgets the rewrites traced after the first `len`, as they were not applied to the model.
+    fn truncate_trace(&mut self, len: usize) {
+        if let Some(trace) = &mut self.trace {
+            trace.truncate(len);
+        }
+    }
+
+    /// Records that the side-effects of `reduction`, made by `rule` at the current path, were
+    /// discarded, if it has any.
+    fn discard_effects(&mut self, rule: &Rule, reduction: &Reduction, reason: DiscardReason) {
+        if !reduction.has_side_effects() {
+            return;
+        }
+        self.record_discarded(DiscardedEffects {
+            rule: rule.name.to_string(),
+            path: self.path.clone(),
+            symbols: reduction.symbols.len(),
+            new_top: !reduction.new_top.is_nothing(),
+            meta: reduction.meta.len(),
+            reason,
+        });
+    }
+
+    /// Records an ambiguity if more than one of the rules of the highest priority in `results`
+    /// applied to `expression`, the expression at `self.path`.
+    fn record_ambiguity(&mut self, results: &[RuleResult], expression: &Expression) {
+        let priority =
+            |result: &RuleResult| self.priorities.get(result.rule.name).copied().unwrap_or(0);
+        let Some(highest) = results.iter().map(priority).max() else {
+            return;
+        };
+        let rules: Vec<String> = results
+            .iter()
+            .filter(|result| priority(result) == highest)
+            .map(|result| result.rule.name.to_string())
+            .collect();
+        if rules.len() < 2 {
+            return;
+        }
+        log::debug!(target: "file", "Rules {:?} of priority {} all applied to expression {}", rules, highest, self.log_label(expression));
+        self.ambiguities.push(RuleAmbiguity {
+            path: self.path.clone(),
+            expression: self.log_label(expression),
+            priority: highest,
+            rules,
+        });
+    }
+
+    /// Records the rewrite at `chosen` in `results` as a choice point, with the others as its
+    /// alternatives, if `options.record_choices` is set and there are any others, where `model`
+    /// is the model the rules were applied to.
+    fn record_choice(&mut self, results: &[RuleResult], chosen: usize, model: &Model) {
+        if !self.options.record_choices || results.len() < 2 {
+            return;
+        }
+        let alternatives = results
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| i != chosen)
+            .map(|(_, result)| (result.rule.name.to_string(), result.reduction.clone()))
+            .collect();
+        self.choices.push(ChoicePoint {
+            rewrites: self.rewrites,
+            model: model.clone(),
+            path: self.path.clone(),
+            chosen: results[chosen].rule.name.to_string(),
+            alternatives,
+        });
+    }
+
+    fn record_discarded(&mut self, discarded: DiscardedEffects) {
+        if self.options.warn_on_discarded_effects {
+            log::warn!(target: "file", "{}", discarded);
+        }
+        self.discarded_effects.push(discarded);
+    }
+
+    /// Records that a rule failed, and quarantines it if it has now failed more than
+    /// `options.quarantine_after` times.
+    fn record_failure(&mut self, error: RuleError) {
+        let Some(index) = self.rules.iter().position(|rule| rule.name == error.rule) else {
+            return;
+        };
+        let rule = self.rules[index];
+        let failures = self.failures.entry(rule.name).or_insert(0);
+        *failures += 1;
+
+        let limit = self.options.quarantine_after.unwrap_or(0);
+        if *failures > limit {
+            log::warn!(target: "file", "Quarantining rule {} after {} failures: {}", rule, failures, error.kind);
+            self.rules.remove(index);
+            self.partial_marks = true;
+            self.quarantined.push(QuarantinedRule {
+                rule: rule.name.to_string(),
+                failures: *failures,
+                error,
+            });
+            self.build_dispatch();
+        }
+    }
+
+    /// Sorts rules of equal priority by the fraction of the times they were tried that they
+    /// applied, highest first. Rules with equal rates keep their current order.
+    ///
+    /// Rates change little from one rewrite to the next, so the rules are only ranked again once
+    /// there have been at least as many rule attempts as there are rules since they were last
+    /// ranked, and only sorted if a rule's rate has overtaken that of the rule before it.
+    fn reorder_rules(&mut self) {
+        let attempts = self.attempts();
+        if attempts < self.reordered_at + self.rules.len() {
+            return;
+        }
+        self.reordered_at = attempts;
+
+        let ranks: Vec<(u8, f64)> = self
+            .rules
+            .iter()
+            .map(|rule| {
+                let priority = self.priorities.get(rule.name).copied().unwrap_or(0);
+                let hit_rate = match self.hit_rates.get(rule.name) {
+                    Some(&(hits, tries)) if tries > 0 => hits as f64 / tries as f64,
+                    _ => 0.0,
+                };
+                (priority, hit_rate)
+            })
+            .collect();
+        let in_order = ranks.windows(2).all(|pair| {
+            pair[0].0 > pair[1].0 || (pair[0].0 == pair[1].0 && pair[0].1 >= pair[1].1)
+        });
+        if in_order {
+            return;
+        }
+
+        let mut ranked: Vec<_> = ranks.into_iter().zip(self.rules.drain(..)).collect();
+        ranked.sort_by(|((priority_a, rate_a), _), ((priority_b, rate_b), _)| {
+            priority_b.cmp(priority_a).then(rate_b.total_cmp(rate_a))
+        });
+        self.rules = ranked.into_iter().map(|(_, rule)| rule).collect();
+        self.build_dispatch();
+    }
+
+    /// Builds the table of rules to try on each variant of expression from `options.rule_profile`,
+    /// if it is set. Each variant gets the rules that may apply to it, sorted by priority, then by
+    /// how often they applied to it in the profile. Rules the profile ranks equally keep their
+    /// current order.
+    fn build_dispatch(&mut self) {
+        let Some(profile) = &self.options.rule_profile else {
+            return;
+        };
+        let priority = |rule: &Rule| self.priorities.get(rule.name).copied().unwrap_or(0);
+        let tables = Expression::VARIANT_NAMES
+            .iter()
+            .enumerate()
+            .map(|(index, variant)| {
+                let mut rules: Vec<&'r Rule<'r>> = self
+                    .rules
+                    .iter()
+                    .copied()
+                    .filter(|rule| self.reachability.may_apply_to_variant(rule, index))
+                    .collect();
+                rules.sort_by(|a, b| {
+                    priority(b).cmp(&priority(a)).then(
+                        profile
+                            .successes(variant, b.name)
+                            .cmp(&profile.successes(variant, a.name)),
+                    )
                 });
+                rules
+            })
+            .collect();
+        self.dispatch = Some(tables);
+    }
+
+    /// Attributes an error to `rule`, at the current path and iteration.
+    fn rule_error(&self, rule: &Rule, kind: RuleErrorKind) -> RuleError {
+        RuleError {
+            rule: rule.name.to_string(),
+            path: self.path.clone(),
+            iteration: self.rewrites,
+            kind,
+            checkpoint: None,
+            repro: None,
+        }
+    }
+
+    /// Visits the constraints and their sub-expressions in pre-order, and applies the first
+    /// applicable rule found.
+    ///
+    /// The constraints are rewritten in place. A pass that finds nothing to rewrite only marks the
+    /// expressions it visited as clean, so builds no expressions.
+    ///
+    /// # Returns
+    /// - Some(<step>) after applying the first applicable rule to the constraints or a
+    ///   sub-expression. The reduction holds the side-effects of the rule, but not the rewritten
+    ///   expression.
+    /// - None if no rule is applicable to the expression or any sub-expression.
+    /// - An error if a rule misbehaved in a way that `options` asks us to report. The constraints
+    ///   are left as they were.
+    fn rewrite_iteration(&mut self, model: &mut Model) -> Result<Option<Step<'r>>, RewriteError> {
+        if let Some(mut arena) = self.arena.take() {
+            let result = self.arena_iteration(&mut arena, model);
+            self.arena = Some(arena);
+            return result;
+        }
+        if self.normal_forms.is_some() {
+            return self.normalise_all(model);
+        }
+        if self.apply_optimizations
+            && model
+                .constraints
+                .is_clean_for(self.clean_mask, self.generation)
+        {
+            // Skip processing this expression if it's clean
+            return Ok(None);
+        }
+
+        if self.work_stealing_threads > 1
+            && model.constraints.size() >= self.options.parallel_min_size
+        {
+            let trace_before = self.trace.as_ref().map_or(0, Vec::len);
+            let step = self.shared_pass(model);
+            // The threads trace their rewrites in the order they made them, so they are put back
+            // in pre-order, which is the order of their paths
+            if let Some(trace) = &mut self.trace {
+                trace[trace_before..].sort_by(|a, b| a.path.cmp(&b.path));
             }
-            Err(_) => {
-                log::trace!(target: "file", "Rule attempted but not applied: {:?}, to Expression: {:?}", rule, expression);
-                stats.rewriter_rule_application_attempts =
-                    Some(stats.rewriter_rule_application_attempts.unwrap() + 1);
+            return step;
+        }
+
+        self.path.clear();
+        let found = self.search(None, model)?;
+        Ok(self.commit_with_buffers(&mut model.constraints, found))
+    }
+
+    /// Calls [`Rewriter::commit`] with the buffers kept by the rewriter.
+    fn commit_with_buffers(
+        &mut self,
+        expression: &mut Expression,
+        found: Vec<FoundRewrite<'r>>,
+    ) -> Option<Step<'r>> {
+        let mut stack = std::mem::take(&mut self.frames);
+        let mut scratch = std::mem::take(&mut self.scratch);
+        let rebuild_start = self.options.perf_timeline.then(Instant::now);
+        let step = self.commit(expression, found, &mut stack, &mut scratch);
+        if let Some(rebuild_start) = rebuild_start {
+            self.rebuild_time += rebuild_start.elapsed();
+        }
+        // Keep the buffers, emptied, for the next iteration
+        stack.clear();
+        scratch.clear();
+        self.frames = stack;
+        self.scratch = scratch;
+        step
+    }
+
+    /// Visits the expressions held in `arena` in pre-order, and applies the first applicable rule
+    /// found, as [`Rewriter::rewrite_iteration`] does.
+    ///
+    /// An expression is only rebuilt from the arena if some rule may apply to it. The rewrite,
+    /// along with any new top-level constraints, is made in the arena, so the returned reduction
+    /// only holds the new symbols.
+    fn arena_iteration(
+        &mut self,
+        arena: &mut Arena,
+        model: &Model,
+    ) -> Result<Option<Step<'r>>, RewriteError> {
+        self.path.clear();
+        // The nodes being visited, and the index of the next child of each to visit
+        let mut stack: Vec<(NodeId, usize)> = Vec::new();
+        let mut next = Some(arena.root());
+
+        loop {
+            if let Some(id) = next.take() {
+                let shell = arena.shell(id);
+                let clean = match self.apply_optimizations {
+                    true => shell.clean_rule_sets(self.generation),
+                    false => 0,
+                };
+                if self
+                    .rules
+                    .iter()
+                    .any(|rule| self.may_try(rule, shell, clean))
+                {
+                    let mut expression = arena.to_expression(id);
+                    let size = match tracks_size(self.options) {
+                        true => expression.size(),
+                        false => 0,
+                    };
+                    if let Some(mut new) =
+                        self.apply_all_rules(&mut Subtree::owned(&mut expression), model)?
+                    {
+                        let mut new_expression = std::mem::replace(
+                            &mut new.reduction.new_expression,
+                            Expression::Nothing,
+                        );
+                        let mut new_top =
+                            std::mem::replace(&mut new.reduction.new_top, Expression::Nothing);
+                        if self.apply_optimizations {
+                            new_expression.clear_clean_marks();
+                            new_top.clear_clean_marks();
+                            if !new_expression.is_clean_for(self.clean_mask, self.generation) {
+                                for &(ancestor, _) in &stack {
+                                    arena.shell_mut(ancestor).set_clean(false);
+                                }
+                            }
+                        }
+                        if tracks_size(self.options) {
+                            self.size = (self.size + new_expression.size()).saturating_sub(size);
+                        }
+
+                        let rebuild_start = self.options.perf_timeline.then(Instant::now);
+                        let ancestors: Vec<NodeId> = stack.iter().map(|&(id, _)| id).collect();
+                        arena.replace(id, new_expression, &ancestors);
+                        if !new_top.is_nothing() {
+                            if tracks_size(self.options) {
+                                self.size += new_top.size();
+                                // A new conjunction is created to hold the new top-level constraint
+                                if !matches!(arena.shell(arena.root()), Expression::And(_, _)) {
+                                    self.size += 1;
+                                }
+                            }
+                            arena.add_top(new_top);
+                        }
+                        if let Some(rebuild_start) = rebuild_start {
+                            self.rebuild_time += rebuild_start.elapsed();
+                        }
+                        return Ok(Some(Step {
+                            rules: vec![new.rule],
+                            reduction: new.reduction,
+                        }));
+                    }
+                }
+
+                // No rule applies, so mark the expression as clean for the rule sets being applied
+                if self.apply_optimizations {
+                    arena
+                        .shell_mut(id)
+                        .mark_clean_for(self.clean_mask, self.generation);
+                }
+                stack.push((id, 0));
+            }
+
+            let Some(frame) = stack.last_mut() else {
+                return Ok(None); // No rules applicable to any sub-expression
+            };
+            let (id, i) = *frame;
+            if let Some(&child) = arena.children(id).get(i) {
+                frame.1 += 1;
+                // Skip processing this sub-expression if it's clean, or no rule can change it
+                if (self.apply_optimizations && arena.is_clean_for(child, self.clean_mask))
+                    || arena.variants(child) & self.reachability.matchable == 0
+                {
+                    continue;
+                }
+                self.path.push(i);
+                if let Some(limit) = self.options.max_recursion_depth {
+                    if self.path.len() > limit {
+                        let path = self.path.clone();
+                        return Err(EngineError::DepthLimitExceeded { limit, path }.into());
+                    }
+                }
+                next = Some(child);
+            } else {
+                // The expression and all of its sub-expressions have now been visited
+                stack.pop();
+                arena.refresh(id);
+                if !stack.is_empty() {
+                    self.path.pop();
+                }
+            }
+        }
+    }
+
+    /// Makes a pass over the expression in batch mode, shared between
+    /// `self.work_stealing_threads` threads.
+    ///
+    /// The top of the expression is visited breadth-first on this thread, until there are
+    /// [`TASKS_PER_THREAD`] unvisited sub-expressions per thread. The threads then take those
+    /// sub-expressions one at a time, each visiting them with its own copy of the rewriter and
+    /// model. The results are put back in order once every thread has finished.
+    fn shared_pass(&mut self, model: &mut Model) -> Result<Option<Step<'r>>, RewriteError> {
+        let threads = self.work_stealing_threads;
+        if self.peak_memory.is_some() {
+            // The pass is made on a copy of the constraints
+            self.observe_memory(expression_bytes(model.constraints.size()));
+        }
+        let mut rules = Vec::new();
+        let mut nodes = vec![PassNode {
+            expression: model.constraints.clone(),
+            path: Vec::new(),
+            children: None,
+            visited: false,
+            changed: false,
+        }];
+
+        // Visit the top of the expression, until there is enough left to share between threads
+        let mut next = 0;
+        while next < nodes.len() && nodes.len() - next < threads * TASKS_PER_THREAD {
+            let index = next;
+            next += 1;
+            if nodes[index].visited {
+                continue;
+            }
+            nodes[index].visited = true;
+            self.path.clone_from(&nodes[index].path);
+
+            if let Some(new) =
+                self.apply_all_rules(&mut Subtree::owned(&mut nodes[index].expression), model)?
+            {
+                if new.reduction.has_side_effects() {
+                    return Err(self
+                        .rule_error(new.rule, RuleErrorKind::ImpureRewrite)
+                        .into());
+                }
+                let mut new_expression = new.reduction.new_expression;
+                if self.apply_optimizations {
+                    new_expression.clear_clean_marks();
+                }
+                nodes[index].expression = new_expression;
+                nodes[index].changed = true;
+                rules.push(new.rule);
                 continue;
             }
+
+            if self.apply_optimizations {
+                nodes[index]
+                    .expression
+                    .mark_clean_for(self.clean_mask, self.generation);
+            }
+            let mut children = Vec::new();
+            nodes[index].expression.take_children(&mut children);
+            let start = nodes.len();
+            for (i, child) in children.into_iter().enumerate() {
+                let mut path = nodes[index].path.clone();
+                path.push(i);
+                // Sub-expressions that are clean, or that no rule can change, are not visited
+                let visited = (self.apply_optimizations
+                    && child.is_clean_for(self.clean_mask, self.generation))
+                    || !self.reachability.may_change(&child);
+                if let (false, Some(limit)) = (visited, self.options.max_recursion_depth) {
+                    if path.len() > limit {
+                        return Err(EngineError::DepthLimitExceeded { limit, path }.into());
+                    }
+                }
+                nodes.push(PassNode {
+                    expression: child,
+                    path,
+                    children: None,
+                    visited,
+                    changed: false,
+                });
+            }
+            nodes[index].children = Some(start..nodes.len());
+        }
+
+        // Share the rest between threads
+        let tasks: Vec<usize> = (next..nodes.len()).filter(|&i| !nodes[i].visited).collect();
+        if !tasks.is_empty() {
+            let slots: Vec<Mutex<Expression>> = tasks
+                .iter()
+                .map(|&i| {
+                    Mutex::new(std::mem::replace(
+                        &mut nodes[i].expression,
+                        Expression::Nothing,
+                    ))
+                })
+                .collect();
+            let paths: Vec<&[usize]> = tasks.iter().map(|&i| nodes[i].path.as_slice()).collect();
+            let results = self.run_tasks(&slots, &paths, model, threads.min(tasks.len()))?;
+
+            for (&i, result) in tasks.iter().zip(results) {
+                let (expression, task_rules) = result?;
+                nodes[i].expression = expression;
+                nodes[i].changed = !task_rules.is_empty();
+                rules.extend(task_rules);
+            }
+        }
+
+        // Put the sub-expressions back into their parents, innermost first
+        for i in (0..next).rev() {
+            let Some(children) = nodes[i].children.clone() else {
+                continue;
+            };
+            let changed = children.clone().any(|child| nodes[child].changed);
+            let mut buffer: Vec<Expression> = children
+                .map(|child| std::mem::replace(&mut nodes[child].expression, Expression::Nothing))
+                .collect();
+            nodes[i].expression.restore_children(&mut buffer, 0);
+            if changed {
+                nodes[i].changed = true;
+                if self.apply_optimizations {
+                    nodes[i].expression.set_clean(false);
+                }
+            } else if self.apply_optimizations {
+                nodes[i]
+                    .expression
+                    .refresh_subtree_clean_marks(self.generation);
+            }
+        }
+
+        // Keep the clean marks, even if nothing was rewritten
+        model.constraints = std::mem::replace(&mut nodes[0].expression, Expression::Nothing);
+        if rules.is_empty() {
+            return Ok(None);
+        }
+        if tracks_size(self.options) {
+            self.size = model.constraints.size();
+        }
+        Ok(Some(Step {
+            rules,
+            reduction: Reduction::pure(Expression::Nothing),
+        }))
+    }
+
+    /// Visits the expressions in `slots` on `threads` threads, each taking the next unvisited
+    /// expression when it finishes one.
+    ///
+    /// # Returns
+    /// For each expression, in order, the expression after the visit and the rules applied to it,
+    /// or the error that stopped the visit. An error if a thread could not be started, and
+    /// `options.on_spawn_failure` asks for one.
+    fn run_tasks(
+        &mut self,
+        slots: &[Mutex<Expression>],
+        paths: &[&[usize]],
+        model: &Model,
+        threads: usize,
+    ) -> Result<Vec<TaskResult<'r>>, RewriteError> {
+        let mut workers: Vec<Rewriter<'r, 'o>> = (0..threads).map(|_| self.fork()).collect();
+        let mut models = std::mem::take(&mut self.worker_models);
+        while models.len() < threads {
+            models.push(Model::new(
+                model.variables.clone(),
+                Expression::Nothing,
+                model.context.clone(),
+            ));
+        }
+
+        let next_task = AtomicUsize::new(0);
+        let work = |worker: &mut Rewriter<'r, 'o>, worker_model: &mut Model| {
+            let mut done = Vec::new();
+            loop {
+                let task = next_task.fetch_add(1, Ordering::Relaxed);
+                let Some(slot) = slots.get(task) else {
+                    break;
+                };
+                let expression = {
+                    // Each slot is only locked once, so cannot be poisoned
+                    let mut slot = slot.lock().unwrap_or_else(|e| e.into_inner());
+                    std::mem::replace(&mut *slot, Expression::Nothing)
+                };
+                worker.path = paths[task].to_vec();
+                done.push((task, worker.visit_task(expression, worker_model)));
+            }
+            done
+        };
+
+        let mut results: Vec<Option<TaskResult<'r>>> = slots.iter().map(|_| None).collect();
+        let mut fallback = None;
+        let started = thread::scope(|scope| {
+            let mut handles = Vec::new();
+            let mut failure = None;
+            for (worker, worker_model) in workers.iter_mut().zip(models.iter_mut()) {
+                match thread::Builder::new().spawn_scoped(scope, move || work(worker, worker_model))
+                {
+                    Ok(handle) => handles.push(handle),
+                    Err(e) => failure = failure.or(Some(e)),
+                }
+            }
+
+            let started = match failure {
+                Some(e) => spawn_failed(self.options, e),
+                None => Ok(()),
+            };
+            if started.is_ok() && handles.len() < threads {
+                // Take the tasks the missing threads would have taken on this thread
+                let mut worker = self.fork();
+                let mut worker_model = Model::new(
+                    model.variables.clone(),
+                    Expression::Nothing,
+                    model.context.clone(),
+                );
+                for (task, result) in work(&mut worker, &mut worker_model) {
+                    results[task] = Some(result);
+                }
+                fallback = Some(worker);
+            }
+
+            for handle in handles {
+                match handle.join() {
+                    Ok(done) => {
+                        for (task, result) in done {
+                            results[task] = Some(result);
+                        }
+                    }
+                    Err(payload) => panic::resume_unwind(payload),
+                }
+            }
+            started
+        });
+
+        for worker in workers.into_iter().chain(fallback) {
+            self.absorb(worker);
+        }
+        self.worker_models = models;
+        started?;
+        // Every task is taken by some thread before it finishes
+        Ok(results
+            .into_iter()
+            .map(|result| result.unwrap_or_else(|| Ok((Expression::Nothing, Vec::new()))))
+            .collect())
+    }
+
+    /// Visits one of the sub-expressions shared out by [`Rewriter::shared_pass`], from
+    /// `self.path`.
+    fn visit_task(&mut self, mut expression: Expression, model: &mut Model) -> TaskResult<'r> {
+        let found = self.search(Some(&expression), model)?;
+        match self.commit_with_buffers(&mut expression, found) {
+            Some(step) => {
+                if step.reduction.has_side_effects() {
+                    return Err(self
+                        .rule_error(step.last_rule(), RuleErrorKind::ImpureRewrite)
+                        .into());
+                }
+                Ok((expression, step.rules))
+            }
+            None => Ok((expression, Vec::new())),
+        }
+    }
+
+    /// A copy of the rewriter for another thread to make part of a pass with, with the same rules
+    /// and options, but none of the counts and results gathered so far.
+    fn fork(&self) -> Rewriter<'r, 'o> {
+        Rewriter {
+            rules: self.rules.clone(),
+            options: self.options,
+            apply_optimizations: self.apply_optimizations,
+            clean_mask: self.clean_mask,
+            generation: self.generation,
+            tier: self.tier,
+            rule_masks: self.rule_masks.clone(),
+            path: Vec::new(),
+            span: None,
+            rewrites: self.rewrites,
+            iterations: self.iterations,
+            visited: 0,
+            traced: 0,
+            size: 0,
+            slow_rules: Vec::new(),
+            discarded_effects: Vec::new(),
+            failures: HashMap::new(),
+            quarantined: Vec::new(),
+            failed_attempts: self.failed_attempts.clone(),
+            priorities: self.priorities.clone(),
+            hit_rates: HashMap::new(),
+            reordered_at: 0,
+            normal_forms: None,
+            reachability: self.reachability.clone(),
+            rule_perf: self.rule_perf.as_ref().map(|_| HashMap::new()),
+            profile: self.profile.as_ref().map(|_| RuleProfile::new()),
+            timeline: None,
+            rule_time: Duration::ZERO,
+            rebuild_time: Duration::ZERO,
+            heatmap: self.heatmap.as_ref().map(|_| AttemptHeatmap::new()),
+            dispatch: self.dispatch.clone(),
+            trace: self.trace.as_ref().map(|_| Vec::new()),
+            // The threads sharing the pass try rules one at a time
+            rule_threads: 0,
+            work_stealing_threads: 0,
+            arena: None,
+            worker_models: Vec::new(),
+            frames: Vec::new(),
+            scratch: Vec::new(),
+            rewritten: Vec::new(),
+            stats: RewriterStats {
+                is_optimization_enabled: self.stats.is_optimization_enabled,
+                rewriter_run_time: None,
+                rewriter_rule_application_attempts: Some(0),
+                rewriter_rule_applications: Some(0),
+                rewriter_peak_memory: None,
+            },
+            held_bytes: 0,
+            // Rewrites found by other threads are not tracked
+            peak_memory: None,
+            start: self.start,
+            stopped: false,
+            partial_marks: false,
+            ambiguities: Vec::new(),
+            choices: Vec::new(),
+        }
+    }
+
+    /// Adds the counts and results gathered by a [`Rewriter::fork`] of this rewriter to its own.
+    fn absorb(&mut self, worker: Rewriter<'r, 'o>) {
+        self.visited += worker.visited;
+        self.rule_time += worker.rule_time;
+        self.rebuild_time += worker.rebuild_time;
+        self.stats.rewriter_rule_application_attempts = Some(self.attempts() + worker.attempts());
+        self.stats.rewriter_rule_applications = Some(
+            self.stats.rewriter_rule_applications.unwrap_or(0)
+                + worker.stats.rewriter_rule_applications.unwrap_or(0),
+        );
+        self.slow_rules.extend(worker.slow_rules);
+        self.partial_marks |= worker.partial_marks;
+        self.discarded_effects.extend(worker.discarded_effects);
+        self.ambiguities.extend(worker.ambiguities);
+        self.choices.extend(worker.choices);
+        self.failed_attempts.extend(worker.failed_attempts);
+        for (rule, (hits, tries)) in worker.hit_rates {
+            let rate = self.hit_rates.entry(rule).or_insert((0, 0));
+            rate.0 += hits;
+            rate.1 += tries;
+        }
+        if let (Some(rule_perf), Some(worker_perf)) = (&mut self.rule_perf, worker.rule_perf) {
+            for (rule, perf) in worker_perf {
+                let total = rule_perf.entry(rule).or_default();
+                total.attempts += perf.attempts;
+                total.successes += perf.successes;
+                total.nodes_produced += perf.nodes_produced;
+                total.time += perf.time;
+            }
+        }
+        if let (Some(profile), Some(worker_profile)) = (&mut self.profile, worker.profile) {
+            profile.merge(&worker_profile);
+        }
+        if let (Some(heatmap), Some(worker_heatmap)) = (&mut self.heatmap, worker.heatmap) {
+            heatmap.merge(&worker_heatmap);
+        }
+        if let (Some(trace), Some(worker_trace)) = (&mut self.trace, worker.trace) {
+            trace.extend(worker_trace);
+        }
+    }
+
+    /// Visits the expression and its sub-expressions in pre-order, trying rules on each, without
+    /// changing anything.
+    ///
+    /// The traversal keeps its own stack of partially visited sub-expressions rather than
+    /// recursing, so that deeply nested expressions do not overflow the call stack.
+    ///
+    /// Searches `expression`, or the constraints of `model` if it is `None`. The meta in scope of
+    /// each binder is set in `model` while its sub-expressions are visited, and put back after.
+    ///
+    /// # Returns
+    /// The first applicable rewrite found, or in batch mode, every rewrite found without visiting
+    /// the sub-expressions of a rewritten expression. Each is numbered by the order in which the
+    /// expression it rewrites was visited, for [`Rewriter::commit`].
+    fn search(
+        &mut self,
+        expression: Option<&Expression>,
+        model: &mut Model,
+    ) -> Result<Vec<FoundRewrite<'r>>, RewriteError> {
+        // The values replaced by the meta in scope of each binder being visited, and the length
+        // of the stack once the binder was pushed onto it
+        let mut scopes = Vec::new();
+        let found = self.search_in_scopes(expression, model, &mut scopes);
+        for (scope, _) in scopes.into_iter().rev() {
+            model.meta.leave_scope(scope);
+        }
+        found
+    }
+
+    /// [`Rewriter::search`], leaving the binders it was visiting when it returned in `scopes`.
+    fn search_in_scopes(
+        &mut self,
+        expression: Option<&Expression>,
+        model: &mut Model,
+        scopes: &mut Vec<(MetaScope, usize)>,
+    ) -> Result<Vec<FoundRewrite<'r>>, RewriteError> {
+        let mut found = Vec::new();
+        // The expressions being visited, and the index of the next child of each to visit
+        let mut stack: Vec<(&Expression, usize)> = Vec::new();
+        let mut next = Some(expression.unwrap_or(&model.constraints));
+        let mut visits = 0;
+
+        loop {
+            if let Some(expression) = next.take() {
+                let visit = visits;
+                visits += 1;
+                if let Some(mut new) =
+                    self.apply_all_rules(&mut Subtree::borrowed(expression), model)?
+                {
+                    // If a rule is applied, mark the expression as dirty. Rules often build new
+                    // expressions from the metadata of old ones, so the whole of the new
+                    // expression is marked dirty, not only its root.
+                    if self.apply_optimizations {
+                        new.reduction.new_expression.clear_clean_marks();
+                        new.reduction.new_top.clear_clean_marks();
+                    }
+                    if tracks_size(self.options) {
+                        self.size = (self.size + new.reduction.new_expression.size())
+                            .saturating_sub(expression.size());
+                    }
+                    if !self.options.derived_meta.is_empty() {
+                        self.rewritten
+                            .push((expression.clone(), new.reduction.new_expression.clone()));
+                        if !new.reduction.new_top.is_nothing() {
+                            self.rewritten
+                                .push((Expression::Nothing, new.reduction.new_top.clone()));
+                        }
+                    }
+
+                    found.push(FoundRewrite { visit, result: new });
+                    if !self.options.batch_rewrites {
+                        return Ok(found);
+                    }
+                    // Carry on with the siblings of the rewritten expression, without visiting
+                    // its sub-expressions
+                    if !stack.is_empty() {
+                        self.path.pop();
+                    }
+                    continue;
+                }
+                stack.push((expression, 0));
+                if let Some(scope) = self
+                    .options
+                    .binders
+                    .as_ref()
+                    .and_then(|binder| binder(expression, model))
+                {
+                    scopes.push((model.meta.enter_scope(scope), stack.len()));
+                }
+            }
+
+            let Some(frame) = stack.last_mut() else {
+                return Ok(found); // Every sub-expression has been visited
+            };
+            let (expression, i) = *frame;
+            match expression.child(i) {
+                Some(child) => {
+                    frame.1 += 1;
+                    // Skip processing this sub-expression if it's clean, or no rule can change it
+                    if (self.apply_optimizations
+                        && child.is_clean_for(self.clean_mask, self.generation))
+                        || !self.reachability.may_change(child)
+                    {
+                        continue;
+                    }
+                    self.path.push(i);
+                    if let Some(limit) = self.options.max_recursion_depth {
+                        if self.path.len() > limit {
+                            let path = self.path.clone();
+                            return Err(EngineError::DepthLimitExceeded { limit, path }.into());
+                        }
+                    }
+                    next = Some(child);
+                }
+                None => {
+                    if scopes
+                        .last()
+                        .is_some_and(|(_, depth)| *depth == stack.len())
+                    {
+                        if let Some((scope, _)) = scopes.pop() {
+                            model.meta.leave_scope(scope);
+                        }
+                    }
+                    stack.pop();
+                    if !stack.is_empty() {
+                        self.path.pop();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Makes the rewrites found by [`Rewriter::search`] in place, and marks the expressions it
+    /// visited without finding a rewrite as clean.
+    ///
+    /// The expressions are visited again in the same order, without trying any rules. The
+    /// children of each expression on `stack` are moved out of it onto the end of `scratch` while
+    /// they are visited, and moved back once they have all been visited, so no expression is
+    /// rebuilt. Both buffers are kept between iterations, so this does not allocate once they have
+    /// grown.
+    ///
+    /// # Returns
+    /// - Some(<step>) holding the rules applied and their combined side-effects, if any rewrite
+    ///   was found.
+    /// - None otherwise.
+    fn commit(
+        &self,
+        root: &mut Expression,
+        found: Vec<FoundRewrite<'r>>,
+        stack: &mut Vec<Frame>,
+        scratch: &mut Vec<Expression>,
+    ) -> Option<Step<'r>> {
+        let mut found = found.into_iter().peekable();
+        let mut rules = Vec::new();
+        let mut side_effects = Reduction::pure(Expression::Nothing);
+        let mut next = Some(std::mem::replace(root, Expression::Nothing));
+        let mut visits = 0;
+        // Whether the rest of the expressions were not visited by the search
+        let mut finished = false;
+
+        loop {
+            if let Some(mut expression) = next.take() {
+                let visit = visits;
+                visits += 1;
+                if let Some(new) = found.next_if(|found| found.visit == visit) {
+                    rules.push(new.result.rule);
+                    side_effects.symbols.extend(new.result.reduction.symbols);
+                    side_effects.meta.extend(new.result.reduction.meta);
+                    side_effects.new_top =
+                        and_top(side_effects.new_top, new.result.reduction.new_top);
+                    let new_expression = new.result.reduction.new_expression;
+                    match stack.last_mut() {
+                        Some(parent) => {
+                            scratch[parent.start + parent.next_child - 1] = new_expression;
+                            parent.changed = true;
+                        }
+                        None => *root = new_expression,
+                    }
+                    finished = !self.options.batch_rewrites;
+                    continue;
+                }
+
+                // No rule applies, so mark the expression as clean for the rule sets being applied.
+                // In a tier pass only the rules of the tier have been tried, so the mark is only
+                // good for the rest of the pass, unless the pass reaches the fixpoint
+                if self.apply_optimizations && !self.stopped {
+                    expression.mark_clean_for(self.clean_mask, self.generation);
+                }
+
+                let start = scratch.len();
+                expression.take_children(scratch);
+                stack.push(Frame {
+                    expression,
+                    start,
+                    next_child: 0,
+                    changed: false,
+                });
+            }
+
+            let Some(frame) = stack.last_mut() else {
+                break;
+            };
+
+            // The frame is the innermost, so all of the expressions after its start are its children
+            if !finished && frame.start + frame.next_child < scratch.len() {
+                let i = frame.next_child;
+                frame.next_child += 1;
+                let child = &mut scratch[frame.start + i];
+
+                // Skip the sub-expressions the search skipped
+                if (self.apply_optimizations
+                    && child.is_clean_for(self.clean_mask, self.generation))
+                    || !self.reachability.may_change(child)
+                {
+                    continue;
+                }
+                // Move the child out while it is visited, rather than cloning it
+                next = Some(std::mem::replace(child, Expression::Nothing));
+            } else if let Some(mut done) = stack.pop() {
+                // Put the visited expression back into its parent, keeping its clean marks and
+                // those of its children, which stay valid for later visits. If some of its
+                // sub-expressions have been rewritten, it is no longer clean. Otherwise it is clean
+                // throughout wherever its children are.
+                match done.changed {
+                    true => {
+                        done.expression.restore_children(scratch, done.start);
+                        if self.apply_optimizations {
+                            done.expression.set_clean(false);
+                        }
+                    }
+                    false => {
+                        done.expression
+                            .restore_unchanged_children(scratch, done.start);
+                        if self.apply_optimizations && !self.stopped {
+                            done.expression.refresh_subtree_clean_marks(self.generation);
+                        }
+                    }
+                }
+                match stack.last_mut() {
+                    Some(parent) => {
+                        scratch[parent.start + parent.next_child - 1] = done.expression;
+                        parent.changed |= done.changed;
+                    }
+                    None => *root = done.expression,
+                }
+            }
+        }
+
+        match rules.is_empty() {
+            true => None,
+            false => Some(Step {
+                rules,
+                reduction: side_effects,
+            }),
+        }
+    }
+
+    /// Normalises the whole expression bottom-up, using and filling the normal form cache.
+    ///
+    /// # Returns
+    /// - Some(<step>) holding every rule applied, if any rule applied.
+    /// - None if the expression is already in normal form.
+    fn normalise_all(&mut self, model: &mut Model) -> Result<Option<Step<'r>>, RewriteError> {
+        self.path.clear();
+        self.scratch.clear();
+        let mut rules = Vec::new();
+        let normal = self.normalise(model.constraints.clone(), model, &mut rules)?;
+        if rules.is_empty() {
+            return Ok(None);
+        }
+        model.constraints = normal;
+        if tracks_size(self.options) {
+            self.size = model.constraints.size();
+        }
+        Ok(Some(Step {
+            rules,
+            reduction: Reduction::pure(Expression::Nothing),
+        }))
+    }
+
+    /// Returns the normal form of `expression`, adding the rules applied to `rules`.
+    ///
+    /// The children are normalised first, then rules are applied to the expression itself, and
+    /// the result normalised again, until no rule applies. If the rewrite budget runs out, the
+    /// timeout is reached or rewriting is cancelled, the expression is returned as it is, and is
+    /// not cached.
+    ///
+    /// The expressions being normalised are kept on a stack, as in [`Rewriter::commit`], so that
+    /// deep expressions do not overflow the call stack.
+    fn normalise(
+        &mut self,
+        expression: Expression,
+        model: &Model,
+        rules: &mut Vec<&'r Rule<'r>>,
+    ) -> Result<Expression, RewriteError> {
+        let mut stack: Vec<NormalFrame> = Vec::new();
+        // The expressions being normalised, and those rewritten into them, with their hashes
+        let mut sources: Vec<(u64, Expression)> = Vec::new();
+        // The next expression to normalise, and the index in `sources` of the first expression it
+        // was rewritten from
+        let mut next = Some((expression, 0));
+        let mut normal = Expression::Nothing;
+
+        loop {
+            if let Some((mut expression, sources_start)) = next.take() {
+                let hash = expression.subtree_hash();
+                let cached = self
+                    .normal_forms
+                    .as_ref()
+                    .and_then(|cache| cache.get(&hash))
+                    .filter(|(source, _)| *source == expression)
+                    .map(|(_, normal)| normal.clone());
+                match cached {
+                    Some(cached) => {
+                        self.finish_normal_form(
+                            cached,
+                            &mut sources,
+                            sources_start,
+                            rules.len(),
+                            &mut stack,
+                            &mut normal,
+                        );
+                    }
+                    None => {
+                        sources.push((hash, expression.clone()));
+                        let start = self.scratch.len();
+                        expression.take_children(&mut self.scratch);
+                        stack.push(NormalFrame {
+                            expression,
+                            start,
+                            next_child: 0,
+                            sources_start,
+                        });
+                    }
+                }
+            }
+
+            let Some(frame) = stack.last_mut() else {
+                break;
+            };
+
+            // The frame is the innermost, so all of the expressions after its start are its children
+            if frame.start + frame.next_child < self.scratch.len() {
+                let i = frame.next_child;
+                frame.next_child += 1;
+                let child = &mut self.scratch[frame.start + i];
+                // A sub-expression no rule can change is already in normal form, and one left
+                // after rewriting is interrupted is left as it is
+                if !self.reachability.may_change(child) || self.interrupted() {
+                    continue;
+                }
+                let child = std::mem::replace(child, Expression::Nothing);
+                self.path.push(i);
+                if let Some(limit) = self.options.max_recursion_depth {
+                    if self.path.len() > limit {
+                        let path = self.path.clone();
+                        return Err(EngineError::DepthLimitExceeded { limit, path }.into());
+                    }
+                }
+                next = Some((child, sources.len()));
+            } else if let Some(mut done) = stack.pop() {
+                done.expression
+                    .restore_children(&mut self.scratch, done.start);
+                let new = match budget_exhausted(
+                    self.options,
+                    self.rewrites + rules.len(),
+                    self.attempts(),
+                ) || self.interrupted()
+                {
+                    true => None,
+                    false => {
+                        self.apply_all_rules(&mut Subtree::owned(&mut done.expression), model)?
+                    }
+                };
+                match new {
+                    Some(new) => {
+                        if new.reduction.has_side_effects() {
+                            return Err(self
+                                .rule_error(new.rule, RuleErrorKind::ImpureRewrite)
+                                .into());
+                        }
+                        rules.push(new.rule);
+                        next = Some((new.reduction.new_expression, done.sources_start));
+                    }
+                    None => self.finish_normal_form(
+                        done.expression,
+                        &mut sources,
+                        done.sources_start,
+                        rules.len(),
+                        &mut stack,
+                        &mut normal,
+                    ),
+                }
+            }
+        }
+        Ok(normal)
+    }
+
+    /// Caches `normal` as the normal form of the expressions in `sources` from `sources_start`
+    /// onwards, then moves it into the expression it is a child of, at the top of
+    /// `stack`, or into `root` if there is none.
+    fn finish_normal_form(
+        &mut self,
+        normal: Expression,
+        sources: &mut Vec<(u64, Expression)>,
+        sources_start: usize,
+        new_rewrites: usize,
+        stack: &mut [NormalFrame],
+        root: &mut Expression,
+    ) {
+        let sources = sources.drain(sources_start..);
+        // If the budget ran out, or rewriting was stopped or interrupted, `normal` may not be in
+        // normal form yet
+        if !budget_exhausted(self.options, self.rewrites + new_rewrites, self.attempts())
+            && !self.stopped
+            && !self.interrupted()
+        {
+            if let Some(cache) = &mut self.normal_forms {
+                for (hash, source) in sources {
+                    cache.insert(hash, (source, normal.clone()));
+                }
+            }
+        }
+        match stack.last_mut() {
+            Some(parent) => {
+                self.scratch[parent.start + parent.next_child - 1] = normal;
+                self.path.pop();
+            }
+            None => *root = normal,
         }
     }
-    results
+
+    /// # Returns
+    /// - The rewrite to use, after applying all rules to the expression in `subtree`: the first
+    ///   found, or the one chosen by `options.rewrite_chooser` or `options.rewrite_selector` if
+    ///   either is set.
+    /// - None if no rules are applicable, the selector chose none of the rewrites, or rewriting
+    ///   has been stopped.
+    ///
+    /// A rule may take the expression from `subtree`. No more rules are tried after that, unless
+    /// a selector is set, in which case the expression is put back for the other rules.
+    fn apply_all_rules(
+        &mut self,
+        subtree: &mut Subtree,
+        model: &Model,
+    ) -> Result<Option<RuleResult<'r>>, RewriteError> {
+        if self.stopped {
+            return Ok(None);
+        }
+        let selects =
+            self.options.rewrite_chooser.is_some() || self.options.rewrite_selector.is_some();
+        // Every rule is tried if a rewrite is to be selected, or ambiguities or choices are to be
+        // found
+        let tries_all = selects || self.options.report_ambiguities || self.options.record_choices;
+        let mut results = Vec::new();
+        self.visited += 1;
+        self.span = subtree.span();
+        let clean = match self.apply_optimizations {
+            true => subtree.clean_rule_sets(self.generation),
+            false => 0,
+        };
+        // Hashed before any rule can take the expression, so that rewrites that leave it unchanged
+        // are caught either way
+        let checks_no_ops = self.options.on_no_op != NoOpPolicy::Allow;
+        let hash = match self.options.memoize_failures || checks_no_ops {
+            true => subtree.subtree_hash(),
+            false => 0,
+        };
+
+        // A rule may move the expression out of an owned subtree, so it is copied first to trace or
+        // observe
+        let observed = self.trace.is_some() || !self.options.observers.is_empty();
+        let mut before = match (observed && subtree.is_owned()) || tries_all {
+            true => Some(Expression::clone(subtree)),
+            false => None,
+        };
+
+        let rules = match &self.dispatch {
+            Some(tables) => &tables[subtree.variant_index()],
+            None => &self.rules,
+        };
+        let mut candidates: Vec<&'r Rule<'r>> = rules
+            .iter()
+            .copied()
+            .filter(|rule| {
+                let memoized = self.options.memoize_failures
+                    && self.failed_attempts.contains(&(rule.name, hash));
+                !memoized && self.may_try(rule, subtree, clean)
+            })
+            .collect();
+        let tried = candidates.len();
+        candidates.retain(|rule| rule.can_read(&model.meta));
+        self.partial_marks |= candidates.len() < tried;
+        let mut trials = self.try_in_parallel(&candidates, subtree, model)?;
+
+        for (rule, trial) in candidates.into_iter().zip(trials.iter_mut()) {
+            self.stats.rewriter_rule_application_attempts = Some(self.attempts() + 1);
+            if self.options.adaptive_rule_order {
+                self.hit_rates.entry(rule.name).or_insert((0, 0)).1 += 1;
+            }
+            let (application, elapsed, taken) = match trial.take() {
+                Some((application, elapsed)) => (application, elapsed, false),
+                None => {
+                    let rule_start = Instant::now();
+                    let application = try_rule(rule, subtree, model, self.options.catch_panics);
+                    (application, rule_start.elapsed(), subtree.is_taken())
+                }
+            };
+            #[cfg(feature = "tracing")]
+            tracing::trace!(
+                rule = rule.name,
+                path = ?self.path,
+                applies = matches!(application, Ok(Ok(_))),
+                elapsed = ?elapsed,
+                "rule attempted"
+            );
+            #[cfg(feature = "metrics")]
+            metrics::histogram!("conjure_rewriter_rule_duration_seconds", "rule" => rule.name.to_string())
+                .record(elapsed);
+            for observer in &self.options.observers {
+                observer.on_attempt(rule, &self.path, matches!(application, Ok(Ok(_))));
+            }
+            if self.options.perf_timeline {
+                self.rule_time += elapsed;
+            }
+            if let Some(rule_perf) = &mut self.rule_perf {
+                let perf = rule_perf.entry(rule.name).or_default();
+                perf.attempts += 1;
+                perf.time += elapsed;
+            }
+            if let Some(heatmap) = &mut self.heatmap {
+                heatmap.record(&self.path);
+            }
+
+            // Panics are raised when the rule is reached, so a panic in a rule tried in parallel
+            // is only raised if it would have been raised when trying the rules in order
+            let application = match application {
+                Ok(application) => application,
+                Err(payload) if self.options.catch_panics => {
+                    let message = panic_message(payload.as_ref());
+                    return Err(self
+                        .rule_error(rule, RuleErrorKind::Panicked { message })
+                        .into());
+                }
+                Err(payload) => panic::resume_unwind(payload),
+            };
+            if taken && application.is_err() {
+                return Err(self
+                    .rule_error(rule, RuleErrorKind::TakenWithoutRewrite)
+                    .into());
+            }
+
+            // A rule that took the expression has applied, as it cannot be given back
+            if let Some(threshold) = self
+                .options
+                .slow_rule_threshold
+                .filter(|_| !self.options.deterministic)
+            {
+                if elapsed > threshold && !taken {
+                    log::warn!(target: "file", "Rule {} took {:?} on expression {}, treating it as not applicable", rule, elapsed, self.log_label(subtree));
+                    self.slow_rules.push(SlowRule {
+                        rule: rule.name.to_string(),
+                        path: self.path.clone(),
+                        elapsed,
+                    });
+                    self.partial_marks = true;
+                    if let Ok(red) = &application {
+                        self.discard_effects(rule, red, DiscardReason::TooSlow);
+                    }
+                    continue;
+                }
+            }
+
+            match application {
+                Ok(mut red) => {
+                    let original = match taken {
+                        true => before.as_ref(),
+                        false => Some(&**subtree),
+                    };
+                    if checks_no_ops && is_no_op(hash, original, &red) {
+                        match self.options.on_no_op {
+                            NoOpPolicy::Skip => {
+                                if taken {
+                                    subtree.restore(red.new_expression);
+                                }
+                                log::warn!(target: "file", "Rule {} did not change expression {}, skipping it", rule, self.log_label(subtree));
+                                if self.options.memoize_failures {
+                                    self.failed_attempts.insert((rule.name, hash));
+                                }
+                                continue;
+                            }
+                            NoOpPolicy::Error => {
+                                return Err(self
+                                    .rule_error(rule, RuleErrorKind::NoOpRewrite)
+                                    .into());
+                            }
+                            NoOpPolicy::Allow => {}
+                        }
+                    }
+                    if let Some(type_name) = rule.undeclared_write(&red) {
+                        return Err(self
+                            .rule_error(rule, RuleErrorKind::UndeclaredMeta { type_name })
+                            .into());
+                    }
+
+                    if self.options.provenance {
+                        let provenance = Provenance {
+                            rule: rule.name.to_string(),
+                            iteration: self.iterations,
+                        };
+                        for produced in [&mut red.new_expression, &mut red.new_top] {
+                            if let Some(metadata) = produced.metadata_mut() {
+                                metadata.provenance = Some(Box::new(provenance.clone()));
+                            }
+                        }
+                    }
+                    if let Some(policy) = &self.options.span_policy {
+                        for produced in [&mut red.new_expression, &mut red.new_top] {
+                            produced.assign_spans(self.span, policy.as_ref());
+                        }
+                    }
+                    log::trace!(target: "file", "Rule applied: {:?}, to Expression: {}, resulting in: {}", rule, self.log_label(subtree), self.log_label(&red.new_expression));
+                    #[cfg(feature = "tracing")]
+                    tracing::debug!(rule = rule.name, path = ?self.path, "rule applied");
+                    self.stats.rewriter_rule_applications =
+                        Some(self.stats.rewriter_rule_applications.unwrap_or(0) + 1);
+                    if let Some(rule_perf) = &mut self.rule_perf {
+                        let perf = rule_perf.entry(rule.name).or_default();
+                        perf.successes += 1;
+                        perf.nodes_produced += red.new_expression.size();
+                        if !red.new_top.is_nothing() {
+                            perf.nodes_produced += red.new_top.size();
+                        }
+                    }
+                    if let Some(profile) = &mut self.profile {
+                        profile.record(subtree.variant_name(), rule.name);
+                    }
+                    // With a selector, the rewrite to use is only known once every rule is tried
+                    if !selects && !results.is_empty() {
+                        // Only the first applicable rule is used
+                        self.discard_effects(rule, &red, DiscardReason::Superseded);
+                    }
+                    if !selects && observed && results.is_empty() {
+                        let before = before.get_or_insert_with(|| Expression::clone(subtree));
+                        self.observe_applied(rule, before, &red, elapsed);
+                    }
+                    results.push(RuleResult {
+                        rule,
+                        reduction: red,
+                        elapsed,
+                    });
+                    if self.peak_memory.is_some() {
+                        let pending = results
+                            .iter()
+                            .map(|result| reduction_bytes(&result.reduction))
+                            .sum();
+                        self.observe_memory(pending);
+                    }
+                    if self.options.adaptive_rule_order {
+                        self.hit_rates.entry(rule.name).or_insert((0, 0)).0 += 1;
+                    }
+                    if !tries_all && (self.options.adaptive_rule_order || self.dispatch.is_some()) {
+                        // Only the first applicable rule is used, so do not try the others
+                        break;
+                    }
+                    match (taken, &before) {
+                        (true, Some(before)) if tries_all => {
+                            subtree.restore(before.clone());
+                        }
+                        // No other rule can be applied to the expression
+                        (true, _) => break,
+                        (false, _) => {}
+                    }
+                }
+                Err(ApplicationError::RuleNotApplicable | ApplicationError::NotApplicable(_)) => {
+                    log::trace!(target: "file", "Rule attempted but not applied: {:?}, to Expression: {}", rule, self.log_label(subtree));
+                    if self.options.memoize_failures {
+                        self.failed_attempts.insert((rule.name, hash));
+                    }
+                    continue;
+                }
+                Err(e) => match self.options.rule_error_policy(rule) {
+                    RuleErrorPolicy::Ignore => {
+                        log::trace!(target: "file", "Rule attempted but not applied: {:?}, to Expression: {}", rule, self.log_label(subtree));
+                        continue;
+                    }
+                    RuleErrorPolicy::Warn => {
+                        log::warn!(target: "file", "Rule {} failed on expression {}: {}, skipping it", rule, self.log_label(subtree), e);
+                        continue;
+                    }
+                    RuleErrorPolicy::Error => {
+                        return Err(self.rule_error(rule, RuleErrorKind::Failed(e)).into());
+                    }
+                },
+            }
+        }
+
+        if self.options.report_ambiguities {
+            let expression = before.as_ref().unwrap_or(&**subtree);
+            self.record_ambiguity(&results, expression);
+        }
+        if !selects {
+            self.record_choice(&results, 0, model);
+            return Ok(results.into_iter().next());
+        }
+        if results.is_empty() {
+            return Ok(None);
+        }
+        let candidates: Vec<(&str, &Reduction)> = results
+            .iter()
+            .map(|result| (result.rule.name, &result.reduction))
+            .collect();
+        let choice = match (
+            &self.options.rewrite_chooser,
+            &self.options.rewrite_selector,
+        ) {
+            (Some(chooser), _) => chooser(&CandidateRewrites {
+                // The expression is copied whenever there is a selector
+                expression: before.as_ref().unwrap_or(&**subtree),
+                path: &self.path,
+                model,
+                rewrites: &candidates,
+            }),
+            (None, Some(selector)) => match selector(&candidates) {
+                Some(i) => RewriteChoice::Apply(i),
+                None => RewriteChoice::Decline,
+            },
+            (None, None) => RewriteChoice::Apply(0),
+        };
+        let chosen = match choice {
+            RewriteChoice::Apply(i) => Some(i).filter(|&i| i < results.len()),
+            RewriteChoice::Decline => None,
+            RewriteChoice::Stop => {
+                log::info!(target: "file", "Rewriting stopped by choose_rewrite at {}", self.log_label(subtree));
+                self.stopped = true;
+                None
+            }
+        };
+        for (i, result) in results.iter().enumerate() {
+            let reason = match chosen {
+                Some(chosen) if chosen == i => continue,
+                Some(_) => DiscardReason::Superseded,
+                None => DiscardReason::Declined,
+            };
+            self.discard_effects(result.rule, &result.reduction, reason);
+        }
+        let Some(chosen) = chosen else {
+            self.partial_marks = true;
+            return Ok(None);
+        };
+        self.record_choice(&results, chosen, model);
+        let result = results.swap_remove(chosen);
+        if observed {
+            let before = before.unwrap_or_else(|| Expression::clone(subtree));
+            self.observe_applied(result.rule, &before, &result.reduction, result.elapsed);
+        }
+        Ok(Some(result))
+    }
+
+    /// Tells the observers, and the trace, that `rule` rewrote `before`, the expression at
+    /// `self.path`, as `reduction` says, taking `elapsed`.
+    fn observe_applied(
+        &mut self,
+        rule: &Rule,
+        before: &Expression,
+        reduction: &Reduction,
+        elapsed: Duration,
+    ) {
+        for observer in &self.options.observers {
+            observer.on_applied(rule, &self.path, before, reduction);
+        }
+        let filter = self.options.trace_filter.as_ref();
+        let allowed = filter.is_none_or(|f| f.allows(rule.name, &self.path));
+        if let (Some(trace), true) = (&mut self.trace, allowed) {
+            let mut edits = before.diff(&reduction.new_expression);
+            for edit in &mut edits {
+                edit.path_mut().splice(0..0, self.path.iter().copied());
+            }
+            let step = TraceStep {
+                rule: rule.name.to_string(),
+                path: self.path.clone(),
+                edits,
+                new_top: reduction.new_top.clone(),
+                elapsed,
+                watches: Vec::new(),
+                meta_diffs: Vec::new(),
+            };
+            if filter.is_none_or(|f| f.matches(&step)) {
+                if filter.is_none_or(|f| f.samples(self.traced)) {
+                    trace.push(step);
+                }
+                self.traced += 1;
+            }
+        }
+    }
+
+    /// Returns true unless `rule` is known not to apply to `expression`, going only by the
+    /// expression itself and not its sub-expressions, where `clean` is the rule sets known not to
+    /// apply to it.
+    fn may_try(&self, rule: &Rule, expression: &Expression, clean: u64) -> bool {
+        // Skip rules whose rule sets are all known not to apply
+        let clean_for_rule = self.apply_optimizations
+            && self
+                .rule_masks
+                .get(rule.name)
+                .is_some_and(|&mask| mask & !clean == 0);
+        !clean_for_rule && self.in_tier(rule) && self.reachability.may_apply(rule, expression)
+    }
+
+    /// Returns true if `rule` is in the tier of `options.priority_tiers` being tried.
+    fn in_tier(&self, rule: &Rule) -> bool {
+        let tiers = &self.options.priority_tiers;
+        if tiers.is_empty() {
+            return true;
+        }
+        let priority = self.priorities.get(rule.name).copied().unwrap_or(0);
+        tiers
+            .iter()
+            .take_while(|&&lowest| priority < lowest)
+            .count()
+            == self.tier
+    }
+
+    /// Forgets where rules were found not to apply, as far as `invalidation` says is out of date.
+    fn invalidate(&mut self, invalidation: MetaInvalidation) {
+        match invalidation {
+            MetaInvalidation::Nothing => return,
+            MetaInvalidation::Rules(rules) => self
+                .failed_attempts
+                .retain(|(rule, _)| !rules.contains(rule)),
+            MetaInvalidation::All => self.failed_attempts.clear(),
+        }
+        // Clean marks are not kept per rule
+        if self.apply_optimizations {
+            self.generation = new_generation();
+            if let Some(arena) = &mut self.arena {
+                arena.set_generation(self.generation);
+            }
+        }
+    }
+
+    /// Moves on to trying the rules of `tier`. Expressions are only marked clean for the rules
+    /// of one tier, so the marks made so far are dropped. The marks of the last tier hold for
+    /// every rule once it reaches the fixpoint, as every earlier tier has been exhausted since
+    /// the last rewrite; a run that stops before then leaves no marks, see
+    /// [`Model::clean_generation`].
+    fn set_tier(&mut self, tier: usize) {
+        self.tier = tier;
+        if self.apply_optimizations {
+            self.generation = new_generation();
+            if let Some(arena) = &mut self.arena {
+                arena.set_generation(self.generation);
+            }
+        }
+    }
+
+    /// Tries the pure rules among `rules` on other threads, if `options.rule_threads` is more than
+    /// one and there are at least two pure rules to try.
+    ///
+    /// # Returns
+    /// The result of each rule tried, by its position in `rules`. The other rules, including those
+    /// left by a thread that could not be started, are left to be tried in order on this thread.
+    /// An error if a thread could not be started, and `options.on_spawn_failure` asks for one.
+    fn try_in_parallel(
+        &mut self,
+        rules: &[&'r Rule<'r>],
+        expression: &Expression,
+        model: &Model,
+    ) -> Result<Vec<Option<Trial>>, RewriteError> {
+        let mut trials: Vec<Option<Trial>> = rules.iter().map(|_| None).collect();
+        let pure: Vec<usize> = (0..rules.len()).filter(|&i| rules[i].pure).collect();
+        let threads = self.rule_threads.min(pure.len());
+        if threads < 2 || expression.size() < self.options.parallel_min_size {
+            return Ok(trials);
+        }
+
+        // Models cannot be shared between threads, so each thread gets its own copy of the symbol
+        // table, kept until the symbol table changes
+        let mut workers = std::mem::take(&mut self.worker_models);
+        while workers.len() < threads {
+            workers.push(Model::new(
+                model.variables.clone(),
+                Expression::Nothing,
+                model.context.clone(),
+            ));
+        }
+        // Pure rules only read the meta they declare, which is copied every time, as it may be
+        // scoped to a binder
+        let reads: Vec<MetaKey> = pure
+            .iter()
+            .flat_map(|&i| rules[i].reads_meta.unwrap_or(&[]))
+            .copied()
+            .collect();
+        if !reads.is_empty() {
+            for worker in workers.iter_mut().take(threads) {
+                worker.meta = model.meta.copy_of(reads.iter().copied());
+            }
+        }
+
+        let started = thread::scope(|scope| {
+            let mut handles = Vec::new();
+            let mut failure = None;
+            for (thread, worker) in workers.iter_mut().take(threads).enumerate() {
+                let pure = &pure;
+                let spawned = thread::Builder::new().spawn_scoped(scope, move || {
+                    pure.iter()
+                        .skip(thread)
+                        .step_by(threads)
+                        .map(|&i| {
+                            let rule_start = Instant::now();
+                            let mut subtree = Subtree::borrowed(expression);
+                            // Always catch panics, to be raised in priority order
+                            let application = try_rule(rules[i], &mut subtree, worker, true);
+                            (i, (application, rule_start.elapsed()))
+                        })
+                        .collect::<Vec<_>>()
+                });
+                match spawned {
+                    Ok(handle) => handles.push(handle),
+                    Err(e) => failure = failure.or(Some(e)),
+                }
+            }
+
+            for handle in handles {
+                match handle.join() {
+                    Ok(results) => {
+                        for (i, trial) in results {
+                            trials[i] = Some(trial);
+                        }
+                    }
+                    // Panics in rules are caught, so this is a bug in the rewriter
+                    Err(payload) => panic::resume_unwind(payload),
+                }
+            }
+            match failure {
+                Some(e) => spawn_failed(self.options, e),
+                None => Ok(()),
+            }
+        });
+
+        self.worker_models = workers;
+        started?;
+        Ok(trials)
+    }
 }
 
+/// Handles a thread that could not be started, as `options.on_spawn_failure` asks.
+///
 /// # Returns
-/// - Some(<reduction>) after applying the first rule in `results`.
-/// - None if `results` is empty.
-fn choose_rewrite(results: &[RuleResult]) -> Option<Reduction> {
-    if results.is_empty() {
-        return None;
+/// - Ok if the work the thread would have done should be done on this thread instead.
+/// - An error if rewriting should stop.
+fn spawn_failed(options: &RewriteOptions, error: io::Error) -> Result<(), RewriteError> {
+    match options.on_spawn_failure {
+        SpawnFailurePolicy::Sequential => {
+            log::warn!(target: "file", "Could not start a rewriter thread, so its work will be done on this thread: {}", error);
+            Ok(())
+        }
+        SpawnFailurePolicy::Error => Err(EngineError::SpawnFailed(error).into()),
+    }
+}
+
+/// An expression visited on another thread by [`Rewriter::run_tasks`], and the rules applied to
+/// it, or the error that stopped the visit.
+type TaskResult<'r> = Result<(Expression, Vec<&'r Rule<'r>>), RewriteError>;
+
+/// The result of trying a rule, or its panic payload if it panicked, and how long it took.
+type Trial = (thread::Result<ApplicationResult>, Duration);
+
+/// Applies a single rule to `subtree`, catching any panic if `catch_panics` is set.
+///
+/// # Returns
+/// - The result of the rule.
+/// - The panic payload if the rule panicked and `catch_panics` is set.
+fn try_rule(
+    rule: &Rule,
+    subtree: &mut Subtree,
+    model: &Model,
+    catch_panics: bool,
+) -> thread::Result<ApplicationResult> {
+    if !catch_panics {
+        return Ok(rule.apply_to_subtree(subtree, model));
+    }
+
+    // Model holds a RefCell, so is not UnwindSafe. A rule that panics may leave the model's
+    // variable counter incremented, which is harmless.
+    panic::catch_unwind(AssertUnwindSafe(|| rule.apply_to_subtree(subtree, model)))
+}
+
+/// Extracts the message from a panic payload, if it has one.
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        String::from("<non-string panic payload>")
+    }
+}
+
+/// The number of sub-expressions per thread that [`Rewriter::shared_pass`] shares between
+/// threads, so that threads that finish early have more to take.
+const TASKS_PER_THREAD: usize = 8;
+
+/// A sub-expression visited by [`Rewriter::shared_pass`] on its own thread, or left for the
+/// other threads.
+struct PassNode {
+    expression: Expression,
+    /// The child indices leading from the root to the expression.
+    path: Vec<usize>,
+    /// Where the children of the expression are in the list of nodes, if they have been moved
+    /// out of it.
+    children: Option<Range<usize>>,
+    /// Whether the expression has been visited, or does not need to be.
+    visited: bool,
+    /// Whether the expression or any sub-expression has been rewritten.
+    changed: bool,
+}
+
+/// A rewrite found by [`Rewriter::search`].
+struct FoundRewrite<'r> {
+    /// The number of expressions visited before the rewritten expression.
+    visit: usize,
+    result: RuleResult<'r>,
+}
+
+/// A sub-expression whose children are being visited by [`Rewriter::commit`].
+struct Frame {
+    /// The expression, with its children moved out into the traversal's scratch buffer.
+    expression: Expression,
+    /// The index of the first child of `expression` in the scratch buffer. The child being visited
+    /// is moved out of the buffer, and replaced with `Expression::Nothing` until the visit
+    /// finishes.
+    start: usize,
+    /// The index of the next child to visit.
+    next_child: usize,
+    /// Whether any sub-expression has been rewritten.
+    changed: bool,
+}
+
+/// An expression whose children are being normalised by [`Rewriter::normalise`].
+struct NormalFrame {
+    /// The expression, with its children moved out into the rewriter's scratch buffer.
+    expression: Expression,
+    /// The index of the first child of `expression` in the scratch buffer.
+    start: usize,
+    /// The index of the next child to normalise.
+    next_child: usize,
+    /// The index of the first expression rewritten into `expression`, in the expressions being
+    /// normalised. `expression`, as it was before its children were normalised, is the last of
+    /// them.
+    sources_start: usize,
+}
+
+/// Returns true if `reduction` leaves `original`, the expression with the
+/// [`Expression::subtree_hash`] `hash`, unchanged, and has no side-effects.
+///
+/// The hash of the new expression is worked out only if there are no side-effects, and the new
+/// expression is compared with `original` only if the hashes match, as different expressions may
+/// share a hash. This way a rule that "normalises" an already normal expression is caught cheaply,
+/// before its rewrite is applied. `original` is None if a rule took the expression and no copy
+/// of it was kept, in which case matching hashes are trusted.
+fn is_no_op(hash: u64, original: Option<&Expression>, reduction: &Reduction) -> bool {
+    !reduction.has_side_effects()
+        && reduction.new_expression.subtree_hash() == hash
+        && original.map_or(true, |original| reduction.new_expression == *original)
+}
+
+/// The rewrites made by a single pass of [`Rewriter::rewrite_iteration`].
+struct Step<'r> {
+    /// The rules applied, in order. There is more than one only in batch mode.
+    rules: Vec<&'r Rule<'r>>,
+    /// The combined side-effects of the rules. The rewritten expression is not held here, as the
+    /// constraints are rewritten in place.
+    reduction: Reduction,
+}
+
+impl<'r> Step<'r> {
+    /// The last rule applied, to which errors found after the pass are attributed.
+    fn last_rule(&self) -> &'r Rule<'r> {
+        self.rules[self.rules.len() - 1]
+    }
+}
+
+/// Combines two top-level constraints, either of which may be `Expression::Nothing`.
+fn and_top(a: Expression, b: Expression) -> Expression {
+    match (a, b) {
+        (Expression::Nothing, b) => b,
+        (a, Expression::Nothing) => a,
+        (Expression::And(metadata, mut exprs), b) => {
+            exprs.push(b);
+            let mut and = Expression::And(metadata, exprs);
+            and.invalidate_annotations();
+            and
+        }
+        (a, b) => Expression::And(Metadata::new(), vec![a, b]),
     }
-    // Return the first result for now
-    Some(results[0].reduction.clone())
 }
@@ -0,0 +1,162 @@
+use thiserror::Error;
+
+use crate::ast::{Expression, SymbolTable};
+use crate::rule_engine::resolve_rules::ResolveRulesError;
+use crate::rule_engine::{ApplicationError, ReproBundle};
+
+/// An error returned by [`rewrite_model_with_options`](crate::rule_engine::rewrite_model_with_options).
+///
+/// Use [`RewriteError::category`] to tell errors caused by a misbehaving rule, which are bugs in
+/// the rule, apart from errors caused by the rewriter or its environment, which may go away if
+/// rewriting is retried with different options.
+#[derive(Debug, Error)]
+pub enum RewriteError {
+    /// The rule sets could not be resolved, so no rewriting took place.
+    #[error("Error resolving rules: {0}")]
+    ResolveRulesError(#[from] ResolveRulesError),
+
+    /// A rule misbehaved while rewriting the model.
+    #[error(transparent)]
+    Rule(#[from] RuleError),
+
+    /// The rewriter itself stopped with an error.
+    #[error(transparent)]
+    Engine(#[from] EngineError),
+}
+
+/// Whether an error was caused by a rule, or by the rewriter and its environment.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCategory {
+    /// A rule misbehaved: it returned an error, panicked, or made a bad rewrite.
+    Rule,
+    /// The rewriter could not finish within its limits, or could not be set up.
+    Engine,
+}
+
+impl RewriteError {
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            RewriteError::Rule(_) => ErrorCategory::Rule,
+            RewriteError::ResolveRulesError(_) | RewriteError::Engine(_) => ErrorCategory::Engine,
+        }
+    }
+
+    /// Returns true if the error was caused by a misbehaving rule.
+    pub fn is_rule_error(&self) -> bool {
+        self.category() == ErrorCategory::Rule
+    }
+
+    /// Returns true if the error was caused by the rewriter or its environment.
+    pub fn is_engine_error(&self) -> bool {
+        self.category() == ErrorCategory::Engine
+    }
+
+    /// The name of the rule at fault, if the error was caused by a rule.
+    pub fn rule(&self) -> Option<&str> {
+        match self {
+            RewriteError::Rule(e) => Some(&e.rule),
+            _ => None,
+        }
+    }
+}
+
+/// A rule misbehaved while rewriting the model.
+///
+/// The cause is available as [`RuleError::kind`], and as the error's
+/// [`source`](std::error::Error::source).
+#[derive(Debug, Error)]
+#[error("Rule {rule} failed on the expression at {path:?} after {iteration} rewrites")]
+pub struct RuleError {
+    pub rule: String,
+    /// The child indices leading from the root of the constraints to the expression the rule was
+    /// applied to.
+    pub path: Vec<usize>,
+    /// The number of rewrites applied before the error occurred.
+    pub iteration: usize,
+    #[source]
+    pub kind: RuleErrorKind,
+    /// The last model that passed every check before the error occurred, if
+    /// [`RewriteOptions::checkpoint_interval`](crate::rule_engine::RewriteOptions::checkpoint_interval)
+    /// is set.
+    pub checkpoint: Option<Box<Checkpoint>>,
+    /// Everything needed to reproduce the error, if
+    /// [`RewriteOptions::capture_repro`](crate::rule_engine::RewriteOptions::capture_repro) is set.
+    pub repro: Option<Box<ReproBundle>>,
+}
+
+/// A copy of the model taken during rewriting, which can be rolled back to if rewriting fails.
+///
+/// Only the constraints and symbol table are kept, so that errors stay `Send` and `Sync`.
+#[derive(Clone, Debug)]
+pub struct Checkpoint {
+    pub constraints: Expression,
+    pub variables: SymbolTable,
+    /// The number of rewrites applied to the model when the checkpoint was taken.
+    pub rewrites: usize,
+}
+
+/// The ways in which a rule can misbehave.
+#[derive(Debug, Error)]
+pub enum RuleErrorKind {
+    #[error("the rule returned an error: {0}")]
+    Failed(ApplicationError),
+
+    #[error("the rule returned an expression identical to its input")]
+    NoOpRewrite,
+
+    #[error("the rule took the expression it was applied to, but did not rewrite it")]
+    TakenWithoutRewrite,
+
+    #[error("the rule is marked pure, but added top-level constraints or symbols, or set meta")]
+    ImpureRewrite,
+
+    #[error("the rule set meta of type {type_name}, which it does not declare it sets")]
+    UndeclaredMeta { type_name: &'static str },
+
+    #[error("the rule panicked: {message}")]
+    Panicked { message: String },
+
+    #[error("the constraints grew to {size} expressions, above the limit of {limit}")]
+    SizeLimitExceeded { size: usize, limit: usize },
+
+    #[error("invariant violated: {message}\nConstraints: {shown}")]
+    InvariantViolated {
+        message: String,
+        /// The constraints just after the rule was applied.
+        constraints: Box<Expression>,
+        /// The constraints as shown in the error message, by
+        /// [`RewriteOptions::label_nodes`](crate::rule_engine::RewriteOptions::label_nodes) if it
+        /// is set, and by their `Display` implementation otherwise.
+        shown: Box<str>,
+    },
+}
+
+/// The rewriter stopped with an error that is not the fault of any one rule.
+#[derive(Debug, Error)]
+pub enum EngineError {
+    #[error("Rewrite budget exhausted after {rewrites} rewrites and {attempts} rule attempts")]
+    BudgetExhausted { rewrites: usize, attempts: usize },
+
+    #[error("Rewriting returned to a previous state after applying: {}", rules.join(", "))]
+    CycleDetected { rules: Vec<String> },
+
+    #[error("Expression at {path:?} is nested deeper than the limit of {limit}")]
+    DepthLimitExceeded {
+        limit: usize,
+        /// The child indices leading from the root of the constraints to the expression.
+        path: Vec<usize>,
+    },
+
+    #[error("Could not start a rewriter thread: {0}")]
+    SpawnFailed(#[source] std::io::Error),
+
+    #[error("Every way of rewriting the model led to a dead end, or back to a model already reached, after {dead_ends} dead ends")]
+    DeadEnd {
+        dead_ends: usize,
+        /// The reason given for the last dead end, if there was one.
+        last_reason: Option<String>,
+    },
+
+    #[error("Invalid rewrite options: {0}")]
+    InvalidOptions(String),
+}
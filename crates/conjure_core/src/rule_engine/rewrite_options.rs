@@ -0,0 +1,1393 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::Duration;
+
+use derivative::Derivative;
+
+use crate::ast::Expression;
+use crate::meta::{
+    diff_meta_value, diff_symbols, update_derived, DerivedMeta, DerivedMetaUpdate, DiffMeta,
+    MetaDiff, MetaStore,
+};
+use crate::metadata::Span;
+use crate::rule_engine::{
+    Backtracking, BeamSearch, BestFirst, CostGuided, DivergenceAction, DivergenceCallback,
+    DivergenceMonitor, DivergenceWarning, EngineError, Progress, ProgressCallback, Reduction,
+    ReductionObserver, Rule, RuleProfile, Saturation, Stochastic, TieBreak, TraceFilter,
+};
+use crate::Model;
+
+/// A check run on the model after every rewrite. Returns an error message if the check fails.
+pub type InvariantCheck = Arc<dyn Fn(&Model) -> Result<(), String> + Send + Sync>;
+
+/// A value computed from the constraints and the model after every rewrite, formatted for the
+/// trace. See [`RewriteOptions::watch`].
+pub type Watch = Arc<dyn Fn(&Expression, &Model) -> String + Send + Sync>;
+
+/// Chooses which of the rewrites found for an expression to use, given the name of the rule that
+/// made each. See [`RewriteOptions::select_rewrite`].
+pub type RewriteSelector = Arc<dyn Fn(&[(&str, &Reduction)]) -> Option<usize> + Send + Sync>;
+
+/// Decides what to do with the rewrites found for an expression, and may block while it does, to
+/// ask a person or another service. See [`RewriteOptions::choose_rewrite`].
+pub type RewriteChooser = Arc<dyn Fn(&CandidateRewrites) -> RewriteChoice + Send + Sync>;
+
+/// The rewrites found for an expression, given to a [`RewriteChooser`].
+#[derive(Debug)]
+pub struct CandidateRewrites<'a> {
+    /// The expression, as it was before any rule was tried on it.
+    pub expression: &'a Expression,
+    /// The child indices leading from the root of the constraints to the expression.
+    pub path: &'a [usize],
+    /// The model, as it was before any rule was tried on the expression.
+    pub model: &'a Model,
+    /// The name of the rule that made each rewrite, and the rewrite, in the order they were tried.
+    pub rewrites: &'a [(&'a str, &'a Reduction)],
+}
+
+/// What a [`RewriteChooser`] decided to do with the rewrites found for an expression.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RewriteChoice {
+    /// Use the rewrite at this index. An index out of range is treated as [`Decline`](Self::Decline).
+    Apply(usize),
+    /// Use none of the rewrites, and leave the expression as it is, as if no rule applied.
+    Decline,
+    /// Use none of the rewrites, and stop rewriting, with a status of
+    /// [`RewriteStatus::Cancelled`](crate::rule_engine::RewriteStatus::Cancelled).
+    Stop,
+}
+
+/// Says whether an expression binds names for its children, such as a quantifier, and if so
+/// returns the meta that is in scope in them. See [`RewriteOptions::binders`].
+pub type Binder = Arc<dyn Fn(&Expression, &Model) -> Option<MetaStore> + Send + Sync>;
+
+/// Decides the span of an expression a rule made, given the span of the expression it rewrote.
+/// See [`RewriteOptions::span_policy`].
+pub type SpanPolicy = Arc<dyn Fn(Option<Span>, &Expression) -> Option<Span> + Send + Sync>;
+
+/// Shows an expression in logs and errors. See [`RewriteOptions::label_nodes`].
+pub type NodeLabeler = Arc<dyn Fn(&Expression) -> String + Send + Sync>;
+
+/// Options controlling a single run of the rewriter.
+///
+/// The defaults match the behaviour of [`rewrite_model`](crate::rule_engine::rewrite_model): no
+/// limits are imposed, and rewriting continues until no more rules can be applied.
+///
+/// # Example
+/// ```rust
+/// use conjure_core::rule_engine::{BudgetPolicy, RewriteOptions};
+///
+/// let options = RewriteOptions::new()
+///     .max_rewrites(1000)
+///     .on_budget_exhausted(BudgetPolicy::Error);
+/// ```
+#[derive(Clone, Default, Derivative)]
+#[derivative(Debug)]
+#[non_exhaustive]
+pub struct RewriteOptions {
+    /// The maximum number of rewrites to apply to the model.
+    pub max_rewrites: Option<usize>,
+    /// The maximum number of rule application attempts, successful or not.
+    pub max_rule_attempts: Option<usize>,
+    /// What to do when either of the above limits is reached.
+    pub on_budget_exhausted: BudgetPolicy,
+    /// The maximum wall-clock time to spend rewriting.
+    pub timeout: Option<Duration>,
+    /// Whether to fail with [`EngineError::CycleDetected`](crate::rule_engine::EngineError::CycleDetected)
+    /// when the constraints return to a previously seen state.
+    pub detect_cycles: bool,
+    /// What to do when a rule returns an expression identical to its input.
+    pub on_no_op: NoOpPolicy,
+    /// Whether to catch panics in rules and report them as errors.
+    pub catch_panics: bool,
+    /// The maximum number of expressions the constraints may grow to.
+    pub max_size: Option<usize>,
+    /// The time after which a rule application is discarded as too slow.
+    pub slow_rule_threshold: Option<Duration>,
+    /// A check to run on the model after every rewrite.
+    #[derivative(Debug = "ignore")]
+    pub invariant: Option<InvariantCheck>,
+    /// What to do when a rule returns an error other than `RuleNotApplicable` or `NotApplicable`.
+    pub on_rule_error: RuleErrorPolicy,
+    /// Overrides `on_rule_error` for the rules in the named rule sets.
+    pub rule_set_error_policies: HashMap<String, RuleErrorPolicy>,
+    /// The number of rewrites between checkpoints of the model.
+    pub checkpoint_interval: Option<usize>,
+    /// A flag which, once set, stops the rewriter at the next rewrite.
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// Whether to return the partially rewritten model, rather than `Err`, when rewriting fails.
+    pub partial_on_error: bool,
+    /// Heuristics used to warn when rewriting appears not to terminate.
+    pub divergence_monitor: Option<DivergenceMonitor>,
+    /// Whether to remember which rules did not apply to which expressions.
+    pub memoize_failures: bool,
+    /// Whether to try the rules that have applied most often first, among rules of equal priority.
+    pub adaptive_rule_order: bool,
+    /// Whether to apply every rewrite found in a pass over the constraints, rather than only the
+    /// first.
+    pub batch_rewrites: bool,
+    /// Whether to normalise the constraints bottom-up, remembering the normal form of each distinct
+    /// sub-expression. Only used if every rule is pure.
+    pub cache_normal_forms: bool,
+    /// The number of threads on which to try pure rules. Rules are tried one at a time if this is
+    /// 0 or 1.
+    pub rule_threads: usize,
+    /// Whether to guarantee the same result as trying rules one at a time, by not using anything
+    /// that depends on timing.
+    pub deterministic: bool,
+    /// The number of threads to share each pass over the constraints between, in batch mode. Each
+    /// pass is made on one thread if this is 0 or 1.
+    pub work_stealing_threads: usize,
+    /// The smallest expression, counted in sub-expressions, worth working on with more than one
+    /// thread.
+    pub parallel_min_size: usize,
+    /// The most threads the rewriter may start at once. There is no limit if this is `None`.
+    pub max_threads: Option<usize>,
+    /// What to do when the rewriter cannot start a thread.
+    pub on_spawn_failure: SpawnFailurePolicy,
+    /// Whether to hold the constraints in an arena of nodes while rewriting.
+    pub arena: bool,
+    /// Whether to attach a [`ReproBundle`](crate::rule_engine::ReproBundle) to rule errors.
+    pub capture_repro: bool,
+    /// Whether to return a [`PerfReport`](crate::rule_engine::PerfReport) with the rewritten model.
+    pub perf_report: bool,
+    /// Whether to include in the [`PerfReport`](crate::rule_engine::PerfReport) where the time
+    /// spent on each iteration went.
+    pub perf_timeline: bool,
+    /// Whether to return a [`RuleProfile`] of the rules that applied to each variant of expression.
+    pub record_profile: bool,
+    /// Whether to return an [`AttemptHeatmap`](crate::rule_engine::AttemptHeatmap) of the rules
+    /// tried at each position in the constraints.
+    pub attempt_heatmap: bool,
+    /// Whether to return every expression that more than one rule of the highest priority applied
+    /// to.
+    pub report_ambiguities: bool,
+    /// Whether to return the rewrites not made at each step, with the model they could have been
+    /// made to.
+    pub record_choices: bool,
+    /// How rules of the same priority are ordered.
+    pub tie_break: TieBreak,
+    /// The lowest priority of each tier of rules but the last, highest first. Rules of a tier are
+    /// only tried once no rule of a higher tier applies anywhere in the constraints.
+    pub priority_tiers: Vec<u8>,
+    /// Marks the expressions that bind names for their children, with the meta in scope in them.
+    #[derivative(Debug = "ignore")]
+    pub binders: Option<Binder>,
+    /// The types of meta kept up to date by the rewriter after every rewrite.
+    pub derived_meta: Vec<DerivedMetaUpdate>,
+    /// A profile recorded by earlier runs, used to order the rules tried on each variant of
+    /// expression.
+    #[derivative(Debug = "ignore")]
+    pub rule_profile: Option<Arc<RuleProfile>>,
+    /// Whether to record the most memory held at once by the rewriter.
+    pub track_memory: bool,
+    /// Whether to return a [`TraceStep`](crate::rule_engine::TraceStep) for every rewrite.
+    pub trace: bool,
+    /// Whether to log a warning whenever side-effects made by a rule are discarded.
+    pub warn_on_discarded_effects: bool,
+    /// Which rewrites to record in the trace, if not all of them.
+    pub trace_filter: Option<TraceFilter>,
+    /// Disables a rule once it has had more than this many failures. Must be at least 1.
+    pub quarantine_after: Option<usize>,
+    /// The maximum depth of the expressions visited by the rewriter.
+    pub max_recursion_depth: Option<usize>,
+    /// Called when `divergence_monitor` raises a warning.
+    #[derivative(Debug = "ignore")]
+    pub on_divergence: Option<DivergenceCallback>,
+    /// Called as rules are tried and applied, in the order they were registered.
+    #[derivative(Debug = "ignore")]
+    pub observers: Vec<Arc<dyn ReductionObserver>>,
+    /// Whether to record in each rewritten expression the rule that produced it.
+    pub provenance: bool,
+    /// Decides the spans of the expressions rules make.
+    #[derivative(Debug = "ignore")]
+    pub span_policy: Option<SpanPolicy>,
+    /// Values to record in the trace after every rewrite, by name.
+    #[derivative(Debug = "ignore")]
+    pub watches: Vec<(String, Watch)>,
+    /// Parts of the model whose changes to record in the trace after every rewrite, by name.
+    pub meta_diffs: Vec<(String, MetaDiff)>,
+    /// Shows expressions in logs and errors, if set.
+    #[derivative(Debug = "ignore")]
+    pub labeler: Option<NodeLabeler>,
+    /// Chooses which of the rewrites found for an expression to use, if set.
+    #[derivative(Debug = "ignore")]
+    pub rewrite_selector: Option<RewriteSelector>,
+    /// Decides what to do with the rewrites found for an expression, if set.
+    #[derivative(Debug = "ignore")]
+    pub rewrite_chooser: Option<RewriteChooser>,
+    /// Rewrites by equality saturation rather than by replacing expressions, if set.
+    pub saturation: Option<Saturation>,
+    /// How to choose the rewrites to apply.
+    pub strategy: SearchStrategy,
+    /// Called with the progress of the run, at most once every `progress_interval`.
+    #[derivative(Debug = "ignore")]
+    pub on_progress: Option<ProgressCallback>,
+    /// The least time between calls to `on_progress`, zero by default. When zero, `on_progress` is
+    /// called after every rewrite.
+    pub progress_interval: Duration,
+}
+
+/// What the rewriter should do when it runs out of budget.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BudgetPolicy {
+    /// Stop rewriting and return the model as it is, marked as incomplete.
+    #[default]
+    ReturnPartial,
+    /// Stop rewriting and return [`EngineError::BudgetExhausted`](crate::rule_engine::EngineError::BudgetExhausted).
+    Error,
+}
+
+/// What the rewriter should do when a rule applies but leaves the expression unchanged.
+///
+/// Applying such a rule again and again would prevent the rewriter from ever terminating.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NoOpPolicy {
+    /// Treat the rule as not applicable to the expression.
+    #[default]
+    Skip,
+    /// Stop rewriting and return [`RuleErrorKind::NoOpRewrite`](crate::rule_engine::RuleErrorKind::NoOpRewrite).
+    Error,
+    /// Apply the rule anyway.
+    Allow,
+}
+
+/// What the rewriter should do when a rule returns an error other than
+/// [`ApplicationError::RuleNotApplicable`](crate::rule_engine::ApplicationError::RuleNotApplicable)
+/// or [`ApplicationError::NotApplicable`](crate::rule_engine::ApplicationError::NotApplicable).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RuleErrorPolicy {
+    /// Treat the rule as not applicable to the expression.
+    #[default]
+    Ignore,
+    /// Log a warning, then treat the rule as not applicable to the expression.
+    Warn,
+    /// Stop rewriting and return [`RuleErrorKind::Failed`](crate::rule_engine::RuleErrorKind::Failed).
+    Error,
+}
+
+/// How the rewriter chooses the rewrites to apply.
+///
+/// Every strategy but [`FirstFound`](Self::FirstFound) replaces the rewriter's loop with a search
+/// of its own, so options that act on each rewrite as the loop makes it cannot be used with one:
+/// rewriting fails with [`EngineError::InvalidOptions`] if `on_rule_error` or a rule set's error
+/// policy, `catch_panics`, `observers`, `invariant`, or `max_size` is set. Of the other options,
+/// only `max_rewrites`, `on_budget_exhausted`, `timeout`, `cancel`, `partial_on_error`, and `trace`
+/// are used. Rules that return an error are treated as not applying, and panics in rules are not
+/// caught. No strategy is used if [`RewriteOptions::equality_saturation`] is.
+#[derive(Clone, Debug, Default)]
+pub enum SearchStrategy {
+    /// Apply the first rewrite found, by expression in pre-order, then by rule priority.
+    #[default]
+    FirstFound,
+    /// Apply the rewrite that most reduces a cost at each step.
+    CostGuided(CostGuided),
+    /// Back out of the models rejected as dead ends.
+    Backtracking(Backtracking),
+    /// Keep the cheapest few models found at each step.
+    BeamSearch(BeamSearch),
+    /// Choose each rewrite at random.
+    Stochastic(Stochastic),
+    /// Search for the normal form reached by the cheapest rewrites.
+    BestFirst(BestFirst),
+}
+
+impl SearchStrategy {
+    fn name(&self) -> &'static str {
+        match self {
+            SearchStrategy::FirstFound => "first-found",
+            SearchStrategy::CostGuided(_) => "cost-guided",
+            SearchStrategy::Backtracking(_) => "backtracking",
+            SearchStrategy::BeamSearch(_) => "beam search",
+            SearchStrategy::Stochastic(_) => "stochastic",
+            SearchStrategy::BestFirst(_) => "best-first",
+        }
+    }
+}
+
+/// What the rewriter should do when it cannot start a thread, for example because the system has
+/// run out of threads.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SpawnFailurePolicy {
+    /// Log a warning, then do the work the thread would have done on the calling thread.
+    #[default]
+    Sequential,
+    /// Stop rewriting and return [`EngineError::SpawnFailed`](crate::rule_engine::EngineError::SpawnFailed).
+    Error,
+}
+
+impl RewriteOptions {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Stop after the given number of rewrites have been applied.
+    pub fn max_rewrites(self, max_rewrites: usize) -> Self {
+        Self {
+            max_rewrites: Some(max_rewrites),
+            ..self
+        }
+    }
+
+    /// Stop after the given number of rule applications have been attempted.
+    ///
+    /// The limit is checked between rewrites, so a single pass over the model may overshoot it.
+    pub fn max_rule_attempts(self, max_rule_attempts: usize) -> Self {
+        Self {
+            max_rule_attempts: Some(max_rule_attempts),
+            ..self
+        }
+    }
+
+    pub fn on_budget_exhausted(self, policy: BudgetPolicy) -> Self {
+        Self {
+            on_budget_exhausted: policy,
+            ..self
+        }
+    }
+
+    /// Stop rewriting once the given amount of time has passed.
+    ///
+    /// The timeout is checked between rewrites; when it is reached, the model rewritten so far is
+    /// returned with the status [`RewriteStatus::Timeout`](crate::rule_engine::RewriteStatus::Timeout).
+    pub fn timeout(self, timeout: Duration) -> Self {
+        Self {
+            timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    /// Hash the constraints after every rewrite, and stop with an error if a previously seen
+    /// state recurs.
+    ///
+    /// States are compared by hash only, and metadata is ignored.
+    pub fn detect_cycles(self, detect_cycles: bool) -> Self {
+        Self {
+            detect_cycles,
+            ..self
+        }
+    }
+
+    pub fn on_no_op(self, policy: NoOpPolicy) -> Self {
+        Self {
+            on_no_op: policy,
+            ..self
+        }
+    }
+
+    /// Catch panics raised while applying a rule, and return them as
+    /// [`RuleErrorKind::Panicked`](crate::rule_engine::RuleErrorKind::Panicked) instead of
+    /// unwinding through the rewriter.
+    ///
+    /// The panic hook still runs, so the panic message is printed as usual.
+    pub fn catch_panics(self, catch_panics: bool) -> Self {
+        Self {
+            catch_panics,
+            ..self
+        }
+    }
+
+    /// Stop with [`RuleErrorKind::SizeLimitExceeded`](crate::rule_engine::RuleErrorKind::SizeLimitExceeded)
+    /// if the constraints grow to more than the given number of expressions.
+    ///
+    /// The size is counted once at the start, then updated from the size of each rewritten
+    /// sub-expression.
+    pub fn max_size(self, max_size: usize) -> Self {
+        Self {
+            max_size: Some(max_size),
+            ..self
+        }
+    }
+
+    /// Treat rule applications that take longer than `threshold` as not applicable, and report
+    /// them in [`RewriteOutcome::slow_rules`](crate::rule_engine::RewriteOutcome::slow_rules).
+    ///
+    /// This is not a timeout: rules are not interrupted, so a slow rule still runs to completion,
+    /// and its result is discarded once it returns. Use [`timeout`](Self::timeout) to bound the
+    /// time the whole run takes.
+    pub fn slow_rule_threshold(self, threshold: Duration) -> Self {
+        Self {
+            slow_rule_threshold: Some(threshold),
+            ..self
+        }
+    }
+
+    /// Run the given check on the model after every rewrite, and stop with
+    /// [`RuleErrorKind::InvariantViolated`](crate::rule_engine::RuleErrorKind::InvariantViolated)
+    /// if it fails.
+    pub fn check_invariant(
+        self,
+        invariant: impl Fn(&Model) -> Result<(), String> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            invariant: Some(Arc::new(invariant)),
+            ..self
+        }
+    }
+
+    pub fn on_rule_error(self, policy: RuleErrorPolicy) -> Self {
+        Self {
+            on_rule_error: policy,
+            ..self
+        }
+    }
+
+    /// Use the given policy for errors returned by rules in the named rule set, instead of
+    /// `on_rule_error`.
+    ///
+    /// A rule in several rule sets uses the policy of the first of its rule sets, in the order
+    /// they are listed in the rule's registration, that has one.
+    pub fn on_rule_error_in(mut self, rule_set: &str, policy: RuleErrorPolicy) -> Self {
+        self.rule_set_error_policies
+            .insert(rule_set.to_string(), policy);
+        self
+    }
+
+    /// Keep a copy of the model every `interval` rewrites, and attach the latest copy to any
+    /// [`RuleError`](crate::rule_engine::RuleError) so that callers can roll back to it.
+    ///
+    /// A copy is only taken once the model has passed the size limit, invariant, and cycle checks,
+    /// so the attached model is always known to be good. The input model is the first checkpoint.
+    /// Each checkpoint is a full clone of the constraints and symbol table, sharing nothing with
+    /// the model, so a short interval on a large model costs a clone every few rewrites.
+    pub fn checkpoint_every(self, interval: usize) -> Self {
+        Self {
+            checkpoint_interval: Some(interval),
+            ..self
+        }
+    }
+
+    /// Stop rewriting once `cancel` is set, for example from another thread.
+    ///
+    /// The flag is checked between rewrites; when it is set, the model rewritten so far is
+    /// returned with the status [`RewriteStatus::Cancelled`](crate::rule_engine::RewriteStatus::Cancelled).
+    pub fn cancel_flag(self, cancel: Arc<AtomicBool>) -> Self {
+        Self {
+            cancel: Some(cancel),
+            ..self
+        }
+    }
+
+    /// When rewriting fails, return the last model known to be good, with the status
+    /// [`RewriteStatus::Error`](crate::rule_engine::RewriteStatus::Error), instead of returning
+    /// `Err`.
+    ///
+    /// For errors raised while looking for a rewrite, this is the model as it was before it. A
+    /// rewrite that breaks the [size limit](Self::max_size) or [invariant](Self::check_invariant)
+    /// is undone, and its side-effects are recorded as discarded, unless the constraints are held
+    /// in an [arena](Self::arena), in which case the input model is returned. The input model is
+    /// attached to the error as its [checkpoint](Self::checkpoint_every), if no later one is.
+    ///
+    /// Errors in resolving the rule sets are still returned as `Err`, as no rewriting has happened.
+    pub fn partial_on_error(self, partial_on_error: bool) -> Self {
+        Self {
+            partial_on_error,
+            ..self
+        }
+    }
+
+    /// Log a warning when rewriting appears not to terminate, according to the heuristics in
+    /// `monitor`.
+    ///
+    /// This tracks the size of the constraints as with [`RewriteOptions::max_size`].
+    pub fn monitor_divergence(self, monitor: DivergenceMonitor) -> Self {
+        Self {
+            divergence_monitor: Some(monitor),
+            ..self
+        }
+    }
+
+    /// Call `callback` whenever the divergence monitor raises a warning, and stop rewriting if it
+    /// returns [`DivergenceAction::Stop`].
+    ///
+    /// Has no effect unless [`RewriteOptions::monitor_divergence`] is also set.
+    pub fn on_divergence(
+        self,
+        callback: impl Fn(&DivergenceWarning) -> DivergenceAction + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            on_divergence: Some(Arc::new(callback)),
+            ..self
+        }
+    }
+
+    /// Register `observer` to be called as rules are tried and applied, after any observers
+    /// registered before it.
+    ///
+    /// Observers need the whole of the constraints between rewrites, so the constraints are not
+    /// held in an arena while any are registered.
+    pub fn observer(mut self, observer: impl ReductionObserver + 'static) -> Self {
+        self.observers.push(Arc::new(observer));
+        self
+    }
+
+    /// Compute `watch` from the constraints and the model after every rewrite, and record its value,
+    /// formatted with `Debug`, in [`TraceStep::watches`](crate::rule_engine::TraceStep::watches)
+    /// under `name`, after any watches registered before it.
+    ///
+    /// This gives a cheap view of how a metric, such as the number of variables or the size of the
+    /// constraints, changes as rewriting goes on. Has no effect unless [`RewriteOptions::trace`]
+    /// is also set. With `batch_rewrites`, watches are computed once per pass, and recorded in the
+    /// last step of it.
+    ///
+    /// Watches need the whole of the constraints between rewrites, so the constraints are not held
+    /// in an arena while any are registered.
+    pub fn watch<V: Debug>(
+        mut self,
+        name: impl Into<String>,
+        watch: impl Fn(&Expression, &Model) -> V + Send + Sync + 'static,
+    ) -> Self {
+        self.watches.push((
+            name.into(),
+            Arc::new(move |constraints, model| format!("{:?}", watch(constraints, model))),
+        ));
+        self
+    }
+
+    /// Record how the value of type `K` in the model's [`meta`](Model::meta) changed with each
+    /// rewrite, as described by [`DiffMeta::diff`], in
+    /// [`TraceStep::meta_diffs`](crate::rule_engine::TraceStep::meta_diffs) under the name of the
+    /// type, after any diffs registered before it. Rewrites that did not change it record nothing.
+    ///
+    /// Along with [`diff_symbols`](Self::diff_symbols), this lets the trace say which rule made
+    /// each change to the model, not only to the constraints. Has no effect unless
+    /// [`RewriteOptions::trace`] is also set. The symbol table and meta are copied before every
+    /// rewrite to be compared. With `batch_rewrites`, the changes of a whole pass are recorded in
+    /// the last step of it.
+    pub fn diff_meta<K: DiffMeta>(mut self) -> Self {
+        self.meta_diffs
+            .push((std::any::type_name::<K>().to_string(), diff_meta_value::<K>));
+        self
+    }
+
+    /// Record the symbols added or changed by each rewrite in
+    /// [`TraceStep::meta_diffs`](crate::rule_engine::TraceStep::meta_diffs), under `symbols`, as
+    /// [`diff_meta`](Self::diff_meta) does for meta.
+    pub fn diff_symbols(mut self) -> Self {
+        self.meta_diffs
+            .push((String::from("symbols"), diff_symbols));
+        self
+    }
+
+    /// Use `selector` to choose which rewrite to make when more than one rule applies to an
+    /// expression, rather than using the first, which is the one made by the rule of highest
+    /// priority.
+    ///
+    /// Every rule is tried on each expression, and `selector` is given the rule name and the
+    /// rewrite of each that applied, in the order they were tried. It returns the index of the
+    /// rewrite to use, or None to use none of them, in which case the expression is left as it
+    /// is, as if no rule applied. An index out of range is treated as None. It is not called for
+    /// expressions no rule applies to. Side-effects of the rewrites not used are discarded, and
+    /// reported in [`RewriteOutcome::discarded_effects`](crate::rule_engine::RewriteOutcome::discarded_effects).
+    ///
+    /// Rules that take the expression are given it back for the other rules, so each expression
+    /// is cloned before rules are tried on it. Trying every rule also means
+    /// [`adaptive_rule_order`](Self::adaptive_rule_order) and
+    /// [`rule_profile`](Self::rule_profile) no longer save any attempts.
+    pub fn select_rewrite(
+        self,
+        selector: impl Fn(&[(&str, &Reduction)]) -> Option<usize> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            rewrite_selector: Some(Arc::new(selector)),
+            ..self
+        }
+    }
+
+    /// Use `chooser` to decide what to do with the rewrites found for each expression, like
+    /// [`select_rewrite`](Self::select_rewrite), but with the expression, where it is, and the
+    /// model to go on, and with the choice to stop rewriting altogether. `chooser` may block for
+    /// as long as it likes, such as to ask a person which rewrite to make, or to ask a service
+    /// running a policy; the time spent counts towards `timeout`, but it is only checked between
+    /// iterations.
+    ///
+    /// Every rule is tried on each expression, as with `select_rewrite`, and `chooser` is only
+    /// called for expressions some rule applies to. If it returns [`RewriteChoice::Stop`], no
+    /// more rules are tried, and the model is returned as rewritten so far. The rewrites of an
+    /// iteration already chosen are still applied, even with
+    /// [`batch_rewrites`](Self::batch_rewrites) set. Each pass is made on one thread, so that
+    /// `chooser` is asked about one expression at a time. If this is set, `select_rewrite` is not
+    /// used.
+    pub fn choose_rewrite(
+        self,
+        chooser: impl Fn(&CandidateRewrites) -> RewriteChoice + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            rewrite_chooser: Some(Arc::new(chooser)),
+            ..self
+        }
+    }
+
+    /// Show expressions in logs and errors with `labeler`, rather than with their `Display`
+    /// implementation, which is unreadable for large expressions.
+    ///
+    /// The same function can be passed to [`viz::to_dot_with`](crate::viz::to_dot_with) and
+    /// [`viz::trace_to_text_with`](crate::viz::trace_to_text_with), so that graphs and traces
+    /// show expressions as the logs do.
+    pub fn label_nodes(
+        self,
+        labeler: impl Fn(&Expression) -> String + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            labeler: Some(Arc::new(labeler)),
+            ..self
+        }
+    }
+
+    /// Call `callback` with the progress of the run, between rewrites, once at least `interval`
+    /// has passed since it was last called, and once more when rewriting stops.
+    ///
+    /// This is meant for frontends to show a progress bar during long runs; to report progress to
+    /// another thread, send it over a channel from `callback`. Estimating the work left visits the
+    /// constraints, so a zero `interval` slows rewriting down.
+    pub fn on_progress(
+        self,
+        interval: Duration,
+        callback: impl Fn(&Progress) + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            on_progress: Some(Arc::new(callback)),
+            progress_interval: interval,
+            ..self
+        }
+    }
+
+    /// Record in the metadata of each expression a rule produces, and of each constraint it adds to
+    /// the top of the model, which rule produced it and after how many rewrites, so the rewritten
+    /// model shows where its constraints came from. See [`Expression::provenance`](crate::ast::Expression::provenance).
+    ///
+    /// Only the root of each produced expression is marked; sub-expressions the rule kept from the
+    /// expression it rewrote keep whatever provenance they had.
+    pub fn provenance(self, provenance: bool) -> Self {
+        Self { provenance, ..self }
+    }
+
+    /// Give each expression a rule makes the span `policy` returns for it, given the span of the
+    /// expression the rule rewrote, so that errors about the rewritten model can still point to
+    /// the source. See [`Expression::span`].
+    ///
+    /// Only expressions without a span are given one. Those the rule kept from the expression it
+    /// rewrote keep their own spans, along with their sub-expressions.
+    ///
+    /// # Example
+    /// ```rust
+    /// use conjure_core::ast::Expression;
+    /// use conjure_core::rule_engine::RewriteOptions;
+    ///
+    /// // Constants made by rules are not given spans, as they may stand for many parts of the
+    /// // source
+    /// let options = RewriteOptions::new().span_policy(|redex, expression| match expression {
+    ///     Expression::Constant(_, _) => None,
+    ///     _ => redex,
+    /// });
+    /// ```
+    pub fn span_policy(
+        self,
+        policy: impl Fn(Option<Span>, &Expression) -> Option<Span> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            span_policy: Some(Arc::new(policy)),
+            ..self
+        }
+    }
+
+    /// Give each expression a rule makes the span of the expression it rewrote. See
+    /// [`RewriteOptions::span_policy`].
+    pub fn inherit_spans(self) -> Self {
+        self.span_policy(|redex, _| redex)
+    }
+
+    /// Stop with [`EngineError::DepthLimitExceeded`](crate::rule_engine::EngineError::DepthLimitExceeded)
+    /// when visiting an expression nested more than `depth` levels below the root of the
+    /// constraints.
+    ///
+    /// This bounds the size of models the rewriter accepts. The rewriter does not recurse, and
+    /// neither does cloning, comparing, hashing, dropping or displaying an expression.
+    pub fn max_recursion_depth(self, depth: usize) -> Self {
+        Self {
+            max_recursion_depth: Some(depth),
+            ..self
+        }
+    }
+
+    /// Instead of stopping when a rule misbehaves, disable the rule once it has failed more than
+    /// `failures` times, and carry on rewriting without it. Disabled rules are reported in
+    /// [`RewriteOutcome::quarantined`](crate::rule_engine::RewriteOutcome::quarantined).
+    ///
+    /// Rewriting fails with [`EngineError::InvalidOptions`](crate::rule_engine::EngineError::InvalidOptions)
+    /// if `failures` is 0; stop on the first failure by not setting this instead.
+    ///
+    /// This covers every [`RuleError`](crate::rule_engine::RuleError); errors from rules only
+    /// count as failures if [`RewriteOptions::on_rule_error`] makes them errors. When a size limit
+    /// or invariant is also set, the model is copied before every rewrite, so that a rewrite which
+    /// fails those checks can be undone.
+    pub fn quarantine_after(self, failures: usize) -> Self {
+        Self {
+            quarantine_after: Some(failures),
+            ..self
+        }
+    }
+
+    /// Attach a [`ReproBundle`](crate::rule_engine::ReproBundle) to every
+    /// [`RuleError`](crate::rule_engine::RuleError), which can be saved and replayed later.
+    ///
+    /// When a size limit or invariant is also set, the model is copied before every rewrite, so
+    /// that the expression the rule was applied to is still available if those checks fail.
+    pub fn capture_repro(self, capture_repro: bool) -> Self {
+        Self {
+            capture_repro,
+            ..self
+        }
+    }
+
+    /// Return a [`PerfReport`](crate::rule_engine::PerfReport) in
+    /// [`RewriteOutcome::perf`](crate::rule_engine::RewriteOutcome::perf), with how often each
+    /// rule was tried and applied, how long each took, and how long each phase of rewriting took.
+    ///
+    /// Rules are always timed, so this adds little overhead beyond a map update per attempt.
+    pub fn perf_report(self, perf_report: bool) -> Self {
+        Self {
+            perf_report,
+            ..self
+        }
+    }
+
+    /// Return a [`PerfReport`](crate::rule_engine::PerfReport), as
+    /// [`perf_report`](Self::perf_report) does, with an
+    /// [`IterationTiming`](crate::rule_engine::IterationTiming) for every iteration in its
+    /// `timeline`, splitting its time between traversal, rules, rebuilding the tree, applying
+    /// side-effects, and checks.
+    ///
+    /// Rebuilding the tree is timed separately only when this is set, so it adds a little to the
+    /// time of each iteration, and keeps a timing for each in memory.
+    pub fn perf_timeline(self, perf_timeline: bool) -> Self {
+        Self {
+            perf_timeline,
+            ..self
+        }
+    }
+
+    /// Return a [`RuleProfile`] in
+    /// [`RewriteOutcome::profile`](crate::rule_engine::RewriteOutcome::profile), counting how
+    /// often each rule applied to each variant of expression.
+    ///
+    /// Profiles of several runs can be combined with [`RuleProfile::merge`], saved, and passed to
+    /// later runs with [`RewriteOptions::rule_profile`].
+    pub fn record_profile(self, record_profile: bool) -> Self {
+        Self {
+            record_profile,
+            ..self
+        }
+    }
+
+    /// Return an [`AttemptHeatmap`](crate::rule_engine::AttemptHeatmap) in
+    /// [`RewriteOutcome::heatmap`](crate::rule_engine::RewriteOutcome::heatmap), counting how
+    /// many rules were tried at each position in the constraints.
+    ///
+    /// Positions that absorb many attempts without being rewritten are where clean marks and
+    /// rule dispatch save the most time.
+    pub fn attempt_heatmap(self, attempt_heatmap: bool) -> Self {
+        Self {
+            attempt_heatmap,
+            ..self
+        }
+    }
+
+    /// Return every expression that more than one rule of the highest priority that applied to it
+    /// applied to, in
+    /// [`RewriteOutcome::ambiguities`](crate::rule_engine::RewriteOutcome::ambiguities). Only the
+    /// first of these rules is ever used, so which rewrite is made depends on the order the rules
+    /// happen to be tried in. Rules of the same priority that overlap by mistake can be found
+    /// this way.
+    ///
+    /// Every rule is tried on each expression, as with [`select_rewrite`](Self::select_rewrite),
+    /// so rewriting is slower, and rules that take the expression are given it back for the
+    /// others. An expression is reported each time rules are tried on it, so the same ambiguity
+    /// may be reported more than once.
+    pub fn report_ambiguities(self, report_ambiguities: bool) -> Self {
+        Self {
+            report_ambiguities,
+            ..self
+        }
+    }
+
+    /// Return, for each rewrite made where other rules also applied, the rewrites that were not
+    /// made, in [`RewriteOutcome::choices`](crate::rule_engine::RewriteOutcome::choices). Each
+    /// holds a copy of the model as it was, so another rewrite can be tried from any step with
+    /// [`ChoicePoint::branch`](crate::rule_engine::ChoicePoint::branch), without rewriting the
+    /// model up to that step again.
+    ///
+    /// Every rule is tried on each expression, as with [`report_ambiguities`](Self::report_ambiguities),
+    /// and the model is copied at every choice, so this is slow and uses a lot of memory on large
+    /// models. The constraints are not held in an [`arena`](Self::arena), normal forms are not
+    /// [cached](Self::cache_normal_forms), and work is not [stolen](Self::work_stealing_threads),
+    /// as these do not keep the whole model up to date while rules are tried.
+    pub fn record_choices(self, record_choices: bool) -> Self {
+        Self {
+            record_choices,
+            ..self
+        }
+    }
+
+    /// Order rules of the same priority by `tie_break`, rather than by name. Rules are tried in
+    /// order, and the first that applies to an expression is used, so this decides which rewrite
+    /// is made when rules of the same priority overlap.
+    ///
+    /// Rewriting is deterministic for a given tie-break: the same model and rules always make the
+    /// same rewrites, whatever order the rules are stored in. [`TieBreak::Registration`] is only
+    /// the same for every run of one build. [`adaptive_rule_order`](Self::adaptive_rule_order)
+    /// and [`rule_profile`](Self::rule_profile) reorder rules of the same priority by how often
+    /// they apply, and only keep this order between rules they rank equally. Rules that overlap
+    /// can be found with [`report_ambiguities`](Self::report_ambiguities).
+    pub fn tie_break(self, tie_break: TieBreak) -> Self {
+        Self { tie_break, ..self }
+    }
+
+    /// Split the rules into tiers by priority, where each of `boundaries` is the lowest priority
+    /// of a tier, and the rules below the lowest boundary make up the last tier. The rules of a
+    /// tier are only tried once no rule of a higher tier applies anywhere in the constraints, and
+    /// after every rewrite made by a lower tier, the highest tier is tried again, in case the
+    /// rewrite made something it can rewrite.
+    ///
+    /// Without tiers, priorities only order the rules tried on each expression, so a rule of low
+    /// priority rewrites an expression visited early even while a rule of high priority could
+    /// rewrite one visited later. With tiers, for example `[100]`, every rewrite of priority 100
+    /// or more is made, wherever it is, before any other, so that constraints can be evaluated
+    /// before they are reformulated.
+    ///
+    /// A pass is made over the constraints for each tier with nothing left to rewrite, so
+    /// rewriting is slower the more tiers there are. Normal forms are not
+    /// [cached](Self::cache_normal_forms) with tiers, as they depend on the tier.
+    ///
+    /// # Example
+    /// ```rust
+    /// use conjure_core::rule_engine::RewriteOptions;
+    ///
+    /// // Rules of priority 100 or more, then 50 or more, then the rest
+    /// let options = RewriteOptions::new().priority_tiers(&[50, 100]);
+    /// ```
+    pub fn priority_tiers(self, boundaries: &[u8]) -> Self {
+        let mut priority_tiers = boundaries.to_vec();
+        priority_tiers.sort_unstable_by(|a, b| b.cmp(a));
+        priority_tiers.dedup();
+        Self {
+            priority_tiers,
+            ..self
+        }
+    }
+
+    /// Treat the expressions for which `binder` returns meta as binders, such as quantifiers and
+    /// comprehensions, whose children are rewritten with that meta in scope.
+    ///
+    /// When the rewriter descends into the children of a binder, it sets the values `binder`
+    /// returned in the [`meta`](Model::meta) of the model, replacing any of the same type, and
+    /// puts the values they replaced back once it has left them. Binders may be nested, each
+    /// seeing the meta of the binders around it. This way a rule can look up the
+    /// declarations in scope where it is tried, without working them out from the expressions
+    /// around it, which it is not given.
+    ///
+    /// The meta set by rewrites is set in the model itself, not in the scope they were made in.
+    /// The constraints are not held in an [`arena`](Self::arena), normal forms are not
+    /// [cached](Self::cache_normal_forms), and work is not [stolen](Self::work_stealing_threads),
+    /// as these do not visit binders before their children. Not used by the alternative ways of
+    /// rewriting, such as [`beam_search`](Self::beam_search).
+    ///
+    /// # Example
+    /// ```rust
+    /// use conjure_core::ast::Expression;
+    /// use conjure_core::meta::MetaStore;
+    /// use conjure_core::rule_engine::RewriteOptions;
+    ///
+    /// #[derive(Clone)]
+    /// struct InNegation;
+    ///
+    /// // Rules can tell whether they are rewriting under a negation
+    /// let options = RewriteOptions::new().binders(|expression, _| match expression {
+    ///     Expression::Not(_, _) => {
+    ///         let mut scope = MetaStore::new();
+    ///         scope.insert(InNegation);
+    ///         Some(scope)
+    ///     }
+    ///     _ => None,
+    /// });
+    /// ```
+    pub fn binders(
+        self,
+        binder: impl Fn(&Expression, &Model) -> Option<MetaStore> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            binders: Some(Arc::new(binder)),
+            ..self
+        }
+    }
+
+    /// Keep a value of type `K` in the model's [`meta`](Model::meta), worked out before rewriting
+    /// and brought up to date by [`DerivedMeta::update`] after every rewrite, so that rules can
+    /// look up facts about the whole model without each working them out from the constraints.
+    ///
+    /// Each rewrite is given to the update as the expression rewritten and what it was rewritten
+    /// to, which are copied for it. Where the rules do not apply is then only forgotten as far as
+    /// the update says. The constraints are not held in an [`arena`](Self::arena), normal forms
+    /// are not [cached](Self::cache_normal_forms), and work is not
+    /// [stolen](Self::work_stealing_threads), as these do not make one rewrite at a time. Not
+    /// used by the alternative ways of rewriting, such as [`beam_search`](Self::beam_search).
+    pub fn derive_meta<K: DerivedMeta>(mut self) -> Self {
+        self.derived_meta.push(update_derived::<K>);
+        self
+    }
+
+    /// Before rewriting, build a table of the rules to try on each variant of expression, leaving
+    /// out the rules that cannot apply to it, and putting the rules that applied to it most often
+    /// in `profile` ahead of the other rules of the same priority.
+    ///
+    /// Rules are then tried at each expression only until one applies, rather than all being
+    /// tried. As with [`adaptive_rule_order`](Self::adaptive_rule_order), rules of higher priority
+    /// are still always tried first, but when two rules of equal priority apply to the same
+    /// expression, which of them is applied may differ from a run without the profile. With
+    /// `adaptive_rule_order` also set, the order learnt while rewriting breaks ties between rules
+    /// the profile ranks equally.
+    pub fn rule_profile(self, profile: RuleProfile) -> Self {
+        Self {
+            rule_profile: Some(Arc::new(profile)),
+            ..self
+        }
+    }
+
+    /// Record, in the `rewriter_peak_memory` of the rewriter's stats, the most memory held at once
+    /// for copies of the constraints and symbol table, and for the rewrites found but not yet
+    /// applied.
+    ///
+    /// Memory is estimated rather than measured: each sub-expression counts as the size of one
+    /// [`Expression`](crate::ast::Expression), and each symbol as one name and variable, so heap
+    /// data such as names and domains is not counted. The estimate is taken between rewrites and
+    /// whenever a rule applies, so it is cheap enough to leave on in CI, where a rise in the peak
+    /// points to a rule set that copies or grows the constraints more than before. This tracks the
+    /// size of the constraints as with [`RewriteOptions::max_size`].
+    pub fn track_memory(self, track_memory: bool) -> Self {
+        Self {
+            track_memory,
+            ..self
+        }
+    }
+
+    /// Return, in [`RewriteOutcome::trace`](crate::rule_engine::RewriteOutcome::trace), a
+    /// [`TraceStep`](crate::rule_engine::TraceStep) for every rewrite applied to the model, in
+    /// order, with the rule, where in the constraints it was applied, and the expression before
+    /// and after.
+    ///
+    /// This is meant for debugging why a model was rewritten the way it was. Every rewritten
+    /// expression is copied, and when the rewriter would otherwise move expressions rather than
+    /// copy them, so is every expression rules are tried on, so rewriting is slower.
+    pub fn trace(self, trace: bool) -> Self {
+        Self { trace, ..self }
+    }
+
+    /// Log a warning, with the rule and path, whenever a rule adds symbols or a top-level
+    /// constraint that the rewriter discards along with the rewrite. These are always returned in
+    /// [`RewriteOutcome::discarded_effects`](crate::rule_engine::RewriteOutcome::discarded_effects).
+    pub fn warn_on_discarded_effects(self, warn_on_discarded_effects: bool) -> Self {
+        Self {
+            warn_on_discarded_effects,
+            ..self
+        }
+    }
+
+    /// Record only the rewrites that pass `filter` in the trace. Has no effect unless
+    /// [`RewriteOptions::trace`] is also set.
+    pub fn trace_filter(self, filter: TraceFilter) -> Self {
+        Self {
+            trace_filter: Some(filter),
+            ..self
+        }
+    }
+
+    /// Remember, by the hash of the expression, which rules did not apply to which expressions,
+    /// and do not try them again.
+    ///
+    /// The rewriter starts again from the root after every rewrite, so without this the same
+    /// failed attempts are repeated on every unchanged sub-expression. This assumes that whether a
+    /// rule applies depends only on the expression and the symbol table; the memo is cleared
+    /// whenever the symbol table changes. Hashing every visited expression has a cost of its own.
+    pub fn memoize_failures(self, memoize_failures: bool) -> Self {
+        Self {
+            memoize_failures,
+            ..self
+        }
+    }
+
+    /// Keep track of how often each rule applies when it is tried, and between rewrites, move the
+    /// rules that apply most often ahead of the other rules of the same priority. The rules are
+    /// ranked again at most once per as many rule attempts as there are rules.
+    ///
+    /// Rules are then tried at each expression only until one applies, rather than all being
+    /// tried. Rules of higher priority are still always tried first, but when two rules of equal
+    /// priority apply to the same expression, which of them is applied may differ from run to run
+    /// of the same model with different options.
+    pub fn adaptive_rule_order(self, adaptive_rule_order: bool) -> Self {
+        Self {
+            adaptive_rule_order,
+            ..self
+        }
+    }
+
+    /// In each pass over the constraints, apply a rule to every expression one applies to, rather
+    /// than starting again from the root after the first rewrite.
+    ///
+    /// A rewritten expression is not visited again in the same pass, so the rewrites in a pass
+    /// never overlap. For wide constraints with many independent rewrites, this greatly reduces
+    /// the number of passes. Rules are applied with the model as it was at the start of the pass.
+    ///
+    /// Limits, checks, and cycle detection run between passes rather than between rewrites, so a
+    /// pass may overshoot `max_rewrites`, and errors found by a check are attributed to the last
+    /// rule applied in the pass.
+    pub fn batch_rewrites(self, batch_rewrites: bool) -> Self {
+        Self {
+            batch_rewrites,
+            ..self
+        }
+    }
+
+    /// Normalise the constraints bottom-up, remembering the normal form of each distinct
+    /// sub-expression by its hash, so that repeated sub-expressions are only normalised once.
+    /// Each normal form is kept with a copy of the expression it was made from, and only reused
+    /// for an expression equal to it, as different expressions may share a hash.
+    ///
+    /// This is only sound if every rule is [pure](crate::rule_engine::Rule::pure), and the rules
+    /// are confluent and terminating: the result of rewriting an expression must not depend on
+    /// where it appears, or on the order in which rules are applied to it. If any rule being
+    /// applied is not marked pure, the cache is not used, and this is reported in
+    /// [`RewriteOutcome::ignored_options`](crate::rule_engine::RewriteOutcome::ignored_options). A
+    /// pure rule that adds top-level constraints or symbols fails with
+    /// [`RuleErrorKind::ImpureRewrite`](crate::rule_engine::RuleErrorKind::ImpureRewrite).
+    ///
+    /// The whole of the constraints is normalised in a single pass, so as with
+    /// [`batch_rewrites`](Self::batch_rewrites), checks run between passes rather than between
+    /// rewrites. Rewriting stops partway through the pass if the rewrite budget runs out, the
+    /// [timeout](Self::timeout) is reached or rewriting is [cancelled](Self::cancel_flag).
+    pub fn cache_normal_forms(self, cache_normal_forms: bool) -> Self {
+        Self {
+            cache_normal_forms,
+            ..self
+        }
+    }
+
+    /// Rewrite by equality saturation, as set out in `saturation`: apply every rule to every
+    /// expression without replacing it, keeping all the expressions found to be equal, then return
+    /// the cheapest. This avoids committing to a rewrite that makes a better one impossible, at
+    /// the cost of trying rules on many more expressions.
+    ///
+    /// This is only sound if every rule is [pure](crate::rule_engine::Rule::pure). If any rule
+    /// being applied is not marked pure, the model is rewritten as usual, and this is reported in
+    /// [`RewriteOutcome::ignored_options`](crate::rule_engine::RewriteOutcome::ignored_options). A
+    /// pure rule that adds top-level constraints or symbols fails with
+    /// [`RuleErrorKind::ImpureRewrite`](crate::rule_engine::RuleErrorKind::ImpureRewrite), with an
+    /// empty path, as the expression it was applied to may appear in many places.
+    ///
+    /// Rule priorities have no effect, as every rule is applied. Of the other options, only the
+    /// rewrite budget, `timeout`, `cancel`, and `partial_on_error` are used. Rewrites are counted
+    /// as the number of times two classes of equal expressions are merged, and no trace or
+    /// reports are returned. Rules that keep making larger expressions prevent saturation, so set
+    /// a limit on [`Saturation::max_nodes`] or on the rewrite budget for such rule sets.
+    pub fn equality_saturation(self, saturation: Saturation) -> Self {
+        Self {
+            saturation: Some(saturation),
+            ..self
+        }
+    }
+
+    /// Rewrite by cost, as set out in `guide`: at each step, find every rewrite any rule makes of
+    /// any expression in the constraints, and apply the one that leaves the constraints cheapest,
+    /// until no rewrite does better than the constraints as they are. This makes the rewriter a
+    /// local-search simplifier, rather than one that rewrites until no rule applies.
+    ///
+    /// Rewrites that leave the cost unchanged are applied, but never one that returns to a model
+    /// already seen, so rules that undo each other do not stop rewriting. Unless
+    /// [`CostGuided::allow_increase`] is set, rewrites that make the constraints more costly are
+    /// never applied, and the status is [`RewriteStatus::Fixpoint`](crate::rule_engine::RewriteStatus::Fixpoint)
+    /// once none is left, even if rules still apply. If it is set, rewriting only stops once
+    /// every rewrite leads to a model already seen, so set a rewrite budget.
+    ///
+    /// With [`CostGuided::annealing`] set, a random rewrite is tried at each step instead, and
+    /// applied by the chance the [`Annealing`](crate::rule_engine::Annealing) schedule gives it.
+    /// Models already seen may be returned to, and the cheapest model seen is returned once the
+    /// schedule has cooled, with its seed in the outcome. With [`CostGuided::lookahead`] set,
+    /// each rewrite is judged by the cheapest constraints it leads to within that many further
+    /// rewrites, rather than by the constraints it leaves.
+    ///
+    /// Rule priorities only break ties between rewrites of equal cost to the same expression.
+    /// Every rule is tried on every expression at every step, so this is much slower than
+    /// rewriting as usual. See [`SearchStrategy`] for the options this cannot be used with.
+    pub fn cost_guided(self, guide: CostGuided) -> Self {
+        Self {
+            strategy: SearchStrategy::CostGuided(guide),
+            ..self
+        }
+    }
+
+    /// Rewrite with backtracking, as set out in `backtracking`: when a model is found to be a
+    /// dead end, such as one a solver rejects, go back to the last model where another rewrite
+    /// could have been made, and make that one instead. This can repair models that rewriting as
+    /// usual would turn into ones that cannot be used.
+    ///
+    /// At each model, the rewrites are tried in the order the rewriter would make them: by
+    /// expression in pre-order, then by rule priority. Each model is only rewritten once, so rules
+    /// that undo each other lead back to a choice point rather than round in a cycle. Every model
+    /// on the way to the current one is kept, so memory grows with the depth of rewriting. If
+    /// every way of rewriting the model leads to a dead end, or back to a model already reached,
+    /// rewriting fails with [`EngineError::DeadEnd`](crate::rule_engine::EngineError::DeadEnd).
+    ///
+    /// Rewrites made on the way to a dead end count towards `max_rewrites`, and are left out of
+    /// the trace. See [`SearchStrategy`] for the options this cannot be used with.
+    pub fn backtracking(self, backtracking: Backtracking) -> Self {
+        Self {
+            strategy: SearchStrategy::Backtracking(backtracking),
+            ..self
+        }
+    }
+
+    /// Rewrite by beam search, as set out in `search`: keep the cheapest few models found at each
+    /// step, rewrite each of them in every way the rules allow, and return the cheapest model
+    /// seen. Unlike [`cost_guided`](Self::cost_guided), this can find a cheap model that is only
+    /// reached through more costly ones, without trying every way of rewriting the model.
+    ///
+    /// Models are told apart by their constraints and the names of their symbols, and each is
+    /// only kept the first time it is reached. The cheapest model seen need not be a normal form.
+    /// The status is [`RewriteStatus::Fixpoint`](crate::rule_engine::RewriteStatus::Fixpoint) if
+    /// no model in the beam could be rewritten, and
+    /// [`RewriteStatus::BudgetExhausted`](crate::rule_engine::RewriteStatus::BudgetExhausted) if
+    /// [`BeamSearch::steps`] or `max_rewrites` ran out first.
+    ///
+    /// Every rule is tried on every expression of every model in the beam at each step, so this
+    /// is much slower than rewriting as usual. See [`SearchStrategy`] for the options this cannot
+    /// be used with.
+    pub fn beam_search(self, search: BeamSearch) -> Self {
+        Self {
+            strategy: SearchStrategy::BeamSearch(search),
+            ..self
+        }
+    }
+
+    /// Rewrite by random choice, as set out in `stochastic`: at each step, find every rewrite any
+    /// rule makes of any expression in the constraints, and apply one chosen at random from the
+    /// seed, weighted by rule. This is useful for fuzzing rule sets, and for restarting a search
+    /// from many different models. The seed is returned in
+    /// [`RewriteOutcome::seed`](crate::rule_engine::RewriteOutcome::seed), so that a run can be
+    /// repeated.
+    ///
+    /// Rewriting stops once no rule with a weight above 0 applies. Rules that undo each other
+    /// keep rewriting until the budget runs out, so set one. See [`SearchStrategy`] for the
+    /// options this cannot be used with.
+    pub fn stochastic(self, stochastic: Stochastic) -> Self {
+        Self {
+            strategy: SearchStrategy::Stochastic(stochastic),
+            ..self
+        }
+    }
+
+    /// Rewrite by best-first search, as set out in `search`: explore the models the rules can
+    /// reach in order of the cost of the rewrites that reach them plus an estimate of the cost
+    /// left, and return the first normal form explored. This finds the shortest way of rewriting
+    /// the model, or the cheapest by [`BestFirst::rule_cost`], where the order rewrites are made
+    /// in by the rewriter would take a longer way round.
+    ///
+    /// Models are told apart by their constraints and the names of their symbols, and each is
+    /// only explored once. If every model reached is explored without finding a normal form, as
+    /// when rules undo each other, the error is
+    /// [`EngineError::DeadEnd`](crate::rule_engine::EngineError::DeadEnd). If `max_rewrites` runs
+    /// out first, the model explored with the least estimate is returned.
+    ///
+    /// Every rule is tried on every expression of every model explored, and every model reached
+    /// is kept, so this is much slower than rewriting as usual, and needs a budget on anything
+    /// but small models. See [`SearchStrategy`] for the options this cannot be used with.
+    pub fn best_first(self, search: BestFirst) -> Self {
+        Self {
+            strategy: SearchStrategy::BestFirst(search),
+            ..self
+        }
+    }
+
+    /// Try the [pure](crate::rule_engine::Rule::pure) rules that may apply to an expression on
+    /// `rule_threads` threads at once, then apply the first that succeeded in priority order.
+    ///
+    /// Rules that are not pure are still tried one at a time, in order, as they may change the
+    /// model. Each thread tries rules against its own copy of the symbol table, so this only pays
+    /// off when rules are expensive to try, or many rules may apply to the same expression. The
+    /// result is the same as trying the rules one at a time.
+    pub fn parallel_rule_trials(self, rule_threads: usize) -> Self {
+        Self {
+            rule_threads,
+            ..self
+        }
+    }
+
+    /// Guarantee that the rewritten model is identical to the one produced by trying rules one at
+    /// a time, whatever the number of threads and however long rules take.
+    ///
+    /// Rules tried in parallel are always committed in priority order, and their panics raised in
+    /// priority order, so only options that depend on timing can make the result differ. In
+    /// deterministic mode, `slow_rule_threshold` is not used, as a rule sharing the machine with
+    /// others may take longer than it would alone, and this is reported in
+    /// [`RewriteOutcome::ignored_options`](crate::rule_engine::RewriteOutcome::ignored_options).
+    /// `timeout` still stops rewriting, as this is reported by
+    /// [`RewriteStatus::Timeout`](crate::rule_engine::RewriteStatus::Timeout).
+    pub fn deterministic(self, deterministic: bool) -> Self {
+        Self {
+            deterministic,
+            ..self
+        }
+    }
+
+    /// Share each pass over the constraints in [`batch_rewrites`](Self::batch_rewrites) mode
+    /// between `threads` threads.
+    ///
+    /// The top of the constraints is visited on one thread, until there are several
+    /// sub-expressions per thread left to visit. Each thread then takes the next unvisited
+    /// sub-expression whenever it finishes one, so that a thread given a small sub-expression
+    /// goes on to share the rest of the work. The rewrites found by each thread are kept apart
+    /// until the end of the pass, then merged in the order the sub-expressions appear in, so the
+    /// result does not depend on the number of threads.
+    ///
+    /// This is only used if `batch_rewrites` is set and every rule is
+    /// [pure](crate::rule_engine::Rule::pure), as each thread applies rules with its own copy of
+    /// the symbol table. Otherwise each pass is made on one thread, and this is reported in
+    /// [`RewriteOutcome::ignored_options`](crate::rule_engine::RewriteOutcome::ignored_options).
+    pub fn work_stealing(self, threads: usize) -> Self {
+        Self {
+            work_stealing_threads: threads,
+            ..self
+        }
+    }
+
+    /// Only work on expressions of at least `size` sub-expressions with more than one thread, so
+    /// that small models do not pay for starting threads.
+    ///
+    /// With [`parallel_rule_trials`](Self::parallel_rule_trials), rules are tried one at a time on
+    /// smaller expressions. With [`work_stealing`](Self::work_stealing), passes over smaller
+    /// constraints are made on one thread.
+    pub fn parallel_min_size(self, size: usize) -> Self {
+        Self {
+            parallel_min_size: size,
+            ..self
+        }
+    }
+
+    /// Start at most `threads` threads at once, however many are asked for by
+    /// [`parallel_rule_trials`](Self::parallel_rule_trials) or [`work_stealing`](Self::work_stealing).
+    ///
+    /// The threads sharing a pass in `work_stealing` mode try rules one at a time, so the two
+    /// never start threads at once.
+    pub fn max_threads(self, threads: usize) -> Self {
+        Self {
+            max_threads: Some(threads),
+            ..self
+        }
+    }
+
+    pub fn on_spawn_failure(self, policy: SpawnFailurePolicy) -> Self {
+        Self {
+            on_spawn_failure: policy,
+            ..self
+        }
+    }
+
+    /// Hold the constraints in an arena while rewriting, as a flat list of nodes that refer to
+    /// their children by index, and convert them back to an expression at the end.
+    ///
+    /// Passes over the arena visit the nodes of a flat list rather than following boxes, and
+    /// skip sub-expressions no rule can change without looking inside them. An expression is only
+    /// rebuilt from the arena when a rule may apply to it, so this pays off for rule sets that
+    /// apply to few variants of expression, and costs more than it saves when rules apply almost
+    /// everywhere.
+    ///
+    /// The arena is not used with options that inspect the whole of the constraints between
+    /// rewrites (`invariant`, `detect_cycles`, `checkpoint_interval`, `capture_repro`,
+    /// `quarantine_after`, `observers`, and `watches`), nor with `batch_rewrites`,
+    /// `cache_normal_forms`, or `work_stealing`, which make passes of their own. If it is not used,
+    /// this is reported in
+    /// [`RewriteOutcome::ignored_options`](crate::rule_engine::RewriteOutcome::ignored_options).
+    pub fn arena(self, arena: bool) -> Self {
+        Self { arena, ..self }
+    }
+
+    /// Checks that the options make sense together.
+    pub(super) fn validate(&self) -> Result<(), EngineError> {
+        if self.quarantine_after == Some(0) {
+            return Err(EngineError::InvalidOptions(String::from(
+                "quarantine_after must be at least 1",
+            )));
+        }
+        if !matches!(self.strategy, SearchStrategy::FirstFound) {
+            let unsupported = [
+                (
+                    "on_rule_error",
+                    self.on_rule_error != RuleErrorPolicy::default()
+                        || !self.rule_set_error_policies.is_empty(),
+                ),
+                ("catch_panics", self.catch_panics),
+                ("observers", !self.observers.is_empty()),
+                ("invariant", self.invariant.is_some()),
+                ("max_size", self.max_size.is_some()),
+            ];
+            if let Some((name, _)) = unsupported.iter().find(|(_, set)| *set) {
+                return Err(EngineError::InvalidOptions(format!(
+                    "{name} cannot be used with the {} search strategy",
+                    self.strategy.name()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// The policy to use for errors returned by `rule`.
+    pub fn rule_error_policy(&self, rule: &Rule) -> RuleErrorPolicy {
+        rule.rule_sets
+            .iter()
+            .find_map(|(name, _)| self.rule_set_error_policies.get(*name))
+            .copied()
+            .unwrap_or(self.on_rule_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_rejects_quarantine_after_no_failures() {
+        let options = RewriteOptions::new().quarantine_after(0);
+        assert!(matches!(
+            options.validate(),
+            Err(EngineError::InvalidOptions(_))
+        ));
+        assert!(RewriteOptions::new().quarantine_after(1).validate().is_ok());
+    }
+
+    #[test]
+    fn validate_rejects_options_a_search_strategy_cannot_use() {
+        let search = RewriteOptions::new().cost_guided(CostGuided::new(|_| 1.0));
+        for options in [
+            search.clone().on_rule_error(RuleErrorPolicy::Error),
+            search
+                .clone()
+                .on_rule_error_in("Base", RuleErrorPolicy::Error),
+            search.clone().catch_panics(true),
+            search.clone().check_invariant(|_| Ok(())),
+            search.clone().max_size(100),
+        ] {
+            assert!(matches!(
+                options.validate(),
+                Err(EngineError::InvalidOptions(_))
+            ));
+        }
+
+        // The same options are fine with the rewriter's own loop
+        let options = RewriteOptions::new()
+            .on_rule_error(RuleErrorPolicy::Error)
+            .catch_panics(true)
+            .check_invariant(|_| Ok(()))
+            .max_size(100);
+        assert!(options.validate().is_ok());
+    }
+}
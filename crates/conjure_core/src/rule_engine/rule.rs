@@ -1,30 +1,44 @@
+use std::any::TypeId;
 use std::fmt::{self, Display, Formatter};
 use std::hash::Hash;
 
 use thiserror::Error;
 
-use crate::ast::{Expression, SymbolTable};
+use crate::ast::{Expression, Name, SymbolTable};
+use crate::meta::{MetaKey, MetaStore, MetaValue};
 use crate::metadata::Metadata;
 use crate::model::Model;
+use crate::rule_engine::{MetaSnapshot, Subtree};
 
 #[derive(Debug, Error)]
 pub enum ApplicationError {
     #[error("Rule is not applicable")]
     RuleNotApplicable,
 
+    /// As `RuleNotApplicable`, with the reason the rule does not apply, reported by
+    /// [`explain`](crate::rule_engine::explain).
+    #[error("Rule is not applicable: {0}")]
+    NotApplicable(String),
+
     #[error("Could not find the min/max bounds for the expression")]
     BoundError,
+
+    /// A rule that changes the model directly removed symbols, which cannot be made part of its
+    /// reduction. See [`apply_to_model_copy`].
+    #[error("Rule removed symbols from the model: {0:?}")]
+    SymbolsRemoved(Vec<Name>),
 }
 
 /// The result of applying a rule to an expression.
 ///
-/// Contains an expression to replace the original, a top-level constraint to add to the top of the constraint AST, and an expansion to the model symbol table.
+/// Contains an expression to replace the original, a top-level constraint to add to the top of the constraint AST, an expansion to the model symbol table, and values to set in the model's [`MetaStore`].
 #[non_exhaustive]
 #[derive(Clone, Debug)]
 pub struct Reduction {
     pub new_expression: Expression,
     pub new_top: Expression,
     pub symbols: SymbolTable,
+    pub meta: MetaStore,
 }
 
 /// The result of applying a rule to an expression.
@@ -37,6 +51,7 @@ impl Reduction {
             new_expression,
             new_top,
             symbols,
+            meta: MetaStore::new(),
         }
     }
 
@@ -46,6 +61,7 @@ impl Reduction {
             new_expression,
             new_top: Expression::Nothing,
             symbols: SymbolTable::new(),
+            meta: MetaStore::new(),
         }
     }
 
@@ -55,6 +71,7 @@ impl Reduction {
             new_expression,
             new_top: Expression::Nothing,
             symbols,
+            meta: MetaStore::new(),
         }
     }
 
@@ -64,43 +81,110 @@ impl Reduction {
             new_expression,
             new_top,
             symbols: SymbolTable::new(),
+            meta: MetaStore::new(),
         }
     }
 
+    /// The same reduction, which also sets the value of type `K` in the model's [`MetaStore`].
+    pub fn with_meta<K: MetaValue>(mut self, value: K) -> Self {
+        self.meta.insert(value);
+        self
+    }
+
+    /// Whether applying the reduction changes anything but the expression it replaces.
+    pub fn has_side_effects(&self) -> bool {
+        !self.new_top.is_nothing() || !self.symbols.is_empty() || !self.meta.is_empty()
+    }
+
     // Apply side-effects (e.g. symbol table updates
     pub fn apply(self, model: &mut Model) {
         model.variables.extend(self.symbols); // Add new assignments to the symbol table
+        model.meta.extend(self.meta);
         if self.new_top.is_nothing() {
-            model.constraints = self.new_expression.clone();
+            model.constraints = self.new_expression;
         } else {
-            model.constraints = match self.new_expression {
-                Expression::And(metadata, mut exprs) => {
-                    // Avoid creating a nested conjunction
-                    exprs.push(self.new_top.clone());
-                    Expression::And(metadata.clone(), exprs)
-                }
-                _ => Expression::And(
-                    Metadata::new(),
-                    vec![self.new_expression.clone(), self.new_top],
-                ),
-            };
+            let mut constraints = self.new_expression;
+            if let Expression::And(_, exprs) = &mut constraints {
+                // Avoid creating a nested conjunction
+                exprs.push(self.new_top);
+                constraints.invalidate_annotations();
+            } else {
+                constraints = Expression::And(Metadata::new(), vec![constraints, self.new_top]);
+            }
+            model.constraints = constraints;
         }
     }
 }
 
+/// Applies `application`, a rule that changes the model it is given directly, to `expr` in `mdl`,
+/// without changing `mdl`.
+///
+/// The rule is given a [snapshot](MetaSnapshot::snapshot) of `mdl`: a copy of its symbol table
+/// and [`MetaStore`], without its constraints. If it applies, the symbols it added to the copy or
+/// changed in it are added to its reduction, so that they are made to the model along with the
+/// rewrite, or not at all if the rewrite is not kept. The same goes for the values it set in the
+/// copy's [`MetaStore`], or borrowed mutably. If it does not apply, the copy is dropped, undoing
+/// its changes. Symbols and values in the reduction the rule returns take precedence over those in
+/// the copy. A reduction cannot remove symbols, so a rule that removes symbols from the copy fails
+/// with [`ApplicationError::SymbolsRemoved`]. Rules that read the constraints must take `&Model`.
+///
+/// Rules registered with `#[register_rule]` that take `&mut Model` are applied this way. The
+/// symbol table is copied each time the rule is tried, so this is slower than returning the
+/// changes in the reduction, but simpler for rules that make several changes as they go. Meta
+/// values are shared with the copy rather than copied, until the rule changes them, see
+/// [`MetaStore`].
+pub fn apply_to_model_copy(
+    expr: &Expression,
+    mdl: &Model,
+    application: fn(&Expression, &mut Model) -> ApplicationResult,
+) -> ApplicationResult {
+    let mut copy = mdl.snapshot();
+    let mut reduction = application(expr, &mut copy)?;
+    mdl.reserve_names(&copy);
+    let removed: Vec<Name> = mdl
+        .variables
+        .keys()
+        .filter(|name| !copy.variables.contains_key(*name))
+        .cloned()
+        .collect();
+    if !removed.is_empty() {
+        return Err(ApplicationError::SymbolsRemoved(removed));
+    }
+    reduction.meta.extend_missing(copy.meta.take_changed());
+    for (name, variable) in copy.variables {
+        if mdl.variables.get(&name) != Some(&variable) {
+            reduction.symbols.entry(name).or_insert(variable);
+        }
+    }
+    Ok(reduction)
+}
+
 /**
  * A rule with a name, application function, and rule sets.
  *
  * # Fields
  * - `name` The name of the rule.
  * - `application` The function to apply the rule.
+ * - `subtree_application` The function to apply the rule, if it takes a [`Subtree`] rather than
+ *   an `&Expression`. `application` then calls it with a borrowed subtree.
  * - `rule_sets` A list of rule set names and priorities that this rule is a part of. This is used to populate rulesets at runtime.
+ * - `pure` Whether the rule is pure: see [`Rule::pure`].
+ * - `applies_to` The variants of expression the rule can apply to, if known: see [`Rule::applies_to`].
+ * - `produces` The variants of expression the rule can create, if known: see [`Rule::produces`].
+ * - `reads_meta` The types of meta the rule reads, if known: see [`Rule::reads_meta`].
+ * - `writes_meta` The types of meta the rule sets, if known: see [`Rule::writes_meta`].
  */
 #[derive(Clone, Debug)]
 pub struct Rule<'a> {
     pub name: &'a str,
     pub application: fn(&Expression, &Model) -> ApplicationResult,
+    pub subtree_application: Option<fn(&mut Subtree, &Model) -> ApplicationResult>,
     pub rule_sets: &'a [(&'a str, u8)], // (name, priority). At runtime, we add the rule to rulesets
+    pub pure: bool,
+    pub applies_to: Option<&'a [&'a str]>,
+    pub produces: Option<&'a [&'a str]>,
+    pub reads_meta: Option<&'a [MetaKey]>,
+    pub writes_meta: Option<&'a [MetaKey]>,
 }
 
 impl<'a> Rule<'a> {
@@ -112,13 +196,104 @@ impl<'a> Rule<'a> {
         Self {
             name,
             application,
+            subtree_application: None,
             rule_sets,
+            pure: false,
+            applies_to: None,
+            produces: None,
+            reads_meta: None,
+            writes_meta: None,
+        }
+    }
+
+    /// Marks the rule as pure: whether it applies, and what it rewrites an expression to, depend
+    /// only on the expression and the meta it [reads](Rule::reads_meta), and it never adds
+    /// top-level constraints or symbols, or sets meta.
+    ///
+    /// Rules registered with `#[register_rule(("RuleSet", 10), pure)]` are marked pure.
+    pub const fn pure(self) -> Self {
+        Self { pure: true, ..self }
+    }
+
+    /// Declares the variants of expression the rule can apply to, by their names in
+    /// [`Expression::VARIANT_NAMES`]. The rule is only tried on expressions of these variants.
+    ///
+    /// Rules registered with `#[register_rule(("RuleSet", 10), applies_to(Lt, Gt))]` are given
+    /// these variants.
+    pub const fn applies_to(self, variants: &'a [&'a str]) -> Self {
+        Self {
+            applies_to: Some(variants),
+            ..self
         }
     }
 
+    /// Declares the variants of expression the rule can create, in its new expression or new
+    /// top-level constraints, by their names in [`Expression::VARIANT_NAMES`]. Sub-expressions
+    /// moved or copied from the expression the rule was applied to do not count.
+    ///
+    /// Along with [`Rule::applies_to`], this lets the rewriter work out which rules can never
+    /// apply to a model, and which sub-expressions no rule can ever change.
+    pub const fn produces(self, variants: &'a [&'a str]) -> Self {
+        Self {
+            produces: Some(variants),
+            ..self
+        }
+    }
+
+    /// Declares the types of [`meta`](Model::meta) the rule reads. The rule is only tried on
+    /// models with a value of each of these types, and if it is [pure](Rule::pure), it is given
+    /// copies of them when it is tried on another thread.
+    ///
+    /// Rules registered with `#[register_rule(("RuleSet", 10), reads_meta(Bounds))]` are given
+    /// these types.
+    pub const fn reads_meta(self, types: &'a [MetaKey]) -> Self {
+        Self {
+            reads_meta: Some(types),
+            ..self
+        }
+    }
+
+    /// Declares the types of [`meta`](Model::meta) the rule may set. Setting any other stops
+    /// rewriting with [`RuleErrorKind::UndeclaredMeta`](crate::rule_engine::RuleErrorKind::UndeclaredMeta).
+    ///
+    /// Rules registered with `#[register_rule(("RuleSet", 10), writes_meta(Bounds))]` are given
+    /// these types.
+    pub const fn writes_meta(self, types: &'a [MetaKey]) -> Self {
+        Self {
+            writes_meta: Some(types),
+            ..self
+        }
+    }
+
+    /// Whether `meta` has a value of every type the rule reads.
+    pub fn can_read(&self, meta: &MetaStore) -> bool {
+        self.reads_meta
+            .is_none_or(|types| types.iter().all(|&key| meta.contains_key(key)))
+    }
+
+    /// The name of a type of meta set by `reduction` that the rule does not declare it sets, if
+    /// the rule declares the types it sets.
+    pub fn undeclared_write(&self, reduction: &Reduction) -> Option<&'static str> {
+        let declared: Vec<TypeId> = self.writes_meta?.iter().map(|key| key()).collect();
+        reduction
+            .meta
+            .types()
+            .find(|(key, _)| !declared.contains(key))
+            .map(|(_, name)| name)
+    }
+
     pub fn apply(&self, expr: &Expression, mdl: &Model) -> ApplicationResult {
         (self.application)(expr, mdl)
     }
+
+    /// Applies the rule to a subtree, which the rule may take rather than clone if it was
+    /// written to take a [`Subtree`].
+    pub fn apply_to_subtree(&self, subtree: &mut Subtree, mdl: &Model) -> ApplicationResult {
+        match self.subtree_application {
+            Some(application) => application(subtree, mdl),
+            None => (self.application)(subtree, mdl),
+        }
+    }
 }
 
 impl<'a> Display for Rule<'a> {
@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::Result;
+use crate::rule_engine::PerfReport;
+
+/// How often each rule fired over many rewriter runs, such as the runs on a test corpus.
+///
+/// Built from the [`PerfReport`] of each run, so runs must set
+/// [`RewriteOptions::perf_report`](crate::rule_engine::RewriteOptions::perf_report). Rules that
+/// never fire over a representative corpus are candidates for removal, or for new tests.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RuleCoverage {
+    /// The number of runs recorded.
+    pub runs: usize,
+    /// How often each rule was tried and fired, by rule name. Every rule in the rule sets of any
+    /// recorded run has an entry.
+    pub rules: HashMap<String, RuleHits>,
+}
+
+/// How often a single rule was tried and fired, over the runs in a [`RuleCoverage`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RuleHits {
+    /// The number of expressions the rule was tried on.
+    pub attempts: usize,
+    /// The number of expressions the rule applied to.
+    pub fires: usize,
+    /// The number of runs in which the rule applied at least once.
+    pub runs: usize,
+}
+
+impl RuleCoverage {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Records a run, from the [`PerfReport`] returned in
+    /// [`RewriteOutcome::perf`](crate::rule_engine::RewriteOutcome::perf).
+    pub fn record(&mut self, perf: &PerfReport) {
+        self.runs += 1;
+        for (rule, perf) in &perf.rules {
+            let hits = self.rules.entry(rule.clone()).or_default();
+            hits.attempts += perf.attempts;
+            hits.fires += perf.successes;
+            if perf.successes > 0 {
+                hits.runs += 1;
+            }
+        }
+    }
+
+    /// Adds the runs recorded in `other` to this report, for example to combine the coverage of
+    /// several corpora.
+    pub fn merge(&mut self, other: &RuleCoverage) {
+        self.runs += other.runs;
+        for (rule, other_hits) in &other.rules {
+            let hits = self.rules.entry(rule.clone()).or_default();
+            hits.attempts += other_hits.attempts;
+            hits.fires += other_hits.fires;
+            hits.runs += other_hits.runs;
+        }
+    }
+
+    /// The names of the rules that never fired in any run, in alphabetical order.
+    pub fn never_fired(&self) -> Vec<&str> {
+        let mut rules: Vec<_> = self
+            .rules
+            .iter()
+            .filter(|(_, hits)| hits.fires == 0)
+            .map(|(name, _)| name.as_str())
+            .collect();
+        rules.sort();
+        rules
+    }
+
+    /// The names of the rules that were never even tried, as no expression they are declared to
+    /// apply to was seen, in alphabetical order.
+    pub fn never_tried(&self) -> Vec<&str> {
+        let mut rules: Vec<_> = self
+            .rules
+            .iter()
+            .filter(|(_, hits)| hits.attempts == 0)
+            .map(|(name, _)| name.as_str())
+            .collect();
+        rules.sort();
+        rules
+    }
+
+    /// Writes the report to a file as JSON.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let file = File::create(path).map_err(anyhow::Error::from)?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+
+    /// Reads a report written by [`RuleCoverage::save`].
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let file = File::open(path).map_err(anyhow::Error::from)?;
+        Ok(serde_json::from_reader(BufReader::new(file))?)
+    }
+}
+
+impl Display for RuleCoverage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let fired = self.rules.len() - self.never_fired().len();
+        writeln!(
+            f,
+            "{} of {} rules fired over {} runs",
+            fired,
+            self.rules.len(),
+            self.runs
+        )?;
+        writeln!(
+            f,
+            "{:<40} {:>10} {:>10} {:>10}",
+            "Rule", "Attempts", "Fires", "Runs"
+        )?;
+        // Least used first, as those are the rules worth looking at
+        let mut rules: Vec<_> = self.rules.iter().collect();
+        rules.sort_by(|(a, m), (b, n)| m.fires.cmp(&n.fires).then(a.cmp(b)));
+        for (name, hits) in rules {
+            writeln!(
+                f,
+                "{:<40} {:>10} {:>10} {:>10}",
+                name, hits.attempts, hits.fires, hits.runs
+            )?;
+        }
+        Ok(())
+    }
+}
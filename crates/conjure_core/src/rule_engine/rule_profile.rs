@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::Result;
+
+/// How often each rule applied to each variant of expression, recorded over one or more rewriter
+/// runs.
+///
+/// Recorded when [`RewriteOptions::record_profile`](crate::rule_engine::RewriteOptions::record_profile)
+/// is set, and returned in [`RewriteOutcome::profile`](crate::rule_engine::RewriteOutcome::profile).
+/// A profile saved from training runs on representative models can be passed to later runs with
+/// [`RewriteOptions::rule_profile`](crate::rule_engine::RewriteOptions::rule_profile).
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RuleProfile {
+    /// The number of times each rule applied, by the name of the variant of expression it applied
+    /// to, then by rule name.
+    pub successes: HashMap<String, HashMap<String, usize>>,
+}
+
+impl RuleProfile {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// The number of times `rule` applied to expressions of the variant `variant`.
+    pub fn successes(&self, variant: &str, rule: &str) -> usize {
+        self.successes
+            .get(variant)
+            .and_then(|rules| rules.get(rule))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Records that `rule` applied to an expression of the variant `variant`.
+    pub fn record(&mut self, variant: &str, rule: &str) {
+        *self
+            .successes
+            .entry(variant.to_string())
+            .or_default()
+            .entry(rule.to_string())
+            .or_insert(0) += 1;
+    }
+
+    /// Adds the counts in `other` to this profile, for example to combine the profiles of several
+    /// training runs.
+    pub fn merge(&mut self, other: &RuleProfile) {
+        for (variant, rules) in &other.successes {
+            let counts = self.successes.entry(variant.clone()).or_default();
+            for (rule, successes) in rules {
+                *counts.entry(rule.clone()).or_insert(0) += successes;
+            }
+        }
+    }
+
+    /// Writes the profile to a file as JSON.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let file = File::create(path).map_err(anyhow::Error::from)?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+
+    /// Reads a profile written by [`RuleProfile::save`].
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let file = File::open(path).map_err(anyhow::Error::from)?;
+        Ok(serde_json::from_reader(BufReader::new(file))?)
+    }
+}
@@ -0,0 +1,273 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Instant;
+
+use derivative::Derivative;
+use uniplate::uniplate::Uniplate;
+
+use crate::ast::Expression;
+use crate::rule_engine::egraph::{ClassId, EGraph, ENode};
+use crate::rule_engine::reachability::Reachability;
+use crate::rule_engine::rewrite::budget_exhausted;
+use crate::rule_engine::{
+    BudgetPolicy, EngineError, RewriteError, RewriteOptions, RewriteOutcome, RewriteStatus, Rule,
+    RuleError, RuleErrorKind,
+};
+use crate::stats::RewriterStats;
+use crate::Model;
+
+/// The cost of one expression, given with `Expression::Nothing` in place of its children. See
+/// [`Saturation`].
+pub type NodeCost = Arc<dyn Fn(&Expression) -> u64 + Send + Sync>;
+
+/// Settings for rewriting by equality saturation, set with
+/// [`RewriteOptions::equality_saturation`].
+///
+/// Rather than replacing each expression a rule applies to, every rewrite is kept alongside the
+/// expression it was made from, in an e-graph: a set of classes of expressions known to be equal.
+/// Rules are applied until they find nothing new, and the cheapest expression equal to the
+/// constraints is returned. The cost of an expression is the sum of `cost` over its
+/// sub-expressions, so the rewriter never commits to a rewrite that only pays off when followed
+/// by others.
+///
+/// # Example
+/// ```rust
+/// use conjure_core::rule_engine::{RewriteOptions, Saturation};
+///
+/// let options = RewriteOptions::new()
+///     .equality_saturation(Saturation::smallest().max_nodes(10_000));
+/// ```
+#[derive(Clone, Derivative)]
+#[derivative(Debug)]
+pub struct Saturation {
+    #[derivative(Debug = "ignore")]
+    pub cost: NodeCost,
+    /// The maximum number of times to apply every rule to the e-graph.
+    pub max_iterations: Option<usize>,
+    /// The maximum number of distinct expressions, each counted without its children, that the
+    /// e-graph may hold.
+    pub max_nodes: Option<usize>,
+}
+
+impl Saturation {
+    /// Return the expression of least total `cost`.
+    pub fn new(cost: impl Fn(&Expression) -> u64 + Send + Sync + 'static) -> Self {
+        Self {
+            cost: Arc::new(cost),
+            max_iterations: None,
+            max_nodes: None,
+        }
+    }
+
+    /// Return the expression with the fewest sub-expressions.
+    pub fn smallest() -> Self {
+        Self::new(|_| 1)
+    }
+
+    /// Stop after applying every rule `max_iterations` times.
+    pub fn max_iterations(self, max_iterations: usize) -> Self {
+        Self {
+            max_iterations: Some(max_iterations),
+            ..self
+        }
+    }
+
+    /// Stop once the e-graph holds `max_nodes` distinct expressions.
+    pub fn max_nodes(self, max_nodes: usize) -> Self {
+        Self {
+            max_nodes: Some(max_nodes),
+            ..self
+        }
+    }
+}
+
+/// Rewrites `model` by equality saturation. See [`RewriteOptions::equality_saturation`].
+pub(super) fn saturate(
+    model: &Model,
+    rules: &[&Rule],
+    saturation: &Saturation,
+    options: &RewriteOptions,
+) -> Result<RewriteOutcome, RewriteError> {
+    let start = Instant::now();
+    let mut egraph = EGraph::new();
+    let mut terms = Vec::new();
+    let root = add_term(&mut egraph, &model.constraints, &mut terms);
+
+    // The rules tried on each expression, by hash, so that each is only tried once
+    let mut tried: HashSet<(usize, u64)> = HashSet::new();
+    let mut rewrites = 0;
+    let mut attempts = 0;
+    let mut iterations = 0;
+    let mut status = RewriteStatus::Fixpoint;
+    let mut error = None;
+    'saturate: loop {
+        if budget_exhausted(options, rewrites, attempts)
+            || saturation
+                .max_iterations
+                .is_some_and(|max| iterations >= max)
+            || saturation.max_nodes.is_some_and(|max| egraph.len() >= max)
+        {
+            status = RewriteStatus::BudgetExhausted;
+            break;
+        }
+        if options
+            .timeout
+            .is_some_and(|timeout| start.elapsed() >= timeout)
+        {
+            status = RewriteStatus::Timeout;
+            break;
+        }
+        if options
+            .cancel
+            .as_ref()
+            .is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+        {
+            status = RewriteStatus::Cancelled;
+            break;
+        }
+
+        // Rules are tried on the expressions added so far, and on every node with its children
+        // taken from the cheapest expressions of their classes, which finds rewrites that need
+        // the children to have been rewritten first
+        let cheapest = egraph.cheapest(&*saturation.cost);
+        for class in egraph.classes() {
+            for node in egraph.nodes(class) {
+                if let Some(term) = build(&egraph, &cheapest, node) {
+                    terms.push((class, term));
+                }
+            }
+        }
+
+        let attempts_before = attempts;
+        let mut found = Vec::new();
+        for (class, term) in terms.drain(..) {
+            let hash = term.subtree_hash();
+            for (i, rule) in rules.iter().enumerate() {
+                if !Reachability::declared_to_apply(rule, &term) || !tried.insert((i, hash)) {
+                    continue;
+                }
+                attempts += 1;
+                let Ok(reduction) = rule.apply(&term, model) else {
+                    continue;
+                };
+                if reduction.has_side_effects() {
+                    // There is no path to report, as the expression may appear in many places
+                    error = Some(RewriteError::from(RuleError {
+                        rule: rule.name.to_string(),
+                        path: Vec::new(),
+                        iteration: rewrites,
+                        kind: RuleErrorKind::ImpureRewrite,
+                        checkpoint: None,
+                        repro: None,
+                    }));
+                    break 'saturate;
+                }
+                if reduction.new_expression != term {
+                    found.push((class, reduction.new_expression));
+                }
+            }
+        }
+        // Every rule has been tried on every expression in the e-graph, so it is saturated
+        if attempts == attempts_before {
+            break;
+        }
+
+        let mut new_terms = Vec::new();
+        for (class, expression) in found {
+            let new_class = add_term(&mut egraph, &expression, &mut new_terms);
+            if egraph.merge(class, new_class) {
+                rewrites += 1;
+            }
+        }
+        egraph.rebuild();
+        terms = new_terms;
+        iterations += 1;
+    }
+
+    // The constraints have a cost, so their class has a cheapest expression
+    let cheapest = egraph.cheapest(&*saturation.cost);
+    let mut new_model = model.clone();
+    if let Some(constraints) = cheapest
+        .get(&egraph.find(root))
+        .and_then(|(_, node)| build(&egraph, &cheapest, node))
+    {
+        new_model.constraints = constraints;
+    }
+
+    if let Ok(mut context) = model.context.write() {
+        context.stats.add_rewriter_run(RewriterStats {
+            is_optimization_enabled: None,
+            rewriter_run_time: Some(start.elapsed()),
+            rewriter_rule_application_attempts: Some(attempts),
+            rewriter_rule_applications: Some(rewrites),
+            rewriter_peak_memory: None,
+        });
+    }
+
+    if status == RewriteStatus::BudgetExhausted {
+        log::warn!(target: "file", "Equality saturation stopped after {} rewrites and {} rule attempts", rewrites, attempts);
+        if options.on_budget_exhausted == BudgetPolicy::Error {
+            error = Some(EngineError::BudgetExhausted { rewrites, attempts }.into());
+        }
+    }
+    match error {
+        Some(error) if !options.partial_on_error => return Err(error),
+        Some(_) => status = RewriteStatus::Error,
+        None => {}
+    }
+    Ok(RewriteOutcome {
+        model: new_model,
+        status,
+        slow_rules: Vec::new(),
+        discarded_effects: Vec::new(),
+        quarantined: Vec::new(),
+        error,
+        perf: None,
+        profile: None,
+        heatmap: None,
+        trace: None,
+        seed: None,
+        ambiguities: Vec::new(),
+        choices: Vec::new(),
+        ignored_options: Vec::new(),
+    })
+}
+
+/// Adds `expression` to `egraph`, and it and each of its sub-expressions to `terms` with their
+/// classes, so that rules are tried on them as they are.
+fn add_term(
+    egraph: &mut EGraph,
+    expression: &Expression,
+    terms: &mut Vec<(ClassId, Expression)>,
+) -> ClassId {
+    for child in expression.children() {
+        add_term(egraph, &child, terms);
+    }
+    let class = egraph.add(expression);
+    terms.push((class, expression.clone()));
+    class
+}
+
+/// The expression made from `node` by taking each child from the cheapest expression of its
+/// class, or None if a child has no expression of finite cost.
+fn build(
+    egraph: &EGraph,
+    cheapest: &HashMap<ClassId, (u64, &ENode)>,
+    node: &ENode,
+) -> Option<Expression> {
+    let children = node
+        .children
+        .iter()
+        .map(|&class| {
+            cheapest
+                .get(&egraph.find(class))
+                .and_then(|(_, child)| build(egraph, cheapest, child))
+        })
+        .collect::<Option<Vec<_>>>()?;
+    Some(
+        node.shell
+            .with_children(children)
+            .unwrap_or_else(|_| node.shell.clone()),
+    )
+}
@@ -0,0 +1,72 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// Space for rules to keep working state, such as caches, for one run of the rewriter.
+///
+/// Each [`Model`](crate::Model) has a scratch space, which is shared by its copies, so state kept
+/// in it by a rule is there the next time the rule is tried in the same run, even if the rule is
+/// tried on a copy of the model. The rewriter gives the model a new, empty scratch space at the
+/// start of each run, and drops it at the end, so nothing in it is seen by a later run.
+///
+/// State is kept by type, so a rule keeps its own state by keying it with a type of its own,
+/// rather than through statics, or in the [`MetaStore`](crate::meta::MetaStore), which is part of
+/// the model and kept after the run.
+///
+/// # Example
+/// ```rust
+/// use std::collections::HashMap;
+///
+/// use conjure_core::ast::Expression;
+/// use conjure_core::rule_engine::{ApplicationError, ApplicationResult, Reduction};
+/// use conjure_core::Model;
+///
+/// /// The expressions `expensive_rule` has already found it cannot rewrite.
+/// #[derive(Default)]
+/// struct NotApplicable(HashMap<u64, ()>);
+///
+/// fn expensive_rule(expr: &Expression, mdl: &Model) -> ApplicationResult {
+///     let hash = expr.subtree_hash();
+///     if mdl.scratch.with(|seen: &mut NotApplicable| seen.0.contains_key(&hash)) {
+///         return Err(ApplicationError::RuleNotApplicable);
+///     }
+///     // ... work out whether the rule applies
+///     mdl.scratch.with(|seen: &mut NotApplicable| seen.0.insert(hash, ()));
+///     Err(ApplicationError::RuleNotApplicable)
+/// }
+/// ```
+#[derive(Clone, Default)]
+pub struct Scratch(Arc<Mutex<HashMap<TypeId, Box<dyn Any + Send>>>>);
+
+impl Scratch {
+    /// Calls `f` with the state of type `K`, set to its default first if there is none.
+    ///
+    /// The scratch space is locked while `f` runs, so `f` must not use it itself.
+    pub fn with<K: Any + Send + Default, R>(&self, f: impl FnOnce(&mut K) -> R) -> R {
+        let mut values = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        let value = values
+            .entry(TypeId::of::<K>())
+            .or_insert_with(|| Box::new(K::default()));
+        match value.downcast_mut() {
+            Some(value) => f(value),
+            // Values are stored under their own type, so this is never reached
+            None => f(&mut K::default()),
+        }
+    }
+
+    /// Returns true if no state is kept.
+    pub fn is_empty(&self) -> bool {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .is_empty()
+    }
+}
+
+impl Debug for Scratch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let values = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        write!(f, "Scratch({} values)", values.len())
+    }
+}
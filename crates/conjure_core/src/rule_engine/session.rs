@@ -0,0 +1,282 @@
+use std::sync::Arc;
+
+use derivative::Derivative;
+
+use crate::ast::Expression;
+use crate::rule_engine::rewrite::rewrite_owned_model;
+use crate::rule_engine::{
+    BudgetPolicy, RewriteError, RewriteOptions, RewriteStatus, RuleSet, SharedTree, TraceStep,
+};
+use crate::Model;
+
+/// Rewrites a model one rule application at a time, so that a debugger or notebook can inspect
+/// the model between rewrites, and undo them.
+///
+/// Each step is a run of the rewriter limited to one rewrite, so is recorded as a run in the
+/// model's stats. Options that look across rewrites, such as
+/// [`RewriteOptions::detect_cycles`] and [`RewriteOptions::checkpoint_every`], only see one step
+/// at a time. The model is handed to each run rather than copied, but each run still sets the
+/// rewriter up again, such as finding which rules can apply where, so stepping through a model
+/// costs more than rewriting it in one run.
+///
+/// # Example
+/// ```rust
+/// use conjure_core::rule_engine::{get_rule_set_by_name, ReductionSession, RewriteOptions};
+/// use conjure_core::Model;
+///
+/// let model = Model::new_empty(Default::default());
+/// let rule_sets = get_rule_set_by_name("Base").into_iter().collect();
+/// let mut session = ReductionSession::new(model, rule_sets, RewriteOptions::new());
+/// while let Some(step) = session.step().unwrap() {
+///     println!("{} rewrote the expression at {:?}", step.rule, step.path);
+/// }
+/// assert!(session.is_complete());
+/// ```
+///
+/// Breakpoints can be set with [`ReductionSession::add_breakpoint`], to jump to the first rewrite
+/// of interest with [`ReductionSession::run_to_breakpoint`].
+///
+/// The constraints before each rewrite are kept as a [`SharedTree`], which shares the
+/// sub-expressions the rewrite did not change with the constraints after it, so the history of
+/// constraints takes little more space than the rewrites themselves. The symbols and meta of the
+/// model are copied for each rewrite.
+pub struct ReductionSession<'a> {
+    model: Model,
+    /// The constraints of `model`, as a tree that the trees kept in `history` share with.
+    tree: SharedTree,
+    rule_sets: Vec<&'a RuleSet<'a>>,
+    options: RewriteOptions,
+    /// The rewrites applied so far, each with the model as it was before it. The constraints of
+    /// the model are left out, and kept as a tree instead.
+    history: Vec<(Model, SharedTree, TraceStep)>,
+    complete: bool,
+    breakpoints: Vec<Breakpoint>,
+    /// The rewrite the session stopped before at a breakpoint, if it has not moved since.
+    paused: Option<TraceStep>,
+}
+
+/// A condition on a rewrite that stops [`ReductionSession::run_to_breakpoint`] before the rewrite
+/// is applied.
+#[derive(Clone, Derivative)]
+#[derivative(Debug)]
+pub enum Breakpoint {
+    /// Stop before the rule with this name is applied.
+    Rule(String),
+    /// Stop before an expression for which this returns true is rewritten.
+    Node(#[derivative(Debug = "ignore")] Arc<dyn Fn(&Expression) -> bool + Send + Sync>),
+}
+
+impl Breakpoint {
+    /// Stop before the rule `name` is applied.
+    pub fn rule(name: &str) -> Self {
+        Breakpoint::Rule(name.to_string())
+    }
+
+    /// Stop before an expression matching `predicate` is rewritten.
+    pub fn node(predicate: impl Fn(&Expression) -> bool + Send + Sync + 'static) -> Self {
+        Breakpoint::Node(Arc::new(predicate))
+    }
+
+    /// Returns true if the breakpoint stops before `step`, made to the expression `before`.
+    pub fn matches(&self, step: &TraceStep, before: &Expression) -> bool {
+        match self {
+            Breakpoint::Rule(name) => step.rule == *name,
+            Breakpoint::Node(predicate) => predicate(before),
+        }
+    }
+}
+
+/// The tree of the constraints after `step`, sharing the sub-expressions `step` did not change with
+/// `tree`, the tree of the constraints before it, or None if `step` does not fit `tree`.
+fn tree_after(tree: &SharedTree, step: &TraceStep) -> Option<SharedTree> {
+    let tree = step
+        .edits
+        .iter()
+        .try_fold(tree.clone(), |tree, edit| tree.apply_edit(edit))?;
+    // A new top-level constraint is added as `TraceStep::apply` adds it
+    Some(match step.new_top.is_nothing() {
+        true => tree,
+        false => tree.add_top(SharedTree::new(step.new_top.clone())),
+    })
+}
+
+impl<'a> ReductionSession<'a> {
+    /// Starts a session rewriting `model` with the rules in `rule_sets`.
+    ///
+    /// The limits on rewrites and rule attempts in `options` are not used, and rewrites are never
+    /// batched, so that each step applies exactly one rule. Every step is traced, whatever the
+    /// trace filter in `options`.
+    pub fn new(model: Model, rule_sets: Vec<&'a RuleSet<'a>>, options: RewriteOptions) -> Self {
+        let options = RewriteOptions {
+            max_rewrites: Some(1),
+            max_rule_attempts: None,
+            on_budget_exhausted: BudgetPolicy::ReturnPartial,
+            partial_on_error: false,
+            batch_rewrites: false,
+            work_stealing_threads: 0,
+            trace: true,
+            trace_filter: None,
+            ..options
+        };
+        Self {
+            tree: SharedTree::new(model.constraints.clone()),
+            model,
+            rule_sets,
+            options,
+            history: Vec::new(),
+            complete: false,
+            breakpoints: Vec::new(),
+            paused: None,
+        }
+    }
+
+    /// Applies the next rewrite to the model.
+    ///
+    /// # Returns
+    /// - The rewrite applied, or None if no rule applies, or if rewriting stopped for another
+    ///   reason, such as a timeout or cancellation.
+    /// - A `RewriteError` if the rewrite failed, leaving the model as it was.
+    pub fn step(&mut self) -> Result<Option<&TraceStep>, RewriteError> {
+        self.paused = None;
+        // The model is kept without its constraints, which are moved to the rewriter, so that
+        // they are not copied
+        let constraints = std::mem::replace(&mut self.model.constraints, Expression::Nothing);
+        let mut model = self.model.clone();
+        model.constraints = constraints;
+        let outcome = match rewrite_owned_model(model, &self.rule_sets, &self.options) {
+            Ok(outcome) => outcome,
+            Err(error) => {
+                // The constraints as they were are still in the tree
+                self.model.constraints = self.tree.to_expression();
+                self.model.constraints.clear_clean_marks();
+                return Err(error);
+            }
+        };
+        self.complete = outcome.status == RewriteStatus::Fixpoint;
+        let previous = std::mem::replace(&mut self.model, outcome.model);
+        let mut trace = outcome.trace.unwrap_or_default();
+        let tree = trace
+            .iter()
+            .try_fold(self.tree.clone(), |tree, step| tree_after(&tree, step))
+            // The trace always fits the constraints it was made from, but nothing is shared if not
+            .unwrap_or_else(|| SharedTree::new(self.model.constraints.clone()));
+        let Some(step) = trace.pop() else {
+            return Ok(None);
+        };
+        let previous_tree = std::mem::replace(&mut self.tree, tree);
+        self.history.push((previous, previous_tree, step));
+        Ok(self.history.last().map(|(_, _, step)| step))
+    }
+
+    /// Applies rewrites until `predicate`, given each rewrite and the model after it, returns
+    /// true.
+    ///
+    /// # Returns
+    /// - The rewrite `predicate` stopped at, or None if no more rules apply first.
+    /// - A `RewriteError` if a rewrite failed, leaving the model as it was before it.
+    pub fn run_until(
+        &mut self,
+        mut predicate: impl FnMut(&TraceStep, &Model) -> bool,
+    ) -> Result<Option<&TraceStep>, RewriteError> {
+        loop {
+            if self.step()?.is_none() {
+                return Ok(None);
+            }
+            let stop = self
+                .history
+                .last()
+                .is_some_and(|(_, _, step)| predicate(step, &self.model));
+            if stop {
+                return Ok(self.history.last().map(|(_, _, step)| step));
+            }
+        }
+    }
+
+    /// Applies rewrites until the next rewrite would hit one of the breakpoints, and stops before
+    /// applying it.
+    ///
+    /// When the session is stopped at a breakpoint, the rewrite it stopped before is applied
+    /// first, without checking the breakpoints again.
+    ///
+    /// # Returns
+    /// - The rewrite the session stopped before, or None if no more rules apply first.
+    /// - A `RewriteError` if a rewrite failed, leaving the model as it was before it.
+    pub fn run_to_breakpoint(&mut self) -> Result<Option<&TraceStep>, RewriteError> {
+        let mut resuming = self.paused.is_some();
+        loop {
+            if self.step()?.is_none() {
+                return Ok(None);
+            }
+            let hit = !resuming
+                && !self.breakpoints.is_empty()
+                && self.history.last().is_some_and(|(_, previous, step)| {
+                    let before = previous.at_path(&step.path).map(SharedTree::to_expression);
+                    self.breakpoints.iter().any(|breakpoint| {
+                        before
+                            .as_ref()
+                            .is_some_and(|before| breakpoint.matches(step, before))
+                    })
+                });
+            resuming = false;
+            if hit {
+                // The rewrite was made to find out whether it hits a breakpoint, so is undone
+                self.paused = self.undo();
+                return Ok(self.paused.as_ref());
+            }
+        }
+    }
+
+    /// Adds a breakpoint, checked by [`ReductionSession::run_to_breakpoint`].
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+        self.breakpoints.push(breakpoint);
+    }
+
+    /// Removes all breakpoints.
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    /// The rewrite the session is stopped before at a breakpoint, if it has not been stepped or
+    /// undone since.
+    pub fn paused_at(&self) -> Option<&TraceStep> {
+        self.paused.as_ref()
+    }
+
+    /// Undoes the last rewrite, returning it, or None if there is nothing to undo.
+    pub fn undo(&mut self) -> Option<TraceStep> {
+        self.paused = None;
+        let (mut previous, tree, step) = self.history.pop()?;
+        previous.constraints = tree.to_expression();
+        // The clean marks kept in the tree may be older than the symbols and meta of the model
+        previous.constraints.clear_clean_marks();
+        self.model = previous;
+        self.tree = tree;
+        self.complete = false;
+        Some(step)
+    }
+
+    /// The constraints as they are after the rewrites applied so far.
+    pub fn current_tree(&self) -> &Expression {
+        &self.model.constraints
+    }
+
+    /// The model as it is after the rewrites applied so far.
+    pub fn model(&self) -> &Model {
+        &self.model
+    }
+
+    /// The rewrites applied so far, in order.
+    pub fn history(&self) -> impl Iterator<Item = &TraceStep> {
+        self.history.iter().map(|(_, _, step)| step)
+    }
+
+    /// Returns true if the last step found no rule to apply.
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    /// Ends the session, returning the model as it is.
+    pub fn into_model(self) -> Model {
+        self.model
+    }
+}
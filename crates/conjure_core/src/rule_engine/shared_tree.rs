@@ -0,0 +1,202 @@
+use std::sync::Arc;
+
+use crate::ast::{Expression, TreeEdit};
+use crate::metadata::Metadata;
+
+/// An expression held as a tree of reference-counted nodes, so that copies of it share their
+/// sub-expressions rather than cloning them.
+///
+/// Cloning a tree only counts a reference. Replacing a sub-expression with
+/// [`SharedTree::replace`] copies the expressions on the path down to it, and shares every other
+/// sub-expression between the old tree and the new one. Many versions of the constraints, each a
+/// few rewrites on from the last, then take little more space than the changes between them, as
+/// in the history [`ReductionSession`](crate::rule_engine::ReductionSession) keeps to undo
+/// rewrites.
+///
+/// # Example
+/// ```rust
+/// use conjure_core::ast::{Expression, Name};
+/// use conjure_core::metadata::Metadata;
+/// use conjure_core::rule_engine::SharedTree;
+///
+/// let x = Expression::Reference(Metadata::new(), Name::UserName(String::from("x")));
+/// let y = Expression::Reference(Metadata::new(), Name::UserName(String::from("y")));
+/// let tree = SharedTree::new(Expression::And(Metadata::new(), vec![x.clone(), x.clone()]));
+///
+/// let replaced = tree.replace(&[1], SharedTree::new(y.clone())).unwrap();
+/// assert_eq!(replaced.to_expression(), Expression::And(Metadata::new(), vec![x, y]));
+/// // The first conjunct is shared by both trees
+/// assert!(tree.child(0).unwrap().ptr_eq(replaced.child(0).unwrap()));
+/// ```
+#[derive(Clone, Debug)]
+pub struct SharedTree(Arc<Node>);
+
+#[derive(Debug)]
+struct Node {
+    /// The expression, with `Expression::Nothing` in place of its children.
+    shell: Expression,
+    children: Vec<SharedTree>,
+}
+
+impl SharedTree {
+    /// A tree holding `expression`, sharing nothing with any other tree.
+    pub fn new(expression: Expression) -> Self {
+        // The shells of the expressions in pre-order, with the number of children of each, so the
+        // tree can be built from the leaves up without recursing
+        let mut shells: Vec<(Expression, usize)> = Vec::new();
+        let mut unvisited = vec![expression];
+        while let Some(mut expression) = unvisited.pop() {
+            let start = unvisited.len();
+            expression.take_children(&mut unvisited);
+            let children = unvisited.len() - start;
+            shells.push((expression, children));
+        }
+
+        // The children of each shell were visited last first, so come off the end of `shells`
+        // first to last
+        let mut trees: Vec<SharedTree> = Vec::new();
+        while let Some((shell, children)) = shells.pop() {
+            let children = trees.split_off(trees.len() - children);
+            trees.push(SharedTree(Arc::new(Node { shell, children })));
+        }
+        trees.pop().unwrap_or_else(|| {
+            SharedTree(Arc::new(Node {
+                shell: Expression::Nothing,
+                children: Vec::new(),
+            }))
+        })
+    }
+
+    /// The expression at the root of the tree, with `Expression::Nothing` in place of its
+    /// sub-expressions.
+    pub fn shell(&self) -> &Expression {
+        &self.0.shell
+    }
+
+    /// The sub-tree of the `index`th direct sub-expression, if there is one.
+    pub fn child(&self, index: usize) -> Option<&SharedTree> {
+        self.0.children.get(index)
+    }
+
+    /// The sub-tree at `path`, a list of child indices, if there is one.
+    pub fn at_path(&self, path: &[usize]) -> Option<&SharedTree> {
+        path.iter().try_fold(self, |tree, &index| tree.child(index))
+    }
+
+    /// A tree with `subtree` in place of the sub-tree at `path`, sharing every other sub-tree with
+    /// this one, or None if there is no sub-tree at `path`.
+    pub fn replace(&self, path: &[usize], subtree: SharedTree) -> Option<SharedTree> {
+        let Some((&index, rest)) = path.split_first() else {
+            return Some(subtree);
+        };
+        let mut children = self.0.children.clone();
+        let child = children.get_mut(index)?;
+        *child = child.replace(rest, subtree)?;
+        Some(SharedTree(Arc::new(Node {
+            shell: self.0.shell.clone(),
+            children,
+        })))
+    }
+
+    /// A tree with `edit`, as made by [`Expression::diff`], applied to it, sharing every sub-tree
+    /// the edit does not change with this one, or None if the edit's path is not in the tree.
+    pub fn apply_edit(&self, edit: &TreeEdit) -> Option<SharedTree> {
+        if let TreeEdit::Changed { path, expression } = edit {
+            return self.replace(path, SharedTree::new(expression.clone()));
+        }
+
+        // Insertions and deletions are made in the list of operands of the parent
+        let (&index, parent_path) = edit.path().split_last()?;
+        let parent = self.at_path(parent_path)?;
+        let mut shell = parent.0.shell.clone();
+        let (Expression::Sum(_, operands)
+        | Expression::Min(_, operands)
+        | Expression::Or(_, operands)
+        | Expression::And(_, operands)
+        | Expression::AllDiff(_, operands)) = &mut shell
+        else {
+            return None;
+        };
+        let mut children = parent.0.children.clone();
+        match edit {
+            TreeEdit::Inserted { expression, .. } if index <= children.len() => {
+                operands.push(Expression::Nothing);
+                children.insert(index, SharedTree::new(expression.clone()));
+            }
+            TreeEdit::Deleted { .. } if index < children.len() => {
+                operands.pop();
+                children.remove(index);
+            }
+            _ => return None,
+        }
+        self.replace(parent_path, SharedTree(Arc::new(Node { shell, children })))
+    }
+
+    /// A tree with `top` added as a top-level constraint, in the same way as
+    /// [`Reduction::apply`](crate::rule_engine::Reduction::apply) adds one.
+    pub fn add_top(&self, top: SharedTree) -> SharedTree {
+        match &self.0.shell {
+            Expression::And(metadata, exprs) => {
+                // Avoid creating a nested conjunction
+                let mut children = self.0.children.clone();
+                children.push(top);
+                SharedTree(Arc::new(Node {
+                    shell: Expression::And(
+                        metadata.clone(),
+                        vec![Expression::Nothing; exprs.len() + 1],
+                    ),
+                    children,
+                }))
+            }
+            _ => SharedTree(Arc::new(Node {
+                shell: Expression::And(
+                    Metadata::new(),
+                    vec![Expression::Nothing, Expression::Nothing],
+                ),
+                children: vec![self.clone(), top],
+            })),
+        }
+    }
+
+    /// Builds the expression the tree holds, cloning the expressions in it.
+    pub fn to_expression(&self) -> Expression {
+        // The trees being built, and the index of the next child of each to build, as in
+        // `Expression::clone`
+        let mut stack = vec![(self, 0)];
+        let mut built = Vec::new();
+        while let Some((tree, i)) = stack.pop() {
+            match tree.child(i) {
+                Some(child) => {
+                    stack.push((tree, i + 1));
+                    stack.push((child, 0));
+                }
+                None => {
+                    let mut expression = tree.0.shell.clone();
+                    let start = built.len() - i;
+                    expression.restore_children(&mut built, start);
+                    built.push(expression);
+                }
+            }
+        }
+        built.pop().unwrap_or(Expression::Nothing)
+    }
+
+    /// Returns true if the two trees are the same tree, rather than only equal.
+    pub fn ptr_eq(&self, other: &SharedTree) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// Nodes are dropped from a stack rather than by recursion, so that deep trees do not overflow
+/// the call stack.
+impl Drop for Node {
+    fn drop(&mut self) {
+        let mut children = std::mem::take(&mut self.children);
+        while let Some(child) = children.pop() {
+            // Sub-trees shared with other trees are left to them
+            if let Some(mut node) = Arc::into_inner(child.0) {
+                children.append(&mut node.children);
+            }
+        }
+    }
+}
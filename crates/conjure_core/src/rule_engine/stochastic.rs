@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::rule_engine::normal_forms::candidates;
+use crate::rule_engine::random::SplitMix64;
+use crate::rule_engine::rewrite::budget_exhausted;
+use crate::rule_engine::{
+    BudgetPolicy, EngineError, RewriteError, RewriteOptions, RewriteOutcome, RewriteStatus, Rule,
+};
+use crate::stats::RewriterStats;
+use crate::Model;
+
+/// Settings for rewriting by random choice, set with [`RewriteOptions::stochastic`].
+///
+/// At each step, every rewrite any rule makes of any expression in the constraints is found, and
+/// one is chosen at random, with a chance in proportion to the weight of the rule that made it.
+/// The same seed and rules always make the same choices, so a run can be repeated from the seed
+/// in [`RewriteOutcome::seed`](crate::rule_engine::RewriteOutcome::seed).
+///
+/// # Example
+/// ```rust
+/// use conjure_core::rule_engine::{RewriteOptions, Stochastic};
+///
+/// let options = RewriteOptions::new()
+///     .stochastic(Stochastic::new(42).weight("lt_to_gt", 0.5))
+///     .max_rewrites(1000);
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stochastic {
+    pub seed: u64,
+    /// The weight of each rule, by name. Rules not given a weight have a weight of 1.
+    pub weights: HashMap<String, f64>,
+}
+
+impl Stochastic {
+    /// Choose rewrites from `seed`.
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            weights: HashMap::new(),
+        }
+    }
+
+    /// Choose rewrites from a seed taken from the clock, so that each run differs.
+    pub fn from_clock() -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos());
+        Self::new(nanos as u64)
+    }
+
+    /// Choose the rewrites of the rule named `rule` with a chance in proportion to `weight`,
+    /// rather than 1. Rules with a weight of 0 or less are never applied.
+    pub fn weight(mut self, rule: &str, weight: f64) -> Self {
+        self.weights.insert(rule.to_string(), weight);
+        self
+    }
+}
+
+/// Rewrites `model` by a random rewrite at each step. See [`RewriteOptions::stochastic`].
+pub(super) fn rewrite_stochastic(
+    model: &Model,
+    rules: &[&Rule],
+    stochastic: &Stochastic,
+    options: &RewriteOptions,
+) -> Result<RewriteOutcome, RewriteError> {
+    let start = Instant::now();
+    let mut random = SplitMix64::new(stochastic.seed);
+    let mut new_model = model.clone();
+    let mut trace = options.trace.then(Vec::new);
+    let mut rewrites = 0;
+    let mut status = RewriteStatus::Fixpoint;
+    loop {
+        if budget_exhausted(options, rewrites, 0) {
+            status = RewriteStatus::BudgetExhausted;
+            break;
+        }
+        if options
+            .timeout
+            .is_some_and(|timeout| start.elapsed() >= timeout)
+        {
+            status = RewriteStatus::Timeout;
+            break;
+        }
+        if options
+            .cancel
+            .as_ref()
+            .is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+        {
+            status = RewriteStatus::Cancelled;
+            break;
+        }
+
+        let mut found = candidates(&new_model, rules);
+        let weights: Vec<f64> = found
+            .iter()
+            .map(|candidate| {
+                let weight = stochastic.weights.get(candidate.rule.name);
+                weight.copied().unwrap_or(1.0).max(0.0)
+            })
+            .collect();
+        let total: f64 = weights.iter().sum();
+        if total <= 0.0 {
+            break;
+        }
+
+        // The first rewrite whose share of the total weight covers the point chosen
+        let mut point = random.unit() * total;
+        let mut chosen = weights
+            .iter()
+            .rposition(|&weight| weight > 0.0)
+            .unwrap_or(0);
+        for (i, &weight) in weights.iter().enumerate() {
+            if weight > 0.0 && point < weight {
+                chosen = i;
+                break;
+            }
+            point -= weight;
+        }
+        let candidate = found.swap_remove(chosen);
+        if let Some(trace) = &mut trace {
+            trace.push(candidate.trace_step(&new_model.constraints));
+        }
+        new_model = candidate.apply(&new_model);
+        rewrites += 1;
+    }
+
+    if let Ok(mut context) = model.context.write() {
+        context.stats.add_rewriter_run(RewriterStats {
+            is_optimization_enabled: None,
+            rewriter_run_time: Some(start.elapsed()),
+            rewriter_rule_application_attempts: None,
+            rewriter_rule_applications: Some(rewrites),
+            rewriter_peak_memory: None,
+        });
+    }
+
+    let mut error = None;
+    if status == RewriteStatus::BudgetExhausted {
+        log::warn!(target: "file", "Stochastic rewriting with seed {} stopped after {} rewrites", stochastic.seed, rewrites);
+        if options.on_budget_exhausted == BudgetPolicy::Error {
+            error = Some(
+                EngineError::BudgetExhausted {
+                    rewrites,
+                    attempts: 0,
+                }
+                .into(),
+            );
+        }
+    }
+    match error {
+        Some(error) if !options.partial_on_error => return Err(error),
+        Some(_) => status = RewriteStatus::Error,
+        None => {}
+    }
+    Ok(RewriteOutcome {
+        model: new_model,
+        status,
+        slow_rules: Vec::new(),
+        discarded_effects: Vec::new(),
+        quarantined: Vec::new(),
+        error,
+        perf: None,
+        profile: None,
+        heatmap: None,
+        trace,
+        seed: Some(stochastic.seed),
+        ambiguities: Vec::new(),
+        choices: Vec::new(),
+        ignored_options: Vec::new(),
+    })
+}
@@ -0,0 +1,115 @@
+use std::ops::Deref;
+
+use crate::ast::Expression;
+
+/// A handle to the expression a rule is applied to, which lets the rule take the expression
+/// rather than clone it, when it has decided to apply.
+///
+/// When the rewriter owns the expression, taking it moves it out of the tree, so a rule that only
+/// rearranges the pieces of the expression does not pay for a deep copy. Otherwise, taking it
+/// clones it, as rules that take `&Expression` do.
+///
+/// A rule must only take the expression once it is sure to succeed, as the rewriter cannot put it
+/// back: taking the expression and then returning an error is reported as
+/// [`RuleErrorKind::TakenWithoutRewrite`](crate::rule_engine::RuleErrorKind::TakenWithoutRewrite).
+/// Taking the expression and returning it unchanged is a no-op rewrite like any other, handled as
+/// [`RewriteOptions::on_no_op`](crate::rule_engine::RewriteOptions::on_no_op) says.
+/// Sub-expressions cannot be moved out of the taken expression by pattern, so take them with
+/// [`std::mem::take`]. A rule that changes the children of the taken expression in place must call
+/// [`Expression::invalidate_annotations`] on it.
+///
+/// # Example
+/// ```rust
+/// use conjure_core::ast::Expression;
+/// use conjure_core::model::Model;
+/// use conjure_core::rule_engine::{register_rule, ApplicationError, ApplicationResult, Reduction, Subtree};
+///
+/// #[register_rule(("RuleSetName", 10))]
+/// fn unwrap_not(expr: &mut Subtree, _: &Model) -> ApplicationResult {
+///     match expr.take_if(|e| matches!(e, Expression::Not(_, _))) {
+///         Some(Expression::Not(_, ref mut contents)) => Ok(Reduction::pure(std::mem::take(&mut **contents))),
+///         _ => Err(ApplicationError::RuleNotApplicable),
+///     }
+/// }
+/// ```
+#[derive(Debug)]
+pub struct Subtree<'a> {
+    expression: Handle<'a>,
+    taken: bool,
+}
+
+#[derive(Debug)]
+enum Handle<'a> {
+    Borrowed(&'a Expression),
+    Owned(&'a mut Expression),
+}
+
+impl<'a> Subtree<'a> {
+    /// A handle to an expression the rule may not move out of. Taking it clones it.
+    pub fn borrowed(expression: &'a Expression) -> Self {
+        Self {
+            expression: Handle::Borrowed(expression),
+            taken: false,
+        }
+    }
+
+    /// A handle to an expression the rule may move out of. Taking it leaves `Expression::Nothing`
+    /// in its place.
+    pub fn owned(expression: &'a mut Expression) -> Self {
+        Self {
+            expression: Handle::Owned(expression),
+            taken: false,
+        }
+    }
+
+    /// Takes the expression, without cloning it if it is owned.
+    pub fn take(&mut self) -> Expression {
+        self.taken = true;
+        match &mut self.expression {
+            Handle::Borrowed(expression) => (*expression).clone(),
+            Handle::Owned(expression) => std::mem::replace(*expression, Expression::Nothing),
+        }
+    }
+
+    /// Takes the expression if it satisfies `predicate`, without cloning it if it is owned.
+    ///
+    /// # Returns
+    /// - Some(<expression>) if `predicate` holds.
+    /// - None otherwise, leaving the expression in place.
+    pub fn take_if(&mut self, predicate: impl FnOnce(&Expression) -> bool) -> Option<Expression> {
+        match predicate(self) {
+            true => Some(self.take()),
+            false => None,
+        }
+    }
+
+    /// Whether the expression has been taken.
+    pub fn is_taken(&self) -> bool {
+        self.taken
+    }
+
+    /// Whether taking the expression moves it out of the tree, rather than cloning it.
+    pub(super) fn is_owned(&self) -> bool {
+        matches!(self.expression, Handle::Owned(_))
+    }
+
+    /// Puts back an expression equal to the one taken, so that other rules can be tried on it.
+    pub(super) fn restore(&mut self, expression: Expression) {
+        if let Handle::Owned(slot) = &mut self.expression {
+            **slot = expression;
+        }
+        self.taken = false;
+    }
+}
+
+impl<'a> Deref for Subtree<'a> {
+    type Target = Expression;
+
+    /// The expression, or `Expression::Nothing` if it was owned and has been taken.
+    fn deref(&self) -> &Expression {
+        match &self.expression {
+            Handle::Borrowed(expression) => expression,
+            Handle::Owned(expression) => expression,
+        }
+    }
+}
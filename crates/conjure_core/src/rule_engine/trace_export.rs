@@ -0,0 +1,52 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::ast::Expression;
+use crate::error::Result;
+use crate::rule_engine::TraceStep;
+
+/// A rewrite trace, along with the constraints it starts from, for tools outside Rust.
+///
+/// Each step records its rule, path, edits, and timing, so the constraints after any step can be
+/// rebuilt by applying the steps before it in order, as [`TraceStep::apply`] does. Saved as JSON
+/// with [`TraceExport::save`] or [`TraceExport::to_json`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TraceExport {
+    /// The constraints before the first step.
+    pub constraints: Expression,
+    /// The rewrites applied, in order.
+    pub steps: Vec<TraceStep>,
+}
+
+impl TraceExport {
+    /// Exports `steps`, a trace returned in
+    /// [`RewriteOutcome::trace`](crate::rule_engine::RewriteOutcome::trace), of rewriting
+    /// `constraints`.
+    pub fn new(constraints: &Expression, steps: &[TraceStep]) -> Self {
+        Self {
+            constraints: constraints.clone(),
+            steps: steps.to_vec(),
+        }
+    }
+
+    /// The trace as a JSON string.
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// Writes the trace to a file as JSON.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let file = File::create(path).map_err(anyhow::Error::from)?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+
+    /// Reads a trace written by [`TraceExport::save`].
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let file = File::open(path).map_err(anyhow::Error::from)?;
+        Ok(serde_json::from_reader(BufReader::new(file))?)
+    }
+}
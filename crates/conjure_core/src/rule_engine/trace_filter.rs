@@ -0,0 +1,104 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use derivative::Derivative;
+
+use crate::rule_engine::TraceStep;
+
+/// Decides whether to record a rewrite in the trace. See [`TraceFilter::matching`].
+pub type TracePredicate = Arc<dyn Fn(&TraceStep) -> bool + Send + Sync>;
+
+/// Which rewrites to record in the trace, set with
+/// [`RewriteOptions::trace_filter`](crate::rule_engine::RewriteOptions::trace_filter).
+///
+/// Full traces of large models can take gigabytes. A step is recorded only if it passes every
+/// filter set, and is then sampled with [`TraceFilter::every`]. A filtered trace leaves out some
+/// rewrites, so it cannot be replayed with [`TraceStep::apply`].
+#[derive(Clone, Default, Derivative)]
+#[derivative(Debug)]
+pub struct TraceFilter {
+    /// Record only rewrites made by these rules, if set.
+    pub rules: Option<HashSet<String>>,
+    /// Record only rewrites at or below this path, if set.
+    pub path_prefix: Option<Vec<usize>>,
+    /// Record only rewrites at most this many levels below the root of the constraints, if set.
+    pub max_depth: Option<usize>,
+    /// Record only rewrites matching this predicate, if set.
+    #[derivative(Debug = "ignore")]
+    pub predicate: Option<TracePredicate>,
+    /// Record only every `every`th rewrite that passes the other filters. 0 and 1 record all of
+    /// them.
+    pub every: usize,
+}
+
+impl TraceFilter {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Record only rewrites made by the rules named in `rules`.
+    pub fn rules<S: Into<String>>(self, rules: impl IntoIterator<Item = S>) -> Self {
+        Self {
+            rules: Some(rules.into_iter().map(Into::into).collect()),
+            ..self
+        }
+    }
+
+    /// Record only rewrites of the expression at `path`, given as child indices from the root of
+    /// the constraints, or of its sub-expressions.
+    pub fn path_prefix(self, path: Vec<usize>) -> Self {
+        Self {
+            path_prefix: Some(path),
+            ..self
+        }
+    }
+
+    /// Record only rewrites of expressions at most `depth` levels below the root of the
+    /// constraints.
+    pub fn max_depth(self, depth: usize) -> Self {
+        Self {
+            max_depth: Some(depth),
+            ..self
+        }
+    }
+
+    /// Record only rewrites for which `predicate` returns true.
+    pub fn matching(self, predicate: impl Fn(&TraceStep) -> bool + Send + Sync + 'static) -> Self {
+        Self {
+            predicate: Some(Arc::new(predicate)),
+            ..self
+        }
+    }
+
+    /// Record only the first of every `n` rewrites that pass the other filters.
+    ///
+    /// Rewrites are counted separately by each thread sharing a pass in work stealing mode, so
+    /// sampling is only approximate there.
+    pub fn every(self, n: usize) -> Self {
+        Self { every: n, ..self }
+    }
+
+    /// Returns true if a rewrite by `rule` at `path` passes the filters that do not need the
+    /// rewrite itself, so that the step need not be built for rewrites that fail them.
+    pub(super) fn allows(&self, rule: &str, path: &[usize]) -> bool {
+        self.rules.as_ref().is_none_or(|rules| rules.contains(rule))
+            && self
+                .path_prefix
+                .as_ref()
+                .is_none_or(|prefix| path.starts_with(prefix))
+            && self.max_depth.is_none_or(|depth| path.len() <= depth)
+    }
+
+    /// Returns true if `step` passes the predicate, if any.
+    pub(super) fn matches(&self, step: &TraceStep) -> bool {
+        self.predicate
+            .as_ref()
+            .is_none_or(|predicate| predicate(step))
+    }
+
+    /// Returns true if the rewrite numbered `count`, counting from 0 the rewrites that passed the
+    /// filters, is sampled.
+    pub(super) fn samples(&self, count: usize) -> bool {
+        self.every <= 1 || count.is_multiple_of(self.every)
+    }
+}
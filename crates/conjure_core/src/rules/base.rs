@@ -3,7 +3,7 @@ use conjure_core::ast::{
 };
 use conjure_core::metadata::Metadata;
 use conjure_core::rule_engine::{
-    register_rule, register_rule_set, ApplicationError, ApplicationResult, Reduction,
+    register_rule, register_rule_set, ApplicationError, ApplicationResult, Reduction, Subtree,
 };
 use conjure_core::Model;
 use uniplate::uniplate::Uniplate;
@@ -138,10 +138,10 @@ fn sum_constants(expr: &Expr, _: &Model) -> ApplicationResult {
  * sum([a]) = a
  * ```
  */
-#[register_rule(("Base", 100))]
-fn unwrap_sum(expr: &Expr, _: &Model) -> ApplicationResult {
-    match expr {
-        Expr::Sum(_, exprs) if (exprs.len() == 1) => Ok(Reduction::pure(exprs[0].clone())),
+#[register_rule(("Base", 100), applies_to(Sum), produces())]
+fn unwrap_sum(expr: &mut Subtree, _: &Model) -> ApplicationResult {
+    match expr.take_if(|e| matches!(e, Expr::Sum(_, exprs) if exprs.len() == 1)) {
+        Some(Expr::Sum(_, ref mut exprs)) => Ok(Reduction::pure(exprs.swap_remove(0))),
         _ => Err(ApplicationError::RuleNotApplicable),
     }
 }
@@ -251,11 +251,13 @@ fn unwrap_nested_and(expr: &Expr, _: &Model) -> ApplicationResult {
 * not(not(a)) = a
 * ```
  */
-#[register_rule(("Base", 100))]
-fn remove_double_negation(expr: &Expr, _: &Model) -> ApplicationResult {
-    match expr {
-        Expr::Not(_, contents) => match contents.as_ref() {
-            Expr::Not(_, expr_box) => Ok(Reduction::pure(*expr_box.clone())),
+#[register_rule(("Base", 100), applies_to(Not), produces())]
+fn remove_double_negation(expr: &mut Subtree, _: &Model) -> ApplicationResult {
+    match expr
+        .take_if(|e| matches!(e, Expr::Not(_, contents) if matches!(**contents, Expr::Not(_, _))))
+    {
+        Some(Expr::Not(_, ref mut contents)) => match contents.as_mut() {
+            Expr::Not(_, expr_box) => Ok(Reduction::pure(std::mem::take(&mut **expr_box))),
             _ => Err(ApplicationError::RuleNotApplicable),
         },
         _ => Err(ApplicationError::RuleNotApplicable),
@@ -268,15 +270,10 @@ fn remove_double_negation(expr: &Expr, _: &Model) -> ApplicationResult {
  * and([a]) = a
  * ```
  */
-#[register_rule(("Base", 100))]
-fn remove_trivial_and(expr: &Expr, _: &Model) -> ApplicationResult {
-    match expr {
-        Expr::And(_, exprs) => {
-            if exprs.len() == 1 {
-                return Ok(Reduction::pure(exprs[0].clone()));
-            }
-            Err(ApplicationError::RuleNotApplicable)
-        }
+#[register_rule(("Base", 100), applies_to(And), produces())]
+fn remove_trivial_and(expr: &mut Subtree, _: &Model) -> ApplicationResult {
+    match expr.take_if(|e| matches!(e, Expr::And(_, exprs) if exprs.len() == 1)) {
+        Some(Expr::And(_, ref mut exprs)) => Ok(Reduction::pure(exprs.swap_remove(0))),
         _ => Err(ApplicationError::RuleNotApplicable),
     }
 }
@@ -287,15 +284,10 @@ fn remove_trivial_and(expr: &Expr, _: &Model) -> ApplicationResult {
  * or([a]) = a
  * ```
  */
-#[register_rule(("Base", 100))]
-fn remove_trivial_or(expr: &Expr, _: &Model) -> ApplicationResult {
-    match expr {
-        Expr::Or(_, exprs) => {
-            if exprs.len() == 1 {
-                return Ok(Reduction::pure(exprs[0].clone()));
-            }
-            Err(ApplicationError::RuleNotApplicable)
-        }
+#[register_rule(("Base", 100), applies_to(Or), produces())]
+fn remove_trivial_or(expr: &mut Subtree, _: &Model) -> ApplicationResult {
+    match expr.take_if(|e| matches!(e, Expr::Or(_, exprs) if exprs.len() == 1)) {
+        Some(Expr::Or(_, ref mut exprs)) => Ok(Reduction::pure(exprs.swap_remove(0))),
         _ => Err(ApplicationError::RuleNotApplicable),
     }
 }
@@ -498,7 +490,7 @@ fn distribute_or_over_and(expr: &Expr, _: &Model) -> ApplicationResult {
                 let mut rest = exprs.clone();
                 let and_expr = rest.remove(idx);
 
-                match and_expr {
+                match &and_expr {
                     Expr::And(metadata, and_exprs) => {
                         let mut new_and_contents = Vec::new();
 
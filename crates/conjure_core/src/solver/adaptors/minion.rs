@@ -262,23 +262,23 @@ fn parse_expr(
 }
 
 fn read_expr(expr: conjure_ast::Expression) -> Result<minion_ast::Constraint, SolverError> {
-    match expr {
+    match &expr {
         conjure_ast::Expression::SumLeq(_metadata, lhs, rhs) => Ok(minion_ast::Constraint::SumLeq(
             read_vars(lhs)?,
-            read_var(*rhs)?,
+            read_var(rhs)?,
         )),
         conjure_ast::Expression::SumGeq(_metadata, lhs, rhs) => Ok(minion_ast::Constraint::SumGeq(
             read_vars(lhs)?,
-            read_var(*rhs)?,
+            read_var(rhs)?,
         )),
         conjure_ast::Expression::Ineq(_metadata, a, b, c) => Ok(minion_ast::Constraint::Ineq(
-            read_var(*a)?,
-            read_var(*b)?,
-            minion_ast::Constant::Integer(read_const(*c)?),
+            read_var(a)?,
+            read_var(b)?,
+            minion_ast::Constant::Integer(read_const(c)?),
         )),
         conjure_ast::Expression::Neq(_metadata, a, b) => Ok(minion_ast::Constraint::AllDiff(vec![
-            read_var(*a)?,
-            read_var(*b)?,
+            read_var(a)?,
+            read_var(b)?,
         ])),
         // conjure_ast::Expression::DivEq(_metadata, a, b, c) => {
         //     minion_model.constraints.push(minion_ast::Constraint::Div(
@@ -299,7 +299,7 @@ fn read_expr(expr: conjure_ast::Expression) -> Result<minion_ast::Constraint, So
         x => Err(ModelFeatureNotSupported(format!("{:?}", x))),
     }
 }
-fn read_vars(exprs: Vec<conjure_ast::Expression>) -> Result<Vec<minion_ast::Var>, SolverError> {
+fn read_vars(exprs: &[conjure_ast::Expression]) -> Result<Vec<minion_ast::Var>, SolverError> {
     let mut minion_vars: Vec<minion_ast::Var> = vec![];
     for expr in exprs {
         let minion_var = read_var(expr)?;
@@ -308,9 +308,9 @@ fn read_vars(exprs: Vec<conjure_ast::Expression>) -> Result<Vec<minion_ast::Var>
     Ok(minion_vars)
 }
 
-fn read_var(e: conjure_ast::Expression) -> Result<minion_ast::Var, SolverError> {
+fn read_var(e: &conjure_ast::Expression) -> Result<minion_ast::Var, SolverError> {
     // a minion var is either a reference or a "var as const"
-    match _read_ref(e.clone()) {
+    match _read_ref(e) {
         Ok(name) => Ok(minion_ast::Var::NameRef(name)),
         Err(_) => match read_const(e) {
             Ok(n) => Ok(minion_ast::Var::ConstantAsVar(n)),
@@ -319,9 +319,9 @@ fn read_var(e: conjure_ast::Expression) -> Result<minion_ast::Var, SolverError>
     }
 }
 
-fn _read_ref(e: conjure_ast::Expression) -> Result<String, SolverError> {
+fn _read_ref(e: &conjure_ast::Expression) -> Result<String, SolverError> {
     let name = match e {
-        conjure_ast::Expression::Reference(_metadata, n) => Ok(n),
+        conjure_ast::Expression::Reference(_metadata, n) => Ok(n.clone()),
         x => Err(ModelInvalid(format!(
             "expected a reference, but got `{0:?}`",
             x
@@ -332,9 +332,9 @@ fn _read_ref(e: conjure_ast::Expression) -> Result<String, SolverError> {
     Ok(str_name)
 }
 
-fn read_const(e: conjure_ast::Expression) -> Result<i32, SolverError> {
+fn read_const(e: &conjure_ast::Expression) -> Result<i32, SolverError> {
     match e {
-        conjure_ast::Expression::Constant(_, conjure_ast::Constant::Int(n)) => Ok(n),
+        conjure_ast::Expression::Constant(_, conjure_ast::Constant::Int(n)) => Ok(*n),
         x => Err(ModelInvalid(format!(
             "expected a constant, but got `{0:?}`",
             x
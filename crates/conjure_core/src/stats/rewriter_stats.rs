@@ -12,4 +12,7 @@ pub struct RewriterStats {
     pub rewriter_run_time: Option<std::time::Duration>,
     pub rewriter_rule_application_attempts: Option<usize>,
     pub rewriter_rule_applications: Option<usize>,
+    /// The most memory, in approximate bytes, held at once for copies of the constraints and for
+    /// pending rewrites. Only tracked if `RewriteOptions::track_memory` is set.
+    pub rewriter_peak_memory: Option<usize>,
 }
@@ -0,0 +1,201 @@
+//! Rendering of expression trees and rewrites as [Graphviz](https://graphviz.org) DOT graphs, and
+//! of rewrites as text.
+//!
+//! The DOT output can be drawn with, for example, `dot -Tsvg`.
+
+use std::fmt::Write;
+
+use crate::ast::Expression;
+use crate::rule_engine::TraceStep;
+
+/// The default label of a node: the value of constants and references, and the name of the
+/// variant of other expressions.
+pub fn label(expression: &Expression) -> String {
+    match expression {
+        Expression::Constant(_, constant) => constant.to_string(),
+        Expression::Reference(_, name) => name.to_string(),
+        _ => expression.variant_name().to_string(),
+    }
+}
+
+/// Renders `expression` as a DOT graph, with one node per sub-expression, labelled by [`label`].
+pub fn to_dot(expression: &Expression) -> String {
+    to_dot_with(expression, label)
+}
+
+/// Renders `expression` as a DOT graph, with each node labelled by `labeler`.
+pub fn to_dot_with(expression: &Expression, labeler: impl Fn(&Expression) -> String) -> String {
+    let mut graph = DotGraph::new(&labeler, None);
+    graph.add(expression, None, None, false);
+    graph.finish()
+}
+
+/// Renders a rewrite trace, recorded with
+/// [`RewriteOptions::trace`](crate::rule_engine::RewriteOptions::trace), as a sequence of DOT
+/// graphs, with nodes labelled by [`label`].
+///
+/// See [`trace_to_dot_with`].
+pub fn trace_to_dot(constraints: &Expression, trace: &[TraceStep]) -> Vec<String> {
+    trace_to_dot_with(constraints, trace, label)
+}
+
+/// Renders a rewrite trace as a sequence of DOT graphs, with each node labelled by `labeler`.
+///
+/// The first graph is of `constraints`, the constraints the rewriter started from. The rewrites in
+/// `trace` are then replayed on them in order, and each is followed by a graph of the whole of the
+/// constraints, titled with the rule and path, and with the rewritten expression highlighted.
+///
+/// A step that does not fit the constraints, because the trace is not of these constraints,
+/// leaves them unchanged, and its graph has nothing highlighted.
+pub fn trace_to_dot_with(
+    constraints: &Expression,
+    trace: &[TraceStep],
+    labeler: impl Fn(&Expression) -> String,
+) -> Vec<String> {
+    let mut current = constraints.clone();
+    let mut graphs = vec![to_dot_with(&current, &labeler)];
+    for step in trace {
+        let title = format!("{} at {:?}", step.rule, step.path);
+        let mut graph = DotGraph::new(&labeler, Some(&title));
+        let mut next = current.clone();
+        if step.apply(&mut next) {
+            current = next;
+            graph.add(&current, None, Some(&step.path), false);
+        } else {
+            graph.add(&current, None, None, false);
+        }
+        graphs.push(graph.finish());
+    }
+    graphs
+}
+
+/// Renders a rewrite trace, recorded with
+/// [`RewriteOptions::trace`](crate::rule_engine::RewriteOptions::trace), as text, with
+/// expressions shown by their `Display` implementation and cut short after `width` characters.
+///
+/// See [`trace_to_text_with`].
+pub fn trace_to_text(constraints: &Expression, trace: &[TraceStep], width: usize) -> String {
+    trace_to_text_with(constraints, trace, width, Expression::to_string)
+}
+
+/// Renders a rewrite trace as text, one rewrite per line, with each expression shown by
+/// `formatter`.
+///
+/// The rewrites in `trace` are replayed in order on `constraints`, the constraints the rewriter
+/// started from, and each is shown as `rule @ path: before ~> after`, followed by a line for the
+/// new top-level constraint it added, if any. Each expression longer than `width` characters is
+/// cut short, ending with `…`, so huge sub-expressions do not swamp the output.
+///
+/// A step that does not fit the constraints, because the trace is not of these constraints,
+/// leaves them unchanged, and is shown without its expressions.
+pub fn trace_to_text_with(
+    constraints: &Expression,
+    trace: &[TraceStep],
+    width: usize,
+    formatter: impl Fn(&Expression) -> String,
+) -> String {
+    let show = |expression: &Expression| elide(formatter(expression), width);
+    let mut current = constraints.clone();
+    let mut out = String::new();
+    for step in trace {
+        let mut next = current.clone();
+        let before = current.at_path(&step.path);
+        if let (Some(before), true) = (before, step.apply(&mut next)) {
+            let after = next.at_path(&step.path).unwrap_or(&Expression::Nothing);
+            let _ = writeln!(
+                out,
+                "{} @ {:?}: {} ~> {}",
+                step.rule,
+                step.path,
+                show(before),
+                show(after)
+            );
+            if !step.new_top.is_nothing() {
+                let _ = writeln!(out, "    + {}", show(&step.new_top));
+            }
+            current = next;
+        } else {
+            let _ = writeln!(
+                out,
+                "{} @ {:?}: does not fit the constraints",
+                step.rule, step.path
+            );
+        }
+    }
+    out
+}
+
+/// Cuts `text` short to at most `width` characters, ending with `…` if anything was left out.
+fn elide(text: String, width: usize) -> String {
+    if text.chars().count() <= width {
+        return text;
+    }
+    let mut elided: String = text.chars().take(width.saturating_sub(1)).collect();
+    elided.push('…');
+    elided
+}
+
+/// A DOT graph being written.
+struct DotGraph<'a, F: Fn(&Expression) -> String> {
+    labeler: &'a F,
+    out: String,
+    /// The number of nodes written so far, used to name the next node.
+    nodes: usize,
+}
+
+impl<'a, F: Fn(&Expression) -> String> DotGraph<'a, F> {
+    fn new(labeler: &'a F, title: Option<&str>) -> Self {
+        let mut out = String::from("digraph {\n    node [shape=box];\n");
+        if let Some(title) = title {
+            let _ = writeln!(out, "    label=\"{}\";", escape(title));
+        }
+        Self {
+            labeler,
+            out,
+            nodes: 0,
+        }
+    }
+
+    /// Adds `expression` and its sub-expressions below the node `parent`. They are all
+    /// highlighted if `highlighted` is set, and otherwise only the sub-expression at the path
+    /// `highlight` from `expression`, and its own sub-expressions, are.
+    fn add(
+        &mut self,
+        expression: &Expression,
+        parent: Option<usize>,
+        highlight: Option<&[usize]>,
+        highlighted: bool,
+    ) {
+        let id = self.nodes;
+        self.nodes += 1;
+        let highlighted = highlighted || highlight.is_some_and(|path| path.is_empty());
+        let style = match highlighted {
+            true => ", style=filled, fillcolor=yellow",
+            false => "",
+        };
+        let label = escape(&(self.labeler)(expression));
+        let _ = writeln!(self.out, "    n{} [label=\"{}\"{}];", id, label, style);
+        if let Some(parent) = parent {
+            let _ = writeln!(self.out, "    n{} -> n{};", parent, id);
+        }
+
+        let mut i = 0;
+        while let Some(child) = expression.child(i) {
+            let child_highlight = highlight
+                .and_then(|path| path.split_first())
+                .and_then(|(&index, rest)| (index == i).then_some(rest));
+            self.add(child, Some(id), child_highlight, highlighted);
+            i += 1;
+        }
+    }
+
+    fn finish(mut self) -> String {
+        self.out.push_str("}\n");
+        self.out
+    }
+}
+
+/// Escapes `label` for use in a quoted DOT string.
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"")
+}
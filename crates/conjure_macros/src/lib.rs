@@ -3,10 +3,11 @@ use proc_macro::TokenStream;
 use proc_macro2::Span;
 use quote::quote;
 use syn::punctuated::Punctuated;
+use syn::spanned::Spanned;
 use syn::token::Comma;
 use syn::{
-    parenthesized, parse::Parse, parse::ParseStream, parse_macro_input, Ident, ItemFn, LitInt,
-    LitStr, Path, Result,
+    parenthesized, parse::Parse, parse::ParseStream, parse_macro_input, FnArg, Ident, ItemFn,
+    LitInt, LitStr, Path, Result, Type,
 };
 
 #[derive(Debug)]
@@ -26,22 +27,118 @@ impl Parse for RuleSetAndPriority {
     }
 }
 
+#[derive(Debug)]
+enum RegisterRuleArg {
+    RuleSet(RuleSetAndPriority),
+    Pure,
+    AppliesTo(Vec<Ident>),
+    Produces(Vec<Ident>),
+    ReadsMeta(Vec<Type>),
+    WritesMeta(Vec<Type>),
+}
+
+impl Parse for RegisterRuleArg {
+    fn parse(input: ParseStream) -> Result<Self> {
+        if input.peek(Ident) {
+            let ident: Ident = input.parse()?;
+            return match ident.to_string().as_str() {
+                "pure" => Ok(RegisterRuleArg::Pure),
+                "applies_to" => Ok(RegisterRuleArg::AppliesTo(parse_parenthesized(input)?)),
+                "produces" => Ok(RegisterRuleArg::Produces(parse_parenthesized(input)?)),
+                "reads_meta" => Ok(RegisterRuleArg::ReadsMeta(parse_parenthesized(input)?)),
+                "writes_meta" => Ok(RegisterRuleArg::WritesMeta(parse_parenthesized(input)?)),
+                _ => Err(syn::Error::new(
+                    ident.span(),
+                    "expected a (rule set, priority) pair, `pure`, `applies_to(..)`, `produces(..)`, `reads_meta(..)`, or `writes_meta(..)`",
+                )),
+            };
+        }
+        Ok(RegisterRuleArg::RuleSet(input.parse()?))
+    }
+}
+
 #[derive(Debug)]
 struct RegisterRuleArgs {
     pub rule_sets: Vec<RuleSetAndPriority>,
+    pub pure: bool,
+    pub applies_to: Option<Vec<Ident>>,
+    pub produces: Option<Vec<Ident>>,
+    pub reads_meta: Option<Vec<Type>>,
+    pub writes_meta: Option<Vec<Type>>,
 }
 
 impl Parse for RegisterRuleArgs {
     fn parse(input: ParseStream) -> Result<Self> {
-        let rule_sets = Punctuated::<RuleSetAndPriority, Comma>::parse_terminated(input)?;
+        let args = Punctuated::<RegisterRuleArg, Comma>::parse_terminated(input)?;
+        let mut rule_sets = Vec::new();
+        let mut pure = false;
+        let mut applies_to = None;
+        let mut produces = None;
+        let mut reads_meta = None;
+        let mut writes_meta = None;
+        for arg in args {
+            match arg {
+                RegisterRuleArg::RuleSet(rule_set) => rule_sets.push(rule_set),
+                RegisterRuleArg::Pure => pure = true,
+                RegisterRuleArg::AppliesTo(variants) => applies_to = Some(variants),
+                RegisterRuleArg::Produces(variants) => produces = Some(variants),
+                RegisterRuleArg::ReadsMeta(types) => reads_meta = Some(types),
+                RegisterRuleArg::WritesMeta(types) => writes_meta = Some(types),
+            }
+        }
         Ok(RegisterRuleArgs {
-            rule_sets: rule_sets.into_iter().collect(),
+            rule_sets,
+            pure,
+            applies_to,
+            produces,
+            reads_meta,
+            writes_meta,
         })
     }
 }
 
+/// Expands a list of variant names to `Some(&["Variant", ...])`, or `None` if there is no list.
+fn variant_names(variants: &Option<Vec<Ident>>) -> proc_macro2::TokenStream {
+    match variants {
+        Some(variants) => {
+            let names = variants.iter().map(|variant| variant.to_string());
+            quote! { Some(&[#(#names),*]) }
+        }
+        None => quote! { None },
+    }
+}
+
+/// Expands a list of types to `Some(&[TypeId::of::<Type> as MetaKey, ...])`, or `None` if there
+/// is no list.
+fn meta_keys(types: &Option<Vec<Type>>) -> proc_macro2::TokenStream {
+    match types {
+        Some(types) => quote! {
+            Some(&[#(::std::any::TypeId::of::<#types> as ::conjure_core::meta::MetaKey),*])
+        },
+        None => quote! { None },
+    }
+}
+
 /**
  * Register a rule with the given rule sets and priorities.
+ *
+ * Add `pure` to the arguments to mark the rule as pure (see `Rule::pure`), e.g.
+ * `#[register_rule(("MyRuleSet", 10), pure)]`.
+ *
+ * Add `applies_to(..)` and `produces(..)` to declare the variants of expression the rule can
+ * apply to and create (see `Rule::applies_to` and `Rule::produces`), e.g.
+ * `#[register_rule(("MyRuleSet", 10), applies_to(Lt), produces(Gt))]`.
+ *
+ * Add `reads_meta(..)` and `writes_meta(..)` to declare the types of meta the rule reads and sets
+ * (see `Rule::reads_meta` and `Rule::writes_meta`), e.g.
+ * `#[register_rule(("MyRuleSet", 10), reads_meta(Bounds), writes_meta(Stats))]`.
+ *
+ * If the function's first argument is a mutable reference, it is taken to be `&mut Subtree`, and
+ * the rule is also given a version that takes `&Expression`.
+ *
+ * If the function's second argument is a mutable reference, it is taken to be `&mut Model`, and
+ * the rule is applied to a copy of the model that it may change directly (see
+ * `rule_engine::apply_to_model_copy`). Such rules must take `&Expression`.
  */
 #[proc_macro_attribute]
 pub fn register_rule(arg_tokens: TokenStream, item: TokenStream) -> TokenStream {
@@ -62,6 +159,51 @@ pub fn register_rule(arg_tokens: TokenStream, item: TokenStream) -> TokenStream
             }
         })
         .collect::<Vec<_>>();
+    let pure = args.pure;
+    let applies_to = variant_names(&args.applies_to);
+    let produces = variant_names(&args.produces);
+    let reads_meta = meta_keys(&args.reads_meta);
+    let writes_meta = meta_keys(&args.writes_meta);
+
+    if takes_subtree(&func) && takes_model_mut(&func) {
+        return syn::Error::new(
+            func.sig.inputs.span(),
+            "rules that take `&mut Model` must take `&Expression`, not `&mut Subtree`",
+        )
+        .to_compile_error()
+        .into();
+    }
+    let (application, subtree_application) = match takes_subtree(&func) {
+        true => (
+            quote! {
+                {
+                    fn borrowed(
+                        expr: &::conjure_core::ast::Expression,
+                        mdl: &::conjure_core::Model,
+                    ) -> ::conjure_core::rule_engine::ApplicationResult {
+                        #rule_ident(&mut ::conjure_core::rule_engine::Subtree::borrowed(expr), mdl)
+                    }
+                    borrowed
+                }
+            },
+            quote! { Some(#rule_ident) },
+        ),
+        false if takes_model_mut(&func) => (
+            quote! {
+                {
+                    fn copied(
+                        expr: &::conjure_core::ast::Expression,
+                        mdl: &::conjure_core::Model,
+                    ) -> ::conjure_core::rule_engine::ApplicationResult {
+                        ::conjure_core::rule_engine::apply_to_model_copy(expr, mdl, #rule_ident)
+                    }
+                    copied
+                }
+            },
+            quote! { None },
+        ),
+        false => (quote! { #rule_ident }, quote! { None }),
+    };
 
     let expanded = quote! {
         #func
@@ -71,14 +213,39 @@ pub fn register_rule(arg_tokens: TokenStream, item: TokenStream) -> TokenStream
         #[::conjure_core::rule_engine::_dependencies::distributed_slice(::conjure_core::rule_engine::RULES_DISTRIBUTED_SLICE)]
         pub static #static_ident: ::conjure_core::rule_engine::Rule<'static> = ::conjure_core::rule_engine::Rule {
             name: stringify!(#rule_ident),
-            application: #rule_ident,
+            application: #application,
+            subtree_application: #subtree_application,
             rule_sets: &[#(#rule_sets),*],
+            pure: #pure,
+            applies_to: #applies_to,
+            produces: #produces,
+            reads_meta: #reads_meta,
+            writes_meta: #writes_meta,
         };
     };
 
     TokenStream::from(expanded)
 }
 
+/// Whether the first argument of a rule is a mutable reference, i.e. `&mut Subtree`.
+fn takes_subtree(func: &ItemFn) -> bool {
+    is_mut_reference(func.sig.inputs.first())
+}
+
+/// Whether the second argument of a rule is a mutable reference, i.e. `&mut Model`.
+fn takes_model_mut(func: &ItemFn) -> bool {
+    is_mut_reference(func.sig.inputs.iter().nth(1))
+}
+
+fn is_mut_reference(arg: Option<&FnArg>) -> bool {
+    match arg {
+        Some(FnArg::Typed(arg)) => {
+            matches!(arg.ty.as_ref(), Type::Reference(reference) if reference.mutability.is_some())
+        }
+        _ => false,
+    }
+}
+
 fn parse_parenthesized<T: Parse>(input: ParseStream) -> Result<Vec<T>> {
     let content;
     parenthesized!(content in input);
@@ -0,0 +1,190 @@
+//! Trees whose nodes each carry an annotation, such as a source location or a type.
+
+use crate::uniplate::{Uniplate, UniplateError};
+
+/// A node of type `T` paired with an annotation of type `A`, along with the annotated children of
+/// the node.
+///
+/// `Annotated<T, A>` implements [`Uniplate`] with the annotated children as its children, so the
+/// traversals of [`Uniplate`] can be used on the annotated tree. Rebuilding a node with
+/// [`with_children`](Uniplate::with_children) keeps its annotation, so a rule that rewrites one
+/// node leaves the annotations of the rest of the tree as they were.
+///
+/// # Example
+/// ```
+/// use uniplate::annotated::Annotated;
+/// use uniplate::uniplate::{Uniplate, UniplateError};
+///
+/// #[derive(Clone, Debug, PartialEq, Eq)]
+/// enum Ast {
+///     Int(i32),
+///     Add(Box<Ast>, Box<Ast>),
+/// }
+///
+/// impl Uniplate for Ast {
+///     fn uniplate(&self) -> (Vec<Ast>, Box<dyn Fn(Vec<Ast>) -> Result<Ast, UniplateError> + '_>) {
+///         match self {
+///             Ast::Int(i) => (vec![], Box::new(|_| Ok(Ast::Int(*i)))),
+///             Ast::Add(a, b) => (
+///                 vec![*a.clone(), *b.clone()],
+///                 Box::new(|children| match children.as_slice() {
+///                     [a, b] => Ok(Ast::Add(Box::new(a.clone()), Box::new(b.clone()))),
+///                     _ => Err(UniplateError::WrongNumberOfChildren(2, children.len())),
+///                 }),
+///             ),
+///         }
+///     }
+/// }
+///
+/// let ast = Ast::Add(Box::new(Ast::Int(1)), Box::new(Ast::Int(2)));
+/// let annotated = Annotated::new(ast, &|node| match node {
+///     Ast::Int(_) => "parsed int",
+///     Ast::Add(_, _) => "parsed add",
+/// });
+///
+/// // Only the nodes that changed are annotated again
+/// let new = Ast::Add(Box::new(Ast::Int(1)), Box::new(Ast::Int(3)));
+/// let annotated = annotated.reannotate(new.clone(), &|_| "rewritten");
+/// assert_eq!(annotated.annotations(), vec![&"rewritten", &"parsed int", &"rewritten"]);
+/// assert_eq!(annotated.strip(), new);
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Annotated<T, A> {
+    node: T,
+    annotation: A,
+    children: Vec<Annotated<T, A>>,
+}
+
+impl<T: Uniplate, A: Clone + Eq> Annotated<T, A> {
+    /// Annotates every node of `tree` with `annotate`.
+    pub fn new(tree: T, annotate: &dyn Fn(&T) -> A) -> Self {
+        let children = tree
+            .children()
+            .into_iter()
+            .map(|child| Annotated::new(child, annotate))
+            .collect();
+        Self {
+            annotation: annotate(&tree),
+            node: tree,
+            children,
+        }
+    }
+
+    /// The node, with its children.
+    pub fn node(&self) -> &T {
+        &self.node
+    }
+
+    pub fn annotation(&self) -> &A {
+        &self.annotation
+    }
+
+    pub fn annotation_mut(&mut self) -> &mut A {
+        &mut self.annotation
+    }
+
+    /// The same node, with `annotation` instead.
+    pub fn with_annotation(self, annotation: A) -> Self {
+        Self { annotation, ..self }
+    }
+
+    /// The annotated children of the node.
+    pub fn annotated_children(&self) -> &[Annotated<T, A>] {
+        &self.children
+    }
+
+    /// The tree without its annotations.
+    pub fn strip(self) -> T {
+        self.node
+    }
+
+    /// Every annotation in the tree, in pre-order.
+    pub fn annotations(&self) -> Vec<&A> {
+        let mut annotations = vec![&self.annotation];
+        for child in &self.children {
+            annotations.extend(child.annotations());
+        }
+        annotations
+    }
+
+    /// The tree with every annotation replaced by `f` of it.
+    pub fn map_annotations<B: Clone + Eq>(self, f: &dyn Fn(&A) -> B) -> Annotated<T, B> {
+        Annotated {
+            annotation: f(&self.annotation),
+            node: self.node,
+            children: self
+                .children
+                .into_iter()
+                .map(|child| child.map_annotations(f))
+                .collect(),
+        }
+    }
+
+    /// The tree with each annotation paired with the annotation of the same node in `other`.
+    ///
+    /// # Returns
+    /// - The merged tree.
+    /// - None if `other` is not an annotation of the same tree.
+    pub fn merge<B: Clone + Eq>(self, other: Annotated<T, B>) -> Option<Annotated<T, (A, B)>> {
+        if self.node != other.node || self.children.len() != other.children.len() {
+            return None;
+        }
+        let children = self
+            .children
+            .into_iter()
+            .zip(other.children)
+            .map(|(a, b)| a.merge(b))
+            .collect::<Option<_>>()?;
+        Some(Annotated {
+            node: self.node,
+            annotation: (self.annotation, other.annotation),
+            children,
+        })
+    }
+
+    /// Annotates `tree`, a new version of this tree, keeping the annotations of this tree where
+    /// it has not changed.
+    ///
+    /// Each node of `tree` is matched to the node at the same position in this tree. A node that
+    /// is the same as the node it is matched to keeps its annotations, along with its children.
+    /// Other nodes are annotated with `annotate`.
+    pub fn reannotate(&self, tree: T, annotate: &dyn Fn(&T) -> A) -> Self {
+        if tree == self.node {
+            return self.clone();
+        }
+        let children = tree
+            .children()
+            .into_iter()
+            .enumerate()
+            .map(|(i, child)| match self.children.get(i) {
+                Some(old) => old.reannotate(child, annotate),
+                None => Annotated::new(child, annotate),
+            })
+            .collect();
+        Self {
+            annotation: annotate(&tree),
+            node: tree,
+            children,
+        }
+    }
+}
+
+impl<T: Uniplate, A: Clone + Eq> Uniplate for Annotated<T, A> {
+    #[allow(clippy::type_complexity)]
+    fn uniplate(
+        &self,
+    ) -> (
+        Vec<Self>,
+        Box<dyn Fn(Vec<Self>) -> Result<Self, UniplateError> + '_>,
+    ) {
+        let context = Box::new(|children: Vec<Self>| {
+            let nodes = children.iter().map(|child| child.node.clone()).collect();
+            Ok(Self {
+                node: self.node.with_children(nodes)?,
+                annotation: self.annotation.clone(),
+                children,
+            })
+        });
+        (self.children.clone(), context)
+    }
+}
@@ -0,0 +1,138 @@
+use uniplate::annotated::Annotated;
+use uniplate::uniplate::{Uniplate, UniplateError};
+
+use self::Ast::*;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Ast {
+    Int(i32),
+    Neg(Box<Ast>),
+    Add(Box<Ast>, Box<Ast>),
+}
+
+impl Uniplate for Ast {
+    #[allow(clippy::type_complexity)]
+    fn uniplate(
+        &self,
+    ) -> (
+        Vec<Ast>,
+        Box<dyn Fn(Vec<Ast>) -> Result<Ast, UniplateError> + '_>,
+    ) {
+        match self {
+            Int(i) => (vec![], Box::new(|_| Ok(Int(*i)))),
+            Neg(a) => (
+                vec![*a.clone()],
+                Box::new(|children| match children.as_slice() {
+                    [a] => Ok(Neg(Box::new(a.clone()))),
+                    _ => Err(UniplateError::WrongNumberOfChildren(1, children.len())),
+                }),
+            ),
+            Add(a, b) => (
+                vec![*a.clone(), *b.clone()],
+                Box::new(|children| match children.as_slice() {
+                    [a, b] => Ok(Add(Box::new(a.clone()), Box::new(b.clone()))),
+                    _ => Err(UniplateError::WrongNumberOfChildren(2, children.len())),
+                }),
+            ),
+        }
+    }
+}
+
+/// Annotates each node with a made-up source location: the position of the node in pre-order.
+fn locate(tree: Ast) -> Annotated<Ast, usize> {
+    let annotated = Annotated::new(tree, &|_| 0);
+    let mut next = 0;
+    relocate(annotated, &mut next)
+}
+
+fn relocate(annotated: Annotated<Ast, usize>, next: &mut usize) -> Annotated<Ast, usize> {
+    let location = *next;
+    *next += 1;
+    let children = annotated
+        .annotated_children()
+        .iter()
+        .map(|child| relocate(child.clone(), next))
+        .collect();
+    #[allow(clippy::unwrap_used)]
+    annotated
+        .with_children(children)
+        .unwrap()
+        .with_annotation(location)
+}
+
+/// Folds negated integers into the integer, keeping the location of the negation.
+fn fold_neg(annotated: Annotated<Ast, usize>) -> Annotated<Ast, usize> {
+    match annotated.node() {
+        Neg(a) => match a.as_ref() {
+            Int(i) => Annotated::new(Int(-i), &|_| *annotated.annotation()),
+            _ => annotated,
+        },
+        _ => annotated,
+    }
+}
+
+fn example() -> Ast {
+    Add(
+        Box::new(Neg(Box::new(Int(1)))),
+        Box::new(Add(Box::new(Int(2)), Box::new(Neg(Box::new(Int(3)))))),
+    )
+}
+
+#[test]
+fn annotations_are_kept_through_traversals() {
+    let annotated = locate(example());
+    assert_eq!(annotated.annotations(), vec![&0, &1, &2, &3, &4, &5, &6]);
+
+    let folded = annotated.transform(fold_neg).unwrap();
+    assert_eq!(
+        folded.clone().strip(),
+        Add(
+            Box::new(Int(-1)),
+            Box::new(Add(Box::new(Int(2)), Box::new(Int(-3))))
+        )
+    );
+    // The folded integers take the locations of the negations they replaced
+    assert_eq!(folded.annotations(), vec![&0, &1, &3, &4, &5]);
+    assert_eq!(folded.children().len(), 2);
+}
+
+#[test]
+fn with_children_checks_the_number_of_children() {
+    let annotated = locate(example());
+    let children = annotated.children();
+    assert_eq!(
+        annotated.with_children(children[..1].to_vec()),
+        Err(UniplateError::WrongNumberOfChildren(2, 1))
+    );
+}
+
+#[test]
+fn annotations_can_be_mapped_and_merged() {
+    let locations = locate(example());
+    let depths = Annotated::new(example(), &|node| node.universe().len());
+    let merged = locations.clone().merge(depths).unwrap();
+    assert_eq!(merged.annotation(), &(0, 7));
+    assert_eq!(merged.annotations()[1], &(1, 2));
+
+    let doubled = locations.clone().map_annotations(&|location| location * 2);
+    assert_eq!(doubled.annotations()[6], &12);
+
+    // Annotations of different trees do not merge
+    let other = Annotated::new(Int(0), &|_| ());
+    assert!(locations.merge(other).is_none());
+}
+
+#[test]
+fn reannotating_keeps_annotations_of_unchanged_nodes() {
+    let annotated = locate(example());
+    let new = Add(
+        Box::new(Neg(Box::new(Int(1)))),
+        Box::new(Add(Box::new(Int(2)), Box::new(Int(7)))),
+    );
+    let reannotated = annotated.reannotate(new.clone(), &|_| 100);
+    assert_eq!(
+        reannotated.annotations(),
+        vec![&100, &1, &2, &100, &4, &100]
+    );
+    assert_eq!(reannotated.strip(), new);
+}